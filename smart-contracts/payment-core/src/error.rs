@@ -0,0 +1,37 @@
+use core::fmt;
+
+/// Errors from the shared payment logic, mapped by each program onto its own error type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CoreError {
+    /// Basis points above 10000 (100%)
+    InvalidBasisPoints = 0,
+
+    /// Platform fee and commission together exceed the payment amount
+    DeductionsExceedAmount = 1,
+
+    /// Result does not fit the target integer type
+    Overflow = 2,
+
+    /// Platform fee and commission basis points together exceed the configured cap
+    TotalFeeTooHigh = 3,
+}
+
+impl CoreError {
+    /// Stable numeric code of the core error itself. Each program reports its own error
+    /// for it, one per variant, listed in the crate README.
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidBasisPoints => "Basis points above 10000",
+            Self::DeductionsExceedAmount => "Platform fee and commission exceed the amount",
+            Self::Overflow => "Arithmetic overflow",
+            Self::TotalFeeTooHigh => "Platform fee and commission exceed the combined cap",
+        })
+    }
+}
@@ -0,0 +1,73 @@
+//! Platform fee and commission math
+//!
+//! Rounding policy: every share is `amount * bps / 10000` rounded down, computed in
+//! `u128` so no `u64` amount overflows. Whatever rounding leaves over stays in the
+//! net amount, so the merchant never receives less than its exact share.
+
+use crate::CoreError;
+
+/// Basis points making up the whole amount (10000 = 100%)
+pub const BPS_DENOMINATOR: u16 = 10000;
+
+/// Highest platform fee a config may set
+pub const MAX_PLATFORM_FEE_BPS: u16 = 1000; // 10%
+
+/// Highest commission a payment may carry
+pub const MAX_COMMISSION_BPS: u16 = BPS_DENOMINATOR; // 100%
+
+/// Default cap on platform fee and commission together, also the highest cap a config may set
+pub const DEFAULT_MAX_TOTAL_FEE_BPS: u16 = BPS_DENOMINATOR; // 100%
+
+/// How a payment divides between the fee treasury, the API key owner and the hot wallet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeSplit {
+    pub platform_fee: u64,
+    pub commission: u64,
+    /// `amount - platform_fee - commission`
+    pub net_amount: u64,
+}
+
+/// `bps` basis points of `amount`, rounded down
+pub fn bps_share(amount: u64, bps: u16) -> Result<u64, CoreError> {
+    if bps > BPS_DENOMINATOR {
+        return Err(CoreError::InvalidBasisPoints);
+    }
+
+    let share = u128::from(amount) * u128::from(bps) / u128::from(BPS_DENOMINATOR);
+    u64::try_from(share).map_err(|_| CoreError::Overflow)
+}
+
+/// Check platform fee and commission basis points together stay within `max_total_fee_bps`
+///
+/// With a cap of at most 10000, `split_payment` never fails for rates passing this check.
+pub fn check_total_fee(
+    platform_fee_bps: u16,
+    commission_bps: u16,
+    max_total_fee_bps: u16,
+) -> Result<(), CoreError> {
+    if u32::from(platform_fee_bps) + u32::from(commission_bps) > u32::from(max_total_fee_bps) {
+        return Err(CoreError::TotalFeeTooHigh);
+    }
+
+    Ok(())
+}
+
+/// Split `amount` into platform fee, commission and net amount
+pub fn split_payment(
+    amount: u64,
+    platform_fee_bps: u16,
+    commission_bps: u16,
+) -> Result<FeeSplit, CoreError> {
+    let platform_fee = bps_share(amount, platform_fee_bps)?;
+    let commission = bps_share(amount, commission_bps)?;
+    let net_amount = amount
+        .checked_sub(platform_fee)
+        .and_then(|rest| rest.checked_sub(commission))
+        .ok_or(CoreError::DeductionsExceedAmount)?;
+
+    Ok(FeeSplit {
+        platform_fee,
+        commission,
+        net_amount,
+    })
+}
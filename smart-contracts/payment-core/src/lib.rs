@@ -0,0 +1,25 @@
+//! Payment logic shared by the native and Anchor 0xMart programs
+//!
+//! Both programs split payments, price line items and derive order IDs through this
+//! crate, so the two cannot drift apart. It has no Solana dependency and
+//! builds without `std`.
+
+#![no_std]
+
+pub mod error;
+pub mod fees;
+pub mod line_items;
+pub mod order;
+
+pub use error::CoreError;
+pub use fees::{bps_share, check_total_fee, split_payment, FeeSplit};
+pub use order::order_id_hash;
+
+/// Default delay on hot wallet and platform fee changes
+pub const DEFAULT_CHANGE_DELAY_SECS: u32 = 24 * 60 * 60; // 1 day
+
+/// Default limit on how far ahead payment deadlines and quote expiries may lie
+pub const DEFAULT_MAX_QUOTE_LIFETIME_SECS: u32 = 60 * 60; // 1 hour
+
+/// Default time after payment before a processed order can be closed
+pub const DEFAULT_ORDER_RETENTION_SECS: u32 = 90 * 24 * 60 * 60; // 90 days
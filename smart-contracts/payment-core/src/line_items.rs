@@ -0,0 +1,67 @@
+//! Line items of a batch payment
+//!
+//! A cart's items are committed to by a Merkle root over their leaf hashes, so an order
+//! record stores 32 bytes however many items it holds. Leaves and inner nodes are hashed
+//! with distinct prefixes, and a node without a sibling moves up a level unchanged.
+
+use sha2::{Digest, Sha256};
+
+use crate::CoreError;
+
+/// Most line items one batch payment may hold
+pub const MAX_LINE_ITEMS: usize = 10;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// `unit_price * quantity`, failing with `Overflow` when it does not fit a `u64`
+pub fn line_amount(unit_price: u64, quantity: u32) -> Result<u64, CoreError> {
+    unit_price
+        .checked_mul(u64::from(quantity))
+        .ok_or(CoreError::Overflow)
+}
+
+/// Leaf hash of one item: SHA-256 of a zero byte, the product ID hash, then the
+/// little-endian unit price, quantity and commission basis points charged on the item
+pub fn leaf_hash(
+    product_id_hash: &[u8; 32],
+    unit_price: u64,
+    quantity: u32,
+    commission_bps: u16,
+) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(product_id_hash)
+        .chain_update(unit_price.to_le_bytes())
+        .chain_update(quantity.to_le_bytes())
+        .chain_update(commission_bps.to_le_bytes())
+        .finalize()
+        .into()
+}
+
+/// Merkle root of `leaves` in order, all zeros when there are none
+///
+/// Builds the tree in place, so `leaves` holds scratch values afterwards.
+pub fn merkle_root(leaves: &mut [[u8; 32]]) -> [u8; 32] {
+    let mut len = leaves.len();
+    if len == 0 {
+        return [0; 32];
+    }
+
+    while len > 1 {
+        for index in 0..len / 2 {
+            leaves[index] = Sha256::new()
+                .chain_update([NODE_PREFIX])
+                .chain_update(leaves[2 * index])
+                .chain_update(leaves[2 * index + 1])
+                .finalize()
+                .into();
+        }
+        if len % 2 == 1 {
+            leaves[len / 2] = leaves[len - 1];
+        }
+        len = len.div_ceil(2);
+    }
+
+    leaves[0]
+}
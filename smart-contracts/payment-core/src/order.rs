@@ -0,0 +1,12 @@
+//! Order IDs
+//!
+//! The native program takes the backend's order ID as a string and the Anchor program
+//! takes 32 bytes. Both identify an order by the SHA-256 hash of the backend's ID, the
+//! seed of its order PDA, so the same order maps to the same key in either program.
+
+use sha2::{Digest, Sha256};
+
+/// SHA-256 hash of a backend order ID, as used in order PDA seeds
+pub fn order_id_hash(order_id: &str) -> [u8; 32] {
+    Sha256::digest(order_id.as_bytes()).into()
+}
@@ -0,0 +1,241 @@
+// Unit tests for the shared payment logic, with worked examples of each rule
+
+use oxmart_payment_core::{
+    bps_share, check_total_fee, fees::DEFAULT_MAX_TOTAL_FEE_BPS, fees::MAX_COMMISSION_BPS,
+    fees::MAX_PLATFORM_FEE_BPS, line_items, order_id_hash, split_payment, CoreError, FeeSplit,
+};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// bps_share
+
+#[test]
+fn bps_share_takes_basis_points_of_amount() {
+    assert_eq!(bps_share(100_000_000, 250), Ok(2_500_000));
+    assert_eq!(bps_share(100_000_000, 500), Ok(5_000_000));
+    assert_eq!(bps_share(1_000, 10000), Ok(1_000));
+    assert_eq!(bps_share(1_000, 0), Ok(0));
+}
+
+#[test]
+fn bps_share_rounds_down() {
+    assert_eq!(bps_share(9_999, 1), Ok(0));
+    assert_eq!(bps_share(10_000, 1), Ok(1));
+    assert_eq!(bps_share(19_999, 1), Ok(1));
+    assert_eq!(bps_share(333, 3333), Ok(110));
+}
+
+#[test]
+fn bps_share_handles_amounts_overflowing_u64_products() {
+    assert_eq!(bps_share(u64::MAX, 10000), Ok(u64::MAX));
+    assert_eq!(bps_share(u64::MAX, 500), Ok(u64::MAX / 20));
+    assert_eq!(bps_share(u64::MAX, 1), Ok(u64::MAX / 10000));
+}
+
+#[test]
+fn bps_share_rejects_basis_points_above_100_percent() {
+    assert_eq!(bps_share(1_000, 10001), Err(CoreError::InvalidBasisPoints));
+    assert_eq!(bps_share(0, u16::MAX), Err(CoreError::InvalidBasisPoints));
+}
+
+// split_payment
+
+#[test]
+fn split_payment_divides_amount() {
+    assert_eq!(
+        split_payment(100_000_000, 250, 500),
+        Ok(FeeSplit {
+            platform_fee: 2_500_000,
+            commission: 5_000_000,
+            net_amount: 92_500_000,
+        })
+    );
+}
+
+#[test]
+fn split_payment_leaves_rounding_remainder_in_net_amount() {
+    assert_eq!(
+        split_payment(9_999, 1, 1),
+        Ok(FeeSplit {
+            platform_fee: 0,
+            commission: 0,
+            net_amount: 9_999,
+        })
+    );
+    assert_eq!(
+        split_payment(1_001, 1000, 500),
+        Ok(FeeSplit {
+            platform_fee: 100,
+            commission: 50,
+            net_amount: 851,
+        })
+    );
+}
+
+#[test]
+fn split_payment_allows_deductions_of_whole_amount() {
+    assert_eq!(
+        split_payment(1_000, 1000, 9000),
+        Ok(FeeSplit {
+            platform_fee: 100,
+            commission: 900,
+            net_amount: 0,
+        })
+    );
+}
+
+#[test]
+fn split_payment_rejects_deductions_above_amount() {
+    assert_eq!(
+        split_payment(1_000, 100, 10000),
+        Err(CoreError::DeductionsExceedAmount)
+    );
+    assert_eq!(
+        split_payment(u64::MAX, MAX_PLATFORM_FEE_BPS, MAX_COMMISSION_BPS),
+        Err(CoreError::DeductionsExceedAmount)
+    );
+}
+
+#[test]
+fn split_payment_rejects_invalid_basis_points() {
+    assert_eq!(
+        split_payment(1_000, 10001, 0),
+        Err(CoreError::InvalidBasisPoints)
+    );
+    assert_eq!(
+        split_payment(1_000, 0, 10001),
+        Err(CoreError::InvalidBasisPoints)
+    );
+}
+
+#[test]
+fn split_payment_handles_max_amount() {
+    assert_eq!(
+        split_payment(u64::MAX, 0, 500),
+        Ok(FeeSplit {
+            platform_fee: 0,
+            commission: u64::MAX / 20,
+            net_amount: u64::MAX - u64::MAX / 20,
+        })
+    );
+}
+
+// check_total_fee
+
+#[test]
+fn check_total_fee_accepts_rates_up_to_cap() {
+    assert_eq!(check_total_fee(250, 750, 1000), Ok(()));
+    assert_eq!(check_total_fee(0, 0, 0), Ok(()));
+    assert_eq!(
+        check_total_fee(1000, 9000, DEFAULT_MAX_TOTAL_FEE_BPS),
+        Ok(())
+    );
+}
+
+#[test]
+fn check_total_fee_rejects_rates_above_cap() {
+    assert_eq!(
+        check_total_fee(250, 751, 1000),
+        Err(CoreError::TotalFeeTooHigh)
+    );
+    assert_eq!(check_total_fee(1, 0, 0), Err(CoreError::TotalFeeTooHigh));
+    assert_eq!(
+        check_total_fee(
+            MAX_PLATFORM_FEE_BPS,
+            MAX_COMMISSION_BPS,
+            DEFAULT_MAX_TOTAL_FEE_BPS
+        ),
+        Err(CoreError::TotalFeeTooHigh)
+    );
+}
+
+#[test]
+fn check_total_fee_does_not_overflow() {
+    assert_eq!(
+        check_total_fee(u16::MAX, u16::MAX, u16::MAX),
+        Err(CoreError::TotalFeeTooHigh)
+    );
+}
+
+// line_items
+
+#[test]
+fn line_amount_multiplies_unit_price_by_quantity() {
+    assert_eq!(line_items::line_amount(2_500, 4), Ok(10_000));
+    assert_eq!(line_items::line_amount(0, u32::MAX), Ok(0));
+    assert_eq!(line_items::line_amount(u64::MAX, 1), Ok(u64::MAX));
+}
+
+#[test]
+fn line_amount_rejects_overflow() {
+    assert_eq!(
+        line_items::line_amount(u64::MAX, 2),
+        Err(CoreError::Overflow)
+    );
+}
+
+#[test]
+fn leaf_hash_covers_each_field() {
+    let leaf = line_items::leaf_hash(&[1; 32], 2_500, 2, 500);
+
+    assert_eq!(
+        hex(&leaf),
+        "968ff44ae050643bb380995e1b4d18a753f8ed61747ab22beb1fe06b4a341ae5"
+    );
+    assert_ne!(leaf, line_items::leaf_hash(&[2; 32], 2_500, 2, 500));
+    assert_ne!(leaf, line_items::leaf_hash(&[1; 32], 2_501, 2, 500));
+    assert_ne!(leaf, line_items::leaf_hash(&[1; 32], 2_500, 3, 500));
+    assert_ne!(leaf, line_items::leaf_hash(&[1; 32], 2_500, 2, 501));
+}
+
+#[test]
+fn merkle_root_hashes_pairs_and_promotes_odd_node() {
+    let mut leaves = [
+        line_items::leaf_hash(&[1; 32], 2_500, 2, 500),
+        line_items::leaf_hash(&[2; 32], 1_000, 1, 0),
+        line_items::leaf_hash(&[3; 32], 0, 3, 10000),
+    ];
+
+    assert_eq!(
+        hex(&line_items::merkle_root(&mut leaves)),
+        "47dd248068f66101ad3acd8f4133aeb1fd2e529f034a9141331fdc1212cc5449"
+    );
+}
+
+#[test]
+fn merkle_root_of_one_leaf_is_the_leaf() {
+    let leaf = line_items::leaf_hash(&[1; 32], 2_500, 2, 500);
+
+    assert_eq!(line_items::merkle_root(&mut [leaf]), leaf);
+}
+
+#[test]
+fn merkle_root_of_no_leaves_is_zero() {
+    assert_eq!(line_items::merkle_root(&mut []), [0; 32]);
+}
+
+// order_id_hash
+
+#[test]
+fn order_id_hash_is_sha256() {
+    assert_eq!(
+        hex(&order_id_hash("")),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        hex(&order_id_hash("abc")),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+// CoreError
+
+#[test]
+fn core_error_codes_are_stable() {
+    assert_eq!(CoreError::InvalidBasisPoints.code(), 0);
+    assert_eq!(CoreError::DeductionsExceedAmount.code(), 1);
+    assert_eq!(CoreError::Overflow.code(), 2);
+    assert_eq!(CoreError::TotalFeeTooHigh.code(), 3);
+}
@@ -0,0 +1,213 @@
+// Property tests for the shared payment logic over generated inputs
+//
+// Inputs come from a fixed-seed SplitMix64 generator, weighted towards zero, small
+// values and the top of the `u64` range, so failures reproduce exactly.
+
+use oxmart_payment_core::{
+    bps_share, check_total_fee, fees::BPS_DENOMINATOR, line_items, order_id_hash, split_payment,
+    CoreError,
+};
+
+const CASES: usize = 100_000;
+
+struct Gen(u64);
+
+impl Gen {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn amount(&mut self) -> u64 {
+        match self.next() % 4 {
+            0 => self.next() % 1_000,
+            1 => u64::MAX - self.next() % 1_000,
+            2 => self.next() >> (self.next() % 64),
+            _ => self.next(),
+        }
+    }
+
+    /// Basis points, valid (0..=10000) most of the time
+    fn bps(&mut self) -> u16 {
+        match self.next() % 8 {
+            0 => 0,
+            1 => BPS_DENOMINATOR,
+            2 => self.next() as u16,
+            _ => (self.next() % (u64::from(BPS_DENOMINATOR) + 1)) as u16,
+        }
+    }
+
+    fn hash(&mut self) -> [u8; 32] {
+        let mut hash = [0; 32];
+        for chunk in hash.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes());
+        }
+        hash
+    }
+}
+
+/// `amount * bps / 10000` in exact arithmetic, rounded down
+fn exact_share(amount: u64, bps: u16) -> u128 {
+    u128::from(amount) * u128::from(bps) / u128::from(BPS_DENOMINATOR)
+}
+
+#[test]
+fn bps_share_is_exact_share_rounded_down() {
+    let mut gen = Gen::new(1);
+    for _ in 0..CASES {
+        let (amount, bps) = (gen.amount(), gen.bps());
+
+        let share = bps_share(amount, bps);
+
+        if bps > BPS_DENOMINATOR {
+            assert_eq!(share, Err(CoreError::InvalidBasisPoints), "{amount} {bps}");
+        } else {
+            let share = u128::from(share.unwrap());
+            assert_eq!(share, exact_share(amount, bps), "{amount} {bps}");
+            assert!(share <= u128::from(amount), "{amount} {bps}");
+        }
+    }
+}
+
+#[test]
+fn bps_share_is_monotonic() {
+    let mut gen = Gen::new(2);
+    for _ in 0..CASES {
+        let (a, b) = (gen.amount(), gen.amount());
+        let bps = gen.bps() % (BPS_DENOMINATOR + 1);
+        let (low, high) = (a.min(b), a.max(b));
+        assert!(bps_share(low, bps).unwrap() <= bps_share(high, bps).unwrap());
+
+        let amount = gen.amount();
+        let (a, b) = (
+            gen.bps() % (BPS_DENOMINATOR + 1),
+            gen.bps() % (BPS_DENOMINATOR + 1),
+        );
+        let (low, high) = (a.min(b), a.max(b));
+        assert!(bps_share(amount, low).unwrap() <= bps_share(amount, high).unwrap());
+    }
+}
+
+#[test]
+fn split_payment_conserves_amount() {
+    let mut gen = Gen::new(3);
+    for _ in 0..CASES {
+        let (amount, platform_fee_bps, commission_bps) = (gen.amount(), gen.bps(), gen.bps());
+
+        if let Ok(split) = split_payment(amount, platform_fee_bps, commission_bps) {
+            let total = u128::from(split.platform_fee)
+                + u128::from(split.commission)
+                + u128::from(split.net_amount);
+            assert_eq!(
+                total,
+                u128::from(amount),
+                "{amount} {platform_fee_bps} {commission_bps}"
+            );
+        }
+    }
+}
+
+#[test]
+fn split_payment_fails_only_on_invalid_input() {
+    let mut gen = Gen::new(4);
+    for _ in 0..CASES {
+        let (amount, platform_fee_bps, commission_bps) = (gen.amount(), gen.bps(), gen.bps());
+
+        let result = split_payment(amount, platform_fee_bps, commission_bps);
+
+        let expected = if platform_fee_bps > BPS_DENOMINATOR || commission_bps > BPS_DENOMINATOR {
+            Some(CoreError::InvalidBasisPoints)
+        } else if exact_share(amount, platform_fee_bps) + exact_share(amount, commission_bps)
+            > u128::from(amount)
+        {
+            Some(CoreError::DeductionsExceedAmount)
+        } else {
+            None
+        };
+        assert_eq!(
+            result.err(),
+            expected,
+            "{amount} {platform_fee_bps} {commission_bps}"
+        );
+    }
+}
+
+#[test]
+fn split_payment_never_fails_within_100_percent() {
+    let mut gen = Gen::new(5);
+    for _ in 0..CASES {
+        let amount = gen.amount();
+        let platform_fee_bps = gen.bps() % (BPS_DENOMINATOR + 1);
+        let commission_bps = gen.bps() % (BPS_DENOMINATOR - platform_fee_bps + 1);
+
+        let split = split_payment(amount, platform_fee_bps, commission_bps).unwrap();
+
+        assert_eq!(
+            split.platform_fee,
+            bps_share(amount, platform_fee_bps).unwrap()
+        );
+        assert_eq!(split.commission, bps_share(amount, commission_bps).unwrap());
+    }
+}
+
+#[test]
+fn split_payment_never_fails_within_total_fee_cap() {
+    let mut gen = Gen::new(9);
+    for _ in 0..CASES {
+        let (amount, platform_fee_bps, commission_bps) = (gen.amount(), gen.bps(), gen.bps());
+        let max_total_fee_bps = gen.bps() % (BPS_DENOMINATOR + 1);
+
+        if check_total_fee(platform_fee_bps, commission_bps, max_total_fee_bps).is_ok() {
+            let split = split_payment(amount, platform_fee_bps, commission_bps);
+            assert!(
+                split.is_ok(),
+                "{amount} {platform_fee_bps} {commission_bps}"
+            );
+        }
+    }
+}
+
+#[test]
+fn merkle_root_changes_with_any_leaf_or_order() {
+    let mut gen = Gen::new(10);
+    for _ in 0..CASES / 10 {
+        let len = 1 + (gen.next() % line_items::MAX_LINE_ITEMS as u64) as usize;
+        let leaves: Vec<[u8; 32]> = (0..len).map(|_| gen.hash()).collect();
+        let root = line_items::merkle_root(&mut leaves.clone());
+
+        let mut changed = leaves.clone();
+        let index = (gen.next() % len as u64) as usize;
+        changed[index][(gen.next() % 32) as usize] ^= 1;
+        assert_ne!(line_items::merkle_root(&mut changed), root);
+
+        if len > 1 {
+            let mut swapped = leaves.clone();
+            swapped.swap(0, len - 1);
+            assert_ne!(line_items::merkle_root(&mut swapped), root);
+        }
+        let mut extended = leaves.clone();
+        extended.push(root);
+        assert_ne!(line_items::merkle_root(&mut extended), root);
+    }
+}
+
+#[test]
+fn order_id_hash_is_deterministic_and_distinct() {
+    let mut gen = Gen::new(8);
+    let mut hashes = std::collections::HashSet::new();
+    for n in 0..CASES / 10 {
+        let order_id = format!("order-{}-{}", n, gen.next());
+
+        let hash = order_id_hash(&order_id);
+
+        assert_eq!(hash, order_id_hash(&order_id));
+        assert!(hashes.insert(hash), "{order_id}");
+    }
+}
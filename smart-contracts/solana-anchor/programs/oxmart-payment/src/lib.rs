@@ -1,5 +1,15 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::{ed25519_program, sysvar::instructions};
+use anchor_lang::{system_program, Discriminator};
+use anchor_spl::token::spl_token;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+use oxmart_payment_core::{
+    bps_share, check_total_fee, fees, line_items, split_payment, CoreError,
+};
 
 declare_id!("HwjrPzXD2LiotV6uFwMEzRYPKWw9FcVbnMk2vCW4mBPu");
 
@@ -10,86 +20,161 @@ pub mod oxmart_payment {
     /// Initialize the payment program
     pub fn initialize(
         ctx: Context<Initialize>,
-        hot_wallet: Pubkey,
+        fee_treasury: Pubkey,
         platform_fee_bps: u16,
     ) -> Result<()> {
-        require!(platform_fee_bps <= 1000, ErrorCode::FeeTooHigh);
+        require!(platform_fee_bps <= fees::MAX_PLATFORM_FEE_BPS, ErrorCode::FeeTooHigh);
 
         let config = &mut ctx.accounts.config;
         config.authority = ctx.accounts.authority.key();
-        config.hot_wallet = hot_wallet;
+        config.pending_authority = Pubkey::default();
+        config.multisig_signers = Vec::new();
+        config.multisig_threshold = 0;
+        config.fee_treasury = fee_treasury;
+        config.refund_operator = Pubkey::default();
+        config.quote_signer = Pubkey::default();
+        config.payout_wallet_delay_secs = Config::DEFAULT_CHANGE_DELAY_SECS;
+        config.platform_fee_delay_secs = Config::DEFAULT_CHANGE_DELAY_SECS;
+        config.max_quote_lifetime_secs = Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS;
+        config.order_retention_secs = Config::DEFAULT_ORDER_RETENTION_SECS;
+        config.max_total_fee_bps = Config::DEFAULT_MAX_TOTAL_FEE_BPS;
         config.platform_fee_bps = platform_fee_bps;
         config.paused = false;
         config.bump = ctx.bumps.config;
 
         msg!("Payment program initialized");
-        msg!("Hot wallet: {}", hot_wallet);
+        msg!("Fee treasury: {}", fee_treasury);
         msg!("Platform fee: {} bps", platform_fee_bps);
 
         Ok(())
     }
 
     /// Process a single payment
-    pub fn process_payment(
-        ctx: Context<ProcessPayment>,
+    ///
+    /// `order_id` is `oxmart_payment_core::order_id_hash` of the backend's order ID, the
+    /// same hash the native program derives, so an order has one ID in both programs.
+    /// Fails once `expires_at` has passed, or when it lies further ahead than the config's
+    /// maximum quote lifetime. `expires_at` is the buyer's own deadline and not signed by
+    /// the quote, whose `expires_at` is the deadline the backend enforces. The merchant
+    /// receives the amount less the platform fee and commission, the fee treasury the
+    /// platform fee and the affiliate's commission vault the commission, accrued in its
+    /// ledger until the affiliate claims it. The commission rate and its owner come from
+    /// the affiliate account, and a payment without one carries no commission.
+    pub fn process_payment<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessPayment<'info>>,
         order_id: [u8; 32],
         amount: u64,
         product_id: String,
-        api_key_owner: Pubkey,
-        commission_bps: u16,
+        expires_at: i64,
     ) -> Result<()> {
         let config = &ctx.accounts.config;
 
         // Validate not paused
         require!(!config.paused, ErrorCode::ProgramPaused);
 
-        // Validate commission
-        require!(commission_bps <= 10000, ErrorCode::InvalidCommission);
+        // Take the commission from the affiliate registry
+        let (api_key_owner, commission_bps) = match &ctx.accounts.affiliate {
+            Some(affiliate) => affiliate.commission()?,
+            None => (Pubkey::default(), 0),
+        };
 
         // Validate amount
         require!(amount > 0, ErrorCode::InvalidAmount);
 
+        // Validate the product id fits the order record
+        require!(
+            product_id.len() <= OrderRecord::MAX_PRODUCT_ID_LEN,
+            ErrorCode::ProductIdTooLong
+        );
+
+        // Validate the order deadline
+        check_deadline(config, expires_at, ErrorCode::OrderExpired)?;
+
+        // Validate amount against the mint's limits
+        let token_config = &ctx.accounts.token_config;
+        token_config.check_amount(amount)?;
+
         // Check if order already processed
         let order_record = &ctx.accounts.order_record;
         require!(!order_record.processed, ErrorCode::OrderAlreadyProcessed);
-
-        // Calculate fees
-        let platform_fee = (amount as u128)
-            .checked_mul(config.platform_fee_bps as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
-
-        let commission = (amount as u128)
-            .checked_mul(commission_bps as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
-
-        let net_amount = amount.checked_sub(platform_fee).unwrap();
-
-        // Transfer tokens from buyer to hot wallet
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.buyer_token_account.to_account_info(),
-            to: ctx.accounts.hot_wallet_token_account.to_account_info(),
-            authority: ctx.accounts.buyer.to_account_info(),
+        check_not_closed(&ctx.accounts.closed_order)?;
+
+        // Validate the backend quote, when required
+        verify_quote(
+            config,
+            &ctx.accounts.instructions,
+            &QuoteTerms {
+                program_id: crate::ID,
+                order_id,
+                mint: ctx.accounts.mint.key(),
+                amount,
+                merchants: vec![ctx.accounts.merchant.key()],
+                items_root: [0; 32],
+                commission_bps,
+                commission: bps_share(amount, commission_bps).map_err(core_error)?,
+                api_key_owner,
+            },
+        )?;
+
+        // Validate the merchant takes payments in this mint
+        let merchant = &ctx.accounts.merchant;
+        merchant.check_accepts(&ctx.accounts.mint.key())?;
+
+        // Calculate fees. Together the platform fee and commission may not exceed the
+        // config's cap.
+        let platform_fee_bps = merchant.platform_fee_bps(token_config, config);
+        check_total_fee(platform_fee_bps, commission_bps, config.max_total_fee_bps)
+            .map_err(core_error)?;
+        let split = split_payment(amount, platform_fee_bps, commission_bps).map_err(core_error)?;
+
+        // Transfer the net amount to the merchant, the platform fee to the fee treasury and
+        // the commission to the affiliate's commission vault.
+        // Mints with a transfer fee withhold part of each transfer, so record what arrived.
+        let remaining_accounts = ctx.remaining_accounts;
+        let net_amount = ctx
+            .accounts
+            .transfer_to_merchant(split.net_amount, remaining_accounts)?;
+        let platform_fee = ctx
+            .accounts
+            .transfer_to_fee_treasury(split.platform_fee, remaining_accounts)?;
+        let commission = ctx
+            .accounts
+            .accrue_commission(split.commission, remaining_accounts)?;
+        let amount = net_amount
+            .checked_add(platform_fee)
+            .and_then(|amount| amount.checked_add(commission))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if let Some(affiliate) = &mut ctx.accounts.affiliate {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        let leg = MerchantLeg {
+            merchant: ctx.accounts.merchant.key(),
+            amount,
+            platform_fee,
+            commission,
+            net_amount,
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, net_amount)?;
 
         // Mark order as processed
         let order_record = &mut ctx.accounts.order_record;
         order_record.order_id = order_id;
         order_record.buyer = ctx.accounts.buyer.key();
+        order_record.token_mint = ctx.accounts.buyer_token_account.mint;
         order_record.amount = amount;
         order_record.platform_fee = platform_fee;
         order_record.commission = commission;
+        order_record.refunded_amount = 0;
         order_record.api_key_owner = api_key_owner;
         order_record.product_id = product_id.clone();
+        order_record.items_root = [0; 32];
+        order_record.item_count = 0;
         order_record.processed = true;
         order_record.timestamp = Clock::get()?.unix_timestamp;
         order_record.bump = ctx.bumps.order_record;
+        order_record.merchant_legs = vec![leg];
 
         // Emit event
         emit!(PaymentProcessed {
@@ -97,10 +182,12 @@ pub mod oxmart_payment {
             buyer: ctx.accounts.buyer.key(),
             token_mint: ctx.accounts.buyer_token_account.mint,
             amount,
+            net_amount,
             platform_fee,
             api_key_owner,
             commission,
             product_id,
+            merchant: ctx.accounts.merchant.key(),
             timestamp: Clock::get()?.unix_timestamp,
         });
 
@@ -113,283 +200,3485 @@ pub mod oxmart_payment {
         Ok(())
     }
 
+    /// Process a single payment in native SOL
+    ///
+    /// Recorded with the native mint as `token_mint` so SOL orders share the
+    /// `OrderRecord` and `PaymentProcessed` shape with SPL token orders. The merchant must
+    /// accept the native mint; its payout wallet receives the net amount, and its fee
+    /// override replaces the config's platform fee. The API key owner comes from the
+    /// affiliate account, as in `process_payment`, and the commission accrues in lamports
+    /// in the affiliate's SOL commission ledger until `claim_sol_commission`.
+    pub fn process_sol_payment(
+        ctx: Context<ProcessSolPayment>,
+        order_id: [u8; 32],
+        amount: u64,
+        product_id: String,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+
+        // Validate not paused
+        require!(!config.paused, ErrorCode::ProgramPaused);
+
+        // Take the commission from the affiliate registry
+        let (api_key_owner, commission_bps) = match &ctx.accounts.affiliate {
+            Some(affiliate) => affiliate.commission()?,
+            None => (Pubkey::default(), 0),
+        };
+
+        // Validate amount
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        // Validate the product id fits the order record
+        require!(
+            product_id.len() <= OrderRecord::MAX_PRODUCT_ID_LEN,
+            ErrorCode::ProductIdTooLong
+        );
+
+        // Check the order was not closed
+        check_not_closed(&ctx.accounts.closed_order)?;
+
+        // Validate the backend quote, when required
+        verify_quote(
+            config,
+            &ctx.accounts.instructions,
+            &QuoteTerms {
+                program_id: crate::ID,
+                order_id,
+                mint: spl_token::native_mint::ID,
+                amount,
+                merchants: vec![ctx.accounts.merchant.key()],
+                items_root: [0; 32],
+                commission_bps,
+                commission: bps_share(amount, commission_bps).map_err(core_error)?,
+                api_key_owner,
+            },
+        )?;
+
+        // Validate the merchant takes SOL
+        let merchant = &ctx.accounts.merchant;
+        merchant.check_accepts(&spl_token::native_mint::ID)?;
+
+        // Calculate fees. The platform fee may not exceed the config's cap.
+        let platform_fee_bps = merchant.platform_fee_bps.unwrap_or(config.platform_fee_bps);
+        check_total_fee(platform_fee_bps, commission_bps, config.max_total_fee_bps)
+            .map_err(core_error)?;
+        let split = split_payment(amount, platform_fee_bps, commission_bps).map_err(core_error)?;
+        let (platform_fee, commission, net_amount) =
+            (split.platform_fee, split.commission, split.net_amount);
+
+        // Transfer net amount to the merchant, platform fee to fee treasury and commission
+        // to the affiliate's SOL commission ledger
+        ctx.accounts.transfer_to_merchant(net_amount)?;
+        ctx.accounts.transfer_to_fee_treasury(platform_fee)?;
+        ctx.accounts.accrue_commission(commission)?;
+        let leg = MerchantLeg {
+            merchant: ctx.accounts.merchant.key(),
+            amount,
+            platform_fee,
+            commission,
+            net_amount,
+        };
+
+        // Mark order as processed
+        let order_record = &mut ctx.accounts.order_record;
+        order_record.order_id = order_id;
+        order_record.buyer = ctx.accounts.buyer.key();
+        order_record.token_mint = spl_token::native_mint::ID;
+        order_record.amount = amount;
+        order_record.platform_fee = platform_fee;
+        order_record.commission = commission;
+        order_record.refunded_amount = 0;
+        order_record.api_key_owner = api_key_owner;
+        order_record.product_id = product_id.clone();
+        order_record.items_root = [0; 32];
+        order_record.item_count = 0;
+        order_record.processed = true;
+        order_record.timestamp = Clock::get()?.unix_timestamp;
+        order_record.bump = ctx.bumps.order_record;
+        order_record.merchant_legs = vec![leg];
+
+        // Emit event
+        emit!(PaymentProcessed {
+            order_id,
+            buyer: ctx.accounts.buyer.key(),
+            token_mint: spl_token::native_mint::ID,
+            amount,
+            net_amount,
+            platform_fee,
+            api_key_owner,
+            commission,
+            product_id,
+            merchant: ctx.accounts.merchant.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("SOL payment processed successfully");
+        msg!("Order ID: {:?}", order_id);
+        msg!("Amount: {}", amount);
+        msg!("Platform fee: {}", platform_fee);
+        msg!("Commission: {}", commission);
+
+        Ok(())
+    }
+
     /// Process batch payment (shopping cart)
-    pub fn process_batch_payment(
-        ctx: Context<ProcessPayment>,
+    ///
+    /// Line items take the affiliate's commission rate unless they carry a lower one, and
+    /// their amounts must add up to `total_amount`. A quote, when required, covers the
+    /// items' Merkle root and the commission they add up to, so only the backend can lower
+    /// an item's rate. Every item is sold by the one merchant,
+    /// paid as in `process_payment`, and `expires_at` is the buyer's deadline as there.
+    pub fn process_batch_payment<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessPayment<'info>>,
         order_id: [u8; 32],
         total_amount: u64,
-        product_ids: Vec<String>,
-        api_key_owner: Pubkey,
-        commission_bps: u16,
+        line_items: Vec<LineItem>,
+        expires_at: i64,
     ) -> Result<()> {
         let config = &ctx.accounts.config;
 
         require!(!config.paused, ErrorCode::ProgramPaused);
-        require!(commission_bps <= 10000, ErrorCode::InvalidCommission);
+        let (api_key_owner, commission_bps) = match &ctx.accounts.affiliate {
+            Some(affiliate) => affiliate.commission()?,
+            None => (Pubkey::default(), 0),
+        };
         require!(total_amount > 0, ErrorCode::InvalidAmount);
-        require!(!product_ids.is_empty(), ErrorCode::NoProducts);
+        require!(!line_items.is_empty(), ErrorCode::NoProducts);
+        require!(
+            line_items.len() <= line_items::MAX_LINE_ITEMS,
+            ErrorCode::TooManyLineItems
+        );
+        check_deadline(config, expires_at, ErrorCode::OrderExpired)?;
+
+        let token_config = &ctx.accounts.token_config;
+        token_config.check_amount(total_amount)?;
 
         let order_record = &ctx.accounts.order_record;
         require!(!order_record.processed, ErrorCode::OrderAlreadyProcessed);
-
-        let platform_fee = (total_amount as u128)
-            .checked_mul(config.platform_fee_bps as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
-
-        let commission = (total_amount as u128)
-            .checked_mul(commission_bps as u128)
-            .unwrap()
-            .checked_div(10000)
-            .unwrap() as u64;
-
-        let net_amount = total_amount.checked_sub(platform_fee).unwrap();
-
-        // Transfer tokens
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.buyer_token_account.to_account_info(),
-            to: ctx.accounts.hot_wallet_token_account.to_account_info(),
-            authority: ctx.accounts.buyer.to_account_info(),
+        check_not_closed(&ctx.accounts.closed_order)?;
+
+        // Price each line item. The platform fee is charged on the total, the commission on
+        // each item at its own rate; every rate stays within the total fee cap, so together
+        // they never exceed the total.
+        let cart = Cart::price(&line_items, commission_bps)?;
+
+        // Validate the backend quote, which covers the items and their commission
+        verify_quote(
+            config,
+            &ctx.accounts.instructions,
+            &QuoteTerms {
+                program_id: crate::ID,
+                order_id,
+                mint: ctx.accounts.mint.key(),
+                amount: total_amount,
+                merchants: vec![ctx.accounts.merchant.key()],
+                items_root: cart.items_root,
+                commission_bps,
+                commission: cart.commission,
+                api_key_owner,
+            },
+        )?;
+
+        let merchant = &ctx.accounts.merchant;
+        merchant.check_accepts(&ctx.accounts.mint.key())?;
+
+        let platform_fee_bps = merchant.platform_fee_bps(token_config, config);
+        for item in &cart.items {
+            item.check_total_fee(platform_fee_bps, config)?;
+        }
+        require!(cart.total_amount == total_amount, ErrorCode::LineItemsTotalMismatch);
+        let platform_fee = bps_share(total_amount, platform_fee_bps).map_err(core_error)?;
+
+        let net_amount = total_amount
+            .checked_sub(platform_fee)
+            .and_then(|amount| amount.checked_sub(cart.commission))
+            .ok_or(ErrorCode::DeductionsExceedAmount)?;
+
+        // Transfer tokens, recording what arrived after any transfer fee
+        let remaining_accounts = ctx.remaining_accounts;
+        let net_amount = ctx.accounts.transfer_to_merchant(net_amount, remaining_accounts)?;
+        let platform_fee = ctx
+            .accounts
+            .transfer_to_fee_treasury(platform_fee, remaining_accounts)?;
+        let commission = ctx
+            .accounts
+            .accrue_commission(cart.commission, remaining_accounts)?;
+        let total_amount = net_amount
+            .checked_add(platform_fee)
+            .and_then(|amount| amount.checked_add(commission))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if let Some(affiliate) = &mut ctx.accounts.affiliate {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        let leg = MerchantLeg {
+            merchant: ctx.accounts.merchant.key(),
+            amount: total_amount,
+            platform_fee,
+            commission,
+            net_amount,
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, net_amount)?;
 
         // Mark order as processed
         let order_record = &mut ctx.accounts.order_record;
         order_record.order_id = order_id;
         order_record.buyer = ctx.accounts.buyer.key();
+        order_record.token_mint = ctx.accounts.buyer_token_account.mint;
         order_record.amount = total_amount;
         order_record.platform_fee = platform_fee;
         order_record.commission = commission;
+        order_record.refunded_amount = 0;
         order_record.api_key_owner = api_key_owner;
         order_record.product_id = String::from("BATCH");
+        order_record.items_root = cart.items_root;
+        order_record.item_count = line_items.len() as u8;
         order_record.processed = true;
         order_record.timestamp = Clock::get()?.unix_timestamp;
         order_record.bump = ctx.bumps.order_record;
+        order_record.merchant_legs = vec![leg];
 
         emit!(BatchPaymentProcessed {
             order_id,
             buyer: ctx.accounts.buyer.key(),
             token_mint: ctx.accounts.buyer_token_account.mint,
             total_amount,
+            net_amount,
             platform_fee,
             api_key_owner,
             commission,
-            product_count: product_ids.len() as u8,
+            product_count: line_items.len() as u8,
+            items_root: cart.items_root,
+            merchant: ctx.accounts.merchant.key(),
             timestamp: Clock::get()?.unix_timestamp,
         });
+        for (index, (item, priced)) in line_items.iter().zip(&cart.items).enumerate() {
+            emit!(LineItemPaid {
+                order_id,
+                index: index as u8,
+                product_id_hash: item.product_id_hash,
+                unit_price: item.unit_price,
+                quantity: item.quantity,
+                amount: priced.amount,
+                commission_bps: priced.commission_bps,
+                commission: priced.commission,
+            });
+        }
 
         msg!("Batch payment processed successfully");
         msg!("Order ID: {:?}", order_id);
         msg!("Total amount: {}", total_amount);
-        msg!("Products: {}", product_ids.len());
+        msg!("Products: {}", line_items.len());
 
         Ok(())
     }
 
-    /// Update hot wallet address (admin only)
-    pub fn update_hot_wallet(
-        ctx: Context<UpdateConfig>,
-        new_hot_wallet: Pubkey,
+    /// Process a cart paying several merchants at once
+    ///
+    /// The first `2 * merchant_count` remaining accounts are each merchant's PDA followed by
+    /// its payout token account for the mint; any after them are transfer hook accounts. Each
+    /// merchant receives its items' amount less their platform fee and commission, the fee
+    /// treasury the platform fee and the affiliate's commission vault the commission. Items
+    /// take the affiliate's commission rate, and `expires_at` is the buyer's deadline, as in
+    /// `process_batch_payment`.
+    pub fn process_cart_payment<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessCartPayment<'info>>,
+        order_id: [u8; 32],
+        total_amount: u64,
+        merchant_count: u8,
+        cart_items: Vec<CartItem>,
+        expires_at: i64,
     ) -> Result<()> {
-        let config = &mut ctx.accounts.config;
-        let old_hot_wallet = config.hot_wallet;
-        config.hot_wallet = new_hot_wallet;
+        let config = &ctx.accounts.config;
 
-        emit!(HotWalletUpdated {
-            old_hot_wallet,
-            new_hot_wallet,
-            authority: ctx.accounts.authority.key(),
-        });
+        require!(!config.paused, ErrorCode::ProgramPaused);
+        let (api_key_owner, commission_bps) = match &ctx.accounts.affiliate {
+            Some(affiliate) => affiliate.commission()?,
+            None => (Pubkey::default(), 0),
+        };
+        require!(total_amount > 0, ErrorCode::InvalidAmount);
+        require!(!cart_items.is_empty(), ErrorCode::NoProducts);
+        require!(
+            cart_items.len() <= line_items::MAX_LINE_ITEMS,
+            ErrorCode::TooManyLineItems
+        );
+        check_deadline(config, expires_at, ErrorCode::OrderExpired)?;
+
+        let token_config = &ctx.accounts.token_config;
+        token_config.check_amount(total_amount)?;
 
-        msg!("Hot wallet updated");
-        msg!("Old: {}", old_hot_wallet);
-        msg!("New: {}", new_hot_wallet);
+        let order_record = &ctx.accounts.order_record;
+        require!(!order_record.processed, ErrorCode::OrderAlreadyProcessed);
+        check_not_closed(&ctx.accounts.closed_order)?;
+
+        // Price the items as a batch payment does, then split them into one leg per merchant
+        let line_items: Vec<LineItem> =
+            cart_items.iter().map(|cart_item| cart_item.item.clone()).collect();
+        let cart = Cart::price(&line_items, commission_bps)?;
+        require!(cart.total_amount == total_amount, ErrorCode::LineItemsTotalMismatch);
+
+        let remaining_accounts = ctx.remaining_accounts;
+        let payouts = load_merchant_payouts(
+            remaining_accounts,
+            usize::from(merchant_count),
+            &ctx.accounts.mint.key(),
+            &ctx.accounts.token_program.key(),
+            token_config.platform_fee_bps(config),
+        )?;
+
+        // Validate the backend quote, which names the merchants in payment order and covers
+        // the items and their commission
+        verify_quote(
+            config,
+            &ctx.accounts.instructions,
+            &QuoteTerms {
+                program_id: crate::ID,
+                order_id,
+                mint: ctx.accounts.mint.key(),
+                amount: total_amount,
+                merchants: payouts.iter().map(|payout| payout.merchant).collect(),
+                items_root: cart.items_root,
+                commission_bps,
+                commission: cart.commission,
+                api_key_owner,
+            },
+        )?;
+
+        let mut legs: Vec<MerchantLeg> = payouts
+            .iter()
+            .map(|payout| MerchantLeg {
+                merchant: payout.merchant,
+                amount: 0,
+                platform_fee: 0,
+                commission: 0,
+                net_amount: 0,
+            })
+            .collect();
+        for (cart_item, priced) in cart_items.iter().zip(&cart.items) {
+            let index = usize::from(cart_item.merchant_index);
+            let leg = legs.get_mut(index).ok_or(ErrorCode::InvalidMerchantIndex)?;
+            priced.check_total_fee(payouts[index].platform_fee_bps, config)?;
+            leg.amount = leg
+                .amount
+                .checked_add(priced.amount)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            leg.commission = leg
+                .commission
+                .checked_add(priced.commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        // Every merchant passed must sell at least one item
+        for index in 0..legs.len() {
+            require!(
+                cart_items
+                    .iter()
+                    .any(|cart_item| usize::from(cart_item.merchant_index) == index),
+                ErrorCode::InvalidMerchantIndex
+            );
+        }
+
+        // Pay each merchant its leg at its own platform fee, recording what arrived after any
+        // transfer fee
+        let (mut platform_fee, mut commission) = (0u64, 0u64);
+        for (leg, payout) in legs.iter_mut().zip(&payouts) {
+            leg.platform_fee =
+                bps_share(leg.amount, payout.platform_fee_bps).map_err(core_error)?;
+            let net_amount = leg
+                .amount
+                .checked_sub(leg.platform_fee)
+                .and_then(|amount| amount.checked_sub(leg.commission))
+                .ok_or(ErrorCode::DeductionsExceedAmount)?;
+            leg.net_amount = ctx.accounts.transfer_from_buyer(
+                payout.payout_token_account.clone(),
+                net_amount,
+                remaining_accounts,
+            )?;
+            platform_fee = platform_fee
+                .checked_add(leg.platform_fee)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            commission = commission
+                .checked_add(leg.commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        let platform_fee = ctx.accounts.transfer_from_buyer(
+            ctx.accounts.fee_treasury_token_account.to_account_info(),
+            platform_fee,
+            remaining_accounts,
+        )?;
+        let commission_received = ctx
+            .accounts
+            .accrue_commission(commission, remaining_accounts)?;
+        let total_amount = legs
+            .iter()
+            .try_fold(0u64, |total, leg| total.checked_add(leg.net_amount))
+            .and_then(|total| total.checked_add(platform_fee))
+            .and_then(|total| total.checked_add(commission_received))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if let Some(affiliate) = &mut ctx.accounts.affiliate {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission_received)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+
+        let order_record = &mut ctx.accounts.order_record;
+        order_record.order_id = order_id;
+        order_record.buyer = ctx.accounts.buyer.key();
+        order_record.token_mint = ctx.accounts.mint.key();
+        order_record.amount = total_amount;
+        order_record.platform_fee = platform_fee;
+        order_record.commission = commission;
+        order_record.refunded_amount = 0;
+        order_record.api_key_owner = api_key_owner;
+        order_record.product_id = String::from("CART");
+        order_record.items_root = cart.items_root;
+        order_record.item_count = cart_items.len() as u8;
+        order_record.processed = true;
+        order_record.timestamp = Clock::get()?.unix_timestamp;
+        order_record.bump = ctx.bumps.order_record;
+        order_record.merchant_legs = legs;
+
+        emit!(CartPaymentProcessed {
+            order_id,
+            buyer: ctx.accounts.buyer.key(),
+            token_mint: ctx.accounts.mint.key(),
+            total_amount,
+            platform_fee,
+            api_key_owner,
+            commission,
+            merchant_count,
+            product_count: cart_items.len() as u8,
+            items_root: cart.items_root,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        for leg in &order_record.merchant_legs {
+            emit!(MerchantPaid {
+                order_id,
+                merchant: leg.merchant,
+                amount: leg.amount,
+                platform_fee: leg.platform_fee,
+                commission: leg.commission,
+                net_amount: leg.net_amount,
+            });
+        }
+        for (index, (item, priced)) in line_items.iter().zip(&cart.items).enumerate() {
+            emit!(LineItemPaid {
+                order_id,
+                index: index as u8,
+                product_id_hash: item.product_id_hash,
+                unit_price: item.unit_price,
+                quantity: item.quantity,
+                amount: priced.amount,
+                commission_bps: priced.commission_bps,
+                commission: priced.commission,
+            });
+        }
+
+        msg!("Cart payment processed successfully");
+        msg!("Order ID: {:?}", order_id);
+        msg!("Total amount: {}", total_amount);
+        msg!("Merchants: {}", merchant_count);
 
         Ok(())
     }
 
-    /// Update platform fee (admin only)
-    pub fn update_platform_fee(
-        ctx: Context<UpdateConfig>,
-        new_fee_bps: u16,
+    /// Refund part or all of a processed order to its buyer (admin, refund operator or
+    /// holder of the refund operator role)
+    pub fn refund_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, RefundOrder<'info>>,
+        amount: u64,
     ) -> Result<()> {
-        require!(new_fee_bps <= 1000, ErrorCode::FeeTooHigh);
+        // The refund operator refunds alone; admins need the multisig once one is set
+        let config = &ctx.accounts.config;
+        let refunder = ctx.accounts.refunder.key();
+        if refunder != config.refund_operator {
+            check_role(
+                config,
+                ctx.accounts.roles.as_deref(),
+                &refunder,
+                ctx.remaining_accounts,
+                Role::RefundOperator,
+            )?;
+        }
 
-        let config = &mut ctx.accounts.config;
-        config.platform_fee_bps = new_fee_bps;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let order_record = &ctx.accounts.order_record;
+        let refunded_amount = order_record
+            .refunded_amount
+            .checked_add(amount)
+            .ok_or(ErrorCode::RefundExceedsPayment)?;
+        require!(
+            refunded_amount <= order_record.amount,
+            ErrorCode::RefundExceedsPayment
+        );
+
+        // Refund from the signer's own token account back to the buyer
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint,
+            ctx.accounts.refund_source_token_account.to_account_info(),
+            ctx.accounts.buyer_token_account.to_account_info(),
+            ctx.accounts.refunder.to_account_info(),
+            ctx.remaining_accounts,
+            &[],
+            amount,
+        )?;
+
+        let order_record = &mut ctx.accounts.order_record;
+        order_record.refunded_amount = refunded_amount;
+
+        emit!(PaymentRefunded {
+            order_id: order_record.order_id,
+            buyer: order_record.buyer,
+            token_mint: order_record.token_mint,
+            amount,
+            refunded_amount,
+            fully_refunded: refunded_amount == order_record.amount,
+            refunded_by: ctx.accounts.refunder.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
 
-        msg!("Platform fee updated to {} bps", new_fee_bps);
+        msg!("Payment refunded");
+        msg!("Order ID: {:?}", order_record.order_id);
+        msg!("Amount: {}", amount);
+        msg!("Total refunded: {}", refunded_amount);
 
         Ok(())
     }
 
-    /// Pause the program (admin only)
-    pub fn pause(ctx: Context<UpdateConfig>) -> Result<()> {
-        let config = &mut ctx.accounts.config;
-        config.paused = true;
+    /// Close a processed order once the retention period has passed, returning its rent to
+    /// the buyer (anyone)
+    ///
+    /// A closed order marker is created for the order ID out of the order's rent, so it
+    /// cannot be paid again.
+    pub fn close_order(ctx: Context<CloseOrder>) -> Result<()> {
+        let order_record = &ctx.accounts.order_record;
+        let closable_at = order_record
+            .timestamp
+            .saturating_add(i64::from(ctx.accounts.config.order_retention_secs));
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= closable_at, ErrorCode::RetentionPeriodActive);
+
+        mark_order_closed(
+            order_record,
+            &ctx.accounts.closed_order,
+            &ctx.accounts.system_program,
+            ctx.bumps.closed_order,
+        )?;
+
+        emit!(OrderClosed {
+            order_id: order_record.order_id,
+            buyer: order_record.buyer,
+            timestamp: now,
+        });
 
-        msg!("Program paused");
+        msg!("Order closed");
+        msg!("Order ID: {:?}", order_record.order_id);
 
         Ok(())
     }
 
-    /// Unpause the program (admin only)
-    pub fn unpause(ctx: Context<UpdateConfig>) -> Result<()> {
-        let config = &mut ctx.accounts.config;
-        config.paused = false;
+    /// Add a mint to the supported token registry (authority or token manager)
+    pub fn add_supported_token(
+        ctx: Context<AddSupportedToken>,
+        min_amount: u64,
+        max_amount: u64,
+        platform_fee_bps: Option<u16>,
+    ) -> Result<()> {
+        check_role(
+            &ctx.accounts.config,
+            ctx.accounts.roles.as_deref(),
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+            Role::TokenManager,
+        )?;
+
+        let token_config = &mut ctx.accounts.token_config;
+        token_config.mint = ctx.accounts.mint.key();
+        token_config.bump = ctx.bumps.token_config;
+        token_config.set(true, min_amount, max_amount, platform_fee_bps)?;
+
+        emit!(TokenConfigUpdated {
+            mint: token_config.mint,
+            enabled: true,
+            min_amount,
+            max_amount,
+            platform_fee_bps,
+            authority: ctx.accounts.authority.key(),
+        });
 
-        msg!("Program unpaused");
+        msg!("Token added: {}", token_config.mint);
 
         Ok(())
     }
 
-    /// Emergency withdrawal (admin only)
-    pub fn emergency_withdraw(
-        ctx: Context<EmergencyWithdraw>,
-        amount: u64,
+    /// Update a supported mint's status, limits and fee override (authority or token manager)
+    pub fn update_token_config(
+        ctx: Context<UpdateTokenConfig>,
+        enabled: bool,
+        min_amount: u64,
+        max_amount: u64,
+        platform_fee_bps: Option<u16>,
     ) -> Result<()> {
-        require!(amount > 0, ErrorCode::InvalidAmount);
+        check_role(
+            &ctx.accounts.config,
+            ctx.accounts.roles.as_deref(),
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+            Role::TokenManager,
+        )?;
+
+        let token_config = &mut ctx.accounts.token_config;
+        token_config.set(enabled, min_amount, max_amount, platform_fee_bps)?;
+
+        emit!(TokenConfigUpdated {
+            mint: token_config.mint,
+            enabled,
+            min_amount,
+            max_amount,
+            platform_fee_bps,
+            authority: ctx.accounts.authority.key(),
+        });
 
-        let config = &ctx.accounts.config;
+        msg!("Token config updated: {}", token_config.mint);
 
-        // Transfer tokens from program token account to authority
-        let seeds = &[b"config".as_ref(), &[config.bump]];
-        let signer = &[&seeds[..]];
+        Ok(())
+    }
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.program_token_account.to_account_info(),
-            to: ctx.accounts.authority_token_account.to_account_info(),
-            authority: ctx.accounts.config.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        token::transfer(cpi_ctx, amount)?;
+    /// Remove a mint from the registry, closing its config (authority or token manager)
+    pub fn remove_supported_token(ctx: Context<RemoveSupportedToken>) -> Result<()> {
+        check_role(
+            &ctx.accounts.config,
+            ctx.accounts.roles.as_deref(),
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+            Role::TokenManager,
+        )?;
 
-        msg!("Emergency withdrawal: {} tokens", amount);
+        let mint = ctx.accounts.token_config.mint;
+
+        emit!(SupportedTokenRemoved {
+            mint,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Token removed: {}", mint);
 
         Ok(())
     }
-}
 
-// Account structures
+    /// Register an active merchant paid directly by payments (admin only)
+    ///
+    /// `platform_fee_bps` overrides the mint's platform fee on the merchant's sales and may
+    /// not exceed the total fee cap. An empty `allowed_mints` accepts every supported mint.
+    pub fn register_merchant(
+        ctx: Context<RegisterMerchant>,
+        merchant_id: [u8; 32],
+        payout_wallet: Pubkey,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let merchant = &mut ctx.accounts.merchant;
+        merchant.merchant_id = merchant_id;
+        merchant.bump = ctx.bumps.merchant;
+        merchant.set(
+            &ctx.accounts.config,
+            payout_wallet,
+            MerchantStatus::Active,
+            platform_fee_bps,
+            allowed_mints.clone(),
+        )?;
+
+        emit!(MerchantUpdated {
+            merchant_id,
+            payout_wallet,
+            status: MerchantStatus::Active,
+            platform_fee_bps,
+            allowed_mints,
+            authority: ctx.accounts.authority.key(),
+        });
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = 8 + Config::INIT_SPACE,
-        seeds = [b"config"],
-        bump
-    )]
-    pub config: Account<'info, Config>,
+        msg!("Merchant registered: {}", merchant.key());
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+        Ok(())
+    }
 
-    pub system_program: Program<'info, System>,
-}
+    /// Update a merchant's status, fee override and allowed mints (admin only)
+    ///
+    /// A suspended merchant cannot be paid until it is made active again. The fee override is
+    /// checked as in `register_merchant`. The payout wallet changes only through a queued
+    /// `ConfigChange::PayoutWallet`, behind the timelock.
+    pub fn update_merchant(
+        ctx: Context<UpdateMerchant>,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let merchant = &mut ctx.accounts.merchant;
+        let payout_wallet = merchant.payout_wallet;
+        merchant.set(
+            &ctx.accounts.config,
+            payout_wallet,
+            status,
+            platform_fee_bps,
+            allowed_mints.clone(),
+        )?;
+
+        emit!(MerchantUpdated {
+            merchant_id: merchant.merchant_id,
+            payout_wallet,
+            status,
+            platform_fee_bps,
+            allowed_mints,
+            authority: ctx.accounts.authority.key(),
+        });
 
-#[derive(Accounts)]
-#[instruction(order_id: [u8; 32])]
-pub struct ProcessPayment<'info> {
-    #[account(
-        seeds = [b"config"],
-        bump = config.bump
-    )]
-    pub config: Account<'info, Config>,
+        msg!("Merchant updated: {}", merchant.key());
 
-    #[account(
-        init,
-        payer = buyer,
-        space = 8 + OrderRecord::INIT_SPACE,
-        seeds = [b"order", order_id.as_ref()],
-        bump
-    )]
-    pub order_record: Account<'info, OrderRecord>,
+        Ok(())
+    }
 
-    #[account(mut)]
-    pub buyer: Signer<'info>,
+    /// Register an active affiliate earning `commission_bps` on the payments it refers
+    /// (admin only)
+    ///
+    /// `api_key_hash` is the SHA-256 hash of the backend's API key ID.
+    pub fn register_affiliate(
+        ctx: Context<RegisterAffiliate>,
+        api_key_hash: [u8; 32],
+        owner: Pubkey,
+        commission_bps: u16,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let affiliate = &mut ctx.accounts.affiliate;
+        affiliate.api_key_hash = api_key_hash;
+        affiliate.total_earnings = 0;
+        affiliate.bump = ctx.bumps.affiliate;
+        affiliate.set(&ctx.accounts.config, owner, commission_bps, AffiliateStatus::Active)?;
+
+        emit!(AffiliateUpdated {
+            api_key_hash,
+            owner,
+            commission_bps,
+            status: AffiliateStatus::Active,
+            authority: ctx.accounts.authority.key(),
+        });
 
-    #[account(
-        mut,
-        constraint = buyer_token_account.owner == buyer.key() @ ErrorCode::InvalidTokenAccount
-    )]
-    pub buyer_token_account: Account<'info, TokenAccount>,
+        msg!("Affiliate registered: {}", affiliate.key());
 
-    #[account(
-        mut,
-        constraint = hot_wallet_token_account.owner == config.hot_wallet @ ErrorCode::InvalidHotWallet
-    )]
-    pub hot_wallet_token_account: Account<'info, TokenAccount>,
+        Ok(())
+    }
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+    /// Update an affiliate's owner, commission rate and status (admin only)
+    ///
+    /// Payments through a suspended affiliate fail until it is made active again.
+    pub fn update_affiliate(
+        ctx: Context<UpdateAffiliate>,
+        owner: Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let affiliate = &mut ctx.accounts.affiliate;
+        affiliate.set(&ctx.accounts.config, owner, commission_bps, status)?;
+
+        emit!(AffiliateUpdated {
+            api_key_hash: affiliate.api_key_hash,
+            owner,
+            commission_bps,
+            status,
+            authority: ctx.accounts.authority.key(),
+        });
 
-#[derive(Accounts)]
-pub struct UpdateConfig<'info> {
-    #[account(
-        mut,
-        seeds = [b"config"],
-        bump = config.bump,
-        has_one = authority @ ErrorCode::Unauthorized
-    )]
-    pub config: Account<'info, Config>,
+        msg!("Affiliate updated: {}", affiliate.key());
 
-    pub authority: Signer<'info>,
-}
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct EmergencyWithdraw<'info> {
-    #[account(
-        seeds = [b"config"],
-        bump = config.bump,
-        has_one = authority @ ErrorCode::Unauthorized
-    )]
-    pub config: Account<'info, Config>,
+    /// Create an affiliate's commission ledger and vault for a mint
+    ///
+    /// Anyone may pay for them; `process_payment` through the affiliate in that mint needs
+    /// both.
+    pub fn open_commission_ledger(ctx: Context<OpenCommissionLedger>) -> Result<()> {
+        let commission_ledger = &mut ctx.accounts.commission_ledger;
+        commission_ledger.affiliate = ctx.accounts.affiliate.key();
+        commission_ledger.mint = ctx.accounts.mint.key();
+        commission_ledger.accrued = 0;
+        commission_ledger.claimed = 0;
+        commission_ledger.last_claim_at = 0;
+        commission_ledger.bump = ctx.bumps.commission_ledger;
+
+        msg!("Commission ledger opened: {}", commission_ledger.key());
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
+        Ok(())
+    }
 
-    #[account(mut)]
-    pub program_token_account: Account<'info, TokenAccount>,
+    /// Withdraw an affiliate's unclaimed commission in one mint to the owner's token account
+    ///
+    /// Signed by the affiliate's owner; fails while the affiliate is suspended.
+    pub fn claim_commission<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimCommission<'info>>,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.affiliate.status == AffiliateStatus::Active,
+            ErrorCode::AffiliateSuspended
+        );
+
+        let commission_ledger = &ctx.accounts.commission_ledger;
+        let amount = commission_ledger
+            .accrued
+            .checked_sub(commission_ledger.claimed)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        require!(amount > 0, ErrorCode::NoCommissionToClaim);
+
+        let affiliate_key = ctx.accounts.affiliate.key();
+        let mint_key = ctx.accounts.mint.key();
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint,
+            ctx.accounts.commission_vault.to_account_info(),
+            ctx.accounts.owner_token_account.to_account_info(),
+            commission_ledger.to_account_info(),
+            ctx.remaining_accounts,
+            &[&[
+                b"commission",
+                affiliate_key.as_ref(),
+                mint_key.as_ref(),
+                &[commission_ledger.bump],
+            ]],
+            amount,
+        )?;
 
-    #[account(mut)]
-    pub authority_token_account: Account<'info, TokenAccount>,
+        let timestamp = Clock::get()?.unix_timestamp;
+        let commission_ledger = &mut ctx.accounts.commission_ledger;
+        commission_ledger.claimed = commission_ledger.accrued;
+        commission_ledger.last_claim_at = timestamp;
 
-    pub token_program: Program<'info, Token>,
-}
+        emit!(CommissionClaimed {
+            affiliate: affiliate_key,
+            owner: ctx.accounts.owner.key(),
+            mint: mint_key,
+            amount,
+            total_claimed: commission_ledger.claimed,
+            timestamp,
+        });
 
-// Data structures
+        msg!("Commission claimed: {}", amount);
 
-#[account]
-#[derive(InitSpace)]
-pub struct Config {
-    pub authority: Pubkey,          // 32
-    pub hot_wallet: Pubkey,          // 32
-    pub platform_fee_bps: u16,       // 2
-    pub paused: bool,                // 1
-    pub bump: u8,                    // 1
-}
+        Ok(())
+    }
 
-#[account]
-#[derive(InitSpace)]
-pub struct OrderRecord {
-    pub order_id: [u8; 32],          // 32
-    pub buyer: Pubkey,                // 32
-    pub amount: u64,                  // 8
-    pub platform_fee: u64,            // 8
-    pub commission: u64,              // 8
+    /// Create an affiliate's SOL commission ledger
+    ///
+    /// Anyone may pay for it; `process_sol_payment` through the affiliate needs it. The
+    /// ledger holds the unclaimed SOL commission itself, in lamports on top of its rent.
+    pub fn open_sol_commission_ledger(ctx: Context<OpenSolCommissionLedger>) -> Result<()> {
+        let commission_ledger = &mut ctx.accounts.commission_ledger;
+        commission_ledger.affiliate = ctx.accounts.affiliate.key();
+        commission_ledger.mint = spl_token::native_mint::ID;
+        commission_ledger.accrued = 0;
+        commission_ledger.claimed = 0;
+        commission_ledger.last_claim_at = 0;
+        commission_ledger.bump = ctx.bumps.commission_ledger;
+
+        msg!("SOL commission ledger opened: {}", commission_ledger.key());
+
+        Ok(())
+    }
+
+    /// Withdraw an affiliate's unclaimed SOL commission from its ledger to the owner's wallet
+    ///
+    /// Signed by the affiliate's owner; fails while the affiliate is suspended.
+    pub fn claim_sol_commission(ctx: Context<ClaimSolCommission>) -> Result<()> {
+        require!(
+            ctx.accounts.affiliate.status == AffiliateStatus::Active,
+            ErrorCode::AffiliateSuspended
+        );
+
+        let commission_ledger = &ctx.accounts.commission_ledger;
+        let amount = commission_ledger
+            .accrued
+            .checked_sub(commission_ledger.claimed)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        require!(amount > 0, ErrorCode::NoCommissionToClaim);
+
+        // The ledger is owned by this program, so its lamports move without a system transfer
+        let ledger_info = commission_ledger.to_account_info();
+        let owner_info = ctx.accounts.owner.to_account_info();
+        **ledger_info.try_borrow_mut_lamports()? = ledger_info
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        **owner_info.try_borrow_mut_lamports()? = owner_info
+            .lamports()
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        let commission_ledger = &mut ctx.accounts.commission_ledger;
+        commission_ledger.claimed = commission_ledger.accrued;
+        commission_ledger.last_claim_at = timestamp;
+
+        emit!(CommissionClaimed {
+            affiliate: ctx.accounts.affiliate.key(),
+            owner: ctx.accounts.owner.key(),
+            mint: spl_token::native_mint::ID,
+            amount,
+            total_claimed: commission_ledger.claimed,
+            timestamp,
+        });
+
+        msg!("SOL commission claimed: {}", amount);
+
+        Ok(())
+    }
+
+    /// Update fee treasury address (authority or treasury manager)
+    pub fn update_fee_treasury(
+        ctx: Context<UpdateConfigByRole>,
+        new_fee_treasury: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts
+            .check_role(ctx.remaining_accounts, Role::TreasuryManager)?;
+
+        let config = &mut ctx.accounts.config;
+        let old_fee_treasury = config.fee_treasury;
+        config.fee_treasury = new_fee_treasury;
+
+        emit!(FeeTreasuryUpdated {
+            old_fee_treasury,
+            new_fee_treasury,
+            authority: ctx.accounts.signer.key(),
+        });
+
+        msg!("Fee treasury updated");
+        msg!("Old: {}", old_fee_treasury);
+        msg!("New: {}", new_fee_treasury);
+
+        Ok(())
+    }
+
+    /// Update refund operator address (admin only)
+    pub fn update_refund_operator(
+        ctx: Context<UpdateConfig>,
+        new_refund_operator: Pubkey,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        let old_refund_operator = config.refund_operator;
+        config.refund_operator = new_refund_operator;
+
+        emit!(RefundOperatorUpdated {
+            old_refund_operator,
+            new_refund_operator,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Refund operator updated");
+        msg!("Old: {}", old_refund_operator);
+        msg!("New: {}", new_refund_operator);
+
+        Ok(())
+    }
+
+    /// Set how far ahead payment and quote deadlines may lie, 0 for no limit (admin only)
+    pub fn set_max_quote_lifetime(
+        ctx: Context<UpdateConfig>,
+        max_quote_lifetime_secs: u32,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        let old_max_quote_lifetime_secs = config.max_quote_lifetime_secs;
+        config.max_quote_lifetime_secs = max_quote_lifetime_secs;
+
+        emit!(MaxQuoteLifetimeUpdated {
+            old_max_quote_lifetime_secs,
+            new_max_quote_lifetime_secs: max_quote_lifetime_secs,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Max quote lifetime set to {} seconds", max_quote_lifetime_secs);
+
+        Ok(())
+    }
+
+    /// Cap the platform fee plus commission of a payment, at least the current platform fee
+    /// and at most 100% (admin only)
+    ///
+    /// Merchant fee overrides are only checked against the cap when set, so lowering the cap
+    /// below a merchant's override makes its payments fail with `TotalFeeTooHigh` until the
+    /// override is updated.
+    pub fn set_max_total_fee(ctx: Context<UpdateConfig>, max_total_fee_bps: u16) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        require!(
+            max_total_fee_bps <= fees::BPS_DENOMINATOR
+                && max_total_fee_bps >= config.platform_fee_bps,
+            ErrorCode::InvalidMaxTotalFee
+        );
+
+        let old_max_total_fee_bps = config.max_total_fee_bps;
+        config.max_total_fee_bps = max_total_fee_bps;
+
+        emit!(MaxTotalFeeUpdated {
+            old_max_total_fee_bps,
+            new_max_total_fee_bps: max_total_fee_bps,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Max total fee set to {} bps", max_total_fee_bps);
+
+        Ok(())
+    }
+
+    /// Set how long processed orders are kept before they can be closed (admin only)
+    pub fn set_order_retention(
+        ctx: Context<UpdateConfig>,
+        order_retention_secs: u32,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        let old_order_retention_secs = config.order_retention_secs;
+        config.order_retention_secs = order_retention_secs;
+
+        emit!(OrderRetentionUpdated {
+            old_order_retention_secs,
+            new_order_retention_secs: order_retention_secs,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Order retention set to {} seconds", order_retention_secs);
+
+        Ok(())
+    }
+
+    /// Update the backend key whose signed quote every payment must carry (admin only)
+    ///
+    /// The default address stops requiring quotes.
+    pub fn set_quote_signer(ctx: Context<UpdateConfig>, new_quote_signer: Pubkey) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        let old_quote_signer = config.quote_signer;
+        config.quote_signer = new_quote_signer;
+
+        emit!(QuoteSignerUpdated {
+            old_quote_signer,
+            new_quote_signer,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Quote signer updated");
+        msg!("Old: {}", old_quote_signer);
+        msg!("New: {}", new_quote_signer);
+
+        Ok(())
+    }
+
+    /// Propose a new authority, which takes over once it calls `accept_authority` (admin only)
+    pub fn propose_authority(ctx: Context<UpdateConfig>, new_authority: Pubkey) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        require_keys_neq!(
+            new_authority,
+            Pubkey::default(),
+            ErrorCode::InvalidPendingAuthority
+        );
+
+        let config = &mut ctx.accounts.config;
+        config.pending_authority = new_authority;
+
+        emit!(AuthorityProposed {
+            authority: config.authority,
+            pending_authority: new_authority,
+        });
+
+        msg!("Authority transfer proposed to {}", new_authority);
+
+        Ok(())
+    }
+
+    /// Accept a pending authority transfer (pending authority only)
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        require_keys_neq!(
+            config.pending_authority,
+            Pubkey::default(),
+            ErrorCode::NoPendingAuthority
+        );
+        require_keys_eq!(
+            config.pending_authority,
+            ctx.accounts.pending_authority.key(),
+            ErrorCode::NotPendingAuthority
+        );
+
+        let old_authority = config.authority;
+        config.authority = config.pending_authority;
+        config.pending_authority = Pubkey::default();
+
+        emit!(AuthorityTransferred {
+            old_authority,
+            new_authority: config.authority,
+        });
+
+        msg!("Authority transferred");
+        msg!("Old: {}", old_authority);
+        msg!("New: {}", config.authority);
+
+        Ok(())
+    }
+
+    /// Cancel a pending authority transfer (admin only)
+    pub fn cancel_authority_transfer(ctx: Context<UpdateConfig>) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let config = &mut ctx.accounts.config;
+        require_keys_neq!(
+            config.pending_authority,
+            Pubkey::default(),
+            ErrorCode::NoPendingAuthority
+        );
+
+        let pending_authority = config.pending_authority;
+        config.pending_authority = Pubkey::default();
+
+        emit!(AuthorityTransferCancelled {
+            authority: config.authority,
+            pending_authority,
+        });
+
+        msg!("Authority transfer to {} cancelled", pending_authority);
+
+        Ok(())
+    }
+
+    /// Upgrade a config created by the first release to the current layout (admin only)
+    ///
+    /// The fee treasury starts as the old hot wallet, the refund operator as unset and the
+    /// change delays at their default.
+    pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+        let config_info = ctx.accounts.config.to_account_info();
+
+        let legacy = {
+            let data = config_info.try_borrow_data()?;
+            require!(
+                data.len() == 8 + LegacyConfig::LEN,
+                ErrorCode::ConfigAlreadyMigrated
+            );
+            require!(
+                data[..8] == Config::DISCRIMINATOR,
+                anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+            );
+            LegacyConfig::deserialize(&mut &data[8..])?
+        };
+        require_keys_eq!(
+            legacy.authority,
+            ctx.accounts.authority.key(),
+            ErrorCode::Unauthorized
+        );
+
+        // Top up rent for the larger account, then grow it
+        let space = 8 + Config::INIT_SPACE;
+        let lamports = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(config_info.lamports());
+        if lamports > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.authority.to_account_info(),
+                        to: config_info.clone(),
+                    },
+                ),
+                lamports,
+            )?;
+        }
+        config_info.realloc(space, false)?;
+
+        let config = Config {
+            authority: legacy.authority,
+            pending_authority: Pubkey::default(),
+            multisig_signers: Vec::new(),
+            multisig_threshold: 0,
+            fee_treasury: legacy.hot_wallet,
+            refund_operator: Pubkey::default(),
+            quote_signer: Pubkey::default(),
+            payout_wallet_delay_secs: Config::DEFAULT_CHANGE_DELAY_SECS,
+            platform_fee_delay_secs: Config::DEFAULT_CHANGE_DELAY_SECS,
+            max_quote_lifetime_secs: Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS,
+            order_retention_secs: Config::DEFAULT_ORDER_RETENTION_SECS,
+            max_total_fee_bps: Config::DEFAULT_MAX_TOTAL_FEE_BPS,
+            platform_fee_bps: legacy.platform_fee_bps,
+            paused: legacy.paused,
+            bump: legacy.bump,
+        };
+        config.try_serialize(&mut &mut config_info.try_borrow_mut_data()?[..])?;
+
+        msg!("Config migrated to {} bytes", space);
+
+        Ok(())
+    }
+
+    /// Create the roles account (admin only)
+    pub fn initialize_roles(ctx: Context<InitializeRoles>) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let roles = &mut ctx.accounts.roles;
+        roles.members = Vec::new();
+        roles.bump = ctx.bumps.roles;
+
+        msg!("Roles initialized");
+
+        Ok(())
+    }
+
+    /// Grant a role to a wallet (admin only)
+    pub fn grant_role(ctx: Context<UpdateRoles>, member: Pubkey, role: Role) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        require!(ctx.accounts.roles.grant(member, role), ErrorCode::RolesFull);
+
+        emit!(RoleGranted {
+            member,
+            role,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Role {:?} granted to {}", role, member);
+
+        Ok(())
+    }
+
+    /// Revoke a role from a wallet (admin only)
+    pub fn revoke_role(ctx: Context<UpdateRoles>, member: Pubkey, role: Role) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        require!(
+            ctx.accounts.roles.revoke(&member, role),
+            ErrorCode::RoleNotGranted
+        );
+
+        emit!(RoleRevoked {
+            member,
+            role,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Role {:?} revoked from {}", role, member);
+
+        Ok(())
+    }
+
+    /// Queue a merchant payout wallet, platform fee or delay change behind its timelock
+    ///
+    /// Payout wallet changes are allowed to the treasury manager until a multisig is set
+    /// and platform fee changes to the fee manager; delay changes are admin only. Each
+    /// merchant can have one payout wallet change pending at a time.
+    pub fn queue_config_change(ctx: Context<QueueConfigChange>, change: ConfigChange) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let proposer = ctx.accounts.proposer.key();
+        match change.role(config) {
+            Some(role) => check_role(
+                config,
+                ctx.accounts.roles.as_deref(),
+                &proposer,
+                ctx.remaining_accounts,
+                role,
+            )?,
+            None => check_admin(config, &proposer, ctx.remaining_accounts)?,
+        }
+        if let ConfigChange::PlatformFee { new_fee_bps } = change {
+            require!(new_fee_bps <= fees::MAX_PLATFORM_FEE_BPS, ErrorCode::FeeTooHigh);
+            check_total_fee(new_fee_bps, 0, config.max_total_fee_bps).map_err(core_error)?;
+        }
+
+        let queued_at = Clock::get()?.unix_timestamp;
+        let execute_after = queued_at
+            .checked_add(i64::from(change.delay_secs(config)))
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+        let pending_change = &mut ctx.accounts.pending_change;
+        pending_change.change = change;
+        pending_change.proposer = proposer;
+        pending_change.queued_at = queued_at;
+        pending_change.execute_after = execute_after;
+        pending_change.bump = ctx.bumps.pending_change;
+
+        emit!(ConfigChangeQueued {
+            change,
+            proposer,
+            execute_after,
+        });
+
+        msg!("Config change queued: {:?}", change);
+        msg!("Executable after: {}", execute_after);
+
+        Ok(())
+    }
+
+    /// Apply a queued config change once its delay has passed (anyone)
+    ///
+    /// A payout wallet change takes the merchant account it names.
+    pub fn execute_config_change(ctx: Context<ExecuteConfigChange>) -> Result<()> {
+        let pending_change = &ctx.accounts.pending_change;
+        require!(
+            Clock::get()?.unix_timestamp >= pending_change.execute_after,
+            ErrorCode::TimelockNotExpired
+        );
+
+        let config = &mut ctx.accounts.config;
+        match pending_change.change {
+            ConfigChange::PayoutWallet {
+                merchant,
+                new_payout_wallet,
+            } => {
+                let merchant_account = ctx
+                    .accounts
+                    .merchant
+                    .as_mut()
+                    .filter(|merchant_account| merchant_account.key() == merchant)
+                    .ok_or(ErrorCode::InvalidMerchant)?;
+                merchant_account.payout_wallet = new_payout_wallet;
+
+                emit!(MerchantUpdated {
+                    merchant_id: merchant_account.merchant_id,
+                    payout_wallet: new_payout_wallet,
+                    status: merchant_account.status,
+                    platform_fee_bps: merchant_account.platform_fee_bps,
+                    allowed_mints: merchant_account.allowed_mints.clone(),
+                    authority: pending_change.proposer,
+                });
+            }
+            ConfigChange::PlatformFee { new_fee_bps } => {
+                // The cap may have been lowered since the change was queued
+                check_total_fee(new_fee_bps, 0, config.max_total_fee_bps).map_err(core_error)?;
+                config.platform_fee_bps = new_fee_bps;
+            }
+            ConfigChange::TimelockDelays {
+                payout_wallet_delay_secs,
+                platform_fee_delay_secs,
+            } => {
+                config.payout_wallet_delay_secs = payout_wallet_delay_secs;
+                config.platform_fee_delay_secs = platform_fee_delay_secs;
+            }
+        }
+
+        emit!(ConfigChangeExecuted {
+            change: pending_change.change,
+            proposer: pending_change.proposer,
+        });
+
+        msg!("Config change executed: {:?}", pending_change.change);
+
+        Ok(())
+    }
+
+    /// Drop a queued config change (admin only)
+    pub fn cancel_config_change(ctx: Context<CancelConfigChange>) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        let pending_change = &ctx.accounts.pending_change;
+
+        emit!(ConfigChangeCancelled {
+            change: pending_change.change,
+            proposer: pending_change.proposer,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Config change cancelled: {:?}", pending_change.change);
+
+        Ok(())
+    }
+
+    /// Set the M-of-N multisig that replaces the single authority for admin instructions
+    /// (admin only)
+    ///
+    /// Other multisig signers are passed as signing remaining accounts. A zero threshold
+    /// with no signers hands control back to the authority alone.
+    pub fn set_multisig(
+        ctx: Context<UpdateConfig>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+        require!(
+            Config::is_valid_multisig(&signers, threshold),
+            ErrorCode::InvalidMultisig
+        );
+
+        let config = &mut ctx.accounts.config;
+        config.multisig_signers = signers.clone();
+        config.multisig_threshold = threshold;
+
+        emit!(MultisigUpdated {
+            signers,
+            threshold,
+            authority: ctx.accounts.authority.key(),
+        });
+
+        msg!("Multisig set to {} of {}", threshold, config.multisig_signers.len());
+
+        Ok(())
+    }
+
+    /// Pause the program (authority or pauser)
+    pub fn pause(ctx: Context<UpdateConfigByRole>) -> Result<()> {
+        ctx.accounts
+            .check_role(ctx.remaining_accounts, Role::Pauser)?;
+
+        let config = &mut ctx.accounts.config;
+        config.paused = true;
+
+        msg!("Program paused");
+
+        Ok(())
+    }
+
+    /// Unpause the program (authority or pauser)
+    pub fn unpause(ctx: Context<UpdateConfigByRole>) -> Result<()> {
+        ctx.accounts
+            .check_role(ctx.remaining_accounts, Role::Pauser)?;
+
+        let config = &mut ctx.accounts.config;
+        config.paused = false;
+
+        msg!("Program unpaused");
+
+        Ok(())
+    }
+
+    /// Emergency withdrawal (admin only)
+    pub fn emergency_withdraw<'info>(
+        ctx: Context<'_, '_, '_, 'info, EmergencyWithdraw<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        // Multisig signers are passed along to the transfer hook lookup, which ignores them
+        check_admin(
+            &ctx.accounts.config,
+            &ctx.accounts.authority.key(),
+            ctx.remaining_accounts,
+        )?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let config = &ctx.accounts.config;
+
+        // Transfer tokens from program token account to authority
+        let seeds = &[b"config".as_ref(), &[config.bump]];
+        let signer = &[&seeds[..]];
+
+        transfer_tokens(
+            &ctx.accounts.token_program,
+            &ctx.accounts.mint,
+            ctx.accounts.program_token_account.to_account_info(),
+            ctx.accounts.authority_token_account.to_account_info(),
+            ctx.accounts.config.to_account_info(),
+            ctx.remaining_accounts,
+            signer,
+            amount,
+        )?;
+
+        msg!("Emergency withdrawal: {} tokens", amount);
+
+        Ok(())
+    }
+}
+
+// Account structures
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Config::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: [u8; 32])]
+pub struct ProcessPayment<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + OrderRecord::space(1),
+        seeds = [b"order", order_id.as_ref()],
+        bump
+    )]
+    pub order_record: Account<'info, OrderRecord>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"token", mint.key().as_ref()],
+        bump = token_config.bump,
+        constraint = token_config.enabled @ ErrorCode::TokenNotSupported
+    )]
+    pub token_config: Account<'info, TokenConfig>,
+
+    #[account(
+        mut,
+        constraint = buyer_token_account.owner == buyer.key() @ ErrorCode::InvalidTokenAccount,
+        constraint = buyer_token_account.mint == mint.key() @ ErrorCode::InvalidTokenAccount
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = fee_treasury_token_account.owner == config.fee_treasury @ ErrorCode::InvalidFeeTreasury
+    )]
+    pub fee_treasury_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"merchant", merchant.merchant_id.as_ref()],
+        bump = merchant.bump
+    )]
+    pub merchant: Account<'info, Merchant>,
+
+    #[account(
+        mut,
+        constraint = merchant_token_account.owner == merchant.payout_wallet @ ErrorCode::InvalidMerchant,
+        constraint = merchant_token_account.mint == mint.key() @ ErrorCode::InvalidMerchant
+    )]
+    pub merchant_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: instructions sysvar, read for the Ed25519 instruction carrying the quote
+    #[account(address = instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    /// CHECK: closed order marker for the order ID, read by `check_not_closed`; need not exist
+    #[account(seeds = [b"closed_order", order_id.as_ref()], bump)]
+    pub closed_order: UncheckedAccount<'info>,
+
+    /// Affiliate earning the commission, omitted for a payment without commission
+    #[account(
+        mut,
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Option<Account<'info, Affiliate>>,
+
+    /// The affiliate's commission ledger for the mint, required with `affiliate`
+    #[account(
+        mut,
+        seeds = [
+            b"commission",
+            commission_ledger.affiliate.as_ref(),
+            commission_ledger.mint.as_ref()
+        ],
+        bump = commission_ledger.bump
+    )]
+    pub commission_ledger: Option<Account<'info, CommissionLedger>>,
+
+    /// The ledger's vault receiving the commission, required with `affiliate`
+    #[account(mut)]
+    pub commission_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+}
+
+impl<'info> ProcessPayment<'info> {
+    fn transfer_from_buyer(
+        &self,
+        to: AccountInfo<'info>,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.mint,
+            self.buyer_token_account.to_account_info(),
+            to,
+            self.buyer.to_account_info(),
+            remaining_accounts,
+            &[],
+            amount,
+        )
+    }
+
+    fn transfer_to_merchant(
+        &self,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        self.transfer_from_buyer(
+            self.merchant_token_account.to_account_info(),
+            amount,
+            remaining_accounts,
+        )
+    }
+
+    fn transfer_to_fee_treasury(
+        &self,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        self.transfer_from_buyer(
+            self.fee_treasury_token_account.to_account_info(),
+            amount,
+            remaining_accounts,
+        )
+    }
+
+    /// Pay the commission into the affiliate's vault and add what arrived to its ledger
+    fn accrue_commission(
+        &mut self,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        let Some(affiliate) = &self.affiliate else {
+            return Ok(0);
+        };
+        let (Some(commission_ledger), Some(commission_vault)) =
+            (&self.commission_ledger, &self.commission_vault)
+        else {
+            return err!(ErrorCode::InvalidCommissionLedger);
+        };
+        commission_ledger.check_accounts(
+            &affiliate.key(),
+            &self.mint.key(),
+            &commission_vault.key(),
+        )?;
+
+        let commission = self.transfer_from_buyer(
+            commission_vault.to_account_info(),
+            amount,
+            remaining_accounts,
+        )?;
+        if let Some(commission_ledger) = &mut self.commission_ledger {
+            commission_ledger.accrued = commission_ledger
+                .accrued
+                .checked_add(commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+
+        Ok(commission)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: [u8; 32], total_amount: u64, merchant_count: u8)]
+pub struct ProcessCartPayment<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + OrderRecord::space(usize::from(merchant_count)),
+        seeds = [b"order", order_id.as_ref()],
+        bump
+    )]
+    pub order_record: Account<'info, OrderRecord>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"token", mint.key().as_ref()],
+        bump = token_config.bump,
+        constraint = token_config.enabled @ ErrorCode::TokenNotSupported
+    )]
+    pub token_config: Account<'info, TokenConfig>,
+
+    #[account(
+        mut,
+        constraint = buyer_token_account.owner == buyer.key() @ ErrorCode::InvalidTokenAccount,
+        constraint = buyer_token_account.mint == mint.key() @ ErrorCode::InvalidTokenAccount
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = fee_treasury_token_account.owner == config.fee_treasury @ ErrorCode::InvalidFeeTreasury
+    )]
+    pub fee_treasury_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: instructions sysvar, read for the Ed25519 instruction carrying the quote
+    #[account(address = instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    /// CHECK: closed order marker for the order ID, read by `check_not_closed`; need not exist
+    #[account(seeds = [b"closed_order", order_id.as_ref()], bump)]
+    pub closed_order: UncheckedAccount<'info>,
+
+    /// Affiliate earning the commission, omitted for a payment without commission
+    #[account(
+        mut,
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Option<Account<'info, Affiliate>>,
+
+    /// The affiliate's commission ledger for the mint, required with `affiliate`
+    #[account(
+        mut,
+        seeds = [
+            b"commission",
+            commission_ledger.affiliate.as_ref(),
+            commission_ledger.mint.as_ref()
+        ],
+        bump = commission_ledger.bump
+    )]
+    pub commission_ledger: Option<Account<'info, CommissionLedger>>,
+
+    /// The ledger's vault receiving the commission, required with `affiliate`
+    #[account(mut)]
+    pub commission_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+}
+
+impl<'info> ProcessCartPayment<'info> {
+    fn transfer_from_buyer(
+        &self,
+        to: AccountInfo<'info>,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.mint,
+            self.buyer_token_account.to_account_info(),
+            to,
+            self.buyer.to_account_info(),
+            remaining_accounts,
+            &[],
+            amount,
+        )
+    }
+
+    /// Pay the commission into the affiliate's vault and add what arrived to its ledger
+    fn accrue_commission(
+        &mut self,
+        amount: u64,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<u64> {
+        let Some(affiliate) = &self.affiliate else {
+            return Ok(0);
+        };
+        let (Some(commission_ledger), Some(commission_vault)) =
+            (&self.commission_ledger, &self.commission_vault)
+        else {
+            return err!(ErrorCode::InvalidCommissionLedger);
+        };
+        commission_ledger.check_accounts(
+            &affiliate.key(),
+            &self.mint.key(),
+            &commission_vault.key(),
+        )?;
+
+        let commission = self.transfer_from_buyer(
+            commission_vault.to_account_info(),
+            amount,
+            remaining_accounts,
+        )?;
+        if let Some(commission_ledger) = &mut self.commission_ledger {
+            commission_ledger.accrued = commission_ledger
+                .accrued
+                .checked_add(commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+
+        Ok(commission)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: [u8; 32])]
+pub struct ProcessSolPayment<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = 8 + OrderRecord::space(1),
+        seeds = [b"order", order_id.as_ref()],
+        bump
+    )]
+    pub order_record: Account<'info, OrderRecord>,
+
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(
+        seeds = [b"merchant", merchant.merchant_id.as_ref()],
+        bump = merchant.bump
+    )]
+    pub merchant: Account<'info, Merchant>,
+
+    #[account(
+        mut,
+        address = merchant.payout_wallet @ ErrorCode::InvalidMerchant
+    )]
+    pub merchant_payout_wallet: SystemAccount<'info>,
+
+    #[account(
+        mut,
+        address = config.fee_treasury @ ErrorCode::InvalidFeeTreasury
+    )]
+    pub fee_treasury: SystemAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: instructions sysvar, read for the Ed25519 instruction carrying the quote
+    #[account(address = instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    /// CHECK: closed order marker for the order ID, read by `check_not_closed`; need not exist
+    #[account(seeds = [b"closed_order", order_id.as_ref()], bump)]
+    pub closed_order: UncheckedAccount<'info>,
+
+    /// Affiliate earning the commission, omitted for a payment without commission
+    #[account(
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Option<Account<'info, Affiliate>>,
+
+    /// The affiliate's SOL commission ledger, required with the affiliate
+    #[account(
+        mut,
+        seeds = [b"sol_commission", commission_ledger.affiliate.as_ref()],
+        bump = commission_ledger.bump
+    )]
+    pub commission_ledger: Option<Account<'info, CommissionLedger>>,
+}
+
+impl<'info> ProcessSolPayment<'info> {
+    fn transfer_from_buyer(&self, to: AccountInfo<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let cpi_accounts = system_program::Transfer {
+            from: self.buyer.to_account_info(),
+            to,
+        };
+        let cpi_program = self.system_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        system_program::transfer(cpi_ctx, amount)
+    }
+
+    fn transfer_to_merchant(&self, amount: u64) -> Result<()> {
+        self.transfer_from_buyer(self.merchant_payout_wallet.to_account_info(), amount)
+    }
+
+    fn transfer_to_fee_treasury(&self, amount: u64) -> Result<()> {
+        self.transfer_from_buyer(self.fee_treasury.to_account_info(), amount)
+    }
+
+    /// Pay the commission into the affiliate's SOL ledger and add it to the ledger
+    fn accrue_commission(&mut self, amount: u64) -> Result<()> {
+        let Some(affiliate) = &self.affiliate else {
+            return Ok(());
+        };
+        let Some(commission_ledger) = &self.commission_ledger else {
+            return err!(ErrorCode::InvalidCommissionLedger);
+        };
+        require_keys_eq!(
+            commission_ledger.affiliate,
+            affiliate.key(),
+            ErrorCode::InvalidCommissionLedger
+        );
+        require_keys_eq!(
+            commission_ledger.mint,
+            spl_token::native_mint::ID,
+            ErrorCode::InvalidCommissionLedger
+        );
+
+        self.transfer_from_buyer(commission_ledger.to_account_info(), amount)?;
+        if let Some(commission_ledger) = &mut self.commission_ledger {
+            commission_ledger.accrued = commission_ledger
+                .accrued
+                .checked_add(amount)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct RefundOrder<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"order", order_record.order_id.as_ref()],
+        bump = order_record.bump
+    )]
+    pub order_record: Account<'info, OrderRecord>,
+
+    pub refunder: Signer<'info>,
+
+    #[account(
+        address = order_record.token_mint @ ErrorCode::InvalidTokenAccount,
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = refund_source_token_account.owner == refunder.key() @ ErrorCode::InvalidTokenAccount,
+        constraint = refund_source_token_account.mint == order_record.token_mint @ ErrorCode::InvalidTokenAccount
+    )]
+    pub refund_source_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = buyer_token_account.owner == order_record.buyer @ ErrorCode::InvalidTokenAccount,
+        constraint = buyer_token_account.mint == order_record.token_mint @ ErrorCode::InvalidTokenAccount
+    )]
+    pub buyer_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// Only needed when the refunder holds the role through the roles account
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseOrder<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"order", order_record.order_id.as_ref()],
+        bump = order_record.bump,
+        has_one = buyer @ ErrorCode::Unauthorized,
+        close = buyer
+    )]
+    pub order_record: Account<'info, OrderRecord>,
+
+    /// Receives the order record's rent
+    #[account(mut)]
+    pub buyer: SystemAccount<'info>,
+
+    /// CHECK: closed order marker, created by `mark_order_closed`
+    #[account(
+        mut,
+        seeds = [b"closed_order", order_record.order_id.as_ref()],
+        bump
+    )]
+    pub closed_order: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddSupportedToken<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + TokenConfig::INIT_SPACE,
+        seeds = [b"token", mint.key().as_ref()],
+        bump
+    )]
+    pub token_config: Account<'info, TokenConfig>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// Only needed when the signer is not the authority
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTokenConfig<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"token", token_config.mint.as_ref()],
+        bump = token_config.bump
+    )]
+    pub token_config: Account<'info, TokenConfig>,
+
+    pub authority: Signer<'info>,
+
+    /// Only needed when the signer is not the authority
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveSupportedToken<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"token", token_config.mint.as_ref()],
+        bump = token_config.bump
+    )]
+    pub token_config: Account<'info, TokenConfig>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// Only needed when the signer is not the authority
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+#[derive(Accounts)]
+#[instruction(merchant_id: [u8; 32])]
+pub struct RegisterMerchant<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Merchant::INIT_SPACE,
+        seeds = [b"merchant", merchant_id.as_ref()],
+        bump
+    )]
+    pub merchant: Account<'info, Merchant>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(api_key_hash: [u8; 32])]
+pub struct RegisterAffiliate<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Affiliate::INIT_SPACE,
+        seeds = [b"affiliate", api_key_hash.as_ref()],
+        bump
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAffiliate<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OpenCommissionLedger<'info> {
+    #[account(
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    #[account(
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + CommissionLedger::INIT_SPACE,
+        seeds = [b"commission", affiliate.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub commission_ledger: Account<'info, CommissionLedger>,
+
+    #[account(
+        init,
+        payer = payer,
+        seeds = [b"commission_vault", affiliate.key().as_ref(), mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = commission_ledger,
+        token::token_program = token_program
+    )]
+    pub commission_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenSolCommissionLedger<'info> {
+    #[account(
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + CommissionLedger::INIT_SPACE,
+        seeds = [b"sol_commission", affiliate.key().as_ref()],
+        bump
+    )]
+    pub commission_ledger: Account<'info, CommissionLedger>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimSolCommission<'info> {
+    #[account(
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump,
+        has_one = owner @ ErrorCode::Unauthorized
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    #[account(
+        mut,
+        seeds = [b"sol_commission", affiliate.key().as_ref()],
+        bump = commission_ledger.bump
+    )]
+    pub commission_ledger: Account<'info, CommissionLedger>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimCommission<'info> {
+    #[account(
+        seeds = [b"affiliate", affiliate.api_key_hash.as_ref()],
+        bump = affiliate.bump,
+        has_one = owner @ ErrorCode::Unauthorized
+    )]
+    pub affiliate: Account<'info, Affiliate>,
+
+    #[account(
+        mut,
+        seeds = [b"commission", affiliate.key().as_ref(), mint.key().as_ref()],
+        bump = commission_ledger.bump
+    )]
+    pub commission_ledger: Account<'info, CommissionLedger>,
+
+    #[account(
+        mut,
+        seeds = [b"commission_vault", affiliate.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub commission_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = owner_token_account.owner == owner.key() @ ErrorCode::InvalidTokenAccount,
+        constraint = owner_token_account.mint == mint.key() @ ErrorCode::InvalidTokenAccount
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    pub owner: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMerchant<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"merchant", merchant.merchant_id.as_ref()],
+        bump = merchant.bump
+    )]
+    pub merchant: Account<'info, Merchant>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeRoles<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Roles::INIT_SPACE,
+        seeds = [b"roles"],
+        bump
+    )]
+    pub roles: Account<'info, Roles>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateRoles<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [b"roles"],
+        bump = roles.bump
+    )]
+    pub roles: Account<'info, Roles>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub pending_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    /// CHECK: config in the legacy layout, validated and rewritten by the handler
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump,
+        owner = crate::ID
+    )]
+    pub config: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Config update allowed to the authority or a holder of the role the handler checks
+#[derive(Accounts)]
+pub struct UpdateConfigByRole<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub signer: Signer<'info>,
+
+    /// Only needed when the signer is not the authority
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+impl<'info> UpdateConfigByRole<'info> {
+    fn check_role(&self, remaining_accounts: &[AccountInfo], role: Role) -> Result<()> {
+        check_role(
+            &self.config,
+            self.roles.as_deref(),
+            &self.signer.key(),
+            remaining_accounts,
+            role,
+        )
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(change: ConfigChange)]
+pub struct QueueConfigChange<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        init,
+        payer = proposer,
+        space = 8 + PendingChange::INIT_SPACE,
+        seeds = [b"pending_change".as_ref(), &[change.kind()], change.merchant_seed()],
+        bump
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// Only needed when the proposer is not the authority
+    #[account(seeds = [b"roles"], bump = roles.bump)]
+    pub roles: Option<Account<'info, Roles>>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteConfigChange<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pending_change".as_ref(),
+            &[pending_change.change.kind()],
+            pending_change.change.merchant_seed()
+        ],
+        bump = pending_change.bump,
+        has_one = proposer @ ErrorCode::InvalidProposer,
+        close = proposer
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+
+    /// Wallet that queued the change, refunded the pending change rent
+    #[account(mut)]
+    pub proposer: SystemAccount<'info>,
+
+    /// Only needed for a payout wallet change, the merchant it names
+    #[account(mut)]
+    pub merchant: Option<Account<'info, Merchant>>,
+}
+
+#[derive(Accounts)]
+pub struct CancelConfigChange<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"pending_change".as_ref(),
+            &[pending_change.change.kind()],
+            pending_change.change.merchant_seed()
+        ],
+        bump = pending_change.bump,
+        has_one = proposer @ ErrorCode::InvalidProposer,
+        close = proposer
+    )]
+    pub pending_change: Account<'info, PendingChange>,
+
+    /// Wallet that queued the change, refunded the pending change rent
+    #[account(mut)]
+    pub proposer: SystemAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(
+        seeds = [b"config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, Config>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        constraint = *mint.to_account_info().owner == token_program.key() @ ErrorCode::InvalidTokenProgram
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub program_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub authority_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Token transfers
+
+/// `transfer_checked` `amount` tokens of `mint`, skipping zero-value transfers, and return
+/// what the destination received after the Token-2022 transfer fee, if the mint has one
+///
+/// `remaining_accounts` are searched for the mint's transfer hook accounts.
+#[allow(clippy::too_many_arguments)]
+fn transfer_tokens<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<u64> {
+    if amount == 0 {
+        return Ok(0);
+    }
+
+    let transfer_fee = {
+        let mint_info = mint.to_account_info();
+        let mint_data = mint_info.try_borrow_data()?;
+        let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+        match mint_state.get_extension::<TransferFeeConfig>() {
+            Ok(fee_config) => fee_config
+                .calculate_epoch_fee(Clock::get()?.epoch, amount)
+                .ok_or(ErrorCode::ArithmeticOverflow)?,
+            Err(_) => 0,
+        }
+    };
+
+    spl_token_2022::onchain::invoke_transfer_checked(
+        token_program.key,
+        from,
+        mint.to_account_info(),
+        to,
+        authority,
+        remaining_accounts,
+        amount,
+        mint.decimals,
+        signer_seeds,
+    )?;
+
+    Ok(amount
+        .checked_sub(transfer_fee)
+        .ok_or(ErrorCode::ArithmeticOverflow)?)
+}
+
+// Data structures
+
+#[account]
+#[derive(InitSpace)]
+pub struct Config {
+    pub authority: Pubkey,          // 32
+    pub pending_authority: Pubkey,   // 32 (default when no transfer is pending)
+    #[max_len(5)]
+    pub multisig_signers: Vec<Pubkey>, // 4 + 32 * 5
+    pub multisig_threshold: u8,      // 1 (0 when the authority acts alone)
+    pub fee_treasury: Pubkey,        // 32
+    pub refund_operator: Pubkey,     // 32
+    pub quote_signer: Pubkey,        // 32 (default when quotes are not required)
+    pub payout_wallet_delay_secs: u32, // 4
+    pub platform_fee_delay_secs: u32, // 4
+    pub max_quote_lifetime_secs: u32, // 4 (0 = no limit on payment and quote deadlines)
+    pub order_retention_secs: u32,   // 4
+    pub max_total_fee_bps: u16,      // 2 (cap on platform fee plus commission)
+    pub platform_fee_bps: u16,       // 2
+    pub paused: bool,                // 1
+    pub bump: u8,                    // 1
+}
+
+impl Config {
+    pub const DEFAULT_CHANGE_DELAY_SECS: u32 = oxmart_payment_core::DEFAULT_CHANGE_DELAY_SECS;
+    pub const DEFAULT_MAX_QUOTE_LIFETIME_SECS: u32 =
+        oxmart_payment_core::DEFAULT_MAX_QUOTE_LIFETIME_SECS;
+    pub const DEFAULT_ORDER_RETENTION_SECS: u32 = oxmart_payment_core::DEFAULT_ORDER_RETENTION_SECS;
+    pub const DEFAULT_MAX_TOTAL_FEE_BPS: u16 = fees::DEFAULT_MAX_TOTAL_FEE_BPS;
+    pub const MAX_MULTISIG_SIGNERS: usize = 5;
+
+    /// Whether `key` acts as the admin: the authority, or a multisig signer once a multisig is set
+    pub fn is_admin(&self, key: &Pubkey) -> bool {
+        if self.multisig_threshold == 0 {
+            *key == self.authority
+        } else {
+            self.multisig_signers.contains(key)
+        }
+    }
+
+    /// Check a multisig for `set_multisig`: no signers with a zero threshold to disable it,
+    /// otherwise up to `MAX_MULTISIG_SIGNERS` distinct signers and a threshold they can reach
+    pub fn is_valid_multisig(signers: &[Pubkey], threshold: u8) -> bool {
+        if threshold == 0 {
+            return signers.is_empty();
+        }
+
+        signers.len() <= Self::MAX_MULTISIG_SIGNERS
+            && usize::from(threshold) <= signers.len()
+            && !signers.contains(&Pubkey::default())
+            && signers
+                .iter()
+                .enumerate()
+                .all(|(i, signer)| !signers[..i].contains(signer))
+    }
+}
+
+/// Config as laid out by the first release, read by `migrate_config`
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyConfig {
+    pub authority: Pubkey,
+    pub hot_wallet: Pubkey,
+    pub platform_fee_bps: u16,
+    pub paused: bool,
+    pub bump: u8,
+}
+
+impl LegacyConfig {
+    pub const LEN: usize = 32 + 32 + 2 + 1 + 1;
+}
+
+/// Permission that the authority can grant to other wallets
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Pause and unpause the program
+    Pauser,
+    /// Update the platform fee
+    FeeManager,
+    /// Add, update and remove supported tokens
+    TokenManager,
+    /// Refund orders
+    RefundOperator,
+    /// Update merchant payout wallets and the fee treasury
+    TreasuryManager,
+}
+
+impl Role {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct RoleMember {
+    pub member: Pubkey,                    // 32
+    pub roles: u8,                         // 1 (one bit per `Role`)
+}
+
+/// Role assignments; the authority implicitly holds every role
+#[account]
+#[derive(InitSpace)]
+pub struct Roles {
+    #[max_len(10)]
+    pub members: Vec<RoleMember>,          // 4 + 10 * 33
+    pub bump: u8,                          // 1
+}
+
+impl Roles {
+    pub const MAX_MEMBERS: usize = 10;
+
+    pub fn has_role(&self, member: &Pubkey, role: Role) -> bool {
+        self.members
+            .iter()
+            .any(|entry| entry.member == *member && entry.roles & role.mask() != 0)
+    }
+
+    /// Grant `role` to `member`, returning false when a new entry would exceed `MAX_MEMBERS`
+    pub fn grant(&mut self, member: Pubkey, role: Role) -> bool {
+        if let Some(entry) = self.members.iter_mut().find(|entry| entry.member == member) {
+            entry.roles |= role.mask();
+            return true;
+        }
+
+        if self.members.len() >= Self::MAX_MEMBERS {
+            return false;
+        }
+
+        self.members.push(RoleMember {
+            member,
+            roles: role.mask(),
+        });
+        true
+    }
+
+    /// Revoke `role` from `member`, dropping members left without roles.
+    /// Returns false when the member did not hold the role.
+    pub fn revoke(&mut self, member: &Pubkey, role: Role) -> bool {
+        if !self.has_role(member, role) {
+            return false;
+        }
+
+        self.members.retain_mut(|entry| {
+            if entry.member == *member {
+                entry.roles &= !role.mask();
+            }
+            entry.roles != 0
+        });
+        true
+    }
+}
+
+/// Config change queued behind a timelock
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum ConfigChange {
+    /// Pay a merchant at a new payout wallet
+    PayoutWallet {
+        merchant: Pubkey,
+        new_payout_wallet: Pubkey,
+    },
+    /// Charge a new platform fee
+    PlatformFee { new_fee_bps: u16 },
+    /// Change the delays themselves
+    TimelockDelays {
+        payout_wallet_delay_secs: u32,
+        platform_fee_delay_secs: u32,
+    },
+}
+
+impl ConfigChange {
+    /// Seed of the pending change PDA, so at most one change of each kind is queued
+    pub fn kind(&self) -> u8 {
+        match self {
+            ConfigChange::PayoutWallet { .. } => 0,
+            ConfigChange::PlatformFee { .. } => 1,
+            ConfigChange::TimelockDelays { .. } => 2,
+        }
+    }
+
+    /// Extra pending change PDA seed, the merchant for payout wallet changes so each
+    /// merchant gets its own queue slot, and empty otherwise
+    pub fn merchant_seed(&self) -> &[u8] {
+        match self {
+            ConfigChange::PayoutWallet { merchant, .. } => merchant.as_ref(),
+            _ => &[],
+        }
+    }
+
+    /// Role allowed to queue the change besides the admin, `None` when admin only
+    ///
+    /// Payout wallet changes become admin only once a multisig is set.
+    fn role(&self, config: &Config) -> Option<Role> {
+        match self {
+            ConfigChange::PayoutWallet { .. } if config.multisig_threshold > 0 => None,
+            ConfigChange::PayoutWallet { .. } => Some(Role::TreasuryManager),
+            ConfigChange::PlatformFee { .. } => Some(Role::FeeManager),
+            ConfigChange::TimelockDelays { .. } => None,
+        }
+    }
+
+    /// Seconds the change waits before it can be executed
+    pub fn delay_secs(&self, config: &Config) -> u32 {
+        match self {
+            ConfigChange::PayoutWallet { .. } => config.payout_wallet_delay_secs,
+            ConfigChange::PlatformFee { .. } => config.platform_fee_delay_secs,
+            // Shortening a delay must itself wait out the longest current one
+            ConfigChange::TimelockDelays { .. } => config
+                .payout_wallet_delay_secs
+                .max(config.platform_fee_delay_secs),
+        }
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct PendingChange {
+    pub change: ConfigChange,              // 1 + 64
+    pub proposer: Pubkey,                  // 32 (paid for the account, refunded on close)
+    pub queued_at: i64,                    // 8
+    pub execute_after: i64,                // 8
+    pub bump: u8,                          // 1
+}
+
+/// Require `signer` to be the authority or, once a multisig is set, at least
+/// `multisig_threshold` distinct multisig signers among `signer` and the signing
+/// `remaining_accounts`
+fn check_admin(config: &Config, signer: &Pubkey, remaining_accounts: &[AccountInfo]) -> Result<()> {
+    if config.multisig_threshold == 0 {
+        require_keys_eq!(*signer, config.authority, ErrorCode::Unauthorized);
+        return Ok(());
+    }
+
+    let co_signers = remaining_accounts
+        .iter()
+        .filter(|account| account.is_signer)
+        .map(|account| account.key);
+    let mut approvals: Vec<&Pubkey> = Vec::new();
+    for key in std::iter::once(signer).chain(co_signers) {
+        if config.multisig_signers.contains(key) && !approvals.contains(&key) {
+            approvals.push(key);
+        }
+    }
+    require!(
+        approvals.len() >= usize::from(config.multisig_threshold),
+        ErrorCode::MultisigThresholdNotMet
+    );
+    Ok(())
+}
+
+/// Require `signer` to pass `check_admin` or hold `role` in `roles`
+fn check_role(
+    config: &Config,
+    roles: Option<&Roles>,
+    signer: &Pubkey,
+    remaining_accounts: &[AccountInfo],
+    role: Role,
+) -> Result<()> {
+    if config.is_admin(signer) {
+        return check_admin(config, signer, remaining_accounts);
+    }
+
+    require!(
+        roles.is_some_and(|roles| roles.has_role(signer, role)),
+        ErrorCode::Unauthorized
+    );
+    Ok(())
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct TokenConfig {
+    pub mint: Pubkey,                      // 32
+    pub enabled: bool,                     // 1
+    pub min_amount: u64,                   // 8
+    pub max_amount: u64,                   // 8 (0 = no maximum)
+    pub platform_fee_bps: Option<u16>,     // 1 + 2 (overrides config.platform_fee_bps)
+    pub bump: u8,                          // 1
+}
+
+impl TokenConfig {
+    fn set(
+        &mut self,
+        enabled: bool,
+        min_amount: u64,
+        max_amount: u64,
+        platform_fee_bps: Option<u16>,
+    ) -> Result<()> {
+        require!(
+            max_amount == 0 || min_amount <= max_amount,
+            ErrorCode::InvalidAmountLimits
+        );
+        if let Some(fee_bps) = platform_fee_bps {
+            require!(fee_bps <= fees::MAX_PLATFORM_FEE_BPS, ErrorCode::FeeTooHigh);
+        }
+
+        self.enabled = enabled;
+        self.min_amount = min_amount;
+        self.max_amount = max_amount;
+        self.platform_fee_bps = platform_fee_bps;
+
+        Ok(())
+    }
+
+    fn check_amount(&self, amount: u64) -> Result<()> {
+        require!(amount >= self.min_amount, ErrorCode::AmountBelowMinimum);
+        require!(
+            self.max_amount == 0 || amount <= self.max_amount,
+            ErrorCode::AmountAboveMaximum
+        );
+
+        Ok(())
+    }
+
+    /// The mint's fee override, or the program-wide platform fee
+    fn platform_fee_bps(&self, config: &Config) -> u16 {
+        self.platform_fee_bps.unwrap_or(config.platform_fee_bps)
+    }
+}
+
+/// One product of a batch payment
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct LineItem {
+    /// SHA-256 hash of the backend's product ID
+    pub product_id_hash: [u8; 32],
+    pub unit_price: u64,
+    pub quantity: u32,
+    /// Commission on this item, at most the affiliate's rate it takes when `None`
+    pub commission_bps: Option<u16>,
+}
+
+/// One product of a cart payment and the merchant selling it
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct CartItem {
+    /// Position of the merchant among the payment's merchant accounts
+    pub merchant_index: u8,
+    pub item: LineItem,
+}
+
+/// Amount and commission of one priced line item
+struct PricedItem {
+    amount: u64,
+    commission_bps: u16,
+    commission: u64,
+}
+
+impl PricedItem {
+    /// Check the item's commission and `platform_fee_bps` together stay within the cap
+    fn check_total_fee(&self, platform_fee_bps: u16, config: &Config) -> Result<()> {
+        check_total_fee(platform_fee_bps, self.commission_bps, config.max_total_fee_bps)
+            .map_err(core_error)?;
+
+        Ok(())
+    }
+}
+
+/// Line items of a batch payment, priced and committed to by their Merkle root
+struct Cart {
+    items: Vec<PricedItem>,
+    total_amount: u64,
+    commission: u64,
+    items_root: [u8; 32],
+}
+
+impl Cart {
+    /// Price `line_items` at the affiliate's `commission_bps`, which an item may only lower.
+    /// The platform fee depends on who sells an item, so callers check the total fee cap.
+    fn price(line_items: &[LineItem], commission_bps: u16) -> Result<Self> {
+        let mut items = Vec::with_capacity(line_items.len());
+        let mut leaves = Vec::with_capacity(line_items.len());
+        let (mut total_amount, mut commission) = (0u64, 0u64);
+
+        for item in line_items {
+            require!(item.quantity > 0, ErrorCode::InvalidAmount);
+            let item_commission_bps = item.commission_bps.unwrap_or(commission_bps);
+            require!(item_commission_bps <= commission_bps, ErrorCode::InvalidCommission);
+
+            let amount =
+                line_items::line_amount(item.unit_price, item.quantity).map_err(core_error)?;
+            let item_commission = bps_share(amount, item_commission_bps).map_err(core_error)?;
+            total_amount = total_amount
+                .checked_add(amount)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            commission = commission
+                .checked_add(item_commission)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+            leaves.push(line_items::leaf_hash(
+                &item.product_id_hash,
+                item.unit_price,
+                item.quantity,
+                item_commission_bps,
+            ));
+            items.push(PricedItem {
+                amount,
+                commission_bps: item_commission_bps,
+                commission: item_commission,
+            });
+        }
+
+        Ok(Self {
+            items,
+            total_amount,
+            commission,
+            items_root: line_items::merkle_root(&mut leaves),
+        })
+    }
+}
+
+/// Payment terms a quote must match
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct QuoteTerms {
+    pub program_id: Pubkey,
+    pub order_id: [u8; 32],
+    pub mint: Pubkey, // native mint for SOL payments
+    pub amount: u64,
+    pub merchants: Vec<Pubkey>, // merchant PDAs paid, in payment order
+    pub items_root: [u8; 32],   // Merkle root of the line items, all zeros for a single product
+    pub commission_bps: u16,
+    pub commission: u64, // commission charged over all items
+    pub api_key_owner: Pubkey,
+}
+
+/// Payment quote signed by the quote signer, checked through an Ed25519 program
+/// instruction placed right before the payment
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub struct PaymentQuote {
+    pub terms: QuoteTerms,
+    pub expires_at: i64,
+}
+
+/// Require the instruction before this one to be an Ed25519 program check of an unexpired
+/// quote for `terms` signed by the quote signer, unless the config has no quote signer
+fn verify_quote(
+    config: &Config,
+    instructions_sysvar: &AccountInfo,
+    terms: &QuoteTerms,
+) -> Result<()> {
+    if config.quote_signer == Pubkey::default() {
+        return Ok(());
+    }
+
+    let instruction = instructions::get_instruction_relative(-1, instructions_sysvar)
+        .map_err(|_| ErrorCode::InvalidQuote)?;
+    require_keys_eq!(
+        instruction.program_id,
+        ed25519_program::ID,
+        ErrorCode::InvalidQuote
+    );
+
+    let (public_key, message) =
+        parse_ed25519_instruction(&instruction.data).ok_or(ErrorCode::InvalidQuote)?;
+    require!(
+        public_key == config.quote_signer.as_ref(),
+        ErrorCode::InvalidQuote
+    );
+
+    let quote = PaymentQuote::try_from_slice(message).map_err(|_| ErrorCode::InvalidQuote)?;
+    require!(quote.terms == *terms, ErrorCode::InvalidQuote);
+    check_deadline(config, quote.expires_at, ErrorCode::QuoteExpired)
+}
+
+/// Map a shared fee math error onto this program's error codes
+fn core_error(error: CoreError) -> ErrorCode {
+    match error {
+        CoreError::InvalidBasisPoints => ErrorCode::InvalidCommission,
+        CoreError::DeductionsExceedAmount => ErrorCode::DeductionsExceedAmount,
+        CoreError::Overflow => ErrorCode::ArithmeticOverflow,
+        CoreError::TotalFeeTooHigh => ErrorCode::TotalFeeTooHigh,
+    }
+}
+
+/// Fail with `expired_error` once `expires_at` has passed, and reject deadlines further ahead
+/// than the config's maximum quote lifetime
+fn check_deadline(config: &Config, expires_at: i64, expired_error: ErrorCode) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    if now > expires_at {
+        return Err(expired_error.into());
+    }
+
+    let max_lifetime = i64::from(config.max_quote_lifetime_secs);
+    require!(
+        max_lifetime == 0 || expires_at.saturating_sub(now) <= max_lifetime,
+        ErrorCode::ExpiryTooFar
+    );
+
+    Ok(())
+}
+
+/// Public key and message of an Ed25519 program instruction checking a single signature,
+/// `None` unless its signature, key and message all sit in the instruction's own data
+fn parse_ed25519_instruction(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    // One signature count byte, one padding byte, then seven u16 offsets
+    if data.len() < 16 || data[0] != 1 {
+        return None;
+    }
+
+    let offset =
+        |index: usize| usize::from(u16::from_le_bytes([data[2 + 2 * index], data[3 + 2 * index]]));
+    let (public_key_offset, message_offset, message_size) = (offset(2), offset(4), offset(5));
+
+    // Pointing at another instruction would let its data differ from what was checked here
+    let current_instruction = usize::from(u16::MAX);
+    if [offset(1), offset(3), offset(6)] != [current_instruction; 3] {
+        return None;
+    }
+
+    let public_key = data.get(public_key_offset..public_key_offset + 32)?;
+    let message = data.get(message_offset..message_offset + message_size)?;
+
+    Some((public_key, message))
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct OrderRecord {
+    pub order_id: [u8; 32],          // 32
+    pub buyer: Pubkey,                // 32
+    pub token_mint: Pubkey,           // 32
+    pub amount: u64,                  // 8
+    pub platform_fee: u64,            // 8
+    pub commission: u64,              // 8
+    pub refunded_amount: u64,         // 8
     pub api_key_owner: Pubkey,        // 32
     #[max_len(50)]
-    pub product_id: String,           // 4 + 50
+    pub product_id: String,           // 4 + 50 ("BATCH" for batch payments)
+    pub items_root: [u8; 32],         // 32 (Merkle root of the line items, zero unless batch)
+    pub item_count: u8,               // 1 (0 unless batch)
     pub processed: bool,              // 1
     pub timestamp: i64,               // 8
     pub bump: u8,                     // 1
+    #[max_len(0)]
+    pub merchant_legs: Vec<MerchantLeg>, // 4 + 64 per leg (empty for SOL payments)
+}
+
+impl OrderRecord {
+    /// Longest product id, in bytes, the record has room for
+    pub const MAX_PRODUCT_ID_LEN: usize = 50;
+
+    /// Record size, excluding the discriminator, for an order paying `merchant_count`
+    /// merchants
+    pub fn space(merchant_count: usize) -> usize {
+        Self::INIT_SPACE + merchant_count * MerchantLeg::INIT_SPACE
+    }
+}
+
+/// One merchant's share of a payment
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace, PartialEq, Eq, Debug)]
+pub struct MerchantLeg {
+    pub merchant: Pubkey,     // 32 (the merchant PDA)
+    pub amount: u64,          // 8 (the merchant's items)
+    pub platform_fee: u64,    // 8
+    pub commission: u64,      // 8
+    pub net_amount: u64,      // 8 (arrived in the payout token account)
+}
+
+/// Seller paid directly by payments (seeds ["merchant", merchant_id])
+#[account]
+#[derive(InitSpace)]
+pub struct Merchant {
+    pub merchant_id: [u8; 32],           // 32 (SHA-256 hash of the backend's seller ID)
+    pub payout_wallet: Pubkey,           // 32 (owner of the merchant's payout token accounts)
+    pub status: MerchantStatus,          // 1
+    pub platform_fee_bps: Option<u16>,   // 1 + 2 (overrides the mint's platform fee)
+    #[max_len(5)]
+    pub allowed_mints: Vec<Pubkey>,      // 4 + 5 * 32 (empty = every supported mint)
+    pub bump: u8,                        // 1
+}
+
+impl Merchant {
+    pub const MAX_ALLOWED_MINTS: usize = 5;
+
+    /// Replace the merchant's settings; a fee override may exceed neither the platform fee
+    /// limit nor the config's total fee cap
+    fn set(
+        &mut self,
+        config: &Config,
+        payout_wallet: Pubkey,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> Result<()> {
+        if let Some(fee_bps) = platform_fee_bps {
+            require!(
+                fee_bps <= fees::MAX_PLATFORM_FEE_BPS && fee_bps <= config.max_total_fee_bps,
+                ErrorCode::FeeTooHigh
+            );
+        }
+        require!(
+            allowed_mints.len() <= Self::MAX_ALLOWED_MINTS,
+            ErrorCode::TooManyAllowedMints
+        );
+
+        self.payout_wallet = payout_wallet;
+        self.status = status;
+        self.platform_fee_bps = platform_fee_bps;
+        self.allowed_mints = allowed_mints;
+
+        Ok(())
+    }
+
+    /// Check the merchant is active and takes payments in `mint`
+    fn check_accepts(&self, mint: &Pubkey) -> Result<()> {
+        require!(self.status == MerchantStatus::Active, ErrorCode::MerchantSuspended);
+        require!(
+            self.allowed_mints.is_empty() || self.allowed_mints.contains(mint),
+            ErrorCode::MintNotAllowedForMerchant
+        );
+
+        Ok(())
+    }
+
+    /// The merchant's fee override, or the mint's platform fee
+    fn platform_fee_bps(&self, token_config: &TokenConfig, config: &Config) -> u16 {
+        self.platform_fee_bps
+            .unwrap_or_else(|| token_config.platform_fee_bps(config))
+    }
+}
+
+/// Whether a merchant can be paid
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum MerchantStatus {
+    Active,
+    /// Payments to the merchant are rejected
+    Suspended,
+}
+
+/// API key owner earning commission on the payments it refers
+/// (seeds ["affiliate", api_key_hash])
+#[account]
+#[derive(InitSpace)]
+pub struct Affiliate {
+    pub api_key_hash: [u8; 32],          // 32 (SHA-256 hash of the backend's API key ID)
+    pub owner: Pubkey,                   // 32 (recorded as the orders' API key owner)
+    pub commission_bps: u16,             // 2
+    pub status: AffiliateStatus,         // 1
+    pub total_earnings: u64,             // 8 (commission over its lifetime, across mints)
+    pub bump: u8,                        // 1
+}
+
+impl Affiliate {
+    /// Replace the affiliate's settings; the rate may exceed neither the commission limit
+    /// nor what the config's total fee cap leaves after the platform fee
+    fn set(
+        &mut self,
+        config: &Config,
+        owner: Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    ) -> Result<()> {
+        require!(
+            commission_bps <= fees::MAX_COMMISSION_BPS
+                && commission_bps
+                    <= config.max_total_fee_bps.saturating_sub(config.platform_fee_bps),
+            ErrorCode::InvalidCommission
+        );
+
+        self.owner = owner;
+        self.commission_bps = commission_bps;
+        self.status = status;
+
+        Ok(())
+    }
+
+    /// The owner and commission rate of a payment through the affiliate, which must be active
+    fn commission(&self) -> Result<(Pubkey, u16)> {
+        require!(self.status == AffiliateStatus::Active, ErrorCode::AffiliateSuspended);
+
+        Ok((self.owner, self.commission_bps))
+    }
+}
+
+/// Whether an affiliate earns commission
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum AffiliateStatus {
+    Active,
+    /// Payments through the affiliate and its commission claims are rejected
+    Suspended,
+}
+
+/// Commission an affiliate has accrued in one mint, held in the vault at
+/// ["commission_vault", affiliate, mint] until claimed (seeds ["commission", affiliate, mint]).
+/// The SOL ledger at ["sol_commission", affiliate] holds its commission as lamports instead.
+#[account]
+#[derive(InitSpace)]
+pub struct CommissionLedger {
+    pub affiliate: Pubkey,               // 32
+    pub mint: Pubkey,                    // 32
+    pub accrued: u64,                    // 8 (received into the vault over its lifetime)
+    pub claimed: u64,                    // 8 (withdrawn by `claim_commission`)
+    pub last_claim_at: i64,              // 8 (0 before the first claim)
+    pub bump: u8,                        // 1
+}
+
+impl CommissionLedger {
+    /// Check this is `affiliate`'s ledger for `mint` and `vault` its vault
+    fn check_accounts(&self, affiliate: &Pubkey, mint: &Pubkey, vault: &Pubkey) -> Result<()> {
+        require_keys_eq!(self.affiliate, *affiliate, ErrorCode::InvalidCommissionLedger);
+        require_keys_eq!(self.mint, *mint, ErrorCode::InvalidCommissionLedger);
+
+        let (vault_pda, _) = Pubkey::find_program_address(
+            &[b"commission_vault", affiliate.as_ref(), mint.as_ref()],
+            &crate::ID,
+        );
+        require_keys_eq!(*vault, vault_pda, ErrorCode::InvalidCommissionVault);
+
+        Ok(())
+    }
+}
+
+/// A merchant of a cart payment, its payout token account and platform fee
+struct MerchantPayout<'info> {
+    merchant: Pubkey,
+    payout_token_account: AccountInfo<'info>,
+    platform_fee_bps: u16,
+}
+
+/// Read `merchant_count` merchant PDA and payout token account pairs from the front of
+/// `remaining_accounts`, each merchant active, at most once and paid in `mint`.
+/// `mint_fee_bps` is the platform fee of merchants without a fee override.
+fn load_merchant_payouts<'info>(
+    remaining_accounts: &[AccountInfo<'info>],
+    merchant_count: usize,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    mint_fee_bps: u16,
+) -> Result<Vec<MerchantPayout<'info>>> {
+    require!(
+        (1..=line_items::MAX_LINE_ITEMS).contains(&merchant_count),
+        ErrorCode::InvalidMerchantIndex
+    );
+    require!(
+        remaining_accounts.len() >= 2 * merchant_count,
+        ErrorCode::InvalidMerchant
+    );
+
+    let mut payouts: Vec<MerchantPayout> = Vec::with_capacity(merchant_count);
+    for pair in remaining_accounts[..2 * merchant_count].chunks(2) {
+        let (merchant_info, payout_info) = (&pair[0], &pair[1]);
+        require_keys_eq!(*merchant_info.owner, crate::ID, ErrorCode::InvalidMerchant);
+        let merchant = Merchant::try_deserialize(&mut &merchant_info.try_borrow_data()?[..])
+            .map_err(|_| ErrorCode::InvalidMerchant)?;
+        merchant.check_accepts(mint)?;
+        require!(
+            payouts
+                .iter()
+                .all(|payout| payout.merchant != *merchant_info.key),
+            ErrorCode::DuplicateMerchant
+        );
+
+        require_keys_eq!(*payout_info.owner, *token_program, ErrorCode::InvalidMerchant);
+        let payout_token_account =
+            TokenAccount::try_deserialize(&mut &payout_info.try_borrow_data()?[..])
+                .map_err(|_| ErrorCode::InvalidMerchant)?;
+        require!(
+            payout_token_account.owner == merchant.payout_wallet
+                && payout_token_account.mint == *mint,
+            ErrorCode::InvalidMerchant
+        );
+
+        payouts.push(MerchantPayout {
+            merchant: *merchant_info.key,
+            payout_token_account: payout_info.clone(),
+            platform_fee_bps: merchant.platform_fee_bps.unwrap_or(mint_fee_bps),
+        });
+    }
+
+    Ok(payouts)
+}
+
+/// Marker left by `close_order`, so a closed order ID cannot be paid again
+///
+/// One account per closed order (seeds ["closed_order", order_id]). It holds only its
+/// discriminator, and its rent comes out of the closed order's.
+#[account]
+pub struct ClosedOrder {}
+
+impl ClosedOrder {
+    pub const LEN: usize = 0;
+}
+
+/// Fail when the order ID was closed, i.e. its closed order marker exists
+fn check_not_closed(closed_order: &AccountInfo) -> Result<()> {
+    require_keys_neq!(
+        *closed_order.owner,
+        crate::ID,
+        ErrorCode::OrderAlreadyProcessed
+    );
+
+    Ok(())
+}
+
+/// Create the closed order marker, funding it from the order record being closed
+///
+/// The marker is allocated and assigned before any lamports move: the runtime rejects a
+/// CPI made after this program changed the balance of an account it does not own.
+fn mark_order_closed<'info>(
+    order_record: &Account<'info, OrderRecord>,
+    closed_order: &UncheckedAccount<'info>,
+    system_program: &Program<'info, System>,
+    bump: u8,
+) -> Result<()> {
+    let order_id = order_record.order_id;
+    let seeds: &[&[u8]] = &[b"closed_order", order_id.as_ref(), &[bump]];
+    let space = 8 + ClosedOrder::LEN;
+
+    system_program::allocate(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Allocate {
+                account_to_allocate: closed_order.to_account_info(),
+            },
+            &[seeds],
+        ),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            system_program::Assign {
+                account_to_assign: closed_order.to_account_info(),
+            },
+            &[seeds],
+        ),
+        &crate::ID,
+    )?;
+
+    let rent = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(closed_order.lamports());
+    let order_info = order_record.to_account_info();
+    **order_info.try_borrow_mut_lamports()? = order_info
+        .lamports()
+        .checked_sub(rent)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    **closed_order.try_borrow_mut_lamports()? = closed_order
+        .lamports()
+        .checked_add(rent)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+
+    closed_order.try_borrow_mut_data()?[..8].copy_from_slice(&ClosedOrder::DISCRIMINATOR);
+
+    Ok(())
 }
 
 // Events
@@ -400,11 +3689,17 @@ pub struct PaymentProcessed {
     pub buyer: Pubkey,
     pub token_mint: Pubkey,
     pub amount: u64,
+    /// Amount transferred to the merchant's payout wallet or token account
+    pub net_amount: u64,
+    /// Amount transferred to the fee treasury
     pub platform_fee: u64,
     pub api_key_owner: Pubkey,
+    /// Amount accrued in the affiliate's commission ledger, always 0 for SOL payments
     pub commission: u64,
     #[index]
     pub product_id: String,
+    /// Merchant PDA paid
+    pub merchant: Pubkey,
     pub timestamp: i64,
 }
 
@@ -414,20 +3709,234 @@ pub struct BatchPaymentProcessed {
     pub buyer: Pubkey,
     pub token_mint: Pubkey,
     pub total_amount: u64,
+    /// Amount transferred to the merchant
+    pub net_amount: u64,
+    /// Amount transferred to the fee treasury
+    pub platform_fee: u64,
+    pub api_key_owner: Pubkey,
+    /// Amount accrued in the affiliate's commission ledger
+    pub commission: u64,
+    pub product_count: u8,
+    /// Merkle root of the line items, as stored in the order record
+    pub items_root: [u8; 32],
+    /// Merchant PDA paid
+    pub merchant: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CartPaymentProcessed {
+    pub order_id: [u8; 32],
+    pub buyer: Pubkey,
+    pub token_mint: Pubkey,
+    pub total_amount: u64,
+    /// Amount transferred to the fee treasury
     pub platform_fee: u64,
     pub api_key_owner: Pubkey,
+    /// Amount accrued in the affiliate's commission ledger
     pub commission: u64,
+    pub merchant_count: u8,
     pub product_count: u8,
+    /// Merkle root of the line items, as stored in the order record
+    pub items_root: [u8; 32],
     pub timestamp: i64,
 }
 
+/// One merchant's leg of a cart payment, emitted after its `CartPaymentProcessed`
+#[event]
+pub struct MerchantPaid {
+    pub order_id: [u8; 32],
+    pub merchant: Pubkey,
+    pub amount: u64,
+    pub platform_fee: u64,
+    pub commission: u64,
+    /// Amount transferred to the merchant's payout token account
+    pub net_amount: u64,
+}
+
+/// One line item of a batch or cart payment, emitted after its `BatchPaymentProcessed` or
+/// `CartPaymentProcessed`
+#[event]
+pub struct LineItemPaid {
+    pub order_id: [u8; 32],
+    /// Position of the item in the payment, and of its leaf in the items tree
+    pub index: u8,
+    pub product_id_hash: [u8; 32],
+    pub unit_price: u64,
+    pub quantity: u32,
+    /// Unit price times quantity
+    pub amount: u64,
+    pub commission_bps: u16,
+    pub commission: u64,
+}
+
 #[event]
-pub struct HotWalletUpdated {
-    pub old_hot_wallet: Pubkey,
-    pub new_hot_wallet: Pubkey,
+pub struct ConfigChangeQueued {
+    pub change: ConfigChange,
+    pub proposer: Pubkey,
+    pub execute_after: i64,
+}
+
+#[event]
+pub struct ConfigChangeExecuted {
+    pub change: ConfigChange,
+    pub proposer: Pubkey,
+}
+
+#[event]
+pub struct ConfigChangeCancelled {
+    pub change: ConfigChange,
+    pub proposer: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct FeeTreasuryUpdated {
+    pub old_fee_treasury: Pubkey,
+    pub new_fee_treasury: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct RefundOperatorUpdated {
+    pub old_refund_operator: Pubkey,
+    pub new_refund_operator: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct QuoteSignerUpdated {
+    pub old_quote_signer: Pubkey,
+    pub new_quote_signer: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct MaxQuoteLifetimeUpdated {
+    pub old_max_quote_lifetime_secs: u32,
+    pub new_max_quote_lifetime_secs: u32,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct MaxTotalFeeUpdated {
+    pub old_max_total_fee_bps: u16,
+    pub new_max_total_fee_bps: u16,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct OrderRetentionUpdated {
+    pub old_order_retention_secs: u32,
+    pub new_order_retention_secs: u32,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct AuthorityProposed {
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+}
+
+#[event]
+pub struct AuthorityTransferred {
+    pub old_authority: Pubkey,
+    pub new_authority: Pubkey,
+}
+
+#[event]
+pub struct AuthorityTransferCancelled {
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+}
+
+#[event]
+pub struct MultisigUpdated {
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct RoleGranted {
+    pub member: Pubkey,
+    pub role: Role,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct RoleRevoked {
+    pub member: Pubkey,
+    pub role: Role,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct TokenConfigUpdated {
+    pub mint: Pubkey,
+    pub enabled: bool,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    pub platform_fee_bps: Option<u16>,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct SupportedTokenRemoved {
+    pub mint: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct MerchantUpdated {
+    pub merchant_id: [u8; 32],
+    pub payout_wallet: Pubkey,
+    pub status: MerchantStatus,
+    pub platform_fee_bps: Option<u16>,
+    pub allowed_mints: Vec<Pubkey>,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct AffiliateUpdated {
+    pub api_key_hash: [u8; 32],
+    pub owner: Pubkey,
+    pub commission_bps: u16,
+    pub status: AffiliateStatus,
     pub authority: Pubkey,
 }
 
+#[event]
+pub struct CommissionClaimed {
+    pub affiliate: Pubkey,
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub total_claimed: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PaymentRefunded {
+    pub order_id: [u8; 32],
+    pub buyer: Pubkey,
+    pub token_mint: Pubkey,
+    /// Amount refunded by this instruction
+    pub amount: u64,
+    /// Total refunded for the order so far
+    pub refunded_amount: u64,
+    pub fully_refunded: bool,
+    pub refunded_by: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct OrderClosed {
+    pub order_id: [u8; 32],
+    pub buyer: Pubkey,
+    pub timestamp: i64,
+}
+
 // Error codes
 
 #[error_code]
@@ -458,4 +3967,124 @@ pub enum ErrorCode {
 
     #[msg("Unauthorized")]
     Unauthorized,
+
+    #[msg("Invalid fee treasury")]
+    InvalidFeeTreasury,
+
+    #[msg("Refund exceeds the amount paid for the order")]
+    RefundExceedsPayment,
+
+    #[msg("Token program does not own the mint")]
+    InvalidTokenProgram,
+
+    #[msg("Token is not supported")]
+    TokenNotSupported,
+
+    #[msg("Amount below the token's minimum payment")]
+    AmountBelowMinimum,
+
+    #[msg("Amount above the token's maximum payment")]
+    AmountAboveMaximum,
+
+    #[msg("Minimum amount above maximum amount")]
+    InvalidAmountLimits,
+
+    #[msg("Pending authority cannot be the default address")]
+    InvalidPendingAuthority,
+
+    #[msg("No authority transfer is pending")]
+    NoPendingAuthority,
+
+    #[msg("Signer is not the pending authority")]
+    NotPendingAuthority,
+
+    #[msg("Config is already in the current layout")]
+    ConfigAlreadyMigrated,
+
+    #[msg("Roles account has no room for another member")]
+    RolesFull,
+
+    #[msg("Member does not hold the role")]
+    RoleNotGranted,
+
+    #[msg("Config change delay has not passed")]
+    TimelockNotExpired,
+
+    #[msg("Proposer does not match the pending change")]
+    InvalidProposer,
+
+    #[msg("Invalid multisig signers or threshold")]
+    InvalidMultisig,
+
+    #[msg("Not enough multisig signers approved")]
+    MultisigThresholdNotMet,
+
+    #[msg("Payment does not match a quote from the quote signer")]
+    InvalidQuote,
+
+    #[msg("Payment quote has expired")]
+    QuoteExpired,
+
+    #[msg("Order has expired")]
+    OrderExpired,
+
+    #[msg("Deadline is further ahead than the maximum quote lifetime")]
+    ExpiryTooFar,
+
+    #[msg("Order retention period has not passed")]
+    RetentionPeriodActive,
+
+    #[msg("Arithmetic overflow")]
+    ArithmeticOverflow,
+
+    #[msg("Platform fee and commission exceed the amount")]
+    DeductionsExceedAmount,
+
+    #[msg("Product id too long (max 50 bytes)")]
+    ProductIdTooLong,
+
+    #[msg("Platform fee and commission exceed the maximum total fee")]
+    TotalFeeTooHigh,
+
+    #[msg("Max total fee must lie between the platform fee and 100%")]
+    InvalidMaxTotalFee,
+
+    #[msg("Too many line items (max 10)")]
+    TooManyLineItems,
+
+    #[msg("Line item amounts do not add up to the total amount")]
+    LineItemsTotalMismatch,
+
+    #[msg("Invalid merchant or merchant payout token account")]
+    InvalidMerchant,
+
+    #[msg("Merchant index out of range, or a merchant without items")]
+    InvalidMerchantIndex,
+
+    #[msg("Merchant passed more than once")]
+    DuplicateMerchant,
+
+    #[msg("Merchant is suspended")]
+    MerchantSuspended,
+
+    #[msg("Merchant does not accept this mint")]
+    MintNotAllowedForMerchant,
+
+    #[msg("Too many allowed mints for a merchant")]
+    TooManyAllowedMints,
+
+    #[msg("Affiliate is suspended")]
+    AffiliateSuspended,
+
+    #[msg("Commission ledger is not the affiliate's for this mint")]
+    InvalidCommissionLedger,
+
+    #[msg("Commission vault is not the ledger's vault")]
+    InvalidCommissionVault,
+
+    #[msg("No commission to claim")]
+    NoCommissionToClaim,
+
+    #[msg("SOL payments cannot carry commission")]
+    SolCommissionNotSupported,
 }
@@ -0,0 +1,5174 @@
+// In-process tests for the Anchor payment program using solana-program-test.
+//
+// The program runs as a native builtin by default. Set `SBF_OUT_DIR` to the
+// directory holding `oxmart_payment.so` (e.g. `target/deploy` after
+// `anchor build`) to run the same tests against the compiled program.
+
+use anchor_lang::{
+    prelude::AccountInfo, AccountDeserialize, AnchorDeserialize, AnchorSerialize, Discriminator,
+    InstructionData, Space, ToAccountMetas,
+};
+use anchor_spl::token::spl_token;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{
+        transfer_fee::{TransferFee, TransferFeeAmount, TransferFeeConfig},
+        ExtensionType, StateWithExtensions, StateWithExtensionsMut,
+    },
+    state::{Account as TokenAccount, AccountState, Mint},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use oxmart_payment::{
+    Affiliate, AffiliateStatus, AffiliateUpdated, AuthorityProposed, AuthorityTransferCancelled,
+    AuthorityTransferred, BatchPaymentProcessed, CartItem, CartPaymentProcessed, ClosedOrder,
+    CommissionClaimed, CommissionLedger, Config, ConfigChange, ConfigChangeCancelled,
+    ConfigChangeExecuted, ConfigChangeQueued, ErrorCode, FeeTreasuryUpdated, LegacyConfig,
+    LineItem, LineItemPaid, MaxQuoteLifetimeUpdated, MaxTotalFeeUpdated, Merchant, MerchantLeg,
+    MerchantPaid, MerchantStatus, MerchantUpdated, MultisigUpdated, OrderClosed, OrderRecord,
+    OrderRetentionUpdated, PaymentProcessed, PaymentQuote, PaymentRefunded, PendingChange,
+    QuoteSignerUpdated, QuoteTerms, RefundOperatorUpdated, Role, RoleGranted, RoleRevoked, Roles,
+    SupportedTokenRemoved, TokenConfig, TokenConfigUpdated,
+};
+use oxmart_payment_core::{bps_share, line_items};
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    clock::Clock,
+    ed25519_program,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_option::COption,
+    program_pack::Pack,
+    program_stubs::{self, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_program, sysvar,
+    transaction::{Transaction, TransactionError},
+};
+use std::sync::Once;
+
+const INITIAL_BALANCE: u64 = 1_000_000_000;
+const LAMPORTS: u64 = 10_000_000_000;
+
+/// Anchor's generated entrypoint ties the account slice to the account lifetime,
+/// which `processor!` cannot express, so hand it a leaked copy instead.
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts = Box::leak(Box::new(accounts.to_vec()));
+    oxmart_payment::entry(program_id, accounts, data)
+}
+
+/// Builtin programs log `sol_log_data` to stdout instead of the transaction logs,
+/// so Anchor events would be invisible to the tests. This wraps the program-test
+/// stubs and forwards event data through `sol_log` in the usual base64 form.
+struct EventLogStubs(Box<dyn SyscallStubs>);
+
+struct NoStubs;
+impl SyscallStubs for NoStubs {}
+
+impl SyscallStubs for EventLogStubs {
+    fn sol_log(&self, message: &str) {
+        self.0.sol_log(message)
+    }
+
+    fn sol_log_compute_units(&self) {
+        self.0.sol_log_compute_units()
+    }
+
+    fn sol_remaining_compute_units(&self) -> u64 {
+        self.0.sol_remaining_compute_units()
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        self.0
+            .sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_clock_sysvar(var_addr)
+    }
+
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_fees_sysvar(var_addr)
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_rent_sysvar(var_addr)
+    }
+
+    fn sol_get_epoch_rewards_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_rewards_sysvar(var_addr)
+    }
+
+    fn sol_get_last_restart_slot(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_last_restart_slot(var_addr)
+    }
+
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        self.0.sol_get_return_data()
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        self.0.sol_set_return_data(data)
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        let encoded: Vec<String> = fields.iter().map(|field| BASE64.encode(field)).collect();
+        self.0
+            .sol_log(&format!("Program data: {}", encoded.join(" ")))
+    }
+
+    fn sol_get_processed_sibling_instruction(&self, index: usize) -> Option<Instruction> {
+        self.0.sol_get_processed_sibling_instruction(index)
+    }
+
+    fn sol_get_stack_height(&self) -> u64 {
+        self.0.sol_get_stack_height()
+    }
+}
+
+/// Must run after the first bank has started, once program-test installed its stubs
+fn install_event_log_stubs() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let program_test_stubs = program_stubs::set_syscall_stubs(Box::new(NoStubs));
+        program_stubs::set_syscall_stubs(Box::new(EventLogStubs(program_test_stubs)));
+    });
+}
+
+struct TestEnv {
+    context: ProgramTestContext,
+    authority: Keypair,
+    buyer: Keypair,
+    /// Wallet with no part in the program, owning accounts that must be rejected
+    other_wallet: Pubkey,
+    fee_treasury: Pubkey,
+    api_key_owner: Keypair,
+    token_program: Pubkey,
+    transfer_fee_bps: u16,
+    mint: Pubkey,
+    buyer_token: Pubkey,
+    other_wallet_token: Pubkey,
+    fee_treasury_token: Pubkey,
+    api_key_owner_token: Pubkey,
+    /// Merchant PDA paid by `payment_accounts`, registered by `initialized`
+    merchant: Pubkey,
+    /// Affiliate PDA earning `AFFILIATE_COMMISSION_BPS` for `api_key_owner`, registered by
+    /// `initialized` along with its commission ledger for the test mint
+    affiliate: Pubkey,
+    merchant_wallet: Pubkey,
+    merchant_token: Pubkey,
+    /// Bank clock time, kept in step by `advance_clock`
+    now: i64,
+}
+
+impl TestEnv {
+    /// Start a bank with funded authority/buyer, a mint and token accounts, but no config
+    async fn new() -> Self {
+        Self::with_token_program(spl_token::id(), 0).await
+    }
+
+    /// Like `new`, with the mint and token accounts under `token_program` and, when
+    /// `transfer_fee_bps` is non-zero, a Token-2022 transfer fee on the mint
+    async fn with_token_program(token_program: Pubkey, transfer_fee_bps: u16) -> Self {
+        let authority = Keypair::new();
+        let buyer = Keypair::new();
+
+        let mut program_test = ProgramTest::new(
+            "oxmart_payment",
+            oxmart_payment::ID,
+            processor!(process_instruction),
+        );
+        for wallet in [authority.pubkey(), buyer.pubkey()] {
+            program_test.add_account(
+                wallet,
+                Account {
+                    lamports: LAMPORTS,
+                    owner: system_program::id(),
+                    ..Account::default()
+                },
+            );
+        }
+
+        let context = program_test.start_with_context().await;
+        install_event_log_stubs();
+        let clock: Clock = context.banks_client.clone().get_sysvar().await.unwrap();
+
+        let mut env = Self {
+            context,
+            authority,
+            buyer,
+            other_wallet: Pubkey::new_unique(),
+            fee_treasury: Pubkey::new_unique(),
+            api_key_owner: Keypair::new(),
+            token_program,
+            transfer_fee_bps,
+            mint: Pubkey::default(),
+            buyer_token: Pubkey::default(),
+            other_wallet_token: Pubkey::default(),
+            fee_treasury_token: Pubkey::default(),
+            api_key_owner_token: Pubkey::default(),
+            merchant: Self::merchant_address(&MERCHANT_ID),
+            affiliate: Self::affiliate_address(&API_KEY_HASH),
+            merchant_wallet: Pubkey::new_unique(),
+            merchant_token: Pubkey::default(),
+            now: clock.unix_timestamp,
+        };
+
+        let mint = env.add_mint();
+        let (buyer, other_wallet, fee_treasury, api_key_owner, merchant_wallet) = (
+            env.buyer.pubkey(),
+            env.other_wallet,
+            env.fee_treasury,
+            env.api_key_owner.pubkey(),
+            env.merchant_wallet,
+        );
+        env.mint = mint;
+        env.buyer_token = env.add_token_account(&mint, &buyer, INITIAL_BALANCE);
+        env.other_wallet_token = env.add_token_account(&mint, &other_wallet, 0);
+        env.fee_treasury_token = env.add_token_account(&mint, &fee_treasury, 0);
+        env.api_key_owner_token = env.add_token_account(&mint, &api_key_owner, 0);
+        env.merchant_token = env.add_token_account(&mint, &merchant_wallet, 0);
+
+        env
+    }
+
+    /// Start a bank with the config initialized at `platform_fee_bps`, the test mint supported
+    /// and the test merchant and affiliate registered
+    async fn initialized(platform_fee_bps: u16) -> Self {
+        Self::initialized_with_token_program(platform_fee_bps, spl_token::id(), 0).await
+    }
+
+    async fn initialized_with_token_program(
+        platform_fee_bps: u16,
+        token_program: Pubkey,
+        transfer_fee_bps: u16,
+    ) -> Self {
+        let env = Self::with_token_program(token_program, transfer_fee_bps).await;
+        let authority = env.authority.pubkey();
+        let add_token = env.add_supported_token_ix(&authority, &env.mint, 0, 0, None);
+        let add_merchant = env.register_merchant_ix(&authority, MERCHANT_ID, env.merchant_wallet);
+        let add_affiliate =
+            env.register_affiliate_ix(&authority, API_KEY_HASH, AFFILIATE_COMMISSION_BPS);
+        let open_ledger = env.open_commission_ledger_ix(&authority);
+        let open_sol_ledger = env.open_sol_commission_ledger_ix(&authority);
+        env.process(
+            &[
+                env.initialize_ix(platform_fee_bps),
+                add_token,
+                add_merchant,
+                add_affiliate,
+                open_ledger,
+                open_sol_ledger,
+            ],
+            &[&env.authority],
+        )
+        .await
+        .unwrap();
+        env
+    }
+
+    fn config_address() -> Pubkey {
+        Pubkey::find_program_address(&[b"config"], &oxmart_payment::ID).0
+    }
+
+    fn token_config_address(mint: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"token", mint.as_ref()], &oxmart_payment::ID).0
+    }
+
+    fn roles_address() -> Pubkey {
+        Pubkey::find_program_address(&[b"roles"], &oxmart_payment::ID).0
+    }
+
+    fn pending_change_address(change: &ConfigChange) -> Pubkey {
+        Pubkey::find_program_address(
+            &[b"pending_change", &[change.kind()], change.merchant_seed()],
+            &oxmart_payment::ID,
+        )
+        .0
+    }
+
+    fn order_address(order_id: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"order", order_id.as_ref()], &oxmart_payment::ID).0
+    }
+
+    fn merchant_address(merchant_id: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"merchant", merchant_id.as_ref()], &oxmart_payment::ID).0
+    }
+
+    fn affiliate_address(api_key_hash: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"affiliate", api_key_hash.as_ref()], &oxmart_payment::ID).0
+    }
+
+    /// The test affiliate's commission ledger for `mint`
+    fn commission_ledger_address(&self, mint: &Pubkey) -> Pubkey {
+        let seeds: &[&[u8]] = &[b"commission", self.affiliate.as_ref(), mint.as_ref()];
+        Pubkey::find_program_address(seeds, &oxmart_payment::ID).0
+    }
+
+    /// The test affiliate's SOL commission ledger
+    fn sol_commission_ledger_address(&self) -> Pubkey {
+        let seeds: &[&[u8]] = &[b"sol_commission", self.affiliate.as_ref()];
+        Pubkey::find_program_address(seeds, &oxmart_payment::ID).0
+    }
+
+    /// The test affiliate's commission vault for `mint`
+    fn commission_vault_address(&self, mint: &Pubkey) -> Pubkey {
+        let seeds: &[&[u8]] = &[b"commission_vault", self.affiliate.as_ref(), mint.as_ref()];
+        Pubkey::find_program_address(seeds, &oxmart_payment::ID).0
+    }
+
+    fn closed_order_address(order_id: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"closed_order", order_id], &oxmart_payment::ID).0
+    }
+
+    /// Move the bank clock forward by `seconds`, on a fresh blockhash so a retried
+    /// instruction is not rejected as a duplicate transaction
+    async fn advance_clock(&mut self, seconds: i64) {
+        // Wait for a blockhash newer than any used so far, not just newer than the last one
+        // this context fetched
+        let mut banks_client = self.context.banks_client.clone();
+        self.context.last_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+        self.context.get_new_latest_blockhash().await.unwrap();
+        let mut clock: Clock = banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp += seconds;
+        self.context.set_sysvar(&clock);
+        self.now = clock.unix_timestamp;
+    }
+
+    fn set_account(&mut self, address: &Pubkey, owner: &Pubkey, data: Vec<u8>) {
+        let lamports = Rent::default().minimum_balance(data.len());
+        let mut account = AccountSharedData::new(lamports, data.len(), owner);
+        account.set_data_from_slice(&data);
+        self.context.set_account(address, &account);
+    }
+
+    fn add_mint(&mut self) -> Pubkey {
+        let address = Pubkey::new_unique();
+        let mint = Mint {
+            mint_authority: COption::Some(self.authority.pubkey()),
+            supply: INITIAL_BALANCE,
+            decimals: 6,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        };
+
+        let data = if self.transfer_fee_bps == 0 {
+            let mut data = vec![0; Mint::LEN];
+            Mint::pack(mint, &mut data).unwrap();
+            data
+        } else {
+            let len = ExtensionType::try_calculate_account_len::<Mint>(&[
+                ExtensionType::TransferFeeConfig,
+            ])
+            .unwrap();
+            let mut data = vec![0; len];
+            let mut state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+            let transfer_fee = TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: self.transfer_fee_bps.into(),
+            };
+            let fee_config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            fee_config.older_transfer_fee = transfer_fee;
+            fee_config.newer_transfer_fee = transfer_fee;
+            state.base = mint;
+            state.pack_base();
+            state.init_account_type().unwrap();
+            data
+        };
+
+        let token_program = self.token_program;
+        self.set_account(&address, &token_program, data);
+        address
+    }
+
+    fn add_token_account(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let address = Pubkey::new_unique();
+        let token_account = TokenAccount {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+
+        let data = if self.transfer_fee_bps == 0 {
+            let mut data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(token_account, &mut data).unwrap();
+            data
+        } else {
+            let len = ExtensionType::try_calculate_account_len::<TokenAccount>(&[
+                ExtensionType::TransferFeeAmount,
+            ])
+            .unwrap();
+            let mut data = vec![0; len];
+            let mut state =
+                StateWithExtensionsMut::<TokenAccount>::unpack_uninitialized(&mut data).unwrap();
+            state.init_extension::<TransferFeeAmount>(true).unwrap();
+            state.base = token_account;
+            state.pack_base();
+            state.init_account_type().unwrap();
+            data
+        };
+
+        let token_program = self.token_program;
+        self.set_account(&address, &token_program, data);
+        address
+    }
+
+    fn transaction(&self, instructions: &[Instruction], signers: &[&Keypair]) -> Transaction {
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            self.context.last_blockhash,
+        )
+    }
+
+    async fn process(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        let mut banks_client = self.context.banks_client.clone();
+        banks_client
+            .process_transaction(self.transaction(instructions, signers))
+            .await
+    }
+
+    /// Process a transaction and return the Anchor events it emitted, base64-decoded
+    async fn process_with_events(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Vec<Vec<u8>> {
+        let mut banks_client = self.context.banks_client.clone();
+        let result = banks_client
+            .process_transaction_with_metadata(self.transaction(instructions, signers))
+            .await
+            .unwrap();
+        result.result.unwrap();
+        result
+            .metadata
+            .unwrap()
+            .log_messages
+            .iter()
+            // The builtin program's event data arrives as a `Program log:` line
+            .map(|log| log.strip_prefix("Program log: ").unwrap_or(log))
+            .filter_map(|log| log.strip_prefix("Program data: "))
+            .map(|data| BASE64.decode(data).unwrap())
+            .collect()
+    }
+
+    async fn account_data(&self, address: &Pubkey) -> Option<Vec<u8>> {
+        let mut banks_client = self.context.banks_client.clone();
+        banks_client
+            .get_account(*address)
+            .await
+            .unwrap()
+            .map(|account| account.data)
+    }
+
+    async fn config(&self) -> Config {
+        let data = self.account_data(&Self::config_address()).await.unwrap();
+        Config::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn order_record(&self, order_id: &[u8; 32]) -> OrderRecord {
+        let data = self
+            .account_data(&Self::order_address(order_id))
+            .await
+            .unwrap();
+        OrderRecord::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn roles(&self) -> Roles {
+        let data = self.account_data(&Self::roles_address()).await.unwrap();
+        Roles::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn pending_change(&self, change: &ConfigChange) -> Option<PendingChange> {
+        self.account_data(&Self::pending_change_address(change))
+            .await
+            .map(|data| PendingChange::try_deserialize(&mut data.as_slice()).unwrap())
+    }
+
+    async fn token_config(&self, mint: &Pubkey) -> TokenConfig {
+        let data = self
+            .account_data(&Self::token_config_address(mint))
+            .await
+            .unwrap();
+        TokenConfig::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn token_balance(&self, address: &Pubkey) -> u64 {
+        let data = self.account_data(address).await.unwrap();
+        StateWithExtensions::<TokenAccount>::unpack(&data)
+            .unwrap()
+            .base
+            .amount
+    }
+
+    fn initialize_ix(&self, platform_fee_bps: u16) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::Initialize {
+                config: Self::config_address(),
+                authority: self.authority.pubkey(),
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::Initialize {
+                fee_treasury: self.fee_treasury,
+                platform_fee_bps,
+            }
+            .data(),
+        }
+    }
+
+    fn payment_accounts(&self, order_id: &[u8; 32]) -> oxmart_payment::accounts::ProcessPayment {
+        oxmart_payment::accounts::ProcessPayment {
+            config: Self::config_address(),
+            order_record: Self::order_address(order_id),
+            buyer: self.buyer.pubkey(),
+            mint: self.mint,
+            token_config: Self::token_config_address(&self.mint),
+            buyer_token_account: self.buyer_token,
+            fee_treasury_token_account: self.fee_treasury_token,
+            merchant: self.merchant,
+            merchant_token_account: self.merchant_token,
+            token_program: self.token_program,
+            system_program: system_program::id(),
+            instructions: sysvar::instructions::id(),
+            closed_order: Self::closed_order_address(order_id),
+            affiliate: None,
+            commission_ledger: None,
+            commission_vault: None,
+        }
+    }
+
+    /// Like `payment_accounts`, through the test affiliate
+    fn affiliate_payment_accounts(
+        &self,
+        order_id: &[u8; 32],
+    ) -> oxmart_payment::accounts::ProcessPayment {
+        oxmart_payment::accounts::ProcessPayment {
+            affiliate: Some(self.affiliate),
+            commission_ledger: Some(self.commission_ledger_address(&self.mint)),
+            commission_vault: Some(self.commission_vault_address(&self.mint)),
+            ..self.payment_accounts(order_id)
+        }
+    }
+
+    /// Build a `ProcessPayment` instruction expiring in ten minutes
+    fn payment_ix(
+        &self,
+        accounts: oxmart_payment::accounts::ProcessPayment,
+        order_id: [u8; 32],
+        amount: u64,
+    ) -> Instruction {
+        self.expiring_payment_ix(accounts, order_id, amount, self.now + 10 * 60)
+    }
+
+    fn expiring_payment_ix(
+        &self,
+        accounts: oxmart_payment::accounts::ProcessPayment,
+        order_id: [u8; 32],
+        amount: u64,
+        expires_at: i64,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: accounts.to_account_metas(None),
+            data: oxmart_payment::instruction::ProcessPayment {
+                order_id,
+                amount,
+                product_id: "product-1".to_string(),
+                expires_at,
+            }
+            .data(),
+        }
+    }
+
+    /// Build a `ProcessBatchPayment` instruction through the test affiliate, expiring in ten
+    /// minutes
+    fn batch_payment_ix(
+        &self,
+        order_id: [u8; 32],
+        total_amount: u64,
+        line_items: Vec<LineItem>,
+    ) -> Instruction {
+        self.batch_payment_ix_with(
+            self.affiliate_payment_accounts(&order_id),
+            order_id,
+            total_amount,
+            line_items,
+        )
+    }
+
+    fn batch_payment_ix_with(
+        &self,
+        accounts: oxmart_payment::accounts::ProcessPayment,
+        order_id: [u8; 32],
+        total_amount: u64,
+        line_items: Vec<LineItem>,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: accounts.to_account_metas(None),
+            data: oxmart_payment::instruction::ProcessBatchPayment {
+                order_id,
+                total_amount,
+                line_items,
+                expires_at: self.now + 10 * 60,
+            }
+            .data(),
+        }
+    }
+
+    /// Build a `ProcessCartPayment` instruction through the test affiliate paying
+    /// `merchants`, each a merchant PDA and payout token account, expiring in ten minutes
+    fn cart_payment_ix(
+        &self,
+        order_id: [u8; 32],
+        total_amount: u64,
+        merchants: &[(Pubkey, Pubkey)],
+        cart_items: Vec<CartItem>,
+    ) -> Instruction {
+        let mut accounts = oxmart_payment::accounts::ProcessCartPayment {
+            config: Self::config_address(),
+            order_record: Self::order_address(&order_id),
+            buyer: self.buyer.pubkey(),
+            mint: self.mint,
+            token_config: Self::token_config_address(&self.mint),
+            buyer_token_account: self.buyer_token,
+            fee_treasury_token_account: self.fee_treasury_token,
+            token_program: self.token_program,
+            system_program: system_program::id(),
+            instructions: sysvar::instructions::id(),
+            closed_order: Self::closed_order_address(&order_id),
+            affiliate: Some(self.affiliate),
+            commission_ledger: Some(self.commission_ledger_address(&self.mint)),
+            commission_vault: Some(self.commission_vault_address(&self.mint)),
+        }
+        .to_account_metas(None);
+        for (merchant, payout_token_account) in merchants {
+            accounts.push(AccountMeta::new_readonly(*merchant, false));
+            accounts.push(AccountMeta::new(*payout_token_account, false));
+        }
+
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts,
+            data: oxmart_payment::instruction::ProcessCartPayment {
+                order_id,
+                total_amount,
+                merchant_count: merchants.len() as u8,
+                cart_items,
+                expires_at: self.now + 10 * 60,
+            }
+            .data(),
+        }
+    }
+
+    /// Build a `RegisterMerchant` instruction for a merchant without fee override or mint
+    /// restriction
+    fn register_merchant_ix(
+        &self,
+        authority: &Pubkey,
+        merchant_id: [u8; 32],
+        payout_wallet: Pubkey,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::RegisterMerchant {
+                config: Self::config_address(),
+                merchant: Self::merchant_address(&merchant_id),
+                authority: *authority,
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::RegisterMerchant {
+                merchant_id,
+                payout_wallet,
+                platform_fee_bps: None,
+                allowed_mints: Vec::new(),
+            }
+            .data(),
+        }
+    }
+
+    fn update_merchant_ix(
+        &self,
+        authority: &Pubkey,
+        merchant: &Pubkey,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateMerchant {
+                config: Self::config_address(),
+                merchant: *merchant,
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::UpdateMerchant {
+                status,
+                platform_fee_bps,
+                allowed_mints,
+            }
+            .data(),
+        }
+    }
+
+    /// Register merchant `seed` with an empty payout token account for the test mint, and
+    /// return the merchant PDA and payout token account
+    async fn add_merchant(&mut self, seed: u8) -> (Pubkey, Pubkey) {
+        let payout_wallet = Pubkey::new_unique();
+        let ix = self.register_merchant_ix(&self.authority.pubkey(), [seed; 32], payout_wallet);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+        let mint = self.mint;
+        let payout_token_account = self.add_token_account(&mint, &payout_wallet, 0);
+        (Self::merchant_address(&[seed; 32]), payout_token_account)
+    }
+
+    async fn merchant(&self, merchant: &Pubkey) -> Merchant {
+        let data = self.account_data(merchant).await.unwrap();
+        Merchant::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    /// Update `merchant`'s status, fee override and allowed mints
+    async fn set_merchant(
+        &self,
+        merchant: &Pubkey,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) {
+        let ix = self.update_merchant_ix(
+            &self.authority.pubkey(),
+            merchant,
+            status,
+            platform_fee_bps,
+            allowed_mints,
+        );
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Register an active affiliate earning `commission_bps` for the API key owner
+    fn register_affiliate_ix(
+        &self,
+        authority: &Pubkey,
+        api_key_hash: [u8; 32],
+        commission_bps: u16,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::RegisterAffiliate {
+                config: Self::config_address(),
+                affiliate: Self::affiliate_address(&api_key_hash),
+                authority: *authority,
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::RegisterAffiliate {
+                api_key_hash,
+                owner: self.api_key_owner.pubkey(),
+                commission_bps,
+            }
+            .data(),
+        }
+    }
+
+    fn update_affiliate_ix(
+        &self,
+        authority: &Pubkey,
+        owner: Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateAffiliate {
+                config: Self::config_address(),
+                affiliate: self.affiliate,
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::UpdateAffiliate {
+                owner,
+                commission_bps,
+                status,
+            }
+            .data(),
+        }
+    }
+
+    async fn affiliate_state(&self) -> Affiliate {
+        let data = self.account_data(&self.affiliate).await.unwrap();
+        Affiliate::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    /// Set the test affiliate's commission rate and status, keeping its owner
+    async fn set_affiliate(&self, commission_bps: u16, status: AffiliateStatus) {
+        let ix = self.update_affiliate_ix(
+            &self.authority.pubkey(),
+            self.api_key_owner.pubkey(),
+            commission_bps,
+            status,
+        );
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Open the test affiliate's commission ledger for the test mint, paid for by `payer`
+    fn open_commission_ledger_ix(&self, payer: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::OpenCommissionLedger {
+                affiliate: self.affiliate,
+                mint: self.mint,
+                commission_ledger: self.commission_ledger_address(&self.mint),
+                commission_vault: self.commission_vault_address(&self.mint),
+                payer: *payer,
+                token_program: self.token_program,
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::OpenCommissionLedger {}.data(),
+        }
+    }
+
+    /// Claim the test affiliate's commission in the test mint into `owner_token`
+    fn claim_commission_ix(&self, owner: &Pubkey, owner_token: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::ClaimCommission {
+                affiliate: self.affiliate,
+                commission_ledger: self.commission_ledger_address(&self.mint),
+                commission_vault: self.commission_vault_address(&self.mint),
+                owner_token_account: *owner_token,
+                mint: self.mint,
+                owner: *owner,
+                token_program: self.token_program,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::ClaimCommission {}.data(),
+        }
+    }
+
+    async fn claim_commission(&self) -> Result<(), BanksClientError> {
+        let ix = self.claim_commission_ix(&self.api_key_owner.pubkey(), &self.api_key_owner_token);
+        self.process(&[ix], &[&self.api_key_owner]).await
+    }
+
+    /// Open the test affiliate's SOL commission ledger, paid for by `payer`
+    fn open_sol_commission_ledger_ix(&self, payer: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::OpenSolCommissionLedger {
+                affiliate: self.affiliate,
+                commission_ledger: self.sol_commission_ledger_address(),
+                payer: *payer,
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::OpenSolCommissionLedger {}.data(),
+        }
+    }
+
+    /// Claim the test affiliate's SOL commission into `owner`'s wallet
+    fn claim_sol_commission_ix(&self, owner: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::ClaimSolCommission {
+                affiliate: self.affiliate,
+                commission_ledger: self.sol_commission_ledger_address(),
+                owner: *owner,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::ClaimSolCommission {}.data(),
+        }
+    }
+
+    async fn sol_commission_ledger(&self) -> CommissionLedger {
+        let data = self
+            .account_data(&self.sol_commission_ledger_address())
+            .await
+            .unwrap();
+        CommissionLedger::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn commission_ledger(&self) -> CommissionLedger {
+        let data = self
+            .account_data(&self.commission_ledger_address(&self.mint))
+            .await
+            .unwrap();
+        CommissionLedger::try_deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    /// Commission waiting to be claimed in the test mint's vault
+    async fn commission_vault_balance(&self) -> u64 {
+        self.token_balance(&self.commission_vault_address(&self.mint))
+            .await
+    }
+
+    /// Pay without an affiliate, so without commission
+    async fn pay(&self, order_id: [u8; 32], amount: u64) -> Result<(), BanksClientError> {
+        let ix = self.payment_ix(self.payment_accounts(&order_id), order_id, amount);
+        self.process(&[ix], &[&self.buyer]).await
+    }
+
+    /// Pay through the test affiliate, at its current commission rate
+    async fn pay_through_affiliate(
+        &self,
+        order_id: [u8; 32],
+        amount: u64,
+    ) -> Result<(), BanksClientError> {
+        let ix = self.payment_ix(self.affiliate_payment_accounts(&order_id), order_id, amount);
+        self.process(&[ix], &[&self.buyer]).await
+    }
+
+    fn sol_payment_accounts(
+        &self,
+        order_id: &[u8; 32],
+    ) -> oxmart_payment::accounts::ProcessSolPayment {
+        oxmart_payment::accounts::ProcessSolPayment {
+            config: Self::config_address(),
+            order_record: Self::order_address(order_id),
+            buyer: self.buyer.pubkey(),
+            merchant: self.merchant,
+            merchant_payout_wallet: self.merchant_wallet,
+            fee_treasury: self.fee_treasury,
+            system_program: system_program::id(),
+            instructions: sysvar::instructions::id(),
+            closed_order: Self::closed_order_address(order_id),
+            affiliate: None,
+            commission_ledger: None,
+        }
+    }
+
+    /// Like `sol_payment_accounts`, through the test affiliate
+    fn affiliate_sol_payment_accounts(
+        &self,
+        order_id: &[u8; 32],
+    ) -> oxmart_payment::accounts::ProcessSolPayment {
+        oxmart_payment::accounts::ProcessSolPayment {
+            affiliate: Some(self.affiliate),
+            commission_ledger: Some(self.sol_commission_ledger_address()),
+            ..self.sol_payment_accounts(order_id)
+        }
+    }
+
+    fn sol_payment_ix(
+        &self,
+        accounts: oxmart_payment::accounts::ProcessSolPayment,
+        order_id: [u8; 32],
+        amount: u64,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: accounts.to_account_metas(None),
+            data: oxmart_payment::instruction::ProcessSolPayment {
+                order_id,
+                amount,
+                product_id: "product-1".to_string(),
+            }
+            .data(),
+        }
+    }
+
+    async fn lamports(&self, address: &Pubkey) -> u64 {
+        let mut banks_client = self.context.banks_client.clone();
+        banks_client.get_balance(*address).await.unwrap()
+    }
+
+    fn close_order_ix(&self, order_id: &[u8; 32]) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::CloseOrder {
+                config: Self::config_address(),
+                order_record: Self::order_address(order_id),
+                buyer: self.buyer.pubkey(),
+                closed_order: Self::closed_order_address(order_id),
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::CloseOrder {}.data(),
+        }
+    }
+
+    /// Build an `UpdateConfig` instruction signed by `authority`
+    fn update_config_ix(&self, authority: &Pubkey, data: impl InstructionData) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateConfig {
+                config: Self::config_address(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: data.data(),
+        }
+    }
+
+    /// Append the other multisig signers as signing remaining accounts
+    fn with_signers(mut instruction: Instruction, signers: &[&Keypair]) -> Instruction {
+        for signer in signers {
+            instruction
+                .accounts
+                .push(AccountMeta::new_readonly(signer.pubkey(), true));
+        }
+        instruction
+    }
+
+    fn set_multisig_ix(&self, authority: &Pubkey, signers: &[Pubkey], threshold: u8) -> Instruction {
+        self.update_config_ix(
+            authority,
+            oxmart_payment::instruction::SetMultisig {
+                signers: signers.to_vec(),
+                threshold,
+            },
+        )
+    }
+
+    /// Set a `threshold`-of-`members.len()` multisig as the authority
+    async fn set_multisig(&self, members: &[&Keypair], threshold: u8) {
+        let signers: Vec<Pubkey> = members.iter().map(|member| member.pubkey()).collect();
+        let ix = self.set_multisig_ix(&self.authority.pubkey(), &signers, threshold);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    fn set_max_quote_lifetime_ix(
+        &self,
+        authority: &Pubkey,
+        max_quote_lifetime_secs: u32,
+    ) -> Instruction {
+        self.update_config_ix(
+            authority,
+            oxmart_payment::instruction::SetMaxQuoteLifetime {
+                max_quote_lifetime_secs,
+            },
+        )
+    }
+
+    fn set_max_total_fee_ix(&self, authority: &Pubkey, max_total_fee_bps: u16) -> Instruction {
+        self.update_config_ix(
+            authority,
+            oxmart_payment::instruction::SetMaxTotalFee { max_total_fee_bps },
+        )
+    }
+
+    async fn set_max_total_fee(&self, max_total_fee_bps: u16) {
+        let ix = self.set_max_total_fee_ix(&self.authority.pubkey(), max_total_fee_bps);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Require quotes signed by a new key and return it
+    async fn require_quotes(&self) -> Keypair {
+        let quote_signer = Keypair::new();
+        let ix = self.update_config_ix(
+            &self.authority.pubkey(),
+            oxmart_payment::instruction::SetQuoteSigner {
+                new_quote_signer: quote_signer.pubkey(),
+            },
+        );
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+        quote_signer
+    }
+
+    /// Quote for a payment of `mint` to the test merchant, with commission for the API key
+    /// owner, valid for a minute
+    async fn quote(
+        &self,
+        order_id: [u8; 32],
+        mint: Pubkey,
+        amount: u64,
+        commission_bps: u16,
+    ) -> PaymentQuote {
+        let mut banks_client = self.context.banks_client.clone();
+        let clock: Clock = banks_client.get_sysvar().await.unwrap();
+        PaymentQuote {
+            terms: QuoteTerms {
+                program_id: oxmart_payment::ID,
+                order_id,
+                mint,
+                amount,
+                merchants: vec![self.merchant],
+                items_root: [0; 32],
+                commission_bps,
+                commission: bps_share(amount, commission_bps).unwrap(),
+                api_key_owner: self.api_key_owner.pubkey(),
+            },
+            expires_at: clock.unix_timestamp + 60,
+        }
+    }
+
+    /// Build an `UpdateConfigByRole` instruction signed by `signer`, without the roles account
+    fn update_config_by_role_ix(&self, signer: &Pubkey, data: impl InstructionData) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateConfigByRole {
+                config: Self::config_address(),
+                signer: *signer,
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: data.data(),
+        }
+    }
+
+    /// Fill the instruction's optional roles account, which role holders must pass
+    fn with_roles(mut instruction: Instruction) -> Instruction {
+        let roles = instruction
+            .accounts
+            .iter_mut()
+            .find(|meta| meta.pubkey == oxmart_payment::ID)
+            .unwrap();
+        roles.pubkey = Self::roles_address();
+        instruction
+    }
+
+    fn initialize_roles_ix(&self) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::InitializeRoles {
+                config: Self::config_address(),
+                roles: Self::roles_address(),
+                authority: self.authority.pubkey(),
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::InitializeRoles {}.data(),
+        }
+    }
+
+    fn update_roles_ix(&self, authority: &Pubkey, data: impl InstructionData) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateRoles {
+                config: Self::config_address(),
+                roles: Self::roles_address(),
+                authority: *authority,
+            }
+            .to_account_metas(None),
+            data: data.data(),
+        }
+    }
+
+    /// Create the roles account and grant `roles` to `member`
+    async fn grant_roles(&self, member: &Pubkey, roles: &[Role]) {
+        let mut instructions = vec![self.initialize_roles_ix()];
+        for role in roles {
+            instructions.push(self.update_roles_ix(
+                &self.authority.pubkey(),
+                oxmart_payment::instruction::GrantRole {
+                    member: *member,
+                    role: *role,
+                },
+            ));
+        }
+        self.process(&instructions, &[&self.authority]).await.unwrap();
+    }
+
+    /// Build a `queue_config_change` instruction signed by `proposer`, without the roles account
+    fn queue_config_change_ix(&self, proposer: &Pubkey, change: ConfigChange) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::QueueConfigChange {
+                config: Self::config_address(),
+                pending_change: Self::pending_change_address(&change),
+                proposer: *proposer,
+                system_program: system_program::id(),
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::QueueConfigChange { change }.data(),
+        }
+    }
+
+    fn execute_config_change_ix(&self, change: &ConfigChange, proposer: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::ExecuteConfigChange {
+                config: Self::config_address(),
+                pending_change: Self::pending_change_address(change),
+                proposer: *proposer,
+                merchant: match change {
+                    ConfigChange::PayoutWallet { merchant, .. } => Some(*merchant),
+                    _ => None,
+                },
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::ExecuteConfigChange {}.data(),
+        }
+    }
+
+    fn cancel_config_change_ix(
+        &self,
+        authority: &Pubkey,
+        change: &ConfigChange,
+        proposer: &Pubkey,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::CancelConfigChange {
+                config: Self::config_address(),
+                authority: *authority,
+                pending_change: Self::pending_change_address(change),
+                proposer: *proposer,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::CancelConfigChange {}.data(),
+        }
+    }
+
+    /// Queue `change` as the authority, wait out its delay and execute it
+    async fn apply_config_change(&mut self, change: ConfigChange) {
+        let authority = self.authority.pubkey();
+        let ix = self.queue_config_change_ix(&authority, change);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+        let delay = change.delay_secs(&self.config().await);
+        self.advance_clock(i64::from(delay)).await;
+        let ix = self.execute_config_change_ix(&change, &authority);
+        self.process(&[ix], &[]).await.unwrap();
+    }
+
+    fn accept_authority_ix(&self, pending_authority: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::AcceptAuthority {
+                config: Self::config_address(),
+                pending_authority: *pending_authority,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::AcceptAuthority {}.data(),
+        }
+    }
+
+    fn migrate_config_ix(&self, authority: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::MigrateConfig {
+                config: Self::config_address(),
+                authority: *authority,
+                system_program: system_program::id(),
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::MigrateConfig {}.data(),
+        }
+    }
+
+    /// Write a config account in the first release's layout, as deployed before migration
+    fn set_legacy_config(&mut self, legacy: &LegacyConfig) {
+        let mut data = Config::DISCRIMINATOR.to_vec();
+        data.extend(legacy.try_to_vec().unwrap());
+        self.set_account(&Self::config_address(), &oxmart_payment::ID, data);
+    }
+
+    fn add_supported_token_ix(
+        &self,
+        authority: &Pubkey,
+        mint: &Pubkey,
+        min_amount: u64,
+        max_amount: u64,
+        platform_fee_bps: Option<u16>,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::AddSupportedToken {
+                config: Self::config_address(),
+                token_config: Self::token_config_address(mint),
+                mint: *mint,
+                authority: *authority,
+                system_program: system_program::id(),
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::AddSupportedToken {
+                min_amount,
+                max_amount,
+                platform_fee_bps,
+            }
+            .data(),
+        }
+    }
+
+    fn update_token_config_ix(
+        &self,
+        authority: &Pubkey,
+        enabled: bool,
+        min_amount: u64,
+        max_amount: u64,
+        platform_fee_bps: Option<u16>,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::UpdateTokenConfig {
+                config: Self::config_address(),
+                token_config: Self::token_config_address(&self.mint),
+                authority: *authority,
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::UpdateTokenConfig {
+                enabled,
+                min_amount,
+                max_amount,
+                platform_fee_bps,
+            }
+            .data(),
+        }
+    }
+
+    fn remove_supported_token_ix(&self, authority: &Pubkey) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::RemoveSupportedToken {
+                config: Self::config_address(),
+                token_config: Self::token_config_address(&self.mint),
+                authority: *authority,
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::RemoveSupportedToken {}.data(),
+        }
+    }
+
+    fn refund_ix(
+        &self,
+        refunder: &Pubkey,
+        refund_source_token_account: Pubkey,
+        order_id: &[u8; 32],
+        amount: u64,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::RefundOrder {
+                config: Self::config_address(),
+                order_record: Self::order_address(order_id),
+                refunder: *refunder,
+                mint: self.mint,
+                refund_source_token_account,
+                buyer_token_account: self.buyer_token,
+                token_program: self.token_program,
+                roles: None,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::RefundOrder { amount }.data(),
+        }
+    }
+
+    /// Fund a token account for `refunder` and build a refund of `amount` from it
+    fn funded_refund_ix(
+        &mut self,
+        refunder: &Pubkey,
+        order_id: &[u8; 32],
+        amount: u64,
+    ) -> Instruction {
+        let mint = self.mint;
+        let refund_source_token_account = self.add_token_account(&mint, refunder, amount);
+        self.refund_ix(refunder, refund_source_token_account, order_id, amount)
+    }
+
+    fn emergency_withdraw_ix(
+        &self,
+        authority: &Pubkey,
+        program_token_account: Pubkey,
+        authority_token_account: Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        Instruction {
+            program_id: oxmart_payment::ID,
+            accounts: oxmart_payment::accounts::EmergencyWithdraw {
+                config: Self::config_address(),
+                authority: *authority,
+                mint: self.mint,
+                program_token_account,
+                authority_token_account,
+                token_program: self.token_program,
+            }
+            .to_account_metas(None),
+            data: oxmart_payment::instruction::EmergencyWithdraw { amount }.data(),
+        }
+    }
+}
+
+/// ID of the merchant registered by `TestEnv::initialized`
+const MERCHANT_ID: [u8; 32] = [0xee; 32];
+
+/// API key hash and commission rate of the affiliate registered by `TestEnv::initialized`
+const API_KEY_HASH: [u8; 32] = [0xaa; 32];
+const AFFILIATE_COMMISSION_BPS: u16 = 500;
+
+fn order_id(seed: u8) -> [u8; 32] {
+    [seed; 32]
+}
+
+/// Line item of product `seed` at the payment's commission rate
+fn line_item(seed: u8, unit_price: u64, quantity: u32) -> LineItem {
+    LineItem {
+        product_id_hash: [seed; 32],
+        unit_price,
+        quantity,
+        commission_bps: None,
+    }
+}
+
+/// Merkle root of `items`, taking `commission_bps` for items without a rate of their own
+fn items_root(items: &[LineItem], commission_bps: u16) -> [u8; 32] {
+    let mut leaves: Vec<[u8; 32]> = items
+        .iter()
+        .map(|item| {
+            line_items::leaf_hash(
+                &item.product_id_hash,
+                item.unit_price,
+                item.quantity,
+                item.commission_bps.unwrap_or(commission_bps),
+            )
+        })
+        .collect();
+    line_items::merkle_root(&mut leaves)
+}
+
+/// Cart item of product `seed` sold by the merchant at `merchant_index`
+fn cart_item(merchant_index: u8, seed: u8, unit_price: u64, quantity: u32) -> CartItem {
+    CartItem {
+        merchant_index,
+        item: line_item(seed, unit_price, quantity),
+    }
+}
+
+fn decode_event<T: AnchorDeserialize + Discriminator>(events: &[Vec<u8>]) -> T {
+    let data = events
+        .iter()
+        .find(|data| data.starts_with(&T::DISCRIMINATOR))
+        .expect("event not emitted");
+    T::try_from_slice(&data[8..]).unwrap()
+}
+
+/// Every emitted event of type `T`, in order
+fn decode_events<T: AnchorDeserialize + Discriminator>(events: &[Vec<u8>]) -> Vec<T> {
+    events
+        .iter()
+        .filter(|data| data.starts_with(&T::DISCRIMINATOR))
+        .map(|data| T::try_from_slice(&data[8..]).unwrap())
+        .collect()
+}
+
+/// Build an Ed25519 program instruction checking `signer`'s signature over `quote`
+fn quote_ix(signer: &Keypair, quote: &PaymentQuote) -> Instruction {
+    let message = quote.try_to_vec().unwrap();
+    let signature = signer.sign_message(&message);
+
+    // Offsets header, then the public key, signature and message it points at
+    let (public_key_offset, signature_offset, message_offset) = (16, 48, 112);
+    let mut data = vec![1, 0];
+    for offset in [
+        signature_offset,
+        u16::MAX,
+        public_key_offset,
+        u16::MAX,
+        message_offset,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&offset.to_le_bytes());
+    }
+    data.extend_from_slice(signer.pubkey().as_ref());
+    data.extend_from_slice(signature.as_ref());
+    data.extend_from_slice(&message);
+
+    Instruction::new_with_bytes(ed25519_program::id(), &data, vec![])
+}
+
+fn assert_instruction_error(result: Result<(), BanksClientError>, expected: InstructionError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, expected)
+    );
+}
+
+fn assert_program_error(result: Result<(), BanksClientError>, expected: ErrorCode) {
+    assert_instruction_error(result, InstructionError::Custom(expected.into()));
+}
+
+/// Like `assert_program_error`, for a payment following its quote instruction
+fn assert_quoted_program_error(result: Result<(), BanksClientError>, expected: ErrorCode) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(expected.into()))
+    );
+}
+
+// initialize
+
+#[tokio::test]
+async fn initialize_sets_config() {
+    let env = TestEnv::initialized(250).await;
+
+    let config = env.config().await;
+    assert_eq!(config.authority, env.authority.pubkey());
+    assert_eq!(config.fee_treasury, env.fee_treasury);
+    assert_eq!(config.refund_operator, Pubkey::default());
+    assert_eq!(config.quote_signer, Pubkey::default());
+    assert_eq!(config.payout_wallet_delay_secs, Config::DEFAULT_CHANGE_DELAY_SECS);
+    assert_eq!(config.platform_fee_delay_secs, Config::DEFAULT_CHANGE_DELAY_SECS);
+    assert_eq!(
+        config.max_quote_lifetime_secs,
+        Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS
+    );
+    assert_eq!(config.order_retention_secs, Config::DEFAULT_ORDER_RETENTION_SECS);
+    assert_eq!(config.max_total_fee_bps, Config::DEFAULT_MAX_TOTAL_FEE_BPS);
+    assert_eq!(config.platform_fee_bps, 250);
+    assert!(!config.paused);
+}
+
+#[tokio::test]
+async fn initialize_rejects_fee_above_max() {
+    let env = TestEnv::new().await;
+
+    let result = env
+        .process(&[env.initialize_ix(1001)], &[&env.authority])
+        .await;
+
+    assert_program_error(result, ErrorCode::FeeTooHigh);
+}
+
+// process_payment
+
+#[tokio::test]
+async fn process_payment_splits_fee_and_emits_event() {
+    let env = TestEnv::initialized(250).await;
+    let ix = env.payment_ix(
+        env.affiliate_payment_accounts(&order_id(1)),
+        order_id(1),
+        100_000_000,
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500_000);
+    assert_eq!(env.commission_vault_balance().await, 5_000_000);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 100_000_000
+    );
+
+    let event: PaymentProcessed = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.buyer, env.buyer.pubkey());
+    assert_eq!(event.token_mint, env.mint);
+    assert_eq!(event.amount, 100_000_000);
+    assert_eq!(event.net_amount, 92_500_000);
+    assert_eq!(event.platform_fee, 2_500_000);
+    assert_eq!(event.commission, 5_000_000);
+    assert_eq!(event.product_id, "product-1");
+    assert_eq!(event.merchant, env.merchant);
+
+    let order = env.order_record(&order_id(1)).await;
+    assert!(order.processed);
+    assert_eq!(order.buyer, env.buyer.pubkey());
+    assert_eq!(order.amount, 100_000_000);
+    assert_eq!(order.platform_fee, 2_500_000);
+    assert_eq!(order.commission, 5_000_000);
+    assert_eq!(
+        order.merchant_legs,
+        vec![MerchantLeg {
+            merchant: env.merchant,
+            amount: 100_000_000,
+            platform_fee: 2_500_000,
+            commission: 5_000_000,
+            net_amount: 92_500_000,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn process_payment_rejects_duplicate_order() {
+    let env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+
+    let result = env.pay(order_id(1), 2_000).await;
+
+    // The order record `init` fails inside the system program: account already in use
+    assert_instruction_error(result, InstructionError::Custom(0));
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_zero_amount() {
+    let env = TestEnv::initialized(0).await;
+
+    let result = env.pay(order_id(1), 0).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmount);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_total_fee_above_default_cap() {
+    let mut env = TestEnv::initialized(0).await;
+
+    env.set_affiliate(10_000, AffiliateStatus::Active).await;
+    env.apply_config_change(ConfigChange::PlatformFee { new_fee_bps: 250 })
+        .await;
+
+    let result = env.pay_through_affiliate(order_id(1), 100_000).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn process_payment_accepts_deductions_of_whole_amount() {
+    let env = TestEnv::initialized(250).await;
+
+    env.set_affiliate(9_750, AffiliateStatus::Active).await;
+
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.platform_fee, 2_500);
+    assert_eq!(order.commission, 97_500);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_product_id_too_long() {
+    let env = TestEnv::initialized(0).await;
+    let mut ix = env.payment_ix(env.payment_accounts(&order_id(1)), order_id(1), 1_000);
+    ix.data = oxmart_payment::instruction::ProcessPayment {
+        order_id: order_id(1),
+        amount: 1_000,
+        product_id: "p".repeat(OrderRecord::MAX_PRODUCT_ID_LEN + 1),
+        expires_at: env.now + 10 * 60,
+    }
+    .data();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::ProductIdTooLong);
+}
+
+#[tokio::test]
+async fn process_payment_stores_product_id_of_max_length() {
+    let env = TestEnv::initialized(0).await;
+    let product_id = "p".repeat(OrderRecord::MAX_PRODUCT_ID_LEN);
+    let mut ix = env.payment_ix(env.payment_accounts(&order_id(1)), order_id(1), 1_000);
+    ix.data = oxmart_payment::instruction::ProcessPayment {
+        order_id: order_id(1),
+        amount: 1_000,
+        product_id: product_id.clone(),
+        expires_at: env.now + 10 * 60,
+    }
+    .data();
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    assert_eq!(env.order_record(&order_id(1)).await.product_id, product_id);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_when_paused() {
+    let env = TestEnv::initialized(0).await;
+    let pause = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+
+    let result = env.pay(order_id(1), 1_000).await;
+
+    assert_program_error(result, ErrorCode::ProgramPaused);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_foreign_buyer_token_account() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.mint;
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.buyer_token_account = env.add_token_account(&mint, &Pubkey::new_unique(), 1_000);
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenAccount);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_wrong_fee_treasury() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, buyer) = (env.mint, env.buyer.pubkey());
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.fee_treasury_token_account = env.add_token_account(&mint, &buyer, 0);
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidFeeTreasury);
+}
+
+// process_sol_payment
+
+const SOL_PAYMENT: u64 = 1_000_000_000;
+
+#[tokio::test]
+async fn process_sol_payment_splits_fee_and_emits_event() {
+    let env = TestEnv::initialized(250).await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    assert_eq!(env.lamports(&env.merchant_wallet).await, 975_000_000);
+    assert_eq!(env.lamports(&env.fee_treasury).await, 25_000_000);
+
+    let event: PaymentProcessed = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.merchant, env.merchant);
+    assert_eq!(event.token_mint, spl_token::native_mint::id());
+    assert_eq!(event.amount, SOL_PAYMENT);
+    assert_eq!(event.net_amount, 975_000_000);
+    assert_eq!(event.platform_fee, 25_000_000);
+    assert_eq!(event.commission, 0);
+
+    let order = env.order_record(&order_id(1)).await;
+    assert!(order.processed);
+    assert_eq!(order.buyer, env.buyer.pubkey());
+    assert_eq!(order.token_mint, spl_token::native_mint::id());
+    assert_eq!(order.amount, SOL_PAYMENT);
+    assert_eq!(order.merchant_legs.len(), 1);
+    assert_eq!(order.merchant_legs[0].merchant, env.merchant);
+}
+
+#[tokio::test]
+async fn process_sol_payment_accrues_commission_in_sol_ledger() {
+    let env = TestEnv::initialized(250).await;
+    let ledger_rent = env.lamports(&env.sol_commission_ledger_address()).await;
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    assert_eq!(env.lamports(&env.merchant_wallet).await, 925_000_000);
+    assert_eq!(env.lamports(&env.fee_treasury).await, 25_000_000);
+    assert_eq!(
+        env.lamports(&env.sol_commission_ledger_address()).await,
+        ledger_rent + 50_000_000
+    );
+    assert_eq!(env.lamports(&env.api_key_owner.pubkey()).await, 0);
+    let ledger = env.sol_commission_ledger().await;
+    assert_eq!(ledger.affiliate, env.affiliate);
+    assert_eq!(ledger.mint, spl_token::native_mint::id());
+    assert_eq!(ledger.accrued, 50_000_000);
+    let event: PaymentProcessed = decode_event(&events);
+    assert_eq!(event.net_amount, 925_000_000);
+    assert_eq!(event.commission, 50_000_000);
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.commission, 50_000_000);
+    assert_eq!(order.merchant_legs[0].net_amount, 925_000_000);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_affiliate_without_sol_commission_ledger() {
+    let env = TestEnv::initialized(250).await;
+    let mut accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    accounts.commission_ledger = None;
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommissionLedger);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_token_commission_ledger() {
+    let env = TestEnv::initialized(250).await;
+    let mut accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    accounts.commission_ledger = Some(env.commission_ledger_address(&env.mint));
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(anchor_lang::error::ErrorCode::ConstraintSeeds.into()),
+    );
+}
+
+#[tokio::test]
+async fn process_sol_payment_records_affiliate_without_commission() {
+    let env = TestEnv::initialized(250).await;
+    env.set_affiliate(0, AffiliateStatus::Active).await;
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    assert_eq!(env.lamports(&env.merchant_wallet).await, 975_000_000);
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(order.commission, 0);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_duplicate_order() {
+    let env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(result, InstructionError::Custom(0));
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_zero_amount() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), 0);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmount);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_wallet_other_than_merchant_payout_wallet() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.sol_payment_accounts(&order_id(1));
+    accounts.merchant_payout_wallet = env.other_wallet;
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_merchant_not_accepting_sol() {
+    let env = TestEnv::initialized(0).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Active, None, vec![env.mint])
+        .await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::MintNotAllowedForMerchant);
+}
+
+#[tokio::test]
+async fn process_sol_payment_uses_merchant_fee_override() {
+    let env = TestEnv::initialized(250).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Active, Some(100), Vec::new())
+        .await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    assert_eq!(env.lamports(&env.merchant_wallet).await, 990_000_000);
+    assert_eq!(env.lamports(&env.fee_treasury).await, 10_000_000);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_wrong_fee_treasury() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.sol_payment_accounts(&order_id(1));
+    accounts.fee_treasury = Pubkey::new_unique();
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidFeeTreasury);
+}
+
+// process_batch_payment
+
+#[tokio::test]
+async fn process_batch_payment_emits_event() {
+    let env = TestEnv::initialized(100).await;
+    let ix = env.batch_payment_ix(
+        order_id(1),
+        10_000,
+        vec![line_item(1, 2_500, 2), line_item(2, 5_000, 1)],
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 9_400);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 100);
+    assert_eq!(env.commission_vault_balance().await, 500);
+    assert_eq!(env.commission_ledger().await.accrued, 500);
+    assert_eq!(env.affiliate_state().await.total_earnings, 500);
+
+    let mut leaves = [
+        line_items::leaf_hash(&[1; 32], 2_500, 2, 500),
+        line_items::leaf_hash(&[2; 32], 5_000, 1, 500),
+    ];
+    let items_root = line_items::merkle_root(&mut leaves);
+    let event: BatchPaymentProcessed = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.total_amount, 10_000);
+    assert_eq!(event.net_amount, 9_400);
+    assert_eq!(event.platform_fee, 100);
+    assert_eq!(event.commission, 500);
+    assert_eq!(event.product_count, 2);
+    assert_eq!(event.items_root, items_root);
+    assert_eq!(event.merchant, env.merchant);
+
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.product_id, "BATCH");
+    assert_eq!(order.items_root, items_root);
+    assert_eq!(order.item_count, 2);
+    assert_eq!(order.commission, 500);
+}
+
+#[tokio::test]
+async fn process_batch_payment_emits_each_line_item() {
+    let env = TestEnv::initialized(100).await;
+    let mut discounted = line_item(2, 5_000, 1);
+    discounted.commission_bps = Some(250);
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, 2_500, 2), discounted]);
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    let items: Vec<LineItemPaid> = decode_events(&events);
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].order_id, order_id(1));
+    assert_eq!(items[0].index, 0);
+    assert_eq!(items[0].product_id_hash, [1; 32]);
+    assert_eq!(items[0].unit_price, 2_500);
+    assert_eq!(items[0].quantity, 2);
+    assert_eq!(items[0].amount, 5_000);
+    assert_eq!(items[0].commission_bps, 500);
+    assert_eq!(items[0].commission, 250);
+    assert_eq!(items[1].index, 1);
+    assert_eq!(items[1].product_id_hash, [2; 32]);
+    assert_eq!(items[1].amount, 5_000);
+    assert_eq!(items[1].commission_bps, 250);
+    assert_eq!(items[1].commission, 125);
+
+    let event: BatchPaymentProcessed = decode_event(&events);
+    assert_eq!(event.commission, 375);
+    assert_eq!(event.platform_fee, 100);
+    let mut leaves = [
+        line_items::leaf_hash(&[1; 32], 2_500, 2, 500),
+        line_items::leaf_hash(&[2; 32], 5_000, 1, 250),
+    ];
+    assert_eq!(event.items_root, line_items::merkle_root(&mut leaves));
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_empty_cart() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::NoProducts);
+}
+
+#[tokio::test]
+async fn process_batch_payment_accepts_max_line_items() {
+    let env = TestEnv::initialized(0).await;
+    let items = (0..line_items::MAX_LINE_ITEMS as u8)
+        .map(|seed| line_item(seed, 1_000, 1))
+        .collect();
+    let ix = env.batch_payment_ix(order_id(1), 10_000, items);
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.item_count as usize, line_items::MAX_LINE_ITEMS);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_too_many_line_items() {
+    let env = TestEnv::initialized(0).await;
+    let items = (0..=line_items::MAX_LINE_ITEMS as u8)
+        .map(|seed| line_item(seed, 1_000, 1))
+        .collect();
+    let ix = env.batch_payment_ix(order_id(1), 11_000, items);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::TooManyLineItems);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_total_mismatch() {
+    let env = TestEnv::initialized(0).await;
+    let items = vec![line_item(1, 2_500, 2), line_item(2, 5_000, 1)];
+
+    let ix = env.batch_payment_ix(order_id(1), 10_001, items.clone());
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::LineItemsTotalMismatch);
+
+    let ix = env.batch_payment_ix(order_id(1), 9_999, items);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::LineItemsTotalMismatch);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_zero_quantity() {
+    let env = TestEnv::initialized(0).await;
+    let items = vec![line_item(1, 10_000, 1), line_item(2, 5_000, 0)];
+    let ix = env.batch_payment_ix(order_id(1), 10_000, items);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmount);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_line_amount_overflow() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, u64::MAX, 2)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::ArithmeticOverflow);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_item_commission_above_cap() {
+    let env = TestEnv::initialized(100).await;
+    env.set_max_total_fee(1_000).await;
+    env.set_affiliate(900, AffiliateStatus::Active).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Active, Some(101), Vec::new())
+        .await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, 10_000, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_item_commission_above_affiliate_rate() {
+    let env = TestEnv::initialized(0).await;
+    let mut item = line_item(1, 10_000, 1);
+    item.commission_bps = Some(501);
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![item.clone()]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::InvalidCommission);
+
+    // Without an affiliate no item may carry commission
+    item.commission_bps = Some(1);
+    let accounts = env.payment_accounts(&order_id(1));
+    let ix = env.batch_payment_ix_with(accounts, order_id(1), 10_000, vec![item]);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::InvalidCommission);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized(0).await;
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, 10_000, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::AffiliateSuspended);
+}
+
+// process_cart_payment / register_merchant / update_merchant
+
+#[tokio::test]
+async fn process_cart_payment_pays_each_merchant_and_emits_events() {
+    let mut env = TestEnv::initialized(100).await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    let items = vec![cart_item(0, 1, 2_000, 2), cart_item(1, 2, 6_000, 1)];
+    let ix = env.cart_payment_ix(order_id(1), 10_000, &[first, second], items);
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    // 1% platform fee and 5% commission come out of each merchant's leg
+    assert_eq!(env.token_balance(&first.1).await, 3_760);
+    assert_eq!(env.token_balance(&second.1).await, 5_640);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 100);
+    assert_eq!(env.commission_vault_balance().await, 500);
+    assert_eq!(env.commission_ledger().await.accrued, 500);
+    assert_eq!(env.affiliate_state().await.total_earnings, 500);
+
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.product_id, "CART");
+    assert_eq!(order.amount, 10_000);
+    assert_eq!(order.platform_fee, 100);
+    assert_eq!(order.commission, 500);
+    assert_eq!(order.item_count, 2);
+    assert_eq!(
+        order.merchant_legs,
+        vec![
+            MerchantLeg {
+                merchant: first.0,
+                amount: 4_000,
+                platform_fee: 40,
+                commission: 200,
+                net_amount: 3_760,
+            },
+            MerchantLeg {
+                merchant: second.0,
+                amount: 6_000,
+                platform_fee: 60,
+                commission: 300,
+                net_amount: 5_640,
+            },
+        ]
+    );
+
+    let event: CartPaymentProcessed = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.total_amount, 10_000);
+    assert_eq!(event.platform_fee, 100);
+    assert_eq!(event.commission, 500);
+    assert_eq!(event.merchant_count, 2);
+    assert_eq!(event.product_count, 2);
+    assert_eq!(event.items_root, order.items_root);
+    let legs: Vec<MerchantPaid> = decode_events(&events);
+    assert_eq!(legs.len(), 2);
+    assert_eq!(legs[0].merchant, first.0);
+    assert_eq!(legs[0].net_amount, 3_760);
+    assert_eq!(legs[1].merchant, second.0);
+    assert_eq!(legs[1].net_amount, 5_640);
+    let items: Vec<LineItemPaid> = decode_events(&events);
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[1].amount, 6_000);
+}
+
+#[tokio::test]
+async fn process_cart_payment_groups_items_by_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    let items = vec![
+        cart_item(1, 1, 1_000, 1),
+        cart_item(0, 2, 2_000, 1),
+        cart_item(1, 3, 3_000, 1),
+    ];
+    let ix = env.cart_payment_ix(order_id(1), 6_000, &[first, second], items);
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    let legs = env.order_record(&order_id(1)).await.merchant_legs;
+    assert_eq!(legs[0].amount, 2_000);
+    assert_eq!(legs[1].amount, 4_000);
+    assert_eq!(env.token_balance(&first.1).await, 1_900);
+    assert_eq!(env.token_balance(&second.1).await, 3_800);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_total_mismatch() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        10_001,
+        &[merchant],
+        vec![cart_item(0, 1, 10_000, 1)],
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::LineItemsTotalMismatch);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_merchant_index_out_of_range() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let ix = env.cart_payment_ix(order_id(1), 1_000, &[merchant], vec![cart_item(1, 1, 1_000, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchantIndex);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_merchant_without_items() {
+    let mut env = TestEnv::initialized(0).await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[first, second],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchantIndex);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_duplicate_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let items = vec![cart_item(0, 1, 1_000, 1), cart_item(1, 2, 1_000, 1)];
+    let ix = env.cart_payment_ix(order_id(1), 2_000, &[merchant, merchant], items);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::DuplicateMerchant);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_payout_account_of_other_wallet() {
+    let mut env = TestEnv::initialized(0).await;
+    let (merchant, _) = env.add_merchant(1).await;
+    let buyer_token = env.buyer_token;
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[(merchant, buyer_token)],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_account_that_is_not_a_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let (_, payout_token_account) = env.add_merchant(1).await;
+    let not_merchant = TestEnv::token_config_address(&env.mint);
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[(not_merchant, payout_token_account)],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_missing_merchant_accounts() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let mut ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[merchant],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+    ix.accounts.pop();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+}
+
+#[tokio::test]
+async fn process_cart_payment_order_closes_after_retention() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let ix = env.cart_payment_ix(order_id(1), 1_000, &[merchant], vec![cart_item(0, 1, 1_000, 1)]);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    env.advance_clock(i64::from(Config::DEFAULT_ORDER_RETENTION_SECS))
+        .await;
+    env.process(&[env.close_order_ix(&order_id(1))], &[])
+        .await
+        .unwrap();
+
+    assert!(env
+        .account_data(&TestEnv::order_address(&order_id(1)))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn register_merchant_stores_payout_wallet_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let payout_wallet = Pubkey::new_unique();
+    let ix = env.register_merchant_ix(&env.authority.pubkey(), [1; 32], payout_wallet);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let merchant = env.merchant(&TestEnv::merchant_address(&[1; 32])).await;
+    assert_eq!(merchant.merchant_id, [1; 32]);
+    assert_eq!(merchant.payout_wallet, payout_wallet);
+    assert_eq!(merchant.status, MerchantStatus::Active);
+    assert_eq!(merchant.platform_fee_bps, None);
+    assert!(merchant.allowed_mints.is_empty());
+    let event: MerchantUpdated = decode_event(&events);
+    assert_eq!(event.merchant_id, [1; 32]);
+    assert_eq!(event.payout_wallet, payout_wallet);
+    assert_eq!(event.status, MerchantStatus::Active);
+    assert_eq!(event.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn register_merchant_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.register_merchant_ix(&env.buyer.pubkey(), [1; 32], env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn payout_wallet_change_moves_payouts_to_new_wallet() {
+    let mut env = TestEnv::initialized(0).await;
+    let (merchant, old_payout_token_account) = env.add_merchant(1).await;
+    let new_wallet = Pubkey::new_unique();
+    let mint = env.mint;
+    let new_payout_token_account = env.add_token_account(&mint, &new_wallet, 0);
+    env.apply_config_change(ConfigChange::PayoutWallet {
+        merchant,
+        new_payout_wallet: new_wallet,
+    })
+    .await;
+
+    assert_eq!(env.merchant(&merchant).await.payout_wallet, new_wallet);
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[(merchant, old_payout_token_account)],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+
+    let ix = env.cart_payment_ix(
+        order_id(1),
+        1_000,
+        &[(merchant, new_payout_token_account)],
+        vec![cart_item(0, 1, 1_000, 1)],
+    );
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    assert_eq!(env.token_balance(&new_payout_token_account).await, 950);
+}
+
+#[tokio::test]
+async fn update_merchant_sets_status_fee_and_mints_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let other_mint = Pubkey::new_unique();
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        &env.merchant,
+        MerchantStatus::Suspended,
+        Some(150),
+        vec![env.mint, other_mint],
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let merchant = env.merchant(&env.merchant).await;
+    assert_eq!(merchant.payout_wallet, env.merchant_wallet);
+    assert_eq!(merchant.status, MerchantStatus::Suspended);
+    assert_eq!(merchant.platform_fee_bps, Some(150));
+    assert_eq!(merchant.allowed_mints, vec![env.mint, other_mint]);
+    let event: MerchantUpdated = decode_event(&events);
+    assert_eq!(event.merchant_id, MERCHANT_ID);
+    assert_eq!(event.status, MerchantStatus::Suspended);
+    assert_eq!(event.platform_fee_bps, Some(150));
+    assert_eq!(event.allowed_mints, vec![env.mint, other_mint]);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_fee_above_max() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        &env.merchant,
+        MerchantStatus::Active,
+        Some(1001),
+        Vec::new(),
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::FeeTooHigh);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_fee_above_total_fee_cap() {
+    let env = TestEnv::initialized(0).await;
+    env.set_max_total_fee(300).await;
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        &env.merchant,
+        MerchantStatus::Active,
+        Some(301),
+        Vec::new(),
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::FeeTooHigh);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_too_many_allowed_mints() {
+    let env = TestEnv::initialized(0).await;
+    let allowed_mints = (0..=Merchant::MAX_ALLOWED_MINTS)
+        .map(|_| Pubkey::new_unique())
+        .collect();
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        &env.merchant,
+        MerchantStatus::Active,
+        None,
+        allowed_mints,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::TooManyAllowedMints);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_suspended_merchant() {
+    let env = TestEnv::initialized(0).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Suspended, None, Vec::new())
+        .await;
+
+    let result = env.pay(order_id(1), 1_000).await;
+    assert_program_error(result, ErrorCode::MerchantSuspended);
+
+    env.set_merchant(&env.merchant, MerchantStatus::Active, None, Vec::new())
+        .await;
+    env.pay(order_id(2), 1_000).await.unwrap();
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_mint_not_allowed_for_merchant() {
+    let env = TestEnv::initialized(0).await;
+    let other_mint = Pubkey::new_unique();
+    env.set_merchant(&env.merchant, MerchantStatus::Active, None, vec![other_mint])
+        .await;
+
+    let result = env.pay(order_id(1), 1_000).await;
+    assert_program_error(result, ErrorCode::MintNotAllowedForMerchant);
+
+    env.set_merchant(
+        &env.merchant,
+        MerchantStatus::Active,
+        None,
+        vec![other_mint, env.mint],
+    )
+    .await;
+    env.pay(order_id(2), 1_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn process_payment_uses_merchant_fee_override() {
+    let env = TestEnv::initialized(250).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), true, 0, 0, Some(200));
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.set_merchant(&env.merchant, MerchantStatus::Active, Some(100), Vec::new())
+        .await;
+
+    env.pay(order_id(1), 100_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 99_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 1_000);
+    assert_eq!(env.order_record(&order_id(1)).await.platform_fee, 1_000);
+}
+
+#[tokio::test]
+async fn process_payment_caps_total_fee_at_merchant_fee() {
+    let env = TestEnv::initialized(0).await;
+    env.set_max_total_fee(1_000).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Active, Some(600), Vec::new())
+        .await;
+
+    let result = env.pay_through_affiliate(order_id(1), 100_000).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_payout_account_of_other_wallet() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, buyer) = (env.mint, env.buyer.pubkey());
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.merchant_token_account = env.add_token_account(&mint, &buyer, 0);
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_suspended_merchant() {
+    let env = TestEnv::initialized(0).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Suspended, None, Vec::new())
+        .await;
+    let ix = env.batch_payment_ix(order_id(1), 1_000, vec![line_item(1, 1_000, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::MerchantSuspended);
+}
+
+#[tokio::test]
+async fn process_cart_payment_charges_each_merchant_its_fee() {
+    let mut env = TestEnv::initialized(100).await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    env.set_merchant(&second.0, MerchantStatus::Active, Some(300), Vec::new())
+        .await;
+    let items = vec![cart_item(0, 1, 4_000, 1), cart_item(1, 2, 6_000, 1)];
+    let ix = env.cart_payment_ix(order_id(1), 10_000, &[first, second], items);
+
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    assert_eq!(env.token_balance(&first.1).await, 3_760);
+    assert_eq!(env.token_balance(&second.1).await, 5_520);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 220);
+    assert_eq!(env.commission_vault_balance().await, 500);
+    assert_eq!(env.order_record(&order_id(1)).await.platform_fee, 220);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_affiliate_without_commission_ledger() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let ledger = env.commission_ledger_address(&env.mint);
+    let items = vec![cart_item(0, 1, 1_000, 1)];
+    let mut ix = env.cart_payment_ix(order_id(1), 1_000, &[merchant], items);
+    let meta = ix.accounts.iter_mut().find(|meta| meta.pubkey == ledger).unwrap();
+    *meta = AccountMeta::new_readonly(oxmart_payment::ID, false);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommissionLedger);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_suspended_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    env.set_merchant(&second.0, MerchantStatus::Suspended, None, Vec::new())
+        .await;
+    let items = vec![cart_item(0, 1, 1_000, 1), cart_item(1, 2, 1_000, 1)];
+    let ix = env.cart_payment_ix(order_id(1), 2_000, &[first, second], items);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::MerchantSuspended);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized(0).await;
+    let (merchant, _) = env.add_merchant(1).await;
+    let ix = env.update_merchant_ix(
+        &env.buyer.pubkey(),
+        &merchant,
+        MerchantStatus::Active,
+        None,
+        Vec::new(),
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// register_affiliate / update_affiliate
+
+#[tokio::test]
+async fn register_affiliate_stores_rate_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), [1; 32], 300);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let data = env
+        .account_data(&TestEnv::affiliate_address(&[1; 32]))
+        .await
+        .unwrap();
+    let affiliate = Affiliate::try_deserialize(&mut data.as_slice()).unwrap();
+    assert_eq!(affiliate.api_key_hash, [1; 32]);
+    assert_eq!(affiliate.owner, env.api_key_owner.pubkey());
+    assert_eq!(affiliate.commission_bps, 300);
+    assert_eq!(affiliate.status, AffiliateStatus::Active);
+    assert_eq!(affiliate.total_earnings, 0);
+    let event: AffiliateUpdated = decode_event(&events);
+    assert_eq!(event.api_key_hash, [1; 32]);
+    assert_eq!(event.owner, env.api_key_owner.pubkey());
+    assert_eq!(event.commission_bps, 300);
+    assert_eq!(event.status, AffiliateStatus::Active);
+    assert_eq!(event.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.register_affiliate_ix(&env.buyer.pubkey(), [1; 32], 300);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_commission_above_max() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), [1; 32], 10_001);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommission);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_commission_above_total_fee_cap() {
+    let env = TestEnv::initialized(250).await;
+    env.set_max_total_fee(750).await;
+
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), [1; 32], 501);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::InvalidCommission);
+
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), [1; 32], 500);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_affiliate_sets_owner_rate_and_status_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let owner = Pubkey::new_unique();
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        owner,
+        300,
+        AffiliateStatus::Suspended,
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let affiliate = env.affiliate_state().await;
+    assert_eq!(affiliate.owner, owner);
+    assert_eq!(affiliate.commission_bps, 300);
+    assert_eq!(affiliate.status, AffiliateStatus::Suspended);
+    let event: AffiliateUpdated = decode_event(&events);
+    assert_eq!(event.api_key_hash, API_KEY_HASH);
+    assert_eq!(event.owner, owner);
+    assert_eq!(event.status, AffiliateStatus::Suspended);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_commission_above_max() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        env.api_key_owner.pubkey(),
+        10_001,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommission);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_commission_above_total_fee_cap() {
+    let env = TestEnv::initialized(250).await;
+    env.set_max_total_fee(750).await;
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        env.api_key_owner.pubkey(),
+        501,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommission);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_affiliate_ix(
+        &env.buyer.pubkey(),
+        env.buyer.pubkey(),
+        AFFILIATE_COMMISSION_BPS,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert_eq!(env.affiliate_state().await.owner, env.api_key_owner.pubkey());
+}
+
+#[tokio::test]
+async fn process_payment_takes_commission_from_affiliate() {
+    let env = TestEnv::initialized(250).await;
+    env.set_affiliate(1_000, AffiliateStatus::Active).await;
+    let ix = env.payment_ix(
+        env.affiliate_payment_accounts(&order_id(1)),
+        order_id(1),
+        100_000,
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+    env.pay_through_affiliate(order_id(2), 50_000).await.unwrap();
+
+    assert_eq!(env.commission_vault_balance().await, 15_000);
+    assert_eq!(env.affiliate_state().await.total_earnings, 15_000);
+    let event: PaymentProcessed = decode_event(&events);
+    assert_eq!(event.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(event.commission, 10_000);
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(order.commission, 10_000);
+}
+
+#[tokio::test]
+async fn process_payment_without_affiliate_takes_no_commission() {
+    let env = TestEnv::initialized(250).await;
+
+    env.pay(order_id(1), 100_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 97_500);
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.api_key_owner, Pubkey::default());
+    assert_eq!(order.commission, 0);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized(0).await;
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+
+    let result = env.pay_through_affiliate(order_id(1), 1_000).await;
+    assert_program_error(result, ErrorCode::AffiliateSuspended);
+
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Active)
+        .await;
+    env.pay_through_affiliate(order_id(2), 1_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn process_payment_rejects_merchant_as_affiliate() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.affiliate = Some(env.merchant);
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(
+            anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch.into(),
+        ),
+    );
+}
+
+// open_commission_ledger / claim_commission
+
+#[tokio::test]
+async fn open_commission_ledger_creates_empty_ledger_and_vault() {
+    let env = TestEnv::initialized(0).await;
+
+    let ledger = env.commission_ledger().await;
+
+    assert_eq!(ledger.affiliate, env.affiliate);
+    assert_eq!(ledger.mint, env.mint);
+    assert_eq!(ledger.accrued, 0);
+    assert_eq!(ledger.claimed, 0);
+    assert_eq!(ledger.last_claim_at, 0);
+    assert_eq!(env.commission_vault_balance().await, 0);
+}
+
+#[tokio::test]
+async fn process_payment_accrues_commission_in_ledger() {
+    let env = TestEnv::initialized(0).await;
+
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    env.pay_through_affiliate(order_id(2), 50_000).await.unwrap();
+
+    let ledger = env.commission_ledger().await;
+    assert_eq!(ledger.accrued, 7_500);
+    assert_eq!(ledger.claimed, 0);
+    assert_eq!(env.commission_vault_balance().await, 7_500);
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 0);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_affiliate_without_commission_ledger() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.affiliate_payment_accounts(&order_id(1));
+    accounts.commission_ledger = None;
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommissionLedger);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_wrong_commission_vault() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, ledger) = (env.mint, env.commission_ledger_address(&env.mint));
+    let mut accounts = env.affiliate_payment_accounts(&order_id(1));
+    accounts.commission_vault = Some(env.add_token_account(&mint, &ledger, 0));
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidCommissionVault);
+}
+
+#[tokio::test]
+async fn claim_commission_pays_out_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    let ix = env.claim_commission_ix(&env.api_key_owner.pubkey(), &env.api_key_owner_token);
+
+    let events = env.process_with_events(&[ix], &[&env.api_key_owner]).await;
+
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 5_000);
+    assert_eq!(env.commission_vault_balance().await, 0);
+    let ledger = env.commission_ledger().await;
+    assert_eq!(ledger.accrued, 5_000);
+    assert_eq!(ledger.claimed, 5_000);
+    let event: CommissionClaimed = decode_event(&events);
+    assert_eq!(event.affiliate, env.affiliate);
+    assert_eq!(event.owner, env.api_key_owner.pubkey());
+    assert_eq!(event.mint, env.mint);
+    assert_eq!(event.amount, 5_000);
+    assert_eq!(event.total_claimed, 5_000);
+    assert_eq!(event.timestamp, ledger.last_claim_at);
+    assert!(ledger.last_claim_at > 0);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_empty_balance() {
+    let env = TestEnv::initialized(0).await;
+
+    let result = env.claim_commission().await;
+
+    assert_program_error(result, ErrorCode::NoCommissionToClaim);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_other_signer() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    let (mint, buyer) = (env.mint, env.buyer.pubkey());
+    let buyer_token = env.add_token_account(&mint, &buyer, 0);
+
+    let ix = env.claim_commission_ix(&buyer, &buyer_token);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_token_account_of_other_owner() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    let mint = env.mint;
+    let other_token = env.add_token_account(&mint, &Pubkey::new_unique(), 0);
+
+    let ix = env.claim_commission_ix(&env.api_key_owner.pubkey(), &other_token);
+    let result = env.process(&[ix], &[&env.api_key_owner]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenAccount);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized(0).await;
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+
+    let result = env.claim_commission().await;
+
+    assert_program_error(result, ErrorCode::AffiliateSuspended);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_pays_owner_wallet_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let ledger_rent = env.lamports(&env.sol_commission_ledger_address()).await;
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    let ix = env.claim_sol_commission_ix(&env.api_key_owner.pubkey());
+
+    let events = env.process_with_events(&[ix], &[&env.api_key_owner]).await;
+
+    assert_eq!(env.lamports(&env.api_key_owner.pubkey()).await, 50_000_000);
+    assert_eq!(
+        env.lamports(&env.sol_commission_ledger_address()).await,
+        ledger_rent
+    );
+    let ledger = env.sol_commission_ledger().await;
+    assert_eq!(ledger.accrued, 50_000_000);
+    assert_eq!(ledger.claimed, 50_000_000);
+    let event: CommissionClaimed = decode_event(&events);
+    assert_eq!(event.affiliate, env.affiliate);
+    assert_eq!(event.mint, spl_token::native_mint::id());
+    assert_eq!(event.amount, 50_000_000);
+    assert_eq!(event.timestamp, ledger.last_claim_at);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_rejects_empty_balance() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.claim_sol_commission_ix(&env.api_key_owner.pubkey());
+
+    let result = env.process(&[ix], &[&env.api_key_owner]).await;
+
+    assert_program_error(result, ErrorCode::NoCommissionToClaim);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_rejects_other_signer() {
+    let env = TestEnv::initialized(0).await;
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), SOL_PAYMENT);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    let ix = env.claim_sol_commission_ix(&env.buyer.pubkey());
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// refund_order
+
+#[tokio::test]
+async fn refund_order_returns_full_amount_and_emits_event() {
+    let mut env = TestEnv::initialized(250).await;
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    let authority = env.authority.pubkey();
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 100_000);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.token_mint, env.mint);
+    assert_eq!(order.refunded_amount, 100_000);
+
+    let event: PaymentRefunded = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.buyer, env.buyer.pubkey());
+    assert_eq!(event.token_mint, env.mint);
+    assert_eq!(event.amount, 100_000);
+    assert_eq!(event.refunded_amount, 100_000);
+    assert!(event.fully_refunded);
+    assert_eq!(event.refunded_by, authority);
+}
+
+#[tokio::test]
+async fn refund_order_accumulates_partial_refunds() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let authority = env.authority.pubkey();
+
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 30_000);
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+    let event: PaymentRefunded = decode_event(&events);
+    assert_eq!(event.refunded_amount, 30_000);
+    assert!(!event.fully_refunded);
+
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 70_001);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::RefundExceedsPayment);
+
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 70_000);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert_eq!(
+        env.order_record(&order_id(1)).await.refunded_amount,
+        100_000
+    );
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+}
+
+#[tokio::test]
+async fn refund_order_accepts_refund_operator() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let refund_operator = Keypair::new();
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::UpdateRefundOperator {
+            new_refund_operator: refund_operator.pubkey(),
+        },
+    );
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+    let event: RefundOperatorUpdated = decode_event(&events);
+    assert_eq!(event.old_refund_operator, Pubkey::default());
+    assert_eq!(event.new_refund_operator, refund_operator.pubkey());
+
+    let ix = env.funded_refund_ix(&refund_operator.pubkey(), &order_id(1), 40_000);
+    env.process(&[ix], &[&refund_operator]).await.unwrap();
+
+    assert_eq!(env.order_record(&order_id(1)).await.refunded_amount, 40_000);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let buyer = env.buyer.pubkey();
+    let ix = env.funded_refund_ix(&buyer, &order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_zero_amount() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let authority = env.authority.pubkey();
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 0);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_other_buyer_token_account() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let (mint, authority) = (env.mint, env.authority.pubkey());
+    env.buyer_token = env.add_token_account(&mint, &authority, 0);
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenAccount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_source_of_other_owner() {
+    let env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let ix = env.refund_ix(
+        &env.authority.pubkey(),
+        env.other_wallet_token,
+        &order_id(1),
+        1_000,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenAccount);
+}
+
+#[tokio::test]
+async fn update_refund_operator_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::UpdateRefundOperator {
+            new_refund_operator: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// Token-2022
+
+const TRANSFER_FEE_BPS: u16 = 100;
+
+#[tokio::test]
+async fn token_2022_payment_splits_fee() {
+    let env = TestEnv::initialized_with_token_program(250, spl_token_2022::id(), 0).await;
+
+    env.pay_through_affiliate(order_id(1), 100_000_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500_000);
+    assert_eq!(env.commission_vault_balance().await, 5_000_000);
+    assert_eq!(env.order_record(&order_id(1)).await.amount, 100_000_000);
+}
+
+#[tokio::test]
+async fn transfer_fee_payment_records_received_amounts() {
+    let env =
+        TestEnv::initialized_with_token_program(250, spl_token_2022::id(), TRANSFER_FEE_BPS).await;
+    let ix = env.payment_ix(
+        env.affiliate_payment_accounts(&order_id(1)),
+        order_id(1),
+        100_000_000,
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.buyer]).await;
+
+    // Each transfer loses 1% to the mint's transfer fee
+    assert_eq!(env.token_balance(&env.merchant_token).await, 91_575_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_475_000);
+    assert_eq!(env.commission_vault_balance().await, 4_950_000);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 100_000_000
+    );
+
+    let event: PaymentProcessed = decode_event(&events);
+    assert_eq!(event.amount, 99_000_000);
+    assert_eq!(event.net_amount, 91_575_000);
+    assert_eq!(event.platform_fee, 2_475_000);
+    assert_eq!(event.commission, 4_950_000);
+
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.amount, 99_000_000);
+    assert_eq!(order.platform_fee, 2_475_000);
+}
+
+#[tokio::test]
+async fn transfer_fee_claim_empties_commission_vault() {
+    let env = TestEnv::initialized_with_token_program(0, spl_token_2022::id(), TRANSFER_FEE_BPS)
+        .await;
+    env.pay_through_affiliate(order_id(1), 100_000_000)
+        .await
+        .unwrap();
+
+    env.claim_commission().await.unwrap();
+
+    // The ledger records what left the vault, the owner receives it less the transfer fee
+    assert_eq!(env.commission_ledger().await.claimed, 4_950_000);
+    assert_eq!(env.commission_vault_balance().await, 0);
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 4_900_500);
+}
+
+#[tokio::test]
+async fn token_2022_refund_order_returns_amount_to_buyer() {
+    let mut env = TestEnv::initialized_with_token_program(0, spl_token_2022::id(), 0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let authority = env.authority.pubkey();
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 100_000);
+
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+    assert_eq!(env.order_record(&order_id(1)).await.refunded_amount, 100_000);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_token_program_not_owning_mint() {
+    let env = TestEnv::initialized_with_token_program(0, spl_token_2022::id(), 0).await;
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.token_program = spl_token::id();
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenProgram);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_mint_other_than_order_mint() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    let authority = env.authority.pubkey();
+    let mut ix = env.funded_refund_ix(&authority, &order_id(1), 1_000);
+    ix.accounts[3].pubkey = env.add_mint();
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidTokenAccount);
+}
+
+// add_supported_token / update_token_config / remove_supported_token
+
+#[tokio::test]
+async fn add_supported_token_creates_config_and_emits_event() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.add_mint();
+    let ix = env.add_supported_token_ix(&env.authority.pubkey(), &mint, 1_000, 50_000, Some(100));
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let token_config = env.token_config(&mint).await;
+    assert_eq!(token_config.mint, mint);
+    assert!(token_config.enabled);
+    assert_eq!(token_config.min_amount, 1_000);
+    assert_eq!(token_config.max_amount, 50_000);
+    assert_eq!(token_config.platform_fee_bps, Some(100));
+
+    let event: TokenConfigUpdated = decode_event(&events);
+    assert_eq!(event.mint, mint);
+    assert!(event.enabled);
+    assert_eq!(event.platform_fee_bps, Some(100));
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.add_mint();
+    let ix = env.add_supported_token_ix(&env.buyer.pubkey(), &mint, 0, 0, None);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_fee_override_above_max() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.add_mint();
+    let ix = env.add_supported_token_ix(&env.authority.pubkey(), &mint, 0, 0, Some(1001));
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::FeeTooHigh);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_min_above_max() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.add_mint();
+    let ix = env.add_supported_token_ix(&env.authority.pubkey(), &mint, 2_000, 1_000, None);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmountLimits);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_unregistered_mint() {
+    let mut env = TestEnv::initialized(0).await;
+    let mint = env.add_mint();
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.mint = mint;
+    accounts.token_config = TestEnv::token_config_address(&mint);
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(anchor_lang::error::ErrorCode::AccountNotInitialized as u32),
+    );
+}
+
+#[tokio::test]
+async fn process_payment_rejects_disabled_token() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), false, 0, 0, None);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let result = env.pay(order_id(1), 1_000).await;
+
+    assert_program_error(result, ErrorCode::TokenNotSupported);
+}
+
+#[tokio::test]
+async fn process_payment_enforces_token_amount_limits() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), true, 1_000, 5_000, None);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let result = env.pay(order_id(1), 999).await;
+    assert_program_error(result, ErrorCode::AmountBelowMinimum);
+
+    let result = env.pay(order_id(2), 5_001).await;
+    assert_program_error(result, ErrorCode::AmountAboveMaximum);
+
+    env.pay(order_id(3), 5_000).await.unwrap();
+    assert_eq!(env.token_balance(&env.merchant_token).await, 5_000);
+}
+
+#[tokio::test]
+async fn process_batch_payment_enforces_token_amount_limits() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), true, 0, 5_000, None);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.batch_payment_ix(order_id(1), 5_001, vec![line_item(1, 5_001, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::AmountAboveMaximum);
+}
+
+#[tokio::test]
+async fn process_payment_uses_token_fee_override() {
+    let env = TestEnv::initialized(250).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), true, 0, 0, Some(100));
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    env.pay(order_id(1), 100_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 99_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 1_000);
+    assert_eq!(env.order_record(&order_id(1)).await.platform_fee, 1_000);
+}
+
+#[tokio::test]
+async fn update_token_config_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_token_config_ix(&env.buyer.pubkey(), false, 0, 0, None);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn remove_supported_token_closes_config_and_can_be_re_added() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.remove_supported_token_ix(&env.authority.pubkey());
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let event: SupportedTokenRemoved = decode_event(&events);
+    assert_eq!(event.mint, env.mint);
+    assert!(env
+        .account_data(&TestEnv::token_config_address(&env.mint))
+        .await
+        .is_none());
+    assert!(env.pay(order_id(1), 1_000).await.is_err());
+
+    let ix = env.add_supported_token_ix(&env.authority.pubkey(), &env.mint, 0, 0, None);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    // A different amount, so the bank does not replay the failed transaction's result
+    env.pay(order_id(1), 2_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn remove_supported_token_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.remove_supported_token_ix(&env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// update_fee_treasury
+
+#[tokio::test]
+async fn update_fee_treasury_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let new_fee_treasury = Pubkey::new_unique();
+    let ix = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::UpdateFeeTreasury { new_fee_treasury },
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.fee_treasury, new_fee_treasury);
+    let event: FeeTreasuryUpdated = decode_event(&events);
+    assert_eq!(event.old_fee_treasury, env.fee_treasury);
+    assert_eq!(event.new_fee_treasury, new_fee_treasury);
+}
+
+// set_quote_signer / payment quotes
+
+#[tokio::test]
+async fn set_quote_signer_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let new_quote_signer = Pubkey::new_unique();
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::SetQuoteSigner { new_quote_signer },
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.quote_signer, new_quote_signer);
+    let event: QuoteSignerUpdated = decode_event(&events);
+    assert_eq!(event.old_quote_signer, Pubkey::default());
+    assert_eq!(event.new_quote_signer, new_quote_signer);
+}
+
+#[tokio::test]
+async fn set_quote_signer_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::SetQuoteSigner {
+            new_quote_signer: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn quoted_payment_splits_fee() {
+    let env = TestEnv::initialized(250).await;
+    let quote_signer = env.require_quotes().await;
+    let quote = env.quote(order_id(1), env.mint, 100_000, 500).await;
+    let ix = env.payment_ix(env.affiliate_payment_accounts(&order_id(1)), order_id(1), 100_000);
+
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+}
+
+#[tokio::test]
+async fn process_payment_requires_quote_once_signer_set() {
+    let env = TestEnv::initialized(0).await;
+    env.require_quotes().await;
+
+    let result = env.pay(order_id(1), 1_000).await;
+
+    assert_program_error(result, ErrorCode::InvalidQuote);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_terms_differing_from_quote() {
+    let env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let quote = env.quote(order_id(1), env.mint, 100_000, 500).await;
+
+    // Underpaying, dropping the affiliate or reusing the quote for another order
+    for ix in [
+        env.payment_ix(env.affiliate_payment_accounts(&order_id(1)), order_id(1), 50_000),
+        env.payment_ix(env.payment_accounts(&order_id(1)), order_id(1), 100_000),
+        env.payment_ix(env.affiliate_payment_accounts(&order_id(2)), order_id(2), 100_000),
+    ] {
+        let result = env
+            .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+            .await;
+        assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+    }
+}
+
+#[tokio::test]
+async fn process_payment_rejects_quote_for_other_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let (other_merchant, other_merchant_token) = env.add_merchant(1).await;
+    let quote = env.quote(order_id(1), env.mint, 1_000, AFFILIATE_COMMISSION_BPS).await;
+    let mut accounts = env.affiliate_payment_accounts(&order_id(1));
+    accounts.merchant = other_merchant;
+    accounts.merchant_token_account = other_merchant_token;
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_quote_from_other_signer() {
+    let env = TestEnv::initialized(0).await;
+    env.require_quotes().await;
+    let quote = env.quote(order_id(1), env.mint, 1_000, AFFILIATE_COMMISSION_BPS).await;
+    let ix = env.payment_ix(env.affiliate_payment_accounts(&order_id(1)), order_id(1), 1_000);
+
+    let result = env
+        .process(&[quote_ix(&env.buyer, &quote), ix], &[&env.buyer])
+        .await;
+
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_expired_quote() {
+    let mut env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let quote = env.quote(order_id(1), env.mint, 1_000, AFFILIATE_COMMISSION_BPS).await;
+    env.advance_clock(61).await;
+    let ix = env.payment_ix(env.affiliate_payment_accounts(&order_id(1)), order_id(1), 1_000);
+
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+
+    assert_quoted_program_error(result, ErrorCode::QuoteExpired);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_wrong_instructions_sysvar() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.instructions = Pubkey::new_unique();
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(anchor_lang::error::ErrorCode::ConstraintAddress.into()),
+    );
+}
+
+#[tokio::test]
+async fn quoted_sol_payment_uses_native_mint() {
+    let env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    env.set_affiliate(0, AffiliateStatus::Active).await;
+    let mut quote = env.quote(order_id(1), env.mint, 1_000_000, 0).await;
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), 1_000_000);
+
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+
+    quote.terms.mint = spl_token::native_mint::id();
+    let accounts = env.affiliate_sol_payment_accounts(&order_id(1));
+    let ix = env.sol_payment_ix(accounts, order_id(1), 1_000_000);
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.lamports(&env.merchant_wallet).await, 1_000_000);
+}
+
+#[tokio::test]
+async fn quoted_batch_payment_covers_total_amount() {
+    let env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let items = vec![line_item(1, 1_000, 1), line_item(2, 1_000, 2)];
+    let mut quote = env.quote(order_id(1), env.mint, 3_000, 500).await;
+    quote.terms.items_root = items_root(&items, 500);
+
+    let ix = env.batch_payment_ix(order_id(1), 3_000, items.clone());
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    let ix = env.batch_payment_ix(order_id(2), 3_000, items);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::InvalidQuote);
+}
+
+#[tokio::test]
+async fn quoted_cart_payment_names_merchants_in_order() {
+    let mut env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let first = env.add_merchant(1).await;
+    let second = env.add_merchant(2).await;
+    let items = vec![cart_item(0, 1, 1_000, 1), cart_item(1, 2, 2_000, 1)];
+    let mut quote = env.quote(order_id(1), env.mint, 3_000, 500).await;
+    let line_items: Vec<LineItem> = items.iter().map(|item| item.item.clone()).collect();
+    quote.terms.items_root = items_root(&line_items, 500);
+
+    quote.terms.merchants = vec![second.0, first.0];
+    let ix = env.cart_payment_ix(order_id(1), 3_000, &[first, second], items.clone());
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+
+    quote.terms.merchants = vec![first.0, second.0];
+    let ix = env.cart_payment_ix(order_id(1), 3_000, &[first, second], items);
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn quoted_batch_payment_rejects_item_commission_lowered_by_buyer() {
+    let env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let items = vec![line_item(1, 1_000, 1), line_item(2, 2_000, 1)];
+    let mut quote = env.quote(order_id(1), env.mint, 3_000, 500).await;
+    quote.terms.items_root = items_root(&items, 500);
+
+    let mut unpaid = items.clone();
+    unpaid[1].commission_bps = Some(0);
+    let ix = env.batch_payment_ix(order_id(1), 3_000, unpaid);
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+    assert_eq!(env.commission_ledger().await.accrued, 0);
+
+    let ix = env.batch_payment_ix(order_id(1), 3_000, items);
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+    assert_eq!(env.commission_ledger().await.accrued, 150);
+}
+
+#[tokio::test]
+async fn quoted_cart_payment_rejects_item_commission_lowered_by_buyer() {
+    let mut env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let first = env.add_merchant(1).await;
+    let mut items = vec![cart_item(0, 1, 1_000, 1), cart_item(0, 2, 2_000, 1)];
+    let mut quote = env.quote(order_id(1), env.mint, 3_000, 500).await;
+    let line_items: Vec<LineItem> = items.iter().map(|item| item.item.clone()).collect();
+    quote.terms.merchants = vec![first.0];
+    quote.terms.items_root = items_root(&line_items, 500);
+
+    items[0].item.commission_bps = Some(0);
+    let ix = env.cart_payment_ix(order_id(1), 3_000, &[first], items);
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+
+    assert_quoted_program_error(result, ErrorCode::InvalidQuote);
+    assert_eq!(env.commission_ledger().await.accrued, 0);
+}
+
+// order deadlines / set_max_quote_lifetime
+
+#[tokio::test]
+async fn process_payment_rejects_order_expired_before_processing() {
+    let mut env = TestEnv::initialized(0).await;
+    let ix = env.payment_ix(env.payment_accounts(&order_id(1)), order_id(1), 1_000);
+    env.advance_clock(10 * 60 + 1).await;
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::OrderExpired);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 0);
+}
+
+#[tokio::test]
+async fn process_batch_payment_rejects_order_expired_before_processing() {
+    let mut env = TestEnv::initialized(0).await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, 10_000, 1)]);
+    env.advance_clock(10 * 60 + 1).await;
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::OrderExpired);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 0);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_order_expired_before_processing() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let ix = env.cart_payment_ix(order_id(1), 1_000, &[merchant], vec![cart_item(0, 1, 1_000, 1)]);
+    env.advance_clock(10 * 60 + 1).await;
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::OrderExpired);
+    assert_eq!(env.token_balance(&merchant.1).await, 0);
+}
+
+#[tokio::test]
+async fn process_cart_payment_rejects_expiry_beyond_max_quote_lifetime() {
+    let mut env = TestEnv::initialized(0).await;
+    let merchant = env.add_merchant(1).await;
+    let max_lifetime = i64::from(Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS);
+    let items = vec![cart_item(0, 1, 1_000, 1)];
+    let mut ix = env.cart_payment_ix(order_id(1), 1_000, &[merchant], items.clone());
+    ix.data = oxmart_payment::instruction::ProcessCartPayment {
+        order_id: order_id(1),
+        total_amount: 1_000,
+        merchant_count: 1,
+        cart_items: items,
+        expires_at: env.now + max_lifetime + 1,
+    }
+    .data();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::ExpiryTooFar);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_expiry_beyond_max_quote_lifetime() {
+    let env = TestEnv::initialized(0).await;
+    let max_lifetime = i64::from(Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS);
+    let accounts = env.payment_accounts(&order_id(1));
+
+    let ix = env.expiring_payment_ix(accounts, order_id(1), 1_000, env.now + max_lifetime + 1);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::ExpiryTooFar);
+
+    let accounts = env.payment_accounts(&order_id(1));
+    let ix = env.expiring_payment_ix(accounts, order_id(1), 1_000, env.now + max_lifetime);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+}
+
+#[tokio::test]
+async fn process_payment_rejects_quote_beyond_max_quote_lifetime() {
+    let env = TestEnv::initialized(0).await;
+    let quote_signer = env.require_quotes().await;
+    let mut quote = env.quote(order_id(1), env.mint, 1_000, AFFILIATE_COMMISSION_BPS).await;
+    quote.expires_at = env.now + 2 * 60 * 60;
+    let ix = env.payment_ix(env.affiliate_payment_accounts(&order_id(1)), order_id(1), 1_000);
+
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await;
+
+    assert_quoted_program_error(result, ErrorCode::ExpiryTooFar);
+}
+
+#[tokio::test]
+async fn set_max_quote_lifetime_zero_removes_limit_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.set_max_quote_lifetime_ix(&env.authority.pubkey(), 0);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.max_quote_lifetime_secs, 0);
+    let event: MaxQuoteLifetimeUpdated = decode_event(&events);
+    assert_eq!(
+        event.old_max_quote_lifetime_secs,
+        Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS
+    );
+    assert_eq!(event.new_max_quote_lifetime_secs, 0);
+
+    let accounts = env.payment_accounts(&order_id(1));
+    let ix = env.expiring_payment_ix(accounts, order_id(1), 1_000, i64::MAX);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+}
+
+#[tokio::test]
+async fn set_max_quote_lifetime_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.set_max_quote_lifetime_ix(&env.buyer.pubkey(), 0);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// close_order / set_order_retention
+
+const ORDER_RETENTION: i64 = Config::DEFAULT_ORDER_RETENTION_SECS as i64;
+
+#[tokio::test]
+async fn close_order_returns_rent_and_blocks_repayment() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    let order_rent = env.lamports(&TestEnv::order_address(&order_id(1))).await;
+    let buyer_lamports = env.lamports(&env.buyer.pubkey()).await;
+    env.advance_clock(ORDER_RETENTION).await;
+
+    let events = env
+        .process_with_events(&[env.close_order_ix(&order_id(1))], &[])
+        .await;
+
+    assert!(env
+        .account_data(&TestEnv::order_address(&order_id(1)))
+        .await
+        .is_none());
+    let marker_rent = Rent::default().minimum_balance(8 + ClosedOrder::LEN);
+    assert_eq!(
+        env.account_data(&TestEnv::closed_order_address(&order_id(1)))
+            .await
+            .unwrap(),
+        ClosedOrder::DISCRIMINATOR
+    );
+    assert_eq!(
+        env.lamports(&TestEnv::closed_order_address(&order_id(1))).await,
+        marker_rent
+    );
+    assert_eq!(
+        env.lamports(&env.buyer.pubkey()).await,
+        buyer_lamports + order_rent - marker_rent
+    );
+    let event: OrderClosed = decode_event(&events);
+    assert_eq!(event.order_id, order_id(1));
+    assert_eq!(event.buyer, env.buyer.pubkey());
+
+    let result = env.pay(order_id(1), 1_000).await;
+    assert_program_error(result, ErrorCode::OrderAlreadyProcessed);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn close_order_keeps_other_orders_payable() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    env.process(&[env.close_order_ix(&order_id(1))], &[])
+        .await
+        .unwrap();
+
+    env.pay(order_id(2), 1_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn close_order_rejects_during_retention_period() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    env.advance_clock(ORDER_RETENTION - 60).await;
+
+    let result = env.process(&[env.close_order_ix(&order_id(1))], &[]).await;
+
+    assert_program_error(result, ErrorCode::RetentionPeriodActive);
+}
+
+#[tokio::test]
+async fn close_order_rejects_other_rent_recipient() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 1_000).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    let mut ix = env.close_order_ix(&order_id(1));
+    ix.accounts[2].pubkey = env.other_wallet;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn process_sol_payment_rejects_closed_order() {
+    let mut env = TestEnv::initialized(0).await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    env.process(&[env.close_order_ix(&order_id(1))], &[])
+        .await
+        .unwrap();
+
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::OrderAlreadyProcessed);
+}
+
+#[tokio::test]
+async fn process_payment_rejects_wrong_closed_order_account() {
+    let env = TestEnv::initialized(0).await;
+    let mut accounts = env.payment_accounts(&order_id(1));
+    accounts.closed_order = TestEnv::closed_order_address(&order_id(2));
+    let ix = env.payment_ix(accounts, order_id(1), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(anchor_lang::error::ErrorCode::ConstraintSeeds.into()),
+    );
+}
+
+#[tokio::test]
+async fn set_order_retention_zero_allows_immediate_close_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::SetOrderRetention {
+            order_retention_secs: 0,
+        },
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.order_retention_secs, 0);
+    let event: OrderRetentionUpdated = decode_event(&events);
+    assert_eq!(
+        event.old_order_retention_secs,
+        Config::DEFAULT_ORDER_RETENTION_SECS
+    );
+    assert_eq!(event.new_order_retention_secs, 0);
+
+    env.pay(order_id(1), 1_000).await.unwrap();
+    env.process(&[env.close_order_ix(&order_id(1))], &[])
+        .await
+        .unwrap();
+    assert!(env
+        .account_data(&TestEnv::order_address(&order_id(1)))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn set_order_retention_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::SetOrderRetention {
+            order_retention_secs: 0,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// set_max_total_fee
+
+#[tokio::test]
+async fn set_max_total_fee_allows_payment_at_cap_and_emits_event() {
+    let env = TestEnv::initialized(250).await;
+    let ix = env.set_max_total_fee_ix(&env.authority.pubkey(), 1000);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.max_total_fee_bps, 1000);
+    let event: MaxTotalFeeUpdated = decode_event(&events);
+    assert_eq!(event.old_max_total_fee_bps, Config::DEFAULT_MAX_TOTAL_FEE_BPS);
+    assert_eq!(event.new_max_total_fee_bps, 1000);
+    assert_eq!(event.authority, env.authority.pubkey());
+
+    env.set_affiliate(750, AffiliateStatus::Active).await;
+
+    env.pay_through_affiliate(order_id(1), 100_000).await.unwrap();
+    let order = env.order_record(&order_id(1)).await;
+    assert_eq!(order.platform_fee, 2_500);
+    assert_eq!(order.commission, 7_500);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_payment_above_cap() {
+    let env = TestEnv::initialized(250).await;
+    env.set_affiliate(751, AffiliateStatus::Active).await;
+
+    env.set_max_total_fee(1000).await;
+
+    let result = env.pay_through_affiliate(order_id(1), 100_000).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+    assert!(env
+        .account_data(&TestEnv::order_address(&order_id(1)))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn set_max_total_fee_applies_token_fee_override() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_token_config_ix(&env.authority.pubkey(), true, 0, 0, Some(1000));
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.set_max_total_fee(1000).await;
+
+    env.set_affiliate(1, AffiliateStatus::Active).await;
+
+    let result = env.pay_through_affiliate(order_id(1), 100_000).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_sol_payment_above_cap() {
+    let env = TestEnv::initialized(250).await;
+    env.set_merchant(&env.merchant, MerchantStatus::Active, Some(600), Vec::new())
+        .await;
+    env.set_max_total_fee(500).await;
+    let ix = env.sol_payment_ix(env.sol_payment_accounts(&order_id(1)), order_id(1), SOL_PAYMENT);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_batch_payment_above_cap() {
+    let env = TestEnv::initialized(100).await;
+    env.set_max_total_fee(500).await;
+    let ix = env.batch_payment_ix(order_id(1), 10_000, vec![line_item(1, 10_000, 1)]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_accepts_100_percent_and_rejects_more() {
+    let env = TestEnv::initialized(0).await;
+    env.set_max_total_fee(0).await;
+    env.set_max_total_fee(10_000).await;
+
+    let ix = env.set_max_total_fee_ix(&env.authority.pubkey(), 10_001);
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMaxTotalFee);
+    assert_eq!(env.config().await.max_total_fee_bps, 10_000);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_cap_below_platform_fee() {
+    let env = TestEnv::initialized(250).await;
+    env.set_max_total_fee(250).await;
+
+    let ix = env.set_max_total_fee_ix(&env.authority.pubkey(), 249);
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMaxTotalFee);
+    assert_eq!(env.config().await.max_total_fee_bps, 250);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.set_max_total_fee_ix(&env.buyer.pubkey(), 1000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn platform_fee_change_rejects_fee_above_max_total_fee() {
+    let env = TestEnv::initialized(0).await;
+    env.set_max_total_fee(500).await;
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 501 },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn platform_fee_change_rejects_execution_after_cap_lowered() {
+    let mut env = TestEnv::initialized(0).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 500 };
+    let authority = env.authority.pubkey();
+    let ix = env.queue_config_change_ix(&authority, change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.set_max_total_fee(400).await;
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+
+    let ix = env.execute_config_change_ix(&change, &authority);
+    let result = env.process(&[ix], &[]).await;
+
+    assert_program_error(result, ErrorCode::TotalFeeTooHigh);
+    assert_eq!(env.config().await.platform_fee_bps, 0);
+}
+
+// queue_config_change / execute_config_change / cancel_config_change
+
+#[tokio::test]
+async fn payout_wallet_change_applies_after_delay_and_emits_events() {
+    let mut env = TestEnv::initialized(0).await;
+    let new_payout_wallet = Pubkey::new_unique();
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant,
+        new_payout_wallet,
+    };
+    let authority = env.authority.pubkey();
+    let ix = env.queue_config_change_ix(&authority, change);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let pending_change = env.pending_change(&change).await.unwrap();
+    assert_eq!(pending_change.change, change);
+    assert_eq!(pending_change.proposer, authority);
+    assert_eq!(
+        pending_change.execute_after - pending_change.queued_at,
+        i64::from(Config::DEFAULT_CHANGE_DELAY_SECS)
+    );
+    let event: ConfigChangeQueued = decode_event(&events);
+    assert_eq!(event.change, change);
+    assert_eq!(event.proposer, authority);
+    assert_eq!(event.execute_after, pending_change.execute_after);
+    assert_eq!(
+        env.merchant(&env.merchant).await.payout_wallet,
+        env.merchant_wallet
+    );
+
+    let ix = env.execute_config_change_ix(&change, &authority);
+    let result = env.process(&[ix], &[]).await;
+    assert_program_error(result, ErrorCode::TimelockNotExpired);
+
+    // Anyone can execute once the delay has passed
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let ix = env.execute_config_change_ix(&change, &authority);
+    let events = env.process_with_events(&[ix], &[]).await;
+
+    assert_eq!(
+        env.merchant(&env.merchant).await.payout_wallet,
+        new_payout_wallet
+    );
+    assert!(env.pending_change(&change).await.is_none());
+    let event: ConfigChangeExecuted = decode_event(&events);
+    assert_eq!(event.change, change);
+    let event: MerchantUpdated = decode_event(&events);
+    assert_eq!(event.merchant_id, MERCHANT_ID);
+    assert_eq!(event.payout_wallet, new_payout_wallet);
+    assert_eq!(event.authority, authority);
+}
+
+#[tokio::test]
+async fn payout_wallet_change_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant,
+        new_payout_wallet: env.buyer.pubkey(),
+    };
+    let ix = env.queue_config_change_ix(&env.buyer.pubkey(), change);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert!(env.pending_change(&change).await.is_none());
+}
+
+#[tokio::test]
+async fn payout_wallet_change_queues_one_change_per_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let (other_merchant, _) = env.add_merchant(1).await;
+    let changes = [env.merchant, other_merchant].map(|merchant| ConfigChange::PayoutWallet {
+        merchant,
+        new_payout_wallet: Pubkey::new_unique(),
+    });
+    let ixs = changes.map(|change| env.queue_config_change_ix(&env.authority.pubkey(), change));
+
+    env.process(&ixs, &[&env.authority]).await.unwrap();
+
+    for change in &changes {
+        assert_eq!(env.pending_change(change).await.unwrap().change, *change);
+    }
+}
+
+#[tokio::test]
+async fn execute_payout_wallet_change_rejects_other_merchant() {
+    let mut env = TestEnv::initialized(0).await;
+    let (other_merchant, _) = env.add_merchant(1).await;
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant,
+        new_payout_wallet: env.buyer.pubkey(),
+    };
+    let authority = env.authority.pubkey();
+    let ix = env.queue_config_change_ix(&authority, change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let mut ix = env.execute_config_change_ix(&change, &authority);
+    ix.accounts[3].pubkey = other_merchant;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_program_error(result, ErrorCode::InvalidMerchant);
+    assert_eq!(
+        env.merchant(&env.merchant).await.payout_wallet,
+        env.merchant_wallet
+    );
+}
+
+#[tokio::test]
+async fn platform_fee_change_sets_fee() {
+    let mut env = TestEnv::initialized(0).await;
+
+    env.apply_config_change(ConfigChange::PlatformFee { new_fee_bps: 1000 })
+        .await;
+
+    assert_eq!(env.config().await.platform_fee_bps, 1000);
+}
+
+#[tokio::test]
+async fn platform_fee_change_rejects_fee_above_max() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 1001 },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::FeeTooHigh);
+}
+
+#[tokio::test]
+async fn platform_fee_change_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 100 },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn queue_config_change_rejects_second_change_of_same_kind() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 100 },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 200 },
+    );
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    // The system program refuses to create the pending change account again
+    assert_instruction_error(result, InstructionError::Custom(0));
+}
+
+#[tokio::test]
+async fn execute_config_change_rejects_other_proposer() {
+    let mut env = TestEnv::initialized(0).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    let result = env.process(&[ix], &[]).await;
+
+    assert_program_error(result, ErrorCode::InvalidProposer);
+}
+
+#[tokio::test]
+async fn cancel_config_change_refunds_proposer_and_emits_event() {
+    let mut env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let buyer_lamports = env.lamports(&env.buyer.pubkey()).await;
+    let ix = TestEnv::with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    assert!(env.lamports(&env.buyer.pubkey()).await < buyer_lamports);
+
+    let ix = env.cancel_config_change_ix(&env.authority.pubkey(), &change, &env.buyer.pubkey());
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert!(env.pending_change(&change).await.is_none());
+    assert_eq!(env.lamports(&env.buyer.pubkey()).await, buyer_lamports);
+    let event: ConfigChangeCancelled = decode_event(&events);
+    assert_eq!(event.change, change);
+    assert_eq!(event.proposer, env.buyer.pubkey());
+    assert_eq!(event.authority, env.authority.pubkey());
+
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    let result = env.process(&[ix], &[]).await;
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(anchor_lang::error::ErrorCode::AccountNotInitialized as u32),
+    );
+    assert_eq!(env.config().await.platform_fee_bps, 0);
+}
+
+#[tokio::test]
+async fn cancel_config_change_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.cancel_config_change_ix(&env.buyer.pubkey(), &change, &env.authority.pubkey());
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert!(env.pending_change(&change).await.is_some());
+}
+
+#[tokio::test]
+async fn timelock_delays_change_waits_longest_delay() {
+    let mut env = TestEnv::initialized(0).await;
+    env.apply_config_change(ConfigChange::TimelockDelays {
+        payout_wallet_delay_secs: 3 * 24 * 60 * 60,
+        platform_fee_delay_secs: 60 * 60,
+    })
+    .await;
+    let config = env.config().await;
+    assert_eq!(config.payout_wallet_delay_secs, 3 * 24 * 60 * 60);
+    assert_eq!(config.platform_fee_delay_secs, 60 * 60);
+
+    // Platform fee changes now wait an hour
+    let fee_change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), fee_change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let pending_change = env.pending_change(&fee_change).await.unwrap();
+    assert_eq!(pending_change.execute_after - pending_change.queued_at, 60 * 60);
+
+    // Shortening the delays waits out the longest one
+    let delays_change = ConfigChange::TimelockDelays {
+        payout_wallet_delay_secs: 0,
+        platform_fee_delay_secs: 0,
+    };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), delays_change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(24 * 60 * 60).await;
+    let ix = env.execute_config_change_ix(&delays_change, &env.authority.pubkey());
+    let result = env.process(&[ix], &[]).await;
+    assert_program_error(result, ErrorCode::TimelockNotExpired);
+}
+
+#[tokio::test]
+async fn timelock_delays_change_rejects_role_holder() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(
+        &env.buyer.pubkey(),
+        &[Role::FeeManager, Role::TreasuryManager],
+    )
+    .await;
+    let ix = TestEnv::with_roles(env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::TimelockDelays {
+            payout_wallet_delay_secs: 0,
+            platform_fee_delay_secs: 0,
+        },
+    ));
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+// propose_authority / accept_authority / cancel_authority_transfer
+
+#[tokio::test]
+async fn authority_transfer_hands_over_control_and_emits_events() {
+    let env = TestEnv::initialized(0).await;
+    let new_authority = Keypair::new();
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: new_authority.pubkey(),
+        },
+    );
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let config = env.config().await;
+    assert_eq!(config.authority, env.authority.pubkey());
+    assert_eq!(config.pending_authority, new_authority.pubkey());
+    let event: AuthorityProposed = decode_event(&events);
+    assert_eq!(event.authority, env.authority.pubkey());
+    assert_eq!(event.pending_authority, new_authority.pubkey());
+
+    let ix = env.accept_authority_ix(&new_authority.pubkey());
+    let events = env.process_with_events(&[ix], &[&new_authority]).await;
+
+    let config = env.config().await;
+    assert_eq!(config.authority, new_authority.pubkey());
+    assert_eq!(config.pending_authority, Pubkey::default());
+    let event: AuthorityTransferred = decode_event(&events);
+    assert_eq!(event.old_authority, env.authority.pubkey());
+    assert_eq!(event.new_authority, new_authority.pubkey());
+
+    let pause = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+    let result = env.process(&[pause], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::Unauthorized);
+
+    let pause = env.update_config_by_role_ix(
+        &new_authority.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+    env.process(&[pause], &[&new_authority]).await.unwrap();
+    assert!(env.config().await.paused);
+}
+
+#[tokio::test]
+async fn propose_authority_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn propose_authority_rejects_default_address() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: Pubkey::default(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidPendingAuthority);
+}
+
+#[tokio::test]
+async fn accept_authority_rejects_other_signer() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: Pubkey::new_unique(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.accept_authority_ix(&env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::NotPendingAuthority);
+    assert_eq!(env.config().await.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn accept_authority_rejects_without_proposal() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.accept_authority_ix(&env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::NoPendingAuthority);
+}
+
+#[tokio::test]
+async fn cancel_authority_transfer_clears_pending_authority() {
+    let env = TestEnv::initialized(0).await;
+    let new_authority = Keypair::new();
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: new_authority.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::CancelAuthorityTransfer {},
+    );
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert_eq!(env.config().await.pending_authority, Pubkey::default());
+    let event: AuthorityTransferCancelled = decode_event(&events);
+    assert_eq!(event.pending_authority, new_authority.pubkey());
+
+    let ix = env.accept_authority_ix(&new_authority.pubkey());
+    let result = env.process(&[ix], &[&new_authority]).await;
+    assert_program_error(result, ErrorCode::NoPendingAuthority);
+}
+
+#[tokio::test]
+async fn cancel_authority_transfer_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.update_config_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::CancelAuthorityTransfer {},
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert_eq!(env.config().await.pending_authority, env.buyer.pubkey());
+}
+
+// migrate_config
+
+fn legacy_config(env: &TestEnv) -> LegacyConfig {
+    let (_, bump) = Pubkey::find_program_address(&[b"config"], &oxmart_payment::ID);
+    LegacyConfig {
+        authority: env.authority.pubkey(),
+        hot_wallet: env.other_wallet,
+        platform_fee_bps: 250,
+        paused: false,
+        bump,
+    }
+}
+
+#[tokio::test]
+async fn migrate_config_upgrades_legacy_layout() {
+    let mut env = TestEnv::new().await;
+    let legacy = legacy_config(&env);
+    env.set_legacy_config(&legacy);
+
+    let ix = env.migrate_config_ix(&env.authority.pubkey());
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let data = env.account_data(&TestEnv::config_address()).await.unwrap();
+    assert_eq!(data.len(), 8 + Config::INIT_SPACE);
+    let config = env.config().await;
+    assert_eq!(config.authority, env.authority.pubkey());
+    assert_eq!(config.pending_authority, Pubkey::default());
+    assert_eq!(config.fee_treasury, env.other_wallet);
+    assert_eq!(config.refund_operator, Pubkey::default());
+    assert_eq!(config.quote_signer, Pubkey::default());
+    assert_eq!(config.payout_wallet_delay_secs, Config::DEFAULT_CHANGE_DELAY_SECS);
+    assert_eq!(config.platform_fee_delay_secs, Config::DEFAULT_CHANGE_DELAY_SECS);
+    assert_eq!(
+        config.max_quote_lifetime_secs,
+        Config::DEFAULT_MAX_QUOTE_LIFETIME_SECS
+    );
+    assert_eq!(config.order_retention_secs, Config::DEFAULT_ORDER_RETENTION_SECS);
+    assert_eq!(config.max_total_fee_bps, Config::DEFAULT_MAX_TOTAL_FEE_BPS);
+    assert_eq!(config.platform_fee_bps, 250);
+    assert!(config.multisig_signers.is_empty());
+    assert_eq!(config.multisig_threshold, 0);
+    assert!(!config.paused);
+    assert_eq!(config.bump, legacy.bump);
+    assert!(
+        env.lamports(&TestEnv::config_address()).await
+            >= Rent::default().minimum_balance(8 + Config::INIT_SPACE)
+    );
+
+    // The migrated config works with the authority transfer
+    let ix = env.update_config_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.accept_authority_ix(&env.buyer.pubkey());
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    assert_eq!(env.config().await.authority, env.buyer.pubkey());
+}
+
+#[tokio::test]
+async fn migrate_config_rejects_current_layout() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.migrate_config_ix(&env.authority.pubkey());
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::ConfigAlreadyMigrated);
+}
+
+#[tokio::test]
+async fn migrate_config_rejects_unauthorized_caller() {
+    let mut env = TestEnv::new().await;
+    let legacy = legacy_config(&env);
+    env.set_legacy_config(&legacy);
+    let ix = env.migrate_config_ix(&env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    let data = env.account_data(&TestEnv::config_address()).await.unwrap();
+    assert_eq!(data.len(), 8 + LegacyConfig::LEN);
+}
+
+// initialize_roles / grant_role / revoke_role
+
+#[tokio::test]
+async fn grant_role_records_member_roles_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let operator = Pubkey::new_unique();
+    env.process(&[env.initialize_roles_ix()], &[&env.authority])
+        .await
+        .unwrap();
+    let ix = env.update_roles_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::GrantRole {
+            member: operator,
+            role: Role::Pauser,
+        },
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let roles = env.roles().await;
+    assert_eq!(roles.members.len(), 1);
+    assert!(roles.has_role(&operator, Role::Pauser));
+    assert!(!roles.has_role(&operator, Role::TreasuryManager));
+    let event: RoleGranted = decode_event(&events);
+    assert_eq!(event.member, operator);
+    assert_eq!(event.role, Role::Pauser);
+    assert_eq!(event.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn initialize_roles_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let mut ix = env.initialize_roles_ix();
+    ix.accounts[2].pubkey = env.buyer.pubkey();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn grant_role_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.update_roles_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::GrantRole {
+            member: env.buyer.pubkey(),
+            role: Role::TreasuryManager,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn grant_role_rejects_more_than_max_members() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&Pubkey::new_unique(), &[Role::Pauser]).await;
+    let instructions: Vec<_> = (1..Roles::MAX_MEMBERS)
+        .map(|_| {
+            env.update_roles_ix(
+                &env.authority.pubkey(),
+                oxmart_payment::instruction::GrantRole {
+                    member: Pubkey::new_unique(),
+                    role: Role::Pauser,
+                },
+            )
+        })
+        .collect();
+    env.process(&instructions, &[&env.authority]).await.unwrap();
+    assert_eq!(env.roles().await.members.len(), Roles::MAX_MEMBERS);
+
+    let ix = env.update_roles_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::GrantRole {
+            member: Pubkey::new_unique(),
+            role: Role::Pauser,
+        },
+    );
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::RolesFull);
+}
+
+#[tokio::test]
+async fn revoke_role_removes_access_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.update_roles_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::RevokeRole {
+            member: env.buyer.pubkey(),
+            role: Role::Pauser,
+        },
+    );
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    assert!(env.roles().await.members.is_empty());
+    let event: RoleRevoked = decode_event(&events);
+    assert_eq!(event.member, env.buyer.pubkey());
+    assert_eq!(event.role, Role::Pauser);
+
+    let pause = TestEnv::with_roles(env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    ));
+    let result = env.process(&[pause], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn revoke_role_rejects_role_not_granted() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.update_roles_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::RevokeRole {
+            member: env.buyer.pubkey(),
+            role: Role::FeeManager,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::RoleNotGranted);
+}
+
+#[tokio::test]
+async fn pauser_can_pause_and_unpause_but_not_move_funds() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+
+    let pause = TestEnv::with_roles(env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    ));
+    env.process(&[pause], &[&env.buyer]).await.unwrap();
+    assert!(env.config().await.paused);
+
+    let unpause = TestEnv::with_roles(env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Unpause {},
+    ));
+    env.process(&[unpause], &[&env.buyer]).await.unwrap();
+    assert!(!env.config().await.paused);
+
+    let ix = TestEnv::with_roles(env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::PayoutWallet {
+            merchant: env.merchant,
+            new_payout_wallet: env.buyer.pubkey(),
+        },
+    ));
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn role_holder_must_pass_roles_account() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let pause = env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+
+    let result = env.process(&[pause], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn fee_manager_updates_platform_fee() {
+    let mut env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = TestEnv::with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    env.process(&[ix], &[]).await.unwrap();
+
+    assert_eq!(env.config().await.platform_fee_bps, 100);
+}
+
+#[tokio::test]
+async fn treasury_manager_updates_payout_wallet_and_fee_treasury() {
+    let mut env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TreasuryManager])
+        .await;
+    let (new_payout_wallet, new_fee_treasury) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant,
+        new_payout_wallet,
+    };
+    let payout_wallet_ix =
+        TestEnv::with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    let fee_treasury_ix = TestEnv::with_roles(env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::UpdateFeeTreasury { new_fee_treasury },
+    ));
+    env.process(&[payout_wallet_ix, fee_treasury_ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    let events = env.process_with_events(&[ix], &[]).await;
+
+    assert_eq!(
+        env.merchant(&env.merchant).await.payout_wallet,
+        new_payout_wallet
+    );
+    assert_eq!(env.config().await.fee_treasury, new_fee_treasury);
+    let event: MerchantUpdated = decode_event(&events);
+    assert_eq!(event.authority, env.buyer.pubkey());
+}
+
+#[tokio::test]
+async fn token_manager_adds_updates_and_removes_supported_token() {
+    let mut env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TokenManager]).await;
+    let mint = env.add_mint();
+
+    let add = env.add_supported_token_ix(&env.buyer.pubkey(), &mint, 0, 0, None);
+    let add = TestEnv::with_roles(add);
+    env.process(&[add], &[&env.buyer]).await.unwrap();
+    assert!(env.token_config(&mint).await.enabled);
+
+    let mut update = TestEnv::with_roles(env.update_token_config_ix(
+        &env.buyer.pubkey(),
+        false,
+        0,
+        0,
+        None,
+    ));
+    update.accounts[1].pubkey = TestEnv::token_config_address(&mint);
+    env.process(&[update], &[&env.buyer]).await.unwrap();
+    assert!(!env.token_config(&mint).await.enabled);
+
+    let mut remove = TestEnv::with_roles(env.remove_supported_token_ix(&env.buyer.pubkey()));
+    remove.accounts[1].pubkey = TestEnv::token_config_address(&mint);
+    env.process(&[remove], &[&env.buyer]).await.unwrap();
+    assert!(env
+        .account_data(&TestEnv::token_config_address(&mint))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn refund_role_holder_refunds_order() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let refund_operator = Keypair::new();
+    env.grant_roles(&refund_operator.pubkey(), &[Role::RefundOperator])
+        .await;
+    let ix = env.funded_refund_ix(&refund_operator.pubkey(), &order_id(1), 40_000);
+    let ix = TestEnv::with_roles(ix);
+
+    env.process(&[ix], &[&refund_operator]).await.unwrap();
+
+    assert_eq!(env.order_record(&order_id(1)).await.refunded_amount, 40_000);
+}
+
+// set_multisig
+
+#[tokio::test]
+async fn multisig_requires_threshold_of_signers() {
+    let env = TestEnv::initialized(0).await;
+    let members = [Keypair::new(), Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1], &members[2]], 2)
+        .await;
+    let update = |signer: &Keypair| {
+        env.update_config_ix(
+            &signer.pubkey(),
+            oxmart_payment::instruction::UpdateRefundOperator {
+                new_refund_operator: env.buyer.pubkey(),
+            },
+        )
+    };
+
+    // The authority alone no longer suffices
+    let result = env.process(&[update(&env.authority)], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    // Neither does a single member, even listed twice
+    let ix = TestEnv::with_signers(update(&members[0]), &[&members[0]]);
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    // A member listed without signing does not count
+    let mut ix = TestEnv::with_signers(update(&members[0]), &[&members[1]]);
+    ix.accounts[2].is_signer = false;
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    let ix = TestEnv::with_signers(update(&members[0]), &[&members[2]]);
+    env.process(&[ix], &[&members[0], &members[2]])
+        .await
+        .unwrap();
+
+    assert_eq!(env.config().await.refund_operator, env.buyer.pubkey());
+}
+
+#[tokio::test]
+async fn set_multisig_records_signers_and_emits_event() {
+    let env = TestEnv::initialized(0).await;
+    let signers = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+    let ix = env.set_multisig_ix(&env.authority.pubkey(), &signers, 2);
+
+    let events = env.process_with_events(&[ix], &[&env.authority]).await;
+
+    let config = env.config().await;
+    assert_eq!(config.multisig_signers, signers);
+    assert_eq!(config.multisig_threshold, 2);
+    assert!(config.is_admin(&signers[0]));
+    assert!(!config.is_admin(&env.authority.pubkey()));
+    let event: MultisigUpdated = decode_event(&events);
+    assert_eq!(event.signers, signers);
+    assert_eq!(event.threshold, 2);
+    assert_eq!(event.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn set_multisig_rejects_invalid_multisig() {
+    let env = TestEnv::initialized(0).await;
+    let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let too_many: Vec<Pubkey> = (0..=Config::MAX_MULTISIG_SIGNERS)
+        .map(|_| Pubkey::new_unique())
+        .collect();
+    let invalid: [(&[Pubkey], u8); 6] = [
+        (&[first, second], 3),
+        (&[], 1),
+        (&[first], 0),
+        (&[first, first], 2),
+        (&[first, Pubkey::default()], 1),
+        (&too_many, 2),
+    ];
+
+    for (signers, threshold) in invalid {
+        let ix = env.set_multisig_ix(&env.authority.pubkey(), signers, threshold);
+        let result = env.process(&[ix], &[&env.authority]).await;
+        assert_program_error(result, ErrorCode::InvalidMultisig);
+    }
+
+    assert_eq!(env.config().await.multisig_threshold, 0);
+}
+
+#[tokio::test]
+async fn set_multisig_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.set_multisig_ix(&env.buyer.pubkey(), &[env.buyer.pubkey()], 1);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn set_multisig_disabled_restores_authority() {
+    let env = TestEnv::initialized(0).await;
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let ix = TestEnv::with_signers(
+        env.set_multisig_ix(&members[0].pubkey(), &[], 0),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+
+    let pause =
+        env.update_config_by_role_ix(&env.authority.pubkey(), oxmart_payment::instruction::Pause {});
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    assert!(env.config().await.paused);
+}
+
+#[tokio::test]
+async fn multisig_gates_payout_wallet_changes() {
+    let mut env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TreasuryManager])
+        .await;
+    // The authority pays for the pending change as one of the members
+    let member = Keypair::new();
+    env.set_multisig(&[&env.authority, &member], 2).await;
+    let new_payout_wallet = Pubkey::new_unique();
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant,
+        new_payout_wallet,
+    };
+
+    // The treasury manager can no longer queue payout wallet changes
+    let ix = TestEnv::with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    let ix = TestEnv::with_signers(
+        env.queue_config_change_ix(&env.authority.pubkey(), change),
+        &[&member],
+    );
+    env.process(&[ix], &[&env.authority, &member])
+        .await
+        .unwrap();
+    env.advance_clock(i64::from(Config::DEFAULT_CHANGE_DELAY_SECS))
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.authority.pubkey());
+    env.process(&[ix], &[]).await.unwrap();
+
+    assert_eq!(
+        env.merchant(&env.merchant).await.payout_wallet,
+        new_payout_wallet
+    );
+}
+
+#[tokio::test]
+async fn multisig_keeps_role_holders() {
+    let env = TestEnv::initialized(0).await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let pause = TestEnv::with_roles(env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    ));
+    env.process(&[pause], &[&env.buyer]).await.unwrap();
+
+    assert!(env.config().await.paused);
+}
+
+#[tokio::test]
+async fn multisig_gates_admin_refunds() {
+    let mut env = TestEnv::initialized(0).await;
+    env.pay(order_id(1), 100_000).await.unwrap();
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let authority = env.authority.pubkey();
+    let ix = env.funded_refund_ix(&authority, &order_id(1), 40_000);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::Unauthorized);
+
+    let member = members[0].pubkey();
+    let ix = env.funded_refund_ix(&member, &order_id(1), 40_000);
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    let ix = TestEnv::with_signers(
+        env.funded_refund_ix(&member, &order_id(1), 40_000),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+
+    assert_eq!(env.order_record(&order_id(1)).await.refunded_amount, 40_000);
+}
+
+// pause / unpause
+
+#[tokio::test]
+async fn pause_and_unpause() {
+    let env = TestEnv::initialized(0).await;
+
+    let pause = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    assert!(env.config().await.paused);
+
+    let unpause = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::Unpause {},
+    );
+    env.process(&[unpause], &[&env.authority]).await.unwrap();
+    assert!(!env.config().await.paused);
+
+    env.pay(order_id(1), 1_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn pause_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let ix = env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+}
+
+#[tokio::test]
+async fn unpause_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized(0).await;
+    let pause = env.update_config_by_role_ix(
+        &env.authority.pubkey(),
+        oxmart_payment::instruction::Pause {},
+    );
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    let ix = env.update_config_by_role_ix(
+        &env.buyer.pubkey(),
+        oxmart_payment::instruction::Unpause {},
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert!(env.config().await.paused);
+}
+
+// emergency_withdraw
+
+#[tokio::test]
+async fn emergency_withdraw_moves_program_tokens() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, authority) = (env.mint, env.authority.pubkey());
+    let program_token = env.add_token_account(&mint, &TestEnv::config_address(), 5_000);
+    let authority_token = env.add_token_account(&mint, &authority, 0);
+    let ix = env.emergency_withdraw_ix(&authority, program_token, authority_token, 3_000);
+
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.token_balance(&program_token).await, 2_000);
+    assert_eq!(env.token_balance(&authority_token).await, 3_000);
+}
+
+#[tokio::test]
+async fn emergency_withdraw_rejects_zero_amount() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, authority) = (env.mint, env.authority.pubkey());
+    let program_token = env.add_token_account(&mint, &TestEnv::config_address(), 5_000);
+    let authority_token = env.add_token_account(&mint, &authority, 0);
+    let ix = env.emergency_withdraw_ix(&authority, program_token, authority_token, 0);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_program_error(result, ErrorCode::InvalidAmount);
+}
+
+#[tokio::test]
+async fn emergency_withdraw_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized(0).await;
+    let (mint, buyer) = (env.mint, env.buyer.pubkey());
+    let program_token = env.add_token_account(&mint, &TestEnv::config_address(), 5_000);
+    let buyer_token = env.buyer_token;
+    let ix = env.emergency_withdraw_ix(&buyer, program_token, buyer_token, 5_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_program_error(result, ErrorCode::Unauthorized);
+    assert_eq!(env.token_balance(&program_token).await, 5_000);
+}
+
+#[tokio::test]
+async fn emergency_withdraw_requires_multisig_threshold() {
+    let mut env = TestEnv::initialized(0).await;
+    let member = Keypair::new();
+    env.set_multisig(&[&env.authority, &member], 2).await;
+    let (mint, authority) = (env.mint, env.authority.pubkey());
+    let program_token = env.add_token_account(&mint, &TestEnv::config_address(), 5_000);
+    let authority_token = env.add_token_account(&mint, &authority, 0);
+    let ix = env.emergency_withdraw_ix(&authority, program_token, authority_token, 3_000);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_program_error(result, ErrorCode::MultisigThresholdNotMet);
+
+    let ix = env.emergency_withdraw_ix(&authority, program_token, authority_token, 3_000);
+    let ix = TestEnv::with_signers(ix, &[&member]);
+    env.process(&[ix], &[&env.authority, &member])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&program_token).await, 2_000);
+    assert_eq!(env.token_balance(&authority_token).await, 3_000);
+}
@@ -20,6 +20,7 @@ describe("oxmart-payment", () => {
   // Test accounts
   let authority: Keypair;
   let hotWallet: Keypair;
+  let feeTreasury: Keypair;
   let buyer: Keypair;
   let apiKeyOwner: Keypair;
 
@@ -27,6 +28,7 @@ describe("oxmart-payment", () => {
   let tokenMint: PublicKey;
   let buyerTokenAccount: PublicKey;
   let hotWalletTokenAccount: PublicKey;
+  let feeTreasuryTokenAccount: PublicKey;
 
   // PDAs
   let configPDA: PublicKey;
@@ -36,6 +38,7 @@ describe("oxmart-payment", () => {
     // Initialize test accounts
     authority = Keypair.generate();
     hotWallet = Keypair.generate();
+    feeTreasury = Keypair.generate();
     buyer = Keypair.generate();
     apiKeyOwner = Keypair.generate();
 
@@ -88,6 +91,14 @@ describe("oxmart-payment", () => {
     );
     hotWalletTokenAccount = hotWalletAccount.address;
 
+    const feeTreasuryAccount = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      authority,
+      tokenMint,
+      feeTreasury.publicKey
+    );
+    feeTreasuryTokenAccount = feeTreasuryAccount.address;
+
     // Mint tokens to buyer
     await mintTo(
       provider.connection,
@@ -104,7 +115,7 @@ describe("oxmart-payment", () => {
       const platformFeeBps = 0; // 0% platform fee
 
       const tx = await program.methods
-        .initialize(hotWallet.publicKey, platformFeeBps)
+        .initialize(hotWallet.publicKey, feeTreasury.publicKey, platformFeeBps)
         .accounts({
           config: configPDA,
           authority: authority.publicKey,
@@ -123,6 +134,9 @@ describe("oxmart-payment", () => {
       expect(config.hotWallet.toString()).to.equal(
         hotWallet.publicKey.toString()
       );
+      expect(config.feeTreasury.toString()).to.equal(
+        feeTreasury.publicKey.toString()
+      );
       expect(config.platformFeeBps).to.equal(platformFeeBps);
       expect(config.paused).to.be.false;
     });
@@ -142,7 +156,7 @@ describe("oxmart-payment", () => {
 
       try {
         await program.methods
-          .initialize(hotWallet.publicKey, 1001) // 10.01%
+          .initialize(hotWallet.publicKey, feeTreasury.publicKey, 1001) // 10.01%
           .accounts({
             config: newConfigPDA,
             authority: newAuthority.publicKey,
@@ -188,6 +202,7 @@ describe("oxmart-payment", () => {
           buyer: buyer.publicKey,
           buyerTokenAccount: buyerTokenAccount,
           hotWalletTokenAccount: hotWalletTokenAccount,
+          feeTreasuryTokenAccount: feeTreasuryTokenAccount,
           tokenProgram: TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,
         })
@@ -239,6 +254,7 @@ describe("oxmart-payment", () => {
           buyer: buyer.publicKey,
           buyerTokenAccount: buyerTokenAccount,
           hotWalletTokenAccount: hotWalletTokenAccount,
+          feeTreasuryTokenAccount: feeTreasuryTokenAccount,
           tokenProgram: TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,
         })
@@ -261,6 +277,7 @@ describe("oxmart-payment", () => {
             buyer: buyer.publicKey,
             buyerTokenAccount: buyerTokenAccount,
             hotWalletTokenAccount: hotWalletTokenAccount,
+            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
           })
@@ -296,6 +313,7 @@ describe("oxmart-payment", () => {
             buyer: buyer.publicKey,
             buyerTokenAccount: buyerTokenAccount,
             hotWalletTokenAccount: hotWalletTokenAccount,
+            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
           })
@@ -330,6 +348,7 @@ describe("oxmart-payment", () => {
             buyer: buyer.publicKey,
             buyerTokenAccount: buyerTokenAccount,
             hotWalletTokenAccount: hotWalletTokenAccount,
+            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
           })
@@ -369,6 +388,7 @@ describe("oxmart-payment", () => {
           buyer: buyer.publicKey,
           buyerTokenAccount: buyerTokenAccount,
           hotWalletTokenAccount: hotWalletTokenAccount,
+          feeTreasuryTokenAccount: feeTreasuryTokenAccount,
           tokenProgram: TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,
         })
@@ -407,6 +427,7 @@ describe("oxmart-payment", () => {
             buyer: buyer.publicKey,
             buyerTokenAccount: buyerTokenAccount,
             hotWalletTokenAccount: hotWalletTokenAccount,
+            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
           })
@@ -509,6 +530,7 @@ describe("oxmart-payment", () => {
             buyer: buyer.publicKey,
             buyerTokenAccount: buyerTokenAccount,
             hotWalletTokenAccount: hotWalletTokenAccount,
+            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
           })
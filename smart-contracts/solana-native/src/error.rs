@@ -1,3 +1,4 @@
+use oxmart_payment_core::CoreError;
 use solana_program::program_error::ProgramError;
 use thiserror::Error;
 
@@ -35,6 +36,174 @@ pub enum PaymentError {
 
     #[error("Invalid Platform Fee")]
     InvalidPlatformFee,
+
+    #[error("Invalid Account Owner")]
+    InvalidAccountOwner,
+
+    #[error("Invalid Config Account")]
+    InvalidConfigAccount,
+
+    #[error("Invalid Supported Token Account")]
+    InvalidSupportedTokenAccount,
+
+    #[error("Invalid Order Account")]
+    InvalidOrderAccount,
+
+    #[error("Invalid Token Program")]
+    InvalidTokenProgram,
+
+    #[error("Invalid System Program")]
+    InvalidSystemProgram,
+
+    #[error("Invalid Mint")]
+    InvalidMint,
+
+    #[error("Invalid Token Account")]
+    InvalidTokenAccount,
+
+    #[error("Token Mint Mismatch")]
+    TokenMintMismatch,
+
+    #[error("Invalid Buyer Token Account")]
+    InvalidBuyerTokenAccount,
+
+    #[error("Invalid Hot Wallet Token Account")]
+    InvalidHotWalletTokenAccount,
+
+    #[error("Invalid Fee Treasury Token Account")]
+    InvalidFeeTreasuryTokenAccount,
+
+    #[error("Invalid Commission Token Account")]
+    InvalidCommissionTokenAccount,
+
+    #[error("Refund Exceeds Payment")]
+    RefundExceedsPayment,
+
+    #[error("Invalid Refund Token Account")]
+    InvalidRefundTokenAccount,
+
+    #[error("Invalid Order Status")]
+    InvalidOrderStatus,
+
+    #[error("Invalid Vault Account")]
+    InvalidVaultAccount,
+
+    #[error("Dispute Window Closed")]
+    DisputeWindowClosed,
+
+    #[error("Dispute Window Open")]
+    DisputeWindowOpen,
+
+    #[error("Invalid Hot Wallet")]
+    InvalidHotWallet,
+
+    #[error("Invalid Fee Treasury")]
+    InvalidFeeTreasury,
+
+    #[error("Invalid Pending Authority")]
+    InvalidPendingAuthority,
+
+    #[error("No Pending Authority")]
+    NoPendingAuthority,
+
+    #[error("Not Pending Authority")]
+    NotPendingAuthority,
+
+    #[error("Config Already Migrated")]
+    ConfigAlreadyMigrated,
+
+    #[error("Invalid Roles Account")]
+    InvalidRolesAccount,
+
+    #[error("Roles Full")]
+    RolesFull,
+
+    #[error("Role Not Granted")]
+    RoleNotGranted,
+
+    #[error("Invalid Pending Change Account")]
+    InvalidPendingChangeAccount,
+
+    #[error("Change Already Queued")]
+    ChangeAlreadyQueued,
+
+    #[error("Timelock Not Expired")]
+    TimelockNotExpired,
+
+    #[error("Invalid Proposer")]
+    InvalidProposer,
+
+    #[error("Invalid Multisig")]
+    InvalidMultisig,
+
+    #[error("Multisig Threshold Not Met")]
+    MultisigThresholdNotMet,
+
+    #[error("Invalid Instructions Sysvar")]
+    InvalidInstructionsSysvar,
+
+    #[error("Invalid Quote")]
+    InvalidQuote,
+
+    #[error("Quote Expired")]
+    QuoteExpired,
+
+    #[error("Order Expired")]
+    OrderExpired,
+
+    #[error("Expiry Too Far")]
+    ExpiryTooFar,
+
+    #[error("Invalid Closed Order Account")]
+    InvalidClosedOrderAccount,
+
+    #[error("Order Retention Period Not Over")]
+    RetentionPeriodActive,
+
+    #[error("Total Fee Too High")]
+    TotalFeeTooHigh,
+
+    #[error("Invalid Max Total Fee")]
+    InvalidMaxTotalFee,
+
+    #[error("Invalid Merchant Account")]
+    InvalidMerchantAccount,
+
+    #[error("Invalid Merchant Token Account")]
+    InvalidMerchantTokenAccount,
+
+    #[error("Merchant Suspended")]
+    MerchantSuspended,
+
+    #[error("Mint Not Allowed For Merchant")]
+    MintNotAllowedForMerchant,
+
+    #[error("Too Many Allowed Mints")]
+    TooManyAllowedMints,
+
+    #[error("Invalid Affiliate Account")]
+    InvalidAffiliateAccount,
+
+    #[error("Affiliate Suspended")]
+    AffiliateSuspended,
+
+    #[error("Invalid Commission Ledger Account")]
+    InvalidCommissionLedgerAccount,
+
+    #[error("Invalid Commission Vault Account")]
+    InvalidCommissionVaultAccount,
+
+    #[error("No Commission To Claim")]
+    NoCommissionToClaim,
+
+    #[error("Invalid Merchant Wallet")]
+    InvalidMerchantWallet,
+
+    #[error("Invalid API Key Owner")]
+    InvalidApiKeyOwner,
+
+    #[error("Deductions Exceed Amount")]
+    DeductionsExceedAmount,
 }
 
 impl From<PaymentError> for ProgramError {
@@ -42,3 +211,14 @@ impl From<PaymentError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+
+impl From<CoreError> for PaymentError {
+    fn from(e: CoreError) -> Self {
+        match e {
+            CoreError::InvalidBasisPoints => PaymentError::InvalidCommissionRate,
+            CoreError::DeductionsExceedAmount => PaymentError::DeductionsExceedAmount,
+            CoreError::Overflow => PaymentError::ArithmeticOverflow,
+            CoreError::TotalFeeTooHigh => PaymentError::TotalFeeTooHigh,
+        }
+    }
+}
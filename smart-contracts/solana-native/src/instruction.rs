@@ -1,6 +1,13 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::pubkey::Pubkey;
 
+use crate::state::{AffiliateStatus, ConfigChange, MerchantStatus, Role};
+
+/// Payment program instructions
+///
+/// Once a multisig is set with `SetMultisig`, instructions listed as taking the authority
+/// take a multisig signer in its place instead, followed by the other multisig signers as
+/// `[signer]` accounts after the listed ones until the threshold is met.
 #[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
 pub enum PaymentInstruction {
     /// Initialize the payment configuration
@@ -10,78 +17,548 @@ pub enum PaymentInstruction {
     /// 1. `[writable]` Payment config account (PDA)
     /// 2. `[]` System program
     Initialize {
-        hot_wallet: Pubkey,
+        fee_treasury: Pubkey,
     },
 
     /// Process a single payment
     ///
+    /// The net amount goes to the merchant, the platform fee to the fee
+    /// treasury and the commission, at the affiliate's registered rate, to the
+    /// affiliate's commission vault for the mint, to be withdrawn with
+    /// `ClaimCommission`. The buyer pays for the commission ledger and vault on the
+    /// affiliate's first payment in a mint. The merchant must be active and accept
+    /// the mint, and its fee override replaces the platform fee. Works with SPL Token
+    /// and Token-2022 mints; with a transfer fee the order records the amounts
+    /// actually received.
+    /// When the config has a quote signer, the instruction must directly follow an
+    /// Ed25519 program instruction verifying a `PaymentQuote` for this payment. Fails
+    /// once `expires_at` has passed, or when it lies further ahead than the config's
+    /// maximum quote lifetime. `expires_at` is the buyer's own deadline and not part of
+    /// the quote, whose `expires_at` is the deadline the backend enforces.
+    ///
     /// Accounts expected:
     /// 0. `[signer]` Buyer account
     /// 1. `[writable]` Buyer's token account
-    /// 2. `[writable]` Hot wallet's token account
-    /// 3. `[]` Token mint
-    /// 4. `[]` Payment config account (PDA)
-    /// 5. `[writable]` Supported token account (PDA)
-    /// 6. `[writable]` Processed order account (PDA)
-    /// 7. `[]` API key owner account
-    /// 8. `[]` Token program
-    /// 9. `[]` System program
+    /// 2. `[writable]` Merchant's payout token account
+    /// 3. `[writable]` Fee treasury's token account
+    /// 4. `[writable]` Commission vault token account (PDA: ["commission_vault", affiliate,
+    ///    mint]), or this program's id for a payment without commission
+    /// 5. `[]` Token mint
+    /// 6. `[]` Payment config account (PDA)
+    /// 7. `[writable]` Supported token account (PDA)
+    /// 8. `[writable]` Processed order account (PDA)
+    /// 9. `[writable]` Affiliate account (PDA), or this program's id for a payment
+    ///    without commission
+    /// 10. `[writable]` Commission ledger account (PDA: ["commission", affiliate, mint]),
+    ///     or this program's id for a payment without commission
+    /// 11. `[]` Token program (SPL Token or Token-2022, owner of the mint)
+    /// 12. `[]` System program
+    /// 13. `[]` Instructions sysvar
+    /// 14. `[]` Closed order account for the order ID hash (PDA), need not exist
+    /// 15. `[]` Merchant account (PDA)
+    /// 16. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
     ProcessPayment {
         order_id: String,
         amount: u64,
         product_id: String,
-        commission_bps: u16,
+        expires_at: i64,
     },
 
-    /// Add a supported token
+    /// Add a supported token (authority or token manager)
     ///
     /// Accounts expected:
-    /// 0. `[signer]` Authority account
+    /// 0. `[signer]` Authority or token manager account
     /// 1. `[]` Payment config account (PDA)
     /// 2. `[writable]` Supported token account (PDA)
     /// 3. `[]` Token mint
     /// 4. `[]` System program
+    /// 5. `[]` Roles account (PDA), only when the signer is not the authority
     AddSupportedToken {
         token_mint: Pubkey,
     },
 
-    /// Remove a supported token
+    /// Remove a supported token (authority or token manager)
     ///
     /// Accounts expected:
-    /// 0. `[signer]` Authority account
+    /// 0. `[signer]` Authority or token manager account
     /// 1. `[]` Payment config account (PDA)
     /// 2. `[writable]` Supported token account (PDA)
+    /// 3. `[]` Roles account (PDA), only when the signer is not the authority
     RemoveSupportedToken,
 
-    /// Update hot wallet
+    /// Queue a merchant payout wallet change (authority or treasury manager)
+    ///
+    /// Shorthand for `QueueConfigChange` with `ConfigChange::PayoutWallet`, taking the same
+    /// accounts.
+    UpdatePayoutWallet {
+        merchant: Pubkey,
+        new_payout_wallet: Pubkey,
+    },
+
+    /// Queue a platform fee change (authority or fee manager)
+    ///
+    /// Shorthand for `QueueConfigChange` with `ConfigChange::PlatformFee`, taking the same accounts.
+    UpdatePlatformFee {
+        new_fee_bps: u16,
+    },
+
+    /// Pause the contract (authority or pauser)
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority or pauser account
+    /// 1. `[writable]` Payment config account (PDA)
+    /// 2. `[]` Roles account (PDA), only when the signer is not the authority
+    Pause,
+
+    /// Unpause the contract (authority or pauser)
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority or pauser account
+    /// 1. `[writable]` Payment config account (PDA)
+    /// 2. `[]` Roles account (PDA), only when the signer is not the authority
+    Unpause,
+
+    /// Update fee treasury (authority or treasury manager)
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority or treasury manager account
+    /// 1. `[writable]` Payment config account (PDA)
+    /// 2. `[]` Roles account (PDA), only when the signer is not the authority
+    UpdateFeeTreasury {
+        new_fee_treasury: Pubkey,
+    },
+
+    /// Refund part or all of a processed order to its buyer
+    ///
+    /// Tokens are sent from the signer's own token account. Refunds are allowed
+    /// while paused.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority, refund operator or `RefundOperator` role account
+    /// 1. `[writable]` Signer's token account (refund source)
+    /// 2. `[writable]` Buyer's token account
+    /// 3. `[]` Payment config account (PDA)
+    /// 4. `[writable]` Processed order account (PDA)
+    /// 5. `[]` Token mint
+    /// 6. `[]` Token program
+    /// 7. `[]` Roles account (PDA), only when the signer holds the role through it
+    /// 8. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    RefundOrder {
+        amount: u64,
+    },
+
+    /// Update refund operator
     ///
     /// Accounts expected:
     /// 0. `[signer]` Authority account
     /// 1. `[writable]` Payment config account (PDA)
-    UpdateHotWallet {
-        new_hot_wallet: Pubkey,
+    UpdateRefundOperator {
+        new_refund_operator: Pubkey,
     },
 
-    /// Update platform fee
+    /// Process a payment into escrow
+    ///
+    /// The full amount is held in a vault token account owned by the order PDA
+    /// until it is released or the buyer wins a dispute. The order records what
+    /// the vault received; fees and commission are paid out on release. The merchant
+    /// is checked and its fee override applied as in `ProcessPayment`, and quotes are
+    /// checked the same way. The commission rate comes from the affiliate account, and
+    /// the buyer pays for the affiliate's commission ledger and vault for the mint if
+    /// they do not exist yet, so the release can accrue the commission there.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Buyer account
+    /// 1. `[writable]` Buyer's token account
+    /// 2. `[writable]` Vault token account (PDA: ["vault", order_id_hash])
+    /// 3. `[]` Token mint
+    /// 4. `[]` Payment config account (PDA)
+    /// 5. `[writable]` Supported token account (PDA)
+    /// 6. `[writable]` Processed order account (PDA)
+    /// 7. `[]` Affiliate account (PDA), or this program's id for a payment without commission
+    /// 8. `[]` Token program
+    /// 9. `[]` System program
+    /// 10. `[]` Instructions sysvar
+    /// 11. `[]` Closed order account for the order ID hash (PDA), need not exist
+    /// 12. `[]` Merchant account (PDA)
+    /// 13. `[writable]` Commission vault token account (PDA: ["commission_vault", affiliate,
+    ///     mint]), or this program's id for a payment without commission
+    /// 14. `[writable]` Commission ledger account (PDA: ["commission", affiliate, mint]), or
+    ///     this program's id for a payment without commission
+    /// 15. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    ProcessEscrowPayment {
+        order_id: String,
+        amount: u64,
+        product_id: String,
+    },
+
+    /// Release an escrowed payment
+    ///
+    /// The admin can release at any time, the merchant's payout wallet once the
+    /// dispute window has passed. The vault is split like `ProcessPayment`, with the
+    /// commission accruing in the order's affiliate ledger.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority or merchant payout wallet account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Processed order account (PDA)
+    /// 3. `[writable]` Vault token account (PDA)
+    /// 4. `[writable]` Merchant's payout token account
+    /// 5. `[writable]` Fee treasury's token account
+    /// 6. `[writable]` Commission vault token account of the order's affiliate (PDA, checked
+    ///    only when commission is non-zero)
+    /// 7. `[]` Token mint
+    /// 8. `[]` Token program
+    /// 9. `[]` Merchant account the order was paid to (PDA)
+    /// 10. `[writable]` Commission ledger account of the order's affiliate (PDA, checked only
+    ///     when commission is non-zero)
+    /// 11. `[writable]` Affiliate account of the order (PDA, checked only when commission is
+    ///     non-zero)
+    /// 12. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    ReleaseEscrow,
+
+    /// Open a dispute on an escrowed payment within the dispute window
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Buyer account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Processed order account (PDA)
+    OpenDispute,
+
+    /// Settle a dispute, paying the vault out to the merchant or back to the buyer
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority or arbitrator account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Processed order account (PDA)
+    /// 3. `[writable]` Vault token account (PDA)
+    /// 4. `[writable]` Buyer's token account (checked only when settling for the buyer)
+    /// 5. `[writable]` Merchant's payout token account (checked only when releasing)
+    /// 6. `[writable]` Fee treasury's token account (checked only when releasing)
+    /// 7. `[writable]` Commission vault token account of the order's affiliate (PDA, checked
+    ///    only when releasing with commission)
+    /// 8. `[]` Token mint
+    /// 9. `[]` Token program
+    /// 10. `[]` Merchant account the order was paid to (PDA, checked only when releasing)
+    /// 11. `[writable]` Commission ledger account of the order's affiliate (PDA, checked only
+    ///     when releasing with commission)
+    /// 12. `[writable]` Affiliate account of the order (PDA, checked only when releasing with
+    ///     commission)
+    /// 13. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    ResolveDispute {
+        release_to_merchant: bool,
+    },
+
+    /// Update escrow arbitrator and dispute window
     ///
     /// Accounts expected:
     /// 0. `[signer]` Authority account
     /// 1. `[writable]` Payment config account (PDA)
-    UpdatePlatformFee {
-        new_fee_bps: u16,
+    UpdateEscrowSettings {
+        arbitrator: Pubkey,
+        dispute_window_secs: u32,
+    },
+
+    /// Process a single payment in native SOL
+    ///
+    /// Same split as `ProcessPayment`, moved with system transfers to the merchant's
+    /// payout wallet. The merchant must be active and accept the native mint
+    /// (`So11111111111111111111111111111111111111112`), which the order records as its
+    /// token mint and the quote must name; its fee override replaces the platform fee.
+    /// The commission rate comes from the affiliate account, and the commission accrues in
+    /// lamports in the affiliate's SOL commission ledger, to be withdrawn with
+    /// `ClaimSolCommission`. The buyer pays for the ledger on the affiliate's first SOL
+    /// payment.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Buyer account
+    /// 1. `[writable]` Merchant's payout wallet
+    /// 2. `[writable]` Fee treasury account
+    /// 3. `[writable]` SOL commission ledger account (PDA: ["sol_commission", affiliate]), or
+    ///    this program's id for a payment without commission
+    /// 4. `[]` Payment config account (PDA)
+    /// 5. `[writable]` Processed order account (PDA)
+    /// 6. `[]` System program
+    /// 7. `[]` Instructions sysvar
+    /// 8. `[]` Closed order account for the order ID hash (PDA), need not exist
+    /// 9. `[]` Merchant account (PDA)
+    /// 10. `[writable]` Affiliate account (PDA), or this program's id for a payment
+    ///     without commission
+    ProcessSolPayment {
+        order_id: String,
+        amount: u64,
+        product_id: String,
     },
 
-    /// Pause the contract
+    /// Propose a new authority, which takes over once it calls `AcceptAuthority`
     ///
     /// Accounts expected:
     /// 0. `[signer]` Authority account
     /// 1. `[writable]` Payment config account (PDA)
-    Pause,
+    ProposeAuthority {
+        new_authority: Pubkey,
+    },
+
+    /// Accept a pending authority transfer
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Pending authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    AcceptAuthority,
 
-    /// Unpause the contract
+    /// Cancel a pending authority transfer
     ///
     /// Accounts expected:
     /// 0. `[signer]` Authority account
     /// 1. `[writable]` Payment config account (PDA)
-    Unpause,
+    CancelAuthorityTransfer,
+
+    /// Upgrade a config account created by the first release to the current layout
+    ///
+    /// The account is resized and topped up to stay rent exempt. The fee
+    /// treasury starts as the old hot wallet, the refund operator and arbitrator
+    /// as unset and the dispute window and change delays at their defaults.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Authority account (pays for the extra space)
+    /// 1. `[writable]` Payment config account (PDA)
+    /// 2. `[]` System program
+    MigrateConfig,
+
+    /// Create the roles account
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Roles account (PDA)
+    /// 3. `[]` System program
+    InitializeRoles,
+
+    /// Grant a role to a wallet
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Roles account (PDA)
+    GrantRole {
+        member: Pubkey,
+        role: Role,
+    },
+
+    /// Revoke a role from a wallet
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Roles account (PDA)
+    RevokeRole {
+        member: Pubkey,
+        role: Role,
+    },
+
+    /// Queue a config change behind its timelock
+    ///
+    /// Merchant payout wallet changes are allowed to the treasury manager and platform
+    /// fee changes to the fee manager; delay changes are authority only and wait out
+    /// the longest current delay. One change of each kind can be pending, and one
+    /// payout wallet change per merchant.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Authority or role account (pays for the pending change)
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Pending change account (PDA: ["pending_change", kind], followed by
+    ///    the merchant for a payout wallet change)
+    /// 3. `[]` System program
+    /// 4. `[]` Roles account (PDA), only when the signer is not the authority
+    QueueConfigChange {
+        change: ConfigChange,
+    },
+
+    /// Apply a queued config change once its delay has passed (anyone)
+    ///
+    /// The pending change account is closed and its rent returned to the proposer.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Payment config account (PDA)
+    /// 1. `[writable]` Pending change account (PDA)
+    /// 2. `[writable]` Proposer account
+    /// 3. `[writable]` Merchant account (PDA), only for a payout wallet change
+    ExecuteConfigChange,
+
+    /// Drop a queued config change
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Pending change account (PDA)
+    /// 3. `[writable]` Proposer account
+    CancelConfigChange,
+
+    /// Set the M-of-N multisig that replaces the single authority for admin instructions
+    ///
+    /// A zero threshold with no signers disables it again. While set, payout wallet
+    /// changes can no longer be queued by the treasury manager.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    SetMultisig {
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    },
+
+    /// Set the backend key whose signed quotes payments must carry
+    ///
+    /// The default address stops requiring quotes.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    SetQuoteSigner {
+        quote_signer: Pubkey,
+    },
+
+    /// Set how far ahead payment and quote deadlines may lie (admin only)
+    ///
+    /// Zero removes the limit.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    SetMaxQuoteLifetime {
+        max_quote_lifetime_secs: u32,
+    },
+
+    /// Close a settled processed order once the retention period has passed, returning
+    /// its rent to the buyer
+    ///
+    /// Anyone can close an order. A closed order account is created for its ID out of
+    /// the order's rent, so it cannot be paid again. The escrow vault of a released or
+    /// returned order is closed with it; stray tokens left in the vault are returned to
+    /// the buyer's token account.
+    ///
+    /// Accounts expected:
+    /// 0. `[writable]` Processed order account (PDA)
+    /// 1. `[writable]` Buyer account, receives the rent
+    /// 2. `[]` Payment config account (PDA)
+    /// 3. `[writable]` Closed order account (PDA: ["closed_order", order_id_hash])
+    /// 4. `[]` System program
+    /// 5. `[writable]` Vault token account (PDA), only for an escrowed order
+    /// 6. `[writable]` Token mint, only for an escrowed order
+    /// 7. `[]` Token program, only for an escrowed order
+    /// 8. `[writable]` Buyer token account, only for an escrowed order
+    /// 9. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    CloseOrder,
+
+    /// Set how long processed orders are kept before they can be closed (admin only)
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    SetOrderRetention {
+        order_retention_secs: u32,
+    },
+
+    /// Set the cap on platform fee plus commission of a payment (admin only)
+    ///
+    /// The cap may not exceed 10000 (100%) or fall below the current platform fee.
+    /// Merchant fee overrides are only checked against the cap when set, so lowering the
+    /// cap below a merchant's override makes payments to that merchant fail with
+    /// `TotalFeeTooHigh` until its override is updated.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[writable]` Payment config account (PDA)
+    SetMaxTotalFee {
+        max_total_fee_bps: u16,
+    },
+
+    /// Register an active merchant paid by payments and escrow releases (admin only)
+    ///
+    /// `platform_fee_bps` overrides the platform fee on the merchant's sales and may not
+    /// exceed the total fee cap. An empty `allowed_mints` accepts every supported mint.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Authority account, pays for the merchant account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Merchant account (PDA: ["merchant", merchant_id])
+    /// 3. `[]` System program
+    RegisterMerchant {
+        merchant_id: [u8; 32],
+        payout_wallet: Pubkey,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    },
+
+    /// Update a merchant's status, fee override and allowed mints (admin only)
+    ///
+    /// A suspended merchant cannot be paid until it is made active again. The fee
+    /// override is checked as in `RegisterMerchant`. The payout wallet changes only
+    /// through `UpdatePayoutWallet`, behind the timelock.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Merchant account (PDA)
+    UpdateMerchant {
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    },
+
+    /// Register an active affiliate earning `commission_bps` on payments (admin only)
+    ///
+    /// The rate may not exceed the maximum commission, nor leave less than the platform
+    /// fee under the total fee cap.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Authority account, pays for the affiliate account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Affiliate account (PDA: ["affiliate", api_key_hash])
+    /// 3. `[]` System program
+    RegisterAffiliate {
+        api_key_hash: [u8; 32],
+        owner: Pubkey,
+        commission_bps: u16,
+    },
+
+    /// Update an affiliate's owner, commission rate and status (admin only)
+    ///
+    /// Payments naming a suspended affiliate fail until it is made active again. The rate
+    /// is checked as in `RegisterAffiliate`.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Authority account
+    /// 1. `[]` Payment config account (PDA)
+    /// 2. `[writable]` Affiliate account (PDA)
+    UpdateAffiliate {
+        owner: Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    },
+
+    /// Withdraw an affiliate's unclaimed commission in one mint to the owner's token account
+    ///
+    /// Signed by the affiliate's owner; fails while the affiliate is suspended.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` Affiliate owner account
+    /// 1. `[]` Affiliate account (PDA)
+    /// 2. `[writable]` Commission ledger account (PDA)
+    /// 3. `[writable]` Commission vault token account (PDA)
+    /// 4. `[writable]` Affiliate owner's token account
+    /// 5. `[]` Token mint
+    /// 6. `[]` Token program (SPL Token or Token-2022, owner of the mint)
+    /// 7. `[]` Transfer hook extra accounts, if the mint has a transfer hook (zero or more)
+    ClaimCommission,
+
+    /// Withdraw an affiliate's unclaimed SOL commission from its SOL commission ledger to
+    /// the owner's wallet
+    ///
+    /// Signed by the affiliate's owner; fails while the affiliate is suspended.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` Affiliate owner account
+    /// 1. `[]` Affiliate account (PDA)
+    /// 2. `[writable]` SOL commission ledger account (PDA: ["sol_commission", affiliate])
+    ClaimSolCommission,
 }
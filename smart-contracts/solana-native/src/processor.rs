@@ -1,23 +1,50 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use oxmart_payment_core::{check_total_fee, order_id_hash, split_payment};
+use solana_instructions_sysvar::get_instruction_relative;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    ed25519_program,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
-    program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
-    system_instruction,
     sysvar::Sysvar,
     clock::Clock,
 };
-use spl_token::state::Account as TokenAccount;
+use solana_system_interface::instruction as system_instruction;
+use spl_token_2022::{
+    extension::{
+        transfer_fee::{
+            instruction::harvest_withheld_tokens_to_mint, TransferFeeAmount, TransferFeeConfig,
+        },
+        BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+    },
+    state::{Account as TokenAccount, Mint},
+};
 
 use crate::{error::PaymentError, instruction::PaymentInstruction, state::*};
 
 pub struct Processor;
 
+/// Token accounts receiving the net amount, platform fee and commission of a payment
+struct PayoutAccounts<'a, 'b> {
+    /// The merchant's payout token account
+    net_token_info: &'b AccountInfo<'a>,
+    fee_treasury_token_info: &'b AccountInfo<'a>,
+    /// The affiliate's commission vault for the mint
+    commission_vault_info: &'b AccountInfo<'a>,
+}
+
+/// Token program, mint and transfer hook accounts shared by every leg of a `transfer_checked`
+struct TransferAccounts<'a, 'b> {
+    token_program_info: &'b AccountInfo<'a>,
+    mint_info: &'b AccountInfo<'a>,
+    /// Trailing instruction accounts, searched for the mint's transfer hook accounts
+    extra_account_infos: &'b [AccountInfo<'a>],
+}
+
 impl Processor {
     pub fn process(
         program_id: &Pubkey,
@@ -28,15 +55,15 @@ impl Processor {
             .map_err(|_| PaymentError::InvalidInstruction)?;
 
         match instruction {
-            PaymentInstruction::Initialize { hot_wallet } => {
+            PaymentInstruction::Initialize { fee_treasury } => {
                 msg!("Instruction: Initialize");
-                Self::process_initialize(program_id, accounts, hot_wallet)
+                Self::process_initialize(program_id, accounts, fee_treasury)
             }
             PaymentInstruction::ProcessPayment {
                 order_id,
                 amount,
                 product_id,
-                commission_bps,
+                expires_at,
             } => {
                 msg!("Instruction: ProcessPayment");
                 Self::process_payment(
@@ -45,7 +72,7 @@ impl Processor {
                     order_id,
                     amount,
                     product_id,
-                    commission_bps,
+                    expires_at,
                 )
             }
             PaymentInstruction::AddSupportedToken { token_mint } => {
@@ -56,13 +83,27 @@ impl Processor {
                 msg!("Instruction: RemoveSupportedToken");
                 Self::process_remove_supported_token(program_id, accounts)
             }
-            PaymentInstruction::UpdateHotWallet { new_hot_wallet } => {
-                msg!("Instruction: UpdateHotWallet");
-                Self::process_update_hot_wallet(program_id, accounts, new_hot_wallet)
+            PaymentInstruction::UpdatePayoutWallet {
+                merchant,
+                new_payout_wallet,
+            } => {
+                msg!("Instruction: UpdatePayoutWallet");
+                Self::process_queue_config_change(
+                    program_id,
+                    accounts,
+                    ConfigChange::PayoutWallet {
+                        merchant,
+                        new_payout_wallet,
+                    },
+                )
             }
             PaymentInstruction::UpdatePlatformFee { new_fee_bps } => {
                 msg!("Instruction: UpdatePlatformFee");
-                Self::process_update_platform_fee(program_id, accounts, new_fee_bps)
+                Self::process_queue_config_change(
+                    program_id,
+                    accounts,
+                    ConfigChange::PlatformFee { new_fee_bps },
+                )
             }
             PaymentInstruction::Pause => {
                 msg!("Instruction: Pause");
@@ -72,13 +113,197 @@ impl Processor {
                 msg!("Instruction: Unpause");
                 Self::process_unpause(program_id, accounts)
             }
+            PaymentInstruction::UpdateFeeTreasury { new_fee_treasury } => {
+                msg!("Instruction: UpdateFeeTreasury");
+                Self::process_update_fee_treasury(program_id, accounts, new_fee_treasury)
+            }
+            PaymentInstruction::RefundOrder { amount } => {
+                msg!("Instruction: RefundOrder");
+                Self::process_refund_order(program_id, accounts, amount)
+            }
+            PaymentInstruction::UpdateRefundOperator {
+                new_refund_operator,
+            } => {
+                msg!("Instruction: UpdateRefundOperator");
+                Self::process_update_refund_operator(program_id, accounts, new_refund_operator)
+            }
+            PaymentInstruction::ProcessEscrowPayment {
+                order_id,
+                amount,
+                product_id,
+            } => {
+                msg!("Instruction: ProcessEscrowPayment");
+                Self::process_escrow_payment(program_id, accounts, order_id, amount, product_id)
+            }
+            PaymentInstruction::ProcessSolPayment {
+                order_id,
+                amount,
+                product_id,
+            } => {
+                msg!("Instruction: ProcessSolPayment");
+                Self::process_sol_payment(program_id, accounts, order_id, amount, product_id)
+            }
+            PaymentInstruction::ReleaseEscrow => {
+                msg!("Instruction: ReleaseEscrow");
+                Self::process_release_escrow(program_id, accounts)
+            }
+            PaymentInstruction::OpenDispute => {
+                msg!("Instruction: OpenDispute");
+                Self::process_open_dispute(program_id, accounts)
+            }
+            PaymentInstruction::ResolveDispute {
+                release_to_merchant,
+            } => {
+                msg!("Instruction: ResolveDispute");
+                Self::process_resolve_dispute(program_id, accounts, release_to_merchant)
+            }
+            PaymentInstruction::UpdateEscrowSettings {
+                arbitrator,
+                dispute_window_secs,
+            } => {
+                msg!("Instruction: UpdateEscrowSettings");
+                Self::process_update_escrow_settings(
+                    program_id,
+                    accounts,
+                    arbitrator,
+                    dispute_window_secs,
+                )
+            }
+            PaymentInstruction::ProposeAuthority { new_authority } => {
+                msg!("Instruction: ProposeAuthority");
+                Self::process_propose_authority(program_id, accounts, new_authority)
+            }
+            PaymentInstruction::AcceptAuthority => {
+                msg!("Instruction: AcceptAuthority");
+                Self::process_accept_authority(program_id, accounts)
+            }
+            PaymentInstruction::CancelAuthorityTransfer => {
+                msg!("Instruction: CancelAuthorityTransfer");
+                Self::process_cancel_authority_transfer(program_id, accounts)
+            }
+            PaymentInstruction::MigrateConfig => {
+                msg!("Instruction: MigrateConfig");
+                Self::process_migrate_config(program_id, accounts)
+            }
+            PaymentInstruction::InitializeRoles => {
+                msg!("Instruction: InitializeRoles");
+                Self::process_initialize_roles(program_id, accounts)
+            }
+            PaymentInstruction::GrantRole { member, role } => {
+                msg!("Instruction: GrantRole");
+                Self::process_grant_role(program_id, accounts, member, role)
+            }
+            PaymentInstruction::RevokeRole { member, role } => {
+                msg!("Instruction: RevokeRole");
+                Self::process_revoke_role(program_id, accounts, member, role)
+            }
+            PaymentInstruction::QueueConfigChange { change } => {
+                msg!("Instruction: QueueConfigChange");
+                Self::process_queue_config_change(program_id, accounts, change)
+            }
+            PaymentInstruction::ExecuteConfigChange => {
+                msg!("Instruction: ExecuteConfigChange");
+                Self::process_execute_config_change(program_id, accounts)
+            }
+            PaymentInstruction::CancelConfigChange => {
+                msg!("Instruction: CancelConfigChange");
+                Self::process_cancel_config_change(program_id, accounts)
+            }
+            PaymentInstruction::SetMultisig { signers, threshold } => {
+                msg!("Instruction: SetMultisig");
+                Self::process_set_multisig(program_id, accounts, signers, threshold)
+            }
+            PaymentInstruction::SetQuoteSigner { quote_signer } => {
+                msg!("Instruction: SetQuoteSigner");
+                Self::process_set_quote_signer(program_id, accounts, quote_signer)
+            }
+            PaymentInstruction::SetMaxQuoteLifetime {
+                max_quote_lifetime_secs,
+            } => {
+                msg!("Instruction: SetMaxQuoteLifetime");
+                Self::process_set_max_quote_lifetime(program_id, accounts, max_quote_lifetime_secs)
+            }
+            PaymentInstruction::CloseOrder => {
+                msg!("Instruction: CloseOrder");
+                Self::process_close_order(program_id, accounts)
+            }
+            PaymentInstruction::SetOrderRetention {
+                order_retention_secs,
+            } => {
+                msg!("Instruction: SetOrderRetention");
+                Self::process_set_order_retention(program_id, accounts, order_retention_secs)
+            }
+            PaymentInstruction::SetMaxTotalFee { max_total_fee_bps } => {
+                msg!("Instruction: SetMaxTotalFee");
+                Self::process_set_max_total_fee(program_id, accounts, max_total_fee_bps)
+            }
+            PaymentInstruction::RegisterMerchant {
+                merchant_id,
+                payout_wallet,
+                platform_fee_bps,
+                allowed_mints,
+            } => {
+                msg!("Instruction: RegisterMerchant");
+                Self::process_register_merchant(
+                    program_id,
+                    accounts,
+                    merchant_id,
+                    payout_wallet,
+                    platform_fee_bps,
+                    allowed_mints,
+                )
+            }
+            PaymentInstruction::UpdateMerchant {
+                status,
+                platform_fee_bps,
+                allowed_mints,
+            } => {
+                msg!("Instruction: UpdateMerchant");
+                Self::process_update_merchant(
+                    program_id,
+                    accounts,
+                    status,
+                    platform_fee_bps,
+                    allowed_mints,
+                )
+            }
+            PaymentInstruction::RegisterAffiliate {
+                api_key_hash,
+                owner,
+                commission_bps,
+            } => {
+                msg!("Instruction: RegisterAffiliate");
+                Self::process_register_affiliate(
+                    program_id,
+                    accounts,
+                    api_key_hash,
+                    owner,
+                    commission_bps,
+                )
+            }
+            PaymentInstruction::UpdateAffiliate {
+                owner,
+                commission_bps,
+                status,
+            } => {
+                msg!("Instruction: UpdateAffiliate");
+                Self::process_update_affiliate(program_id, accounts, owner, commission_bps, status)
+            }
+            PaymentInstruction::ClaimCommission => {
+                msg!("Instruction: ClaimCommission");
+                Self::process_claim_commission(program_id, accounts)
+            }
+            PaymentInstruction::ClaimSolCommission => {
+                msg!("Instruction: ClaimSolCommission");
+                Self::process_claim_sol_commission(program_id, accounts)
+            }
         }
     }
 
     fn process_initialize(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        hot_wallet: Pubkey,
+        fee_treasury: Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let authority_info = next_account_info(account_info_iter)?;
@@ -90,11 +315,13 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        Self::check_system_program(system_program_info)?;
+
         // Derive PDA
         let (config_pda, bump) = Pubkey::find_program_address(&[b"config"], program_id);
 
         if config_pda != *config_info.key {
-            return Err(ProgramError::InvalidSeeds);
+            return Err(PaymentError::InvalidConfigAccount.into());
         }
 
         // Check if already initialized
@@ -122,19 +349,31 @@ impl Processor {
         // Initialize config
         let config = PaymentConfig {
             authority: *authority_info.key,
-            hot_wallet,
+            pending_authority: Pubkey::default(),
+            multisig_signers: Vec::new(),
+            multisig_threshold: 0,
+            fee_treasury,
+            refund_operator: Pubkey::default(),
+            arbitrator: Pubkey::default(),
+            quote_signer: Pubkey::default(),
+            dispute_window_secs: PaymentConfig::DEFAULT_DISPUTE_WINDOW_SECS,
+            payout_wallet_delay_secs: PaymentConfig::DEFAULT_CHANGE_DELAY_SECS,
+            platform_fee_delay_secs: PaymentConfig::DEFAULT_CHANGE_DELAY_SECS,
+            max_quote_lifetime_secs: PaymentConfig::DEFAULT_MAX_QUOTE_LIFETIME_SECS,
+            order_retention_secs: PaymentConfig::DEFAULT_ORDER_RETENTION_SECS,
             platform_fee_bps: 0,
             max_platform_fee_bps: PaymentConfig::MAX_PLATFORM_FEE_BPS,
             max_commission_bps: PaymentConfig::MAX_COMMISSION_BPS,
+            max_total_fee_bps: PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS,
             is_paused: false,
             bump,
         };
 
-        config.serialize(&mut *config_info.data.borrow_mut())?;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
 
         msg!("Payment config initialized");
         msg!("Authority: {}", authority_info.key);
-        msg!("Hot Wallet: {}", hot_wallet);
+        msg!("Fee Treasury: {}", fee_treasury);
 
         Ok(())
     }
@@ -145,122 +384,153 @@ impl Processor {
         order_id: String,
         amount: u64,
         _product_id: String,
-        commission_bps: u16,
+        expires_at: i64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let buyer_info = next_account_info(account_info_iter)?;
         let buyer_token_info = next_account_info(account_info_iter)?;
-        let hot_wallet_token_info = next_account_info(account_info_iter)?;
+        let merchant_token_info = next_account_info(account_info_iter)?;
+        let fee_treasury_token_info = next_account_info(account_info_iter)?;
+        let commission_vault_info = next_account_info(account_info_iter)?;
         let token_mint_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
         let supported_token_info = next_account_info(account_info_iter)?;
         let processed_order_info = next_account_info(account_info_iter)?;
-        let api_key_owner_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let commission_ledger_info = next_account_info(account_info_iter)?;
         let token_program_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
+        let instructions_sysvar_info = next_account_info(account_info_iter)?;
+        let closed_order_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
 
         // Verify buyer signature
         if !buyer_info.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        // Load and verify config
-        let config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-
-        if config.is_paused {
-            return Err(PaymentError::ContractPaused.into());
-        }
-
-        // Verify PDA
-        let (config_pda, _) = Pubkey::find_program_address(&[b"config"], program_id);
-        if config_pda != *config_info.key {
-            return Err(ProgramError::InvalidSeeds);
-        }
+        Self::check_token_program(token_program_info, token_mint_info)?;
+        Self::check_system_program(system_program_info)?;
 
-        // Check if token is supported
-        let supported_token = SupportedToken::try_from_slice(&supported_token_info.data.borrow())?;
-        if !supported_token.is_supported || supported_token.mint != *token_mint_info.key {
-            return Err(PaymentError::TokenNotSupported.into());
-        }
+        let merchant = Self::load_merchant(program_id, merchant_info)?;
+        Self::check_merchant_accepts(&merchant, token_mint_info.key)?;
 
-        // Validate amount
-        if amount == 0 {
-            return Err(PaymentError::InvalidAmount.into());
-        }
+        // Commission comes from the affiliate registry, never from the buyer
+        let mut affiliate = Self::load_payment_affiliate(program_id, affiliate_info)?;
+        let (api_key_owner, commission_bps) = Self::affiliate_commission(affiliate.as_ref());
 
-        // Validate commission
-        if commission_bps > config.max_commission_bps {
-            return Err(PaymentError::InvalidCommissionRate.into());
-        }
+        let config = Self::load_payment_config(
+            program_id,
+            config_info,
+            token_mint_info,
+            supported_token_info,
+            amount,
+            commission_bps,
+            merchant.platform_fee_bps,
+        )?;
+        Self::check_deadline(&config, expires_at, PaymentError::OrderExpired)?;
 
         // Check if order already processed
-        let order_id_hash = solana_program::hash::hash(order_id.as_bytes()).to_bytes();
-        let (order_pda, order_bump) =
-            Pubkey::find_program_address(&[b"order", &order_id_hash], program_id);
-
-        if order_pda != *processed_order_info.key {
-            return Err(ProgramError::InvalidSeeds);
-        }
-
-        if processed_order_info.data_len() > 0 {
-            return Err(PaymentError::OrderAlreadyProcessed.into());
-        }
+        let (order_id_hash, order_bump) = Self::check_new_order(
+            program_id,
+            processed_order_info,
+            closed_order_info,
+            &order_id,
+        )?;
 
-        // Calculate fees
-        let platform_fee = amount
-            .checked_mul(config.platform_fee_bps as u64)
-            .ok_or(PaymentError::ArithmeticOverflow)?
-            .checked_div(10000)
-            .ok_or(PaymentError::ArithmeticOverflow)?;
+        Self::verify_quote(
+            &config,
+            instructions_sysvar_info,
+            &QuoteTerms {
+                program_id: *program_id,
+                order_id_hash,
+                mint: *token_mint_info.key,
+                amount,
+                merchant: *merchant_info.key,
+                commission_bps,
+                api_key_owner,
+            },
+        )?;
 
-        let commission = amount
-            .checked_mul(commission_bps as u64)
-            .ok_or(PaymentError::ArithmeticOverflow)?
-            .checked_div(10000)
-            .ok_or(PaymentError::ArithmeticOverflow)?;
+        let (platform_fee, commission, net_amount) =
+            Self::calculate_fees(merchant.platform_fee_bps(&config), amount, commission_bps)?;
 
-        // Transfer tokens to hot wallet
-        let transfer_ix = spl_token::instruction::transfer(
-            token_program_info.key,
-            buyer_token_info.key,
-            hot_wallet_token_info.key,
+        // Verify every token account uses the paid mint and belongs to the right wallet
+        Self::load_token_account(
+            buyer_token_info,
+            token_mint_info.key,
             buyer_info.key,
-            &[],
-            amount,
+            PaymentError::InvalidBuyerTokenAccount,
         )?;
-
-        invoke(
-            &transfer_ix,
-            &[
-                buyer_token_info.clone(),
-                hot_wallet_token_info.clone(),
-                buyer_info.clone(),
-                token_program_info.clone(),
-            ],
+        Self::load_token_account(
+            merchant_token_info,
+            token_mint_info.key,
+            &merchant.payout_wallet,
+            PaymentError::InvalidMerchantTokenAccount,
         )?;
 
-        // Create processed order record
-        let rent = Rent::get()?;
-        let space = ProcessedOrder::LEN;
-        let lamports = rent.minimum_balance(space);
-
-        invoke_signed(
-            &system_instruction::create_account(
-                buyer_info.key,
-                processed_order_info.key,
-                lamports,
-                space as u64,
+        // Commission accrues in the affiliate's vault for the mint until claimed
+        let mut ledger = match &affiliate {
+            Some(_) => Some(Self::open_commission_ledger(
                 program_id,
-            ),
-            &[
-                buyer_info.clone(),
-                processed_order_info.clone(),
-                system_program_info.clone(),
-            ],
-            &[&[b"order", &order_id_hash, &[order_bump]]],
+                buyer_info,
+                affiliate_info,
+                commission_ledger_info,
+                commission_vault_info,
+                &transfer,
+                system_program_info,
+            )?),
+            None => None,
+        };
+        let payout = PayoutAccounts {
+            net_token_info: merchant_token_info,
+            fee_treasury_token_info,
+            commission_vault_info,
+        };
+        Self::check_payout_accounts(
+            &config,
+            token_mint_info.key,
+            &payout,
+            commission_ledger_info.key,
+            commission,
+        )?;
+
+        // Split the payment between merchant, fee treasury and affiliate.
+        // Mints with a transfer fee withhold part of each leg, so record what arrived.
+        let (net_amount, platform_fee, commission) = Self::pay_out(
+            &transfer,
+            buyer_token_info,
+            buyer_info,
+            &[],
+            &payout,
+            (net_amount, platform_fee, commission),
         )?;
+        let amount = net_amount
+            .checked_add(platform_fee)
+            .and_then(|total| total.checked_add(commission))
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+
+        if let Some(affiliate) = &mut affiliate {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            affiliate.serialize(&mut &mut affiliate_info.data.borrow_mut()[..])?;
+        }
+        if let Some(ledger) = &mut ledger {
+            ledger.accrued = ledger
+                .accrued
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            ledger.serialize(&mut &mut commission_ledger_info.data.borrow_mut()[..])?;
+        }
 
-        // Save order details
+        // Create processed order record
         let clock = Clock::get()?;
         let processed_order = ProcessedOrder {
             order_id_hash,
@@ -268,222 +538,3171 @@ impl Processor {
             token_mint: *token_mint_info.key,
             amount,
             platform_fee,
-            api_key_owner: *api_key_owner_info.key,
+            api_key_owner,
+            affiliate: affiliate
+                .as_ref()
+                .map_or(Pubkey::default(), |_| *affiliate_info.key),
             commission,
             commission_bps,
+            refunded_amount: 0,
+            merchant: *merchant_info.key,
+            status: OrderStatus::Completed,
             timestamp: clock.unix_timestamp,
             bump: order_bump,
         };
 
-        processed_order.serialize(&mut *processed_order_info.data.borrow_mut())?;
+        Self::create_processed_order(
+            program_id,
+            buyer_info,
+            processed_order_info,
+            system_program_info,
+            &processed_order,
+        )?;
 
         msg!("Payment processed");
         msg!("Order ID Hash: {:?}", order_id_hash);
         msg!("Amount: {}", amount);
+        msg!("Net Amount: {}", net_amount);
         msg!("Platform Fee: {}", platform_fee);
         msg!("Commission: {}", commission);
 
         Ok(())
     }
 
-    fn process_add_supported_token(
+    fn process_sol_payment(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        token_mint: Pubkey,
+        order_id: String,
+        amount: u64,
+        _product_id: String,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
-        let authority_info = next_account_info(account_info_iter)?;
+        let buyer_info = next_account_info(account_info_iter)?;
+        let merchant_wallet_info = next_account_info(account_info_iter)?;
+        let fee_treasury_info = next_account_info(account_info_iter)?;
+        let commission_ledger_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
-        let supported_token_info = next_account_info(account_info_iter)?;
-        let mint_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
         let system_program_info = next_account_info(account_info_iter)?;
+        let instructions_sysvar_info = next_account_info(account_info_iter)?;
+        let closed_order_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
 
-        // Verify authority
-        if !authority_info.is_signer {
+        // Verify buyer signature
+        if !buyer_info.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
-            return Err(PaymentError::NotAuthorized.into());
+        Self::check_system_program(system_program_info)?;
+
+        // Load and verify config
+        let config = Self::load_config(program_id, config_info)?;
+
+        if config.is_paused {
+            return Err(PaymentError::ContractPaused.into());
         }
 
-        // Derive PDA for supported token
-        let (token_pda, bump) =
-            Pubkey::find_program_address(&[b"token", mint_info.key.as_ref()], program_id);
+        // Validate amount
+        if amount == 0 {
+            return Err(PaymentError::InvalidAmount.into());
+        }
 
-        if token_pda != *supported_token_info.key {
-            return Err(ProgramError::InvalidSeeds);
+        // The net amount goes to the merchant's payout wallet
+        let merchant = Self::load_merchant(program_id, merchant_info)?;
+        Self::check_merchant_accepts(&merchant, &spl_token::native_mint::id())?;
+        if *merchant_wallet_info.key != merchant.payout_wallet {
+            return Err(PaymentError::InvalidMerchantWallet.into());
         }
 
-        // Create or update supported token account
-        let rent = Rent::get()?;
-        let space = SupportedToken::LEN;
-        let lamports = rent.minimum_balance(space);
+        // Commission comes from the affiliate registry
+        let mut affiliate = Self::load_payment_affiliate(program_id, affiliate_info)?;
+        let (api_key_owner, commission_bps) = Self::affiliate_commission(affiliate.as_ref());
 
-        if supported_token_info.data_len() == 0 {
-            invoke_signed(
-                &system_instruction::create_account(
-                    authority_info.key,
-                    supported_token_info.key,
-                    lamports,
-                    space as u64,
-                    program_id,
-                ),
-                &[
-                    authority_info.clone(),
-                    supported_token_info.clone(),
-                    system_program_info.clone(),
-                ],
-                &[&[b"token", mint_info.key.as_ref(), &[bump]]],
-            )?;
+        // Validate commission
+        if commission_bps > config.max_commission_bps {
+            return Err(PaymentError::InvalidCommissionRate.into());
         }
+        let platform_fee_bps = merchant.platform_fee_bps(&config);
+        check_total_fee(platform_fee_bps, commission_bps, config.max_total_fee_bps)
+            .map_err(PaymentError::from)?;
 
-        let supported_token = SupportedToken {
-            mint: token_mint,
-            is_supported: true,
-            bump,
-        };
+        if *fee_treasury_info.key != config.fee_treasury {
+            return Err(PaymentError::InvalidFeeTreasury.into());
+        }
 
-        supported_token.serialize(&mut *supported_token_info.data.borrow_mut())?;
+        // Check if order already processed
+        let (order_id_hash, order_bump) = Self::check_new_order(
+            program_id,
+            processed_order_info,
+            closed_order_info,
+            &order_id,
+        )?;
 
-        msg!("Token added: {}", token_mint);
+        Self::verify_quote(
+            &config,
+            instructions_sysvar_info,
+            &QuoteTerms {
+                program_id: *program_id,
+                order_id_hash,
+                mint: spl_token::native_mint::id(),
+                amount,
+                merchant: *merchant_info.key,
+                commission_bps,
+                api_key_owner,
+            },
+        )?;
 
-        Ok(())
-    }
+        let (platform_fee, commission, net_amount) =
+            Self::calculate_fees(platform_fee_bps, amount, commission_bps)?;
 
-    fn process_remove_supported_token(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let authority_info = next_account_info(account_info_iter)?;
-        let config_info = next_account_info(account_info_iter)?;
-        let supported_token_info = next_account_info(account_info_iter)?;
+        // Commission accrues in lamports in the affiliate's SOL ledger until claimed
+        let mut ledger = match &affiliate {
+            Some(_) => Some(Self::open_sol_commission_ledger(
+                program_id,
+                buyer_info,
+                affiliate_info,
+                commission_ledger_info,
+                system_program_info,
+            )?),
+            None => None,
+        };
 
-        // Verify authority
-        if !authority_info.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        // Split the payment between merchant, fee treasury and affiliate
+        Self::transfer_lamports(buyer_info, merchant_wallet_info, system_program_info, net_amount)?;
+        Self::transfer_lamports(buyer_info, fee_treasury_info, system_program_info, platform_fee)?;
+        if ledger.is_some() {
+            Self::transfer_lamports(
+                buyer_info,
+                commission_ledger_info,
+                system_program_info,
+                commission,
+            )?;
         }
 
-        let config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
-            return Err(PaymentError::NotAuthorized.into());
+        if let Some(affiliate) = &mut affiliate {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            affiliate.serialize(&mut &mut affiliate_info.data.borrow_mut()[..])?;
+        }
+        if let Some(ledger) = &mut ledger {
+            ledger.accrued = ledger
+                .accrued
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            ledger.serialize(&mut &mut commission_ledger_info.data.borrow_mut()[..])?;
         }
 
-        // Update supported token
-        let mut supported_token =
-            SupportedToken::try_from_slice(&supported_token_info.data.borrow())?;
-        supported_token.is_supported = false;
+        // Create processed order record
+        let clock = Clock::get()?;
+        let processed_order = ProcessedOrder {
+            order_id_hash,
+            buyer: *buyer_info.key,
+            token_mint: spl_token::native_mint::id(),
+            amount,
+            platform_fee,
+            api_key_owner,
+            affiliate: affiliate
+                .as_ref()
+                .map_or(Pubkey::default(), |_| *affiliate_info.key),
+            commission,
+            commission_bps,
+            refunded_amount: 0,
+            merchant: *merchant_info.key,
+            status: OrderStatus::Completed,
+            timestamp: clock.unix_timestamp,
+            bump: order_bump,
+        };
 
-        supported_token.serialize(&mut *supported_token_info.data.borrow_mut())?;
+        Self::create_processed_order(
+            program_id,
+            buyer_info,
+            processed_order_info,
+            system_program_info,
+            &processed_order,
+        )?;
 
-        msg!("Token removed");
+        msg!("SOL payment processed");
+        msg!("Order ID Hash: {:?}", order_id_hash);
+        msg!("Amount: {}", amount);
+        msg!("Net Amount: {}", net_amount);
+        msg!("Platform Fee: {}", platform_fee);
+        msg!("Commission: {}", commission);
 
         Ok(())
     }
 
-    fn process_update_hot_wallet(
+    fn process_escrow_payment(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        new_hot_wallet: Pubkey,
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let authority_info = next_account_info(account_info_iter)?;
+        order_id: String,
+        amount: u64,
+        _product_id: String,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let buyer_info = next_account_info(account_info_iter)?;
+        let buyer_token_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
+        let supported_token_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let instructions_sysvar_info = next_account_info(account_info_iter)?;
+        let closed_order_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let commission_vault_info = next_account_info(account_info_iter)?;
+        let commission_ledger_info = next_account_info(account_info_iter)?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
 
-        // Verify authority
-        if !authority_info.is_signer {
+        // Verify buyer signature
+        if !buyer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Self::check_token_program(token_program_info, token_mint_info)?;
+        Self::check_system_program(system_program_info)?;
+
+        let merchant = Self::load_merchant(program_id, merchant_info)?;
+        Self::check_merchant_accepts(&merchant, token_mint_info.key)?;
+
+        // Commission comes from the affiliate registry and accrues to it on release
+        let affiliate = Self::load_payment_affiliate(program_id, affiliate_info)?;
+        let (api_key_owner, commission_bps) = Self::affiliate_commission(affiliate.as_ref());
+
+        let config = Self::load_payment_config(
+            program_id,
+            config_info,
+            token_mint_info,
+            supported_token_info,
+            amount,
+            commission_bps,
+            merchant.platform_fee_bps,
+        )?;
+
+        // Check if order already processed
+        let (order_id_hash, order_bump) = Self::check_new_order(
+            program_id,
+            processed_order_info,
+            closed_order_info,
+            &order_id,
+        )?;
+
+        Self::verify_quote(
+            &config,
+            instructions_sysvar_info,
+            &QuoteTerms {
+                program_id: *program_id,
+                order_id_hash,
+                mint: *token_mint_info.key,
+                amount,
+                merchant: *merchant_info.key,
+                commission_bps,
+                api_key_owner,
+            },
+        )?;
+
+        Self::load_token_account(
+            buyer_token_info,
+            token_mint_info.key,
+            buyer_info.key,
+            PaymentError::InvalidBuyerTokenAccount,
+        )?;
+
+        // Open the affiliate's commission ledger now, so the release can accrue into it
+        if affiliate.is_some() {
+            Self::open_commission_ledger(
+                program_id,
+                buyer_info,
+                affiliate_info,
+                commission_ledger_info,
+                commission_vault_info,
+                &transfer,
+                system_program_info,
+            )?;
+        }
+
+        // Create the vault token account, owned by the order PDA
+        let (vault_pda, vault_bump) =
+            Pubkey::find_program_address(&[b"vault", &order_id_hash], program_id);
+        if vault_pda != *vault_info.key {
+            return Err(PaymentError::InvalidVaultAccount.into());
+        }
+
+        Self::create_token_account(
+            buyer_info,
+            vault_info,
+            processed_order_info.key,
+            &transfer,
+            system_program_info,
+            &[b"vault", &order_id_hash, &[vault_bump]],
+        )?;
+
+        // Escrow what the vault received; fees are fixed now and paid out on release
+        let amount = Self::transfer_tokens(
+            &transfer,
+            buyer_token_info,
+            vault_info,
+            buyer_info,
+            &[],
+            amount,
+        )?;
+        let (platform_fee, commission, _) =
+            Self::calculate_fees(merchant.platform_fee_bps(&config), amount, commission_bps)?;
+
+        // Create processed order record
+        let clock = Clock::get()?;
+        let processed_order = ProcessedOrder {
+            order_id_hash,
+            buyer: *buyer_info.key,
+            token_mint: *token_mint_info.key,
+            amount,
+            platform_fee,
+            api_key_owner,
+            affiliate: affiliate
+                .as_ref()
+                .map_or(Pubkey::default(), |_| *affiliate_info.key),
+            commission,
+            commission_bps,
+            refunded_amount: 0,
+            merchant: *merchant_info.key,
+            status: OrderStatus::Escrowed,
+            timestamp: clock.unix_timestamp,
+            bump: order_bump,
+        };
+
+        Self::create_processed_order(
+            program_id,
+            buyer_info,
+            processed_order_info,
+            system_program_info,
+            &processed_order,
+        )?;
+
+        msg!("Payment escrowed");
+        msg!("Order ID Hash: {:?}", order_id_hash);
+        msg!("Amount: {}", amount);
+        msg!("Merchant: {}", merchant_info.key);
+
+        Ok(())
+    }
+
+    fn process_release_escrow(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let releaser_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let merchant_token_info = next_account_info(account_info_iter)?;
+        let fee_treasury_token_info = next_account_info(account_info_iter)?;
+        let commission_vault_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let commission_ledger_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
+
+        if !releaser_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Self::check_token_program(token_program_info, token_mint_info)?;
+
+        let config = Self::load_config(program_id, config_info)?;
+        let mut processed_order = Self::load_processed_order(program_id, processed_order_info)?;
+        Self::check_order_mint(&processed_order, token_mint_info)?;
+
+        if processed_order.status != OrderStatus::Escrowed {
+            return Err(PaymentError::InvalidOrderStatus.into());
+        }
+        let merchant = Self::load_order_merchant(program_id, &processed_order, merchant_info)?;
+
+        // The merchant has to wait out the dispute window, the admin does not
+        if config.is_admin(releaser_info.key) {
+            Self::check_admin(&config, releaser_info, &mut transfer.extra_account_infos.iter())?;
+        } else {
+            if *releaser_info.key != merchant.payout_wallet {
+                return Err(PaymentError::NotAuthorized.into());
+            }
+
+            let clock = Clock::get()?;
+            if clock.unix_timestamp <= Self::dispute_deadline(&config, &processed_order)? {
+                return Err(PaymentError::DisputeWindowOpen.into());
+            }
+        }
+
+        Self::release_vault(
+            program_id,
+            &config,
+            &processed_order,
+            processed_order_info,
+            vault_info,
+            &merchant,
+            &PayoutAccounts {
+                net_token_info: merchant_token_info,
+                fee_treasury_token_info,
+                commission_vault_info,
+            },
+            commission_ledger_info,
+            affiliate_info,
+            &transfer,
+        )?;
+
+        processed_order.status = OrderStatus::Released;
+        processed_order.serialize(&mut &mut processed_order_info.data.borrow_mut()[..])?;
+
+        msg!("Escrow released");
+        msg!("Order ID Hash: {:?}", processed_order.order_id_hash);
+        msg!("Released By: {}", releaser_info.key);
+
+        Ok(())
+    }
+
+    fn process_open_dispute(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let buyer_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
+
+        if !buyer_info.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let mut config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
+        let config = Self::load_config(program_id, config_info)?;
+        let mut processed_order = Self::load_processed_order(program_id, processed_order_info)?;
+
+        if processed_order.buyer != *buyer_info.key {
             return Err(PaymentError::NotAuthorized.into());
         }
 
-        config.hot_wallet = new_hot_wallet;
-        config.serialize(&mut *config_info.data.borrow_mut())?;
+        if processed_order.status != OrderStatus::Escrowed {
+            return Err(PaymentError::InvalidOrderStatus.into());
+        }
+
+        let clock = Clock::get()?;
+        if clock.unix_timestamp > Self::dispute_deadline(&config, &processed_order)? {
+            return Err(PaymentError::DisputeWindowClosed.into());
+        }
 
-        msg!("Hot wallet updated to: {}", new_hot_wallet);
+        processed_order.status = OrderStatus::Disputed;
+        processed_order.serialize(&mut &mut processed_order_info.data.borrow_mut()[..])?;
+
+        msg!("Dispute opened");
+        msg!("Order ID Hash: {:?}", processed_order.order_id_hash);
 
         Ok(())
     }
 
-    fn process_update_platform_fee(
+    fn process_resolve_dispute(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        new_fee_bps: u16,
+        release_to_merchant: bool,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
-        let authority_info = next_account_info(account_info_iter)?;
+        let arbitrator_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let buyer_token_info = next_account_info(account_info_iter)?;
+        let merchant_token_info = next_account_info(account_info_iter)?;
+        let fee_treasury_token_info = next_account_info(account_info_iter)?;
+        let commission_vault_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let commission_ledger_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
 
-        // Verify authority
-        if !authority_info.is_signer {
+        if !arbitrator_info.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let mut config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
-            return Err(PaymentError::NotAuthorized.into());
+        Self::check_token_program(token_program_info, token_mint_info)?;
+
+        let config = Self::load_config(program_id, config_info)?;
+        if *arbitrator_info.key != config.arbitrator {
+            Self::check_admin(&config, arbitrator_info, &mut transfer.extra_account_infos.iter())?;
         }
 
-        if new_fee_bps > config.max_platform_fee_bps {
-            return Err(PaymentError::InvalidPlatformFee.into());
+        let mut processed_order = Self::load_processed_order(program_id, processed_order_info)?;
+        if processed_order.status != OrderStatus::Disputed {
+            return Err(PaymentError::InvalidOrderStatus.into());
         }
+        Self::check_order_mint(&processed_order, token_mint_info)?;
 
-        config.platform_fee_bps = new_fee_bps;
-        config.serialize(&mut *config_info.data.borrow_mut())?;
+        if release_to_merchant {
+            let merchant =
+                Self::load_order_merchant(program_id, &processed_order, merchant_info)?;
+            Self::release_vault(
+                program_id,
+                &config,
+                &processed_order,
+                processed_order_info,
+                vault_info,
+                &merchant,
+                &PayoutAccounts {
+                    net_token_info: merchant_token_info,
+                    fee_treasury_token_info,
+                    commission_vault_info,
+                },
+                commission_ledger_info,
+                affiliate_info,
+                &transfer,
+            )?;
+            processed_order.status = OrderStatus::Released;
+        } else {
+            let escrowed =
+                Self::load_vault(program_id, &processed_order, processed_order_info, vault_info)?;
+            Self::load_token_account(
+                buyer_token_info,
+                &processed_order.token_mint,
+                &processed_order.buyer,
+                PaymentError::InvalidBuyerTokenAccount,
+            )?;
+
+            Self::transfer_tokens(
+                &transfer,
+                vault_info,
+                buyer_token_info,
+                processed_order_info,
+                &[&[b"order", &processed_order.order_id_hash, &[processed_order.bump]]],
+                escrowed,
+            )?;
+            processed_order.status = OrderStatus::ReturnedToBuyer;
+        }
 
-        msg!("Platform fee updated to: {} bps", new_fee_bps);
+        processed_order.serialize(&mut &mut processed_order_info.data.borrow_mut()[..])?;
+
+        msg!("Dispute resolved");
+        msg!("Order ID Hash: {:?}", processed_order.order_id_hash);
+        msg!("Released To Merchant: {}", release_to_merchant);
 
         Ok(())
     }
 
-    fn process_pause(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    fn process_refund_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
-        let authority_info = next_account_info(account_info_iter)?;
+        let refunder_info = next_account_info(account_info_iter)?;
+        let refund_source_token_info = next_account_info(account_info_iter)?;
+        let buyer_token_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
+        let processed_order_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
 
-        // Verify authority
-        if !authority_info.is_signer {
+        // Verify refunder is the admin, the refund operator or holds the refund role
+        if !refunder_info.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let mut config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
-            return Err(PaymentError::NotAuthorized.into());
+        Self::check_token_program(token_program_info, token_mint_info)?;
+
+        let config = Self::load_config(program_id, config_info)?;
+        if config.is_admin(refunder_info.key) {
+            // The other multisig signers sit among the transfer hook extra accounts
+            Self::check_admin(&config, refunder_info, &mut account_info_iter.clone())?;
+        } else if *refunder_info.key != config.refund_operator {
+            Self::check_role(program_id, refunder_info, account_info_iter, Role::RefundOperator)?;
         }
 
-        config.is_paused = true;
-        config.serialize(&mut *config_info.data.borrow_mut())?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
 
-        msg!("Contract paused");
+        let mut processed_order = Self::load_processed_order(program_id, processed_order_info)?;
+
+        // Escrowed funds are settled through the dispute flow instead
+        if !matches!(
+            processed_order.status,
+            OrderStatus::Completed | OrderStatus::Released
+        ) {
+            return Err(PaymentError::InvalidOrderStatus.into());
+        }
+
+        // Validate amount against what is left to refund
+        if amount == 0 {
+            return Err(PaymentError::InvalidAmount.into());
+        }
+
+        let refunded_amount = processed_order
+            .refunded_amount
+            .checked_add(amount)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        if refunded_amount > processed_order.amount {
+            return Err(PaymentError::RefundExceedsPayment.into());
+        }
+
+        // Refund in the paid mint, straight back to the original buyer
+        Self::check_order_mint(&processed_order, token_mint_info)?;
+        Self::load_token_account(
+            refund_source_token_info,
+            &processed_order.token_mint,
+            refunder_info.key,
+            PaymentError::InvalidRefundTokenAccount,
+        )?;
+        Self::load_token_account(
+            buyer_token_info,
+            &processed_order.token_mint,
+            &processed_order.buyer,
+            PaymentError::InvalidBuyerTokenAccount,
+        )?;
+
+        Self::transfer_tokens(
+            &transfer,
+            refund_source_token_info,
+            buyer_token_info,
+            refunder_info,
+            &[],
+            amount,
+        )?;
+
+        processed_order.refunded_amount = refunded_amount;
+        processed_order.serialize(&mut &mut processed_order_info.data.borrow_mut()[..])?;
+
+        msg!("Payment refunded");
+        msg!("Order ID Hash: {:?}", processed_order.order_id_hash);
+        msg!("Refund Amount: {}", amount);
+        msg!("Total Refunded: {}", refunded_amount);
 
         Ok(())
     }
 
-    fn process_unpause(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    fn process_add_supported_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        token_mint: Pubkey,
+    ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let authority_info = next_account_info(account_info_iter)?;
         let config_info = next_account_info(account_info_iter)?;
+        let supported_token_info = next_account_info(account_info_iter)?;
+        let mint_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
 
-        // Verify authority
-        if !authority_info.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        Self::load_config_for_role(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+            Role::TokenManager,
+        )?;
+        Self::check_system_program(system_program_info)?;
+
+        if token_mint != *mint_info.key {
+            return Err(PaymentError::TokenMintMismatch.into());
         }
+        Self::load_mint(mint_info)?;
 
-        let mut config = PaymentConfig::try_from_slice(&config_info.data.borrow())?;
-        if config.authority != *authority_info.key {
-            return Err(PaymentError::NotAuthorized.into());
+        // Derive PDA for supported token
+        let (token_pda, bump) =
+            Pubkey::find_program_address(&[b"token", mint_info.key.as_ref()], program_id);
+
+        if token_pda != *supported_token_info.key {
+            return Err(PaymentError::InvalidSupportedTokenAccount.into());
         }
 
-        config.is_paused = false;
-        config.serialize(&mut *config_info.data.borrow_mut())?;
+        // Create or update supported token account
+        let rent = Rent::get()?;
+        let space = SupportedToken::LEN;
+        let lamports = rent.minimum_balance(space);
 
-        msg!("Contract unpaused");
+        if supported_token_info.data_len() == 0 {
+            invoke_signed(
+                &system_instruction::create_account(
+                    authority_info.key,
+                    supported_token_info.key,
+                    lamports,
+                    space as u64,
+                    program_id,
+                ),
+                &[
+                    authority_info.clone(),
+                    supported_token_info.clone(),
+                    system_program_info.clone(),
+                ],
+                &[&[b"token", mint_info.key.as_ref(), &[bump]]],
+            )?;
+        } else if supported_token_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let supported_token = SupportedToken {
+            mint: token_mint,
+            is_supported: true,
+            bump,
+        };
+
+        supported_token.serialize(&mut &mut supported_token_info.data.borrow_mut()[..])?;
+
+        msg!("Token added: {}", token_mint);
+
+        Ok(())
+    }
+
+    fn process_remove_supported_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let supported_token_info = next_account_info(account_info_iter)?;
+
+        Self::load_config_for_role(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+            Role::TokenManager,
+        )?;
+
+        // Update supported token
+        let mut supported_token = Self::load_supported_token(program_id, supported_token_info)?;
+        supported_token.is_supported = false;
+
+        supported_token.serialize(&mut &mut supported_token_info.data.borrow_mut()[..])?;
+
+        msg!("Token removed: {}", supported_token.mint);
+
+        Ok(())
+    }
+
+    fn process_update_fee_treasury(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_fee_treasury: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_config_for_role(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+            Role::TreasuryManager,
+        )?;
+
+        config.fee_treasury = new_fee_treasury;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Fee treasury updated to: {}", new_fee_treasury);
 
         Ok(())
     }
+
+    fn process_update_refund_operator(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_refund_operator: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        config.refund_operator = new_refund_operator;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Refund operator updated to: {}", new_refund_operator);
+
+        Ok(())
+    }
+
+    fn process_update_escrow_settings(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        arbitrator: Pubkey,
+        dispute_window_secs: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        config.arbitrator = arbitrator;
+        config.dispute_window_secs = dispute_window_secs;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Arbitrator updated to: {}", arbitrator);
+        msg!("Dispute window updated to: {} seconds", dispute_window_secs);
+
+        Ok(())
+    }
+
+    fn process_pause(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_config_for_role(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+            Role::Pauser,
+        )?;
+
+        config.is_paused = true;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Contract paused");
+
+        Ok(())
+    }
+
+    fn process_unpause(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_config_for_role(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+            Role::Pauser,
+        )?;
+
+        config.is_paused = false;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Contract unpaused");
+
+        Ok(())
+    }
+
+    fn process_propose_authority(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_authority: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        if new_authority == Pubkey::default() {
+            return Err(PaymentError::InvalidPendingAuthority.into());
+        }
+
+        config.pending_authority = new_authority;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Authority transfer proposed to: {}", new_authority);
+
+        Ok(())
+    }
+
+    fn process_accept_authority(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let pending_authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        if !pending_authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut config = Self::load_config(program_id, config_info)?;
+
+        if config.pending_authority == Pubkey::default() {
+            return Err(PaymentError::NoPendingAuthority.into());
+        }
+        if config.pending_authority != *pending_authority_info.key {
+            return Err(PaymentError::NotPendingAuthority.into());
+        }
+
+        let old_authority = config.authority;
+        config.authority = config.pending_authority;
+        config.pending_authority = Pubkey::default();
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Authority transferred from {} to {}", old_authority, config.authority);
+
+        Ok(())
+    }
+
+    fn process_cancel_authority_transfer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        if config.pending_authority == Pubkey::default() {
+            return Err(PaymentError::NoPendingAuthority.into());
+        }
+
+        msg!("Authority transfer to {} cancelled", config.pending_authority);
+
+        config.pending_authority = Pubkey::default();
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    fn process_migrate_config(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if !authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Self::check_system_program(system_program_info)?;
+
+        if config_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        if config_info.data_len() != LegacyPaymentConfig::LEN {
+            return Err(PaymentError::ConfigAlreadyMigrated.into());
+        }
+
+        let legacy = LegacyPaymentConfig::try_from_slice(&config_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidConfigAccount)?;
+
+        let config_pda = Pubkey::create_program_address(&[b"config", &[legacy.bump]], program_id)
+            .map_err(|_| PaymentError::InvalidConfigAccount)?;
+        if config_pda != *config_info.key {
+            return Err(PaymentError::InvalidConfigAccount.into());
+        }
+
+        if legacy.authority != *authority_info.key {
+            return Err(PaymentError::NotAuthorized.into());
+        }
+
+        // Top up rent for the larger account, then grow it
+        let space = PaymentConfig::LEN;
+        let lamports = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(config_info.lamports());
+        if lamports > 0 {
+            invoke(
+                &system_instruction::transfer(authority_info.key, config_info.key, lamports),
+                &[authority_info.clone(), config_info.clone(), system_program_info.clone()],
+            )?;
+        }
+        config_info.resize(space)?;
+
+        let config = PaymentConfig {
+            authority: legacy.authority,
+            pending_authority: Pubkey::default(),
+            multisig_signers: Vec::new(),
+            multisig_threshold: 0,
+            fee_treasury: legacy.hot_wallet,
+            refund_operator: Pubkey::default(),
+            arbitrator: Pubkey::default(),
+            quote_signer: Pubkey::default(),
+            dispute_window_secs: PaymentConfig::DEFAULT_DISPUTE_WINDOW_SECS,
+            payout_wallet_delay_secs: PaymentConfig::DEFAULT_CHANGE_DELAY_SECS,
+            platform_fee_delay_secs: PaymentConfig::DEFAULT_CHANGE_DELAY_SECS,
+            max_quote_lifetime_secs: PaymentConfig::DEFAULT_MAX_QUOTE_LIFETIME_SECS,
+            order_retention_secs: PaymentConfig::DEFAULT_ORDER_RETENTION_SECS,
+            platform_fee_bps: legacy.platform_fee_bps,
+            max_platform_fee_bps: legacy.max_platform_fee_bps,
+            max_commission_bps: legacy.max_commission_bps,
+            max_total_fee_bps: PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS,
+            is_paused: legacy.is_paused,
+            bump: legacy.bump,
+        };
+
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Payment config migrated to {} bytes", space);
+
+        Ok(())
+    }
+
+    fn process_initialize_roles(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let roles_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        Self::check_system_program(system_program_info)?;
+
+        let (roles_pda, bump) = Pubkey::find_program_address(&[b"roles"], program_id);
+        if roles_pda != *roles_info.key {
+            return Err(PaymentError::InvalidRolesAccount.into());
+        }
+
+        if roles_info.data_len() > 0 {
+            return Err(PaymentError::AlreadyInitialized.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = Roles::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                authority_info.key,
+                roles_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[authority_info.clone(), roles_info.clone(), system_program_info.clone()],
+            &[&[b"roles", &[bump]]],
+        )?;
+
+        let roles = Roles {
+            members: Vec::new(),
+            bump,
+        };
+        roles.serialize(&mut &mut roles_info.data.borrow_mut()[..])?;
+
+        msg!("Roles initialized");
+
+        Ok(())
+    }
+
+    fn process_grant_role(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        member: Pubkey,
+        role: Role,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let roles_info = next_account_info(account_info_iter)?;
+
+        Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        let mut roles = Self::load_roles(program_id, roles_info)?;
+
+        if !roles.grant(member, role) {
+            return Err(PaymentError::RolesFull.into());
+        }
+        roles.serialize(&mut &mut roles_info.data.borrow_mut()[..])?;
+
+        msg!("Role {:?} granted to: {}", role, member);
+
+        Ok(())
+    }
+
+    fn process_revoke_role(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        member: Pubkey,
+        role: Role,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let roles_info = next_account_info(account_info_iter)?;
+
+        Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        let mut roles = Self::load_roles(program_id, roles_info)?;
+
+        if !roles.revoke(&member, role) {
+            return Err(PaymentError::RoleNotGranted.into());
+        }
+
+        roles.serialize(&mut &mut roles_info.data.borrow_mut()[..])?;
+
+        msg!("Role {:?} revoked from: {}", role, member);
+
+        Ok(())
+    }
+
+    fn process_queue_config_change(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        change: ConfigChange,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let proposer_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let pending_change_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if !proposer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config = Self::load_config(program_id, config_info)?;
+        match change.role(&config) {
+            Some(role) if !config.is_admin(proposer_info.key) => {
+                Self::check_role(program_id, proposer_info, account_info_iter, role)?
+            }
+            _ => Self::check_admin(&config, proposer_info, account_info_iter)?,
+        }
+        Self::check_system_program(system_program_info)?;
+
+        if let ConfigChange::PlatformFee { new_fee_bps } = change {
+            if new_fee_bps > config.max_platform_fee_bps {
+                return Err(PaymentError::InvalidPlatformFee.into());
+            }
+            check_total_fee(new_fee_bps, 0, config.max_total_fee_bps)
+                .map_err(PaymentError::from)?;
+        }
+
+        // Only payout wallet changes name a merchant; for the others the empty seed adds nothing
+        let kind = [change.kind()];
+        let merchant_seed = change
+            .merchant()
+            .map_or(&[][..], |merchant| merchant.as_ref());
+        let (pending_change_pda, bump) =
+            Pubkey::find_program_address(&[b"pending_change", &kind, merchant_seed], program_id);
+        if pending_change_pda != *pending_change_info.key {
+            return Err(PaymentError::InvalidPendingChangeAccount.into());
+        }
+
+        if pending_change_info.data_len() > 0 {
+            return Err(PaymentError::ChangeAlreadyQueued.into());
+        }
+
+        let queued_at = Clock::get()?.unix_timestamp;
+        let execute_after = queued_at
+            .checked_add(change.delay_secs(&config) as i64)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+
+        let rent = Rent::get()?;
+        let space = PendingChange::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                proposer_info.key,
+                pending_change_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[
+                proposer_info.clone(),
+                pending_change_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[b"pending_change", &kind, merchant_seed, &[bump]]],
+        )?;
+
+        let pending_change = PendingChange {
+            change,
+            proposer: *proposer_info.key,
+            queued_at,
+            execute_after,
+            bump,
+        };
+        pending_change.serialize(&mut &mut pending_change_info.data.borrow_mut()[..])?;
+
+        msg!("Config change queued: {:?}", change);
+        msg!("Executable after: {}", execute_after);
+
+        Ok(())
+    }
+
+    fn process_execute_config_change(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let config_info = next_account_info(account_info_iter)?;
+        let pending_change_info = next_account_info(account_info_iter)?;
+        let proposer_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_config(program_id, config_info)?;
+        let pending_change = Self::load_pending_change(program_id, pending_change_info)?;
+
+        if pending_change.proposer != *proposer_info.key {
+            return Err(PaymentError::InvalidProposer.into());
+        }
+
+        if Clock::get()?.unix_timestamp < pending_change.execute_after {
+            return Err(PaymentError::TimelockNotExpired.into());
+        }
+
+        match pending_change.change {
+            ConfigChange::PayoutWallet {
+                merchant,
+                new_payout_wallet,
+            } => {
+                let merchant_info = next_account_info(account_info_iter)?;
+                if *merchant_info.key != merchant {
+                    return Err(PaymentError::InvalidMerchantAccount.into());
+                }
+                let mut merchant = Self::load_merchant(program_id, merchant_info)?;
+                merchant.payout_wallet = new_payout_wallet;
+                merchant.serialize(&mut &mut merchant_info.data.borrow_mut()[..])?;
+                msg!("Merchant updated: {}", merchant_info.key);
+                msg!("Payout wallet: {}", new_payout_wallet);
+            }
+            ConfigChange::PlatformFee { new_fee_bps } => {
+                if new_fee_bps > config.max_platform_fee_bps {
+                    return Err(PaymentError::InvalidPlatformFee.into());
+                }
+                check_total_fee(new_fee_bps, 0, config.max_total_fee_bps)
+                    .map_err(PaymentError::from)?;
+                config.platform_fee_bps = new_fee_bps;
+                msg!("Platform fee updated to: {} bps", new_fee_bps);
+            }
+            ConfigChange::TimelockDelays {
+                payout_wallet_delay_secs,
+                platform_fee_delay_secs,
+            } => {
+                config.payout_wallet_delay_secs = payout_wallet_delay_secs;
+                config.platform_fee_delay_secs = platform_fee_delay_secs;
+                msg!(
+                    "Payout wallet delay updated to: {} seconds",
+                    payout_wallet_delay_secs
+                );
+                msg!("Platform fee delay updated to: {} seconds", platform_fee_delay_secs);
+            }
+        }
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        Self::close_account(pending_change_info, proposer_info)?;
+
+        msg!("Config change executed: {:?}", pending_change.change);
+
+        Ok(())
+    }
+
+    fn process_cancel_config_change(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let pending_change_info = next_account_info(account_info_iter)?;
+        let proposer_info = next_account_info(account_info_iter)?;
+
+        Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        let pending_change = Self::load_pending_change(program_id, pending_change_info)?;
+
+        if pending_change.proposer != *proposer_info.key {
+            return Err(PaymentError::InvalidProposer.into());
+        }
+
+        Self::close_account(pending_change_info, proposer_info)?;
+
+        msg!("Config change cancelled: {:?}", pending_change.change);
+
+        Ok(())
+    }
+
+    fn process_set_multisig(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        if !PaymentConfig::is_valid_multisig(&signers, threshold) {
+            return Err(PaymentError::InvalidMultisig.into());
+        }
+
+        config.multisig_signers = signers;
+        config.multisig_threshold = threshold;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!(
+            "Multisig set to {} of {}",
+            threshold,
+            config.multisig_signers.len()
+        );
+
+        Ok(())
+    }
+
+    fn process_set_quote_signer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quote_signer: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        config.quote_signer = quote_signer;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Quote signer updated to: {}", quote_signer);
+
+        Ok(())
+    }
+
+    fn process_set_max_quote_lifetime(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_quote_lifetime_secs: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        config.max_quote_lifetime_secs = max_quote_lifetime_secs;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Max quote lifetime updated to: {} seconds", max_quote_lifetime_secs);
+
+        Ok(())
+    }
+
+    fn process_close_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let processed_order_info = next_account_info(account_info_iter)?;
+        let buyer_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let closed_order_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        let config = Self::load_config(program_id, config_info)?;
+        let processed_order = Self::load_processed_order(program_id, processed_order_info)?;
+
+        if processed_order.buyer != *buyer_info.key {
+            return Err(PaymentError::NotAuthorized.into());
+        }
+
+        // Escrowed and disputed orders still hold funds
+        if !processed_order.is_settled() {
+            return Err(PaymentError::InvalidOrderStatus.into());
+        }
+
+        let closable_at = processed_order
+            .timestamp
+            .saturating_add(i64::from(config.order_retention_secs));
+        if Clock::get()?.unix_timestamp < closable_at {
+            return Err(PaymentError::RetentionPeriodActive.into());
+        }
+
+        Self::check_system_program(system_program_info)?;
+        let order_id_hash = processed_order.order_id_hash;
+        let closed_order_bump =
+            Self::check_closed_order_account(program_id, closed_order_info, &order_id_hash)?;
+
+        // Released and returned orders went through escrow, and their vault goes too
+        if matches!(
+            processed_order.status,
+            OrderStatus::Released | OrderStatus::ReturnedToBuyer
+        ) {
+            let vault_info = next_account_info(account_info_iter)?;
+            let token_mint_info = next_account_info(account_info_iter)?;
+            let token_program_info = next_account_info(account_info_iter)?;
+            let buyer_token_info = next_account_info(account_info_iter)?;
+            let transfer = TransferAccounts {
+                token_program_info,
+                mint_info: token_mint_info,
+                extra_account_infos: account_info_iter.as_slice(),
+            };
+            Self::check_token_program(token_program_info, token_mint_info)?;
+            Self::check_order_mint(&processed_order, token_mint_info)?;
+
+            Self::close_vault(
+                program_id,
+                &processed_order,
+                processed_order_info,
+                vault_info,
+                buyer_info,
+                buyer_token_info,
+                &transfer,
+            )?;
+        }
+
+        // Mark the order ID before the record goes, so it cannot be paid again
+        Self::mark_order_closed(
+            program_id,
+            processed_order_info,
+            closed_order_info,
+            system_program_info,
+            &[b"closed_order", &order_id_hash, &[closed_order_bump]],
+        )?;
+        Self::close_account(processed_order_info, buyer_info)?;
+
+        msg!("Order closed");
+        msg!("Order ID Hash: {:?}", order_id_hash);
+        msg!("Rent Returned To: {}", buyer_info.key);
+
+        Ok(())
+    }
+
+    fn process_set_order_retention(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_retention_secs: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        config.order_retention_secs = order_retention_secs;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Order retention updated to: {} seconds", order_retention_secs);
+
+        Ok(())
+    }
+
+    fn process_set_max_total_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_total_fee_bps: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+
+        let mut config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+
+        if max_total_fee_bps > PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS
+            || max_total_fee_bps < config.platform_fee_bps
+        {
+            return Err(PaymentError::InvalidMaxTotalFee.into());
+        }
+
+        config.max_total_fee_bps = max_total_fee_bps;
+        config.serialize(&mut &mut config_info.data.borrow_mut()[..])?;
+
+        msg!("Max total fee updated to: {} bps", max_total_fee_bps);
+
+        Ok(())
+    }
+
+    fn process_register_merchant(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        merchant_id: [u8; 32],
+        payout_wallet: Pubkey,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        let config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        Self::check_system_program(system_program_info)?;
+        Self::check_merchant_settings(&config, platform_fee_bps, &allowed_mints)?;
+
+        let (merchant_pda, bump) =
+            Pubkey::find_program_address(&[b"merchant", &merchant_id], program_id);
+        if merchant_pda != *merchant_info.key {
+            return Err(PaymentError::InvalidMerchantAccount.into());
+        }
+
+        if merchant_info.data_len() > 0 {
+            return Err(PaymentError::AlreadyInitialized.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = Merchant::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                authority_info.key,
+                merchant_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[
+                authority_info.clone(),
+                merchant_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[b"merchant", &merchant_id, &[bump]]],
+        )?;
+
+        let merchant = Merchant {
+            merchant_id,
+            payout_wallet,
+            status: MerchantStatus::Active,
+            platform_fee_bps,
+            allowed_mints,
+            bump,
+        };
+        merchant.serialize(&mut &mut merchant_info.data.borrow_mut()[..])?;
+
+        msg!("Merchant registered: {}", merchant_info.key);
+        msg!("Payout wallet: {}", payout_wallet);
+
+        Ok(())
+    }
+
+    fn process_update_merchant(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let merchant_info = next_account_info(account_info_iter)?;
+
+        let config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        Self::check_merchant_settings(&config, platform_fee_bps, &allowed_mints)?;
+
+        let mut merchant = Self::load_merchant(program_id, merchant_info)?;
+        merchant.status = status;
+        merchant.platform_fee_bps = platform_fee_bps;
+        merchant.allowed_mints = allowed_mints;
+        merchant.serialize(&mut &mut merchant_info.data.borrow_mut()[..])?;
+
+        msg!("Merchant updated: {}", merchant_info.key);
+        msg!("Status: {:?}", status);
+
+        Ok(())
+    }
+
+    fn process_register_affiliate(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        api_key_hash: [u8; 32],
+        owner: Pubkey,
+        commission_bps: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        let config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        Self::check_system_program(system_program_info)?;
+        Self::check_affiliate_commission(&config, commission_bps)?;
+
+        let (affiliate_pda, bump) =
+            Pubkey::find_program_address(&[b"affiliate", &api_key_hash], program_id);
+        if affiliate_pda != *affiliate_info.key {
+            return Err(PaymentError::InvalidAffiliateAccount.into());
+        }
+
+        if affiliate_info.data_len() > 0 {
+            return Err(PaymentError::AlreadyInitialized.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = Affiliate::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                authority_info.key,
+                affiliate_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[
+                authority_info.clone(),
+                affiliate_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[b"affiliate", &api_key_hash, &[bump]]],
+        )?;
+
+        let affiliate = Affiliate {
+            api_key_hash,
+            owner,
+            commission_bps,
+            status: AffiliateStatus::Active,
+            total_earnings: 0,
+            bump,
+        };
+        affiliate.serialize(&mut &mut affiliate_info.data.borrow_mut()[..])?;
+
+        msg!("Affiliate registered: {}", affiliate_info.key);
+        msg!("Owner: {}", owner);
+        msg!("Commission: {} bps", commission_bps);
+
+        Ok(())
+    }
+
+    fn process_update_affiliate(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        owner: Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let config_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+
+        let config = Self::load_authorized_config(
+            program_id,
+            authority_info,
+            config_info,
+            account_info_iter,
+        )?;
+        Self::check_affiliate_commission(&config, commission_bps)?;
+
+        let mut affiliate = Self::load_affiliate(program_id, affiliate_info)?;
+        affiliate.owner = owner;
+        affiliate.commission_bps = commission_bps;
+        affiliate.status = status;
+        affiliate.serialize(&mut &mut affiliate_info.data.borrow_mut()[..])?;
+
+        msg!("Affiliate updated: {}", affiliate_info.key);
+        msg!("Status: {:?}", status);
+
+        Ok(())
+    }
+
+    fn process_claim_commission(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let ledger_info = next_account_info(account_info_iter)?;
+        let vault_info = next_account_info(account_info_iter)?;
+        let owner_token_info = next_account_info(account_info_iter)?;
+        let token_mint_info = next_account_info(account_info_iter)?;
+        let token_program_info = next_account_info(account_info_iter)?;
+        let transfer = TransferAccounts {
+            token_program_info,
+            mint_info: token_mint_info,
+            extra_account_infos: account_info_iter.as_slice(),
+        };
+
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Self::check_token_program(token_program_info, token_mint_info)?;
+
+        let affiliate = Self::load_affiliate(program_id, affiliate_info)?;
+        if affiliate.owner != *owner_info.key {
+            return Err(PaymentError::NotAuthorized.into());
+        }
+        if affiliate.status != AffiliateStatus::Active {
+            return Err(PaymentError::AffiliateSuspended.into());
+        }
+
+        let mut ledger = Self::load_commission_ledger(
+            program_id,
+            affiliate_info.key,
+            token_mint_info.key,
+            ledger_info,
+        )?;
+        Self::load_commission_vault(program_id, &ledger, ledger_info, vault_info)?;
+        Self::load_token_account(
+            owner_token_info,
+            token_mint_info.key,
+            owner_info.key,
+            PaymentError::InvalidCommissionTokenAccount,
+        )?;
+
+        let amount = ledger
+            .accrued
+            .checked_sub(ledger.claimed)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        if amount == 0 {
+            return Err(PaymentError::NoCommissionToClaim.into());
+        }
+
+        Self::transfer_tokens(
+            &transfer,
+            vault_info,
+            owner_token_info,
+            ledger_info,
+            &[&[
+                b"commission",
+                affiliate_info.key.as_ref(),
+                token_mint_info.key.as_ref(),
+                &[ledger.bump],
+            ]],
+            amount,
+        )?;
+
+        let clock = Clock::get()?;
+        ledger.claimed = ledger.accrued;
+        ledger.last_claim_at = clock.unix_timestamp;
+        ledger.serialize(&mut &mut ledger_info.data.borrow_mut()[..])?;
+
+        msg!("Commission claimed");
+        msg!("Affiliate: {}", affiliate_info.key);
+        msg!("Mint: {}", ledger.mint);
+        msg!("Amount: {}", amount);
+
+        Ok(())
+    }
+
+    fn process_claim_sol_commission(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+        let affiliate_info = next_account_info(account_info_iter)?;
+        let ledger_info = next_account_info(account_info_iter)?;
+
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let affiliate = Self::load_affiliate(program_id, affiliate_info)?;
+        if affiliate.owner != *owner_info.key {
+            return Err(PaymentError::NotAuthorized.into());
+        }
+        if affiliate.status != AffiliateStatus::Active {
+            return Err(PaymentError::AffiliateSuspended.into());
+        }
+
+        let mut ledger =
+            Self::load_sol_commission_ledger(program_id, affiliate_info.key, ledger_info)?;
+
+        let amount = ledger
+            .accrued
+            .checked_sub(ledger.claimed)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        if amount == 0 {
+            return Err(PaymentError::NoCommissionToClaim.into());
+        }
+
+        // The ledger is owned by this program, so its lamports move without a system transfer
+        **ledger_info.try_borrow_mut_lamports()? = ledger_info
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        **owner_info.try_borrow_mut_lamports()? = owner_info
+            .lamports()
+            .checked_add(amount)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+
+        let clock = Clock::get()?;
+        ledger.claimed = ledger.accrued;
+        ledger.last_claim_at = clock.unix_timestamp;
+        ledger.serialize(&mut &mut ledger_info.data.borrow_mut()[..])?;
+
+        msg!("SOL commission claimed");
+        msg!("Affiliate: {}", affiliate_info.key);
+        msg!("Amount: {}", amount);
+
+        Ok(())
+    }
+
+    /// Load the config and validate a payment's token, amount and commission against it.
+    /// `fee_override` is the paid merchant's platform fee, when it has its own.
+    fn load_payment_config(
+        program_id: &Pubkey,
+        config_info: &AccountInfo,
+        token_mint_info: &AccountInfo,
+        supported_token_info: &AccountInfo,
+        amount: u64,
+        commission_bps: u16,
+        fee_override: Option<u16>,
+    ) -> Result<PaymentConfig, ProgramError> {
+        let config = Self::load_config(program_id, config_info)?;
+
+        if config.is_paused {
+            return Err(PaymentError::ContractPaused.into());
+        }
+
+        // Check if token is supported
+        Self::load_mint(token_mint_info)?;
+        let supported_token = Self::load_supported_token(program_id, supported_token_info)?;
+        if !supported_token.is_supported || supported_token.mint != *token_mint_info.key {
+            return Err(PaymentError::TokenNotSupported.into());
+        }
+
+        // Validate amount
+        if amount == 0 {
+            return Err(PaymentError::InvalidAmount.into());
+        }
+
+        // Validate commission
+        if commission_bps > config.max_commission_bps {
+            return Err(PaymentError::InvalidCommissionRate.into());
+        }
+        let platform_fee_bps = fee_override.unwrap_or(config.platform_fee_bps);
+        check_total_fee(platform_fee_bps, commission_bps, config.max_total_fee_bps)
+            .map_err(PaymentError::from)?;
+
+        Ok(config)
+    }
+
+    /// Check the processed order account is the unused PDA for `order_id` and the order was
+    /// never closed, returning its hash and bump
+    fn check_new_order(
+        program_id: &Pubkey,
+        processed_order_info: &AccountInfo,
+        closed_order_info: &AccountInfo,
+        order_id: &str,
+    ) -> Result<([u8; 32], u8), ProgramError> {
+        let order_id_hash = order_id_hash(order_id);
+        let (order_pda, order_bump) =
+            Pubkey::find_program_address(&[b"order", &order_id_hash], program_id);
+
+        if order_pda != *processed_order_info.key {
+            return Err(PaymentError::InvalidOrderAccount.into());
+        }
+
+        if processed_order_info.data_len() > 0 {
+            return Err(PaymentError::OrderAlreadyProcessed.into());
+        }
+
+        Self::check_closed_order_account(program_id, closed_order_info, &order_id_hash)?;
+        if closed_order_info.owner == program_id {
+            return Err(PaymentError::OrderAlreadyProcessed.into());
+        }
+
+        Ok((order_id_hash, order_bump))
+    }
+
+    /// Check the closed order account is the PDA for `order_id_hash`, returning its bump
+    fn check_closed_order_account(
+        program_id: &Pubkey,
+        closed_order_info: &AccountInfo,
+        order_id_hash: &[u8; 32],
+    ) -> Result<u8, ProgramError> {
+        let (closed_order_pda, bump) =
+            Pubkey::find_program_address(&[b"closed_order", order_id_hash], program_id);
+        if closed_order_pda != *closed_order_info.key {
+            return Err(PaymentError::InvalidClosedOrderAccount.into());
+        }
+
+        Ok(bump)
+    }
+
+    /// Split `amount` into (platform fee, commission, net amount), rounding shares down
+    fn calculate_fees(
+        platform_fee_bps: u16,
+        amount: u64,
+        commission_bps: u16,
+    ) -> Result<(u64, u64, u64), ProgramError> {
+        let split =
+            split_payment(amount, platform_fee_bps, commission_bps).map_err(PaymentError::from)?;
+
+        Ok((split.platform_fee, split.commission, split.net_amount))
+    }
+
+    /// Create the processed order PDA, paid for by `payer_info`, and write `processed_order` to it
+    fn create_processed_order<'a>(
+        program_id: &Pubkey,
+        payer_info: &AccountInfo<'a>,
+        processed_order_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        processed_order: &ProcessedOrder,
+    ) -> ProgramResult {
+        let rent = Rent::get()?;
+        let space = ProcessedOrder::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                processed_order_info.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[
+                payer_info.clone(),
+                processed_order_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[
+                b"order",
+                &processed_order.order_id_hash,
+                &[processed_order.bump],
+            ]],
+        )?;
+
+        processed_order.serialize(&mut &mut processed_order_info.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Create a token account for the transferred mint at the PDA `signer_seeds` derive,
+    /// with `owner` as its authority
+    fn create_token_account<'a>(
+        payer_info: &AccountInfo<'a>,
+        token_account_info: &AccountInfo<'a>,
+        owner: &Pubkey,
+        transfer: &TransferAccounts<'a, '_>,
+        system_program_info: &AccountInfo<'a>,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        // Token-2022 mints may require extensions on every account holding them
+        let len = {
+            let mint_data = transfer.mint_info.try_borrow_data()?;
+            let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+            let extension_types =
+                ExtensionType::get_required_init_account_extensions(&mint.get_extension_types()?);
+            ExtensionType::try_calculate_account_len::<TokenAccount>(&extension_types)?
+        };
+
+        let rent = Rent::get()?;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                token_account_info.key,
+                rent.minimum_balance(len),
+                len as u64,
+                transfer.token_program_info.key,
+            ),
+            &[
+                payer_info.clone(),
+                token_account_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+
+        invoke(
+            &spl_token_2022::instruction::initialize_account3(
+                transfer.token_program_info.key,
+                token_account_info.key,
+                transfer.mint_info.key,
+                owner,
+            )?,
+            &[token_account_info.clone(), transfer.mint_info.clone()],
+        )
+    }
+
+    /// Load the affiliate's commission ledger for the transferred mint, creating the ledger
+    /// and its vault on the affiliate's first payment in that mint
+    fn open_commission_ledger<'a>(
+        program_id: &Pubkey,
+        payer_info: &AccountInfo<'a>,
+        affiliate_info: &AccountInfo<'a>,
+        ledger_info: &AccountInfo<'a>,
+        vault_info: &AccountInfo<'a>,
+        transfer: &TransferAccounts<'a, '_>,
+        system_program_info: &AccountInfo<'a>,
+    ) -> Result<CommissionLedger, ProgramError> {
+        let mint = transfer.mint_info.key;
+        if !ledger_info.data_is_empty() {
+            let ledger =
+                Self::load_commission_ledger(program_id, affiliate_info.key, mint, ledger_info)?;
+            Self::load_commission_vault(program_id, &ledger, ledger_info, vault_info)?;
+            return Ok(ledger);
+        }
+
+        let (ledger_pda, bump) = Pubkey::find_program_address(
+            &[b"commission", affiliate_info.key.as_ref(), mint.as_ref()],
+            program_id,
+        );
+        if ledger_pda != *ledger_info.key {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        let (vault_pda, vault_bump) = Pubkey::find_program_address(
+            &[b"commission_vault", affiliate_info.key.as_ref(), mint.as_ref()],
+            program_id,
+        );
+        if vault_pda != *vault_info.key {
+            return Err(PaymentError::InvalidCommissionVaultAccount.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = CommissionLedger::LEN;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                ledger_info.key,
+                rent.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[
+                payer_info.clone(),
+                ledger_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[b"commission", affiliate_info.key.as_ref(), mint.as_ref(), &[bump]]],
+        )?;
+
+        Self::create_token_account(
+            payer_info,
+            vault_info,
+            ledger_info.key,
+            transfer,
+            system_program_info,
+            &[b"commission_vault", affiliate_info.key.as_ref(), mint.as_ref(), &[vault_bump]],
+        )?;
+
+        let ledger = CommissionLedger {
+            affiliate: *affiliate_info.key,
+            mint: *mint,
+            accrued: 0,
+            claimed: 0,
+            last_claim_at: 0,
+            bump,
+        };
+        ledger.serialize(&mut &mut ledger_info.data.borrow_mut()[..])?;
+
+        Ok(ledger)
+    }
+
+    /// Load the affiliate's SOL commission ledger, creating it on the affiliate's first SOL
+    /// payment. The ledger holds the unclaimed commission in lamports on top of its rent.
+    fn open_sol_commission_ledger<'a>(
+        program_id: &Pubkey,
+        payer_info: &AccountInfo<'a>,
+        affiliate_info: &AccountInfo<'a>,
+        ledger_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+    ) -> Result<CommissionLedger, ProgramError> {
+        if !ledger_info.data_is_empty() {
+            return Self::load_sol_commission_ledger(program_id, affiliate_info.key, ledger_info);
+        }
+
+        let (ledger_pda, bump) = Pubkey::find_program_address(
+            &[b"sol_commission", affiliate_info.key.as_ref()],
+            program_id,
+        );
+        if ledger_pda != *ledger_info.key {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = CommissionLedger::LEN;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                ledger_info.key,
+                rent.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[
+                payer_info.clone(),
+                ledger_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[&[b"sol_commission", affiliate_info.key.as_ref(), &[bump]]],
+        )?;
+
+        let ledger = CommissionLedger {
+            affiliate: *affiliate_info.key,
+            mint: spl_token::native_mint::id(),
+            accrued: 0,
+            claimed: 0,
+            last_claim_at: 0,
+            bump,
+        };
+        ledger.serialize(&mut &mut ledger_info.data.borrow_mut()[..])?;
+
+        Ok(ledger)
+    }
+
+    /// Verify the fee treasury and commission vault token accounts use `mint` and belong to
+    /// the configured wallet and `commission_ledger`; callers check the net amount's recipient
+    fn check_payout_accounts(
+        config: &PaymentConfig,
+        mint: &Pubkey,
+        payout: &PayoutAccounts,
+        commission_ledger: &Pubkey,
+        commission: u64,
+    ) -> ProgramResult {
+        Self::load_token_account(
+            payout.fee_treasury_token_info,
+            mint,
+            &config.fee_treasury,
+            PaymentError::InvalidFeeTreasuryTokenAccount,
+        )?;
+        if commission > 0 {
+            Self::load_token_account(
+                payout.commission_vault_info,
+                mint,
+                commission_ledger,
+                PaymentError::InvalidCommissionTokenAccount,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Transfer (net amount, platform fee, commission) from `source_info` to the payout
+    /// accounts, returning the amounts received after any transfer fee
+    fn pay_out<'a>(
+        transfer: &TransferAccounts<'a, '_>,
+        source_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
+        signer_seeds: &[&[&[u8]]],
+        payout: &PayoutAccounts<'a, '_>,
+        (net_amount, platform_fee, commission): (u64, u64, u64),
+    ) -> Result<(u64, u64, u64), ProgramError> {
+        let net_amount = Self::transfer_tokens(
+            transfer,
+            source_info,
+            payout.net_token_info,
+            authority_info,
+            signer_seeds,
+            net_amount,
+        )?;
+        let platform_fee = Self::transfer_tokens(
+            transfer,
+            source_info,
+            payout.fee_treasury_token_info,
+            authority_info,
+            signer_seeds,
+            platform_fee,
+        )?;
+        let commission = Self::transfer_tokens(
+            transfer,
+            source_info,
+            payout.commission_vault_info,
+            authority_info,
+            signer_seeds,
+            commission,
+        )?;
+
+        Ok((net_amount, platform_fee, commission))
+    }
+
+    /// Buyer disputes are accepted up to this unix timestamp
+    fn dispute_deadline(
+        config: &PaymentConfig,
+        processed_order: &ProcessedOrder,
+    ) -> Result<i64, ProgramError> {
+        processed_order
+            .timestamp
+            .checked_add(config.dispute_window_secs as i64)
+            .ok_or_else(|| PaymentError::ArithmeticOverflow.into())
+    }
+
+    /// Check the vault is the order's PDA token account and return the escrowed balance
+    fn load_vault(
+        program_id: &Pubkey,
+        processed_order: &ProcessedOrder,
+        processed_order_info: &AccountInfo,
+        vault_info: &AccountInfo,
+    ) -> Result<u64, ProgramError> {
+        let (vault_pda, _) =
+            Pubkey::find_program_address(&[b"vault", &processed_order.order_id_hash], program_id);
+        if vault_pda != *vault_info.key {
+            return Err(PaymentError::InvalidVaultAccount.into());
+        }
+
+        let vault = Self::load_token_account(
+            vault_info,
+            &processed_order.token_mint,
+            processed_order_info.key,
+            PaymentError::InvalidVaultAccount,
+        )?;
+
+        Ok(vault.amount)
+    }
+
+    /// Pay an escrowed order out of its vault to `merchant`, split like `ProcessPayment`,
+    /// with the commission accruing in the order's affiliate ledger
+    #[allow(clippy::too_many_arguments)]
+    fn release_vault<'a>(
+        program_id: &Pubkey,
+        config: &PaymentConfig,
+        processed_order: &ProcessedOrder,
+        processed_order_info: &AccountInfo<'a>,
+        vault_info: &AccountInfo<'a>,
+        merchant: &Merchant,
+        payout: &PayoutAccounts<'a, '_>,
+        commission_ledger_info: &AccountInfo<'a>,
+        affiliate_info: &AccountInfo<'a>,
+        transfer: &TransferAccounts<'a, '_>,
+    ) -> ProgramResult {
+        Self::load_vault(program_id, processed_order, processed_order_info, vault_info)?;
+        Self::load_token_account(
+            payout.net_token_info,
+            &processed_order.token_mint,
+            &merchant.payout_wallet,
+            PaymentError::InvalidMerchantTokenAccount,
+        )?;
+
+        // The ledger was opened with the escrowed payment
+        let mut commission_accounts = None;
+        if processed_order.commission > 0 {
+            if *affiliate_info.key != processed_order.affiliate {
+                return Err(PaymentError::InvalidAffiliateAccount.into());
+            }
+            let affiliate = Self::load_affiliate(program_id, affiliate_info)?;
+            let ledger = Self::load_commission_ledger(
+                program_id,
+                affiliate_info.key,
+                &processed_order.token_mint,
+                commission_ledger_info,
+            )?;
+            Self::load_commission_vault(
+                program_id,
+                &ledger,
+                commission_ledger_info,
+                payout.commission_vault_info,
+            )?;
+            commission_accounts = Some((affiliate, ledger));
+        }
+        Self::check_payout_accounts(
+            config,
+            &processed_order.token_mint,
+            payout,
+            commission_ledger_info.key,
+            processed_order.commission,
+        )?;
+
+        let net_amount = processed_order
+            .amount
+            .checked_sub(processed_order.platform_fee)
+            .ok_or(PaymentError::ArithmeticOverflow)?
+            .checked_sub(processed_order.commission)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+
+        let (_, _, commission) = Self::pay_out(
+            transfer,
+            vault_info,
+            processed_order_info,
+            &[&[
+                b"order",
+                &processed_order.order_id_hash,
+                &[processed_order.bump],
+            ]],
+            payout,
+            (net_amount, processed_order.platform_fee, processed_order.commission),
+        )?;
+
+        if let Some((mut affiliate, mut ledger)) = commission_accounts {
+            affiliate.total_earnings = affiliate
+                .total_earnings
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            affiliate.serialize(&mut &mut affiliate_info.data.borrow_mut()[..])?;
+            ledger.accrued = ledger
+                .accrued
+                .checked_add(commission)
+                .ok_or(PaymentError::ArithmeticOverflow)?;
+            ledger.serialize(&mut &mut commission_ledger_info.data.borrow_mut()[..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the config PDA, checking it is owned by this program and sits at the canonical address
+    fn load_config(
+        program_id: &Pubkey,
+        config_info: &AccountInfo,
+    ) -> Result<PaymentConfig, ProgramError> {
+        if config_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if config_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        // The account is sized for `MAX_MULTISIG_SIGNERS`, so a shorter signer list leaves
+        // trailing bytes
+        let config = PaymentConfig::deserialize(&mut &config_info.data.borrow()[..])
+            .map_err(|_| PaymentError::InvalidConfigAccount)?;
+
+        let config_pda = Pubkey::create_program_address(&[b"config", &[config.bump]], program_id)
+            .map_err(|_| PaymentError::InvalidConfigAccount)?;
+        if config_pda != *config_info.key {
+            return Err(PaymentError::InvalidConfigAccount.into());
+        }
+
+        Ok(config)
+    }
+
+    /// Load the config and verify the signer is its authority, or that the multisig approved
+    ///
+    /// Multisig signers other than the signer are taken from the rest of `account_info_iter`.
+    fn load_authorized_config<'a, 'b>(
+        program_id: &Pubkey,
+        authority_info: &'b AccountInfo<'a>,
+        config_info: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> Result<PaymentConfig, ProgramError> {
+        // Verify authority
+        if !authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config = Self::load_config(program_id, config_info)?;
+        Self::check_admin(&config, authority_info, account_info_iter)?;
+
+        Ok(config)
+    }
+
+    /// Verify the signer is the authority or, once a multisig is set, that at least
+    /// `multisig_threshold` distinct multisig signers signed among the signer and the
+    /// rest of `account_info_iter`
+    fn check_admin<'a, 'b>(
+        config: &PaymentConfig,
+        signer_info: &'b AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> ProgramResult {
+        if config.multisig_threshold == 0 {
+            if config.authority != *signer_info.key {
+                return Err(PaymentError::NotAuthorized.into());
+            }
+            return Ok(());
+        }
+
+        let mut approvals: Vec<&Pubkey> = Vec::new();
+        for account_info in std::iter::once(signer_info).chain(account_info_iter) {
+            if account_info.is_signer
+                && config.multisig_signers.contains(account_info.key)
+                && !approvals.contains(&account_info.key)
+            {
+                approvals.push(account_info.key);
+            }
+        }
+
+        if approvals.len() < usize::from(config.multisig_threshold) {
+            return Err(PaymentError::MultisigThresholdNotMet.into());
+        }
+
+        Ok(())
+    }
+
+    /// Load the config and verify the signer is the admin or holds `role`
+    ///
+    /// When the signer is the authority or a multisig signer, the rest of `account_info_iter`
+    /// holds the other multisig signers; otherwise its next account is the roles PDA.
+    fn load_config_for_role<'a, 'b>(
+        program_id: &Pubkey,
+        signer_info: &'b AccountInfo<'a>,
+        config_info: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        role: Role,
+    ) -> Result<PaymentConfig, ProgramError> {
+        if !signer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config = Self::load_config(program_id, config_info)?;
+        if config.is_admin(signer_info.key) {
+            Self::check_admin(&config, signer_info, account_info_iter)?;
+        } else {
+            Self::check_role(program_id, signer_info, account_info_iter, role)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Verify the signer holds `role` in the roles PDA, the next account in `account_info_iter`
+    fn check_role<'a, 'b>(
+        program_id: &Pubkey,
+        signer_info: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        role: Role,
+    ) -> ProgramResult {
+        let roles_info =
+            next_account_info(account_info_iter).map_err(|_| PaymentError::NotAuthorized)?;
+        let roles = Self::load_roles(program_id, roles_info)?;
+        if !roles.has_role(signer_info.key, role) {
+            return Err(PaymentError::NotAuthorized.into());
+        }
+
+        Ok(())
+    }
+
+    /// Load the roles PDA, checking it is owned by this program and sits at the canonical address
+    fn load_roles(program_id: &Pubkey, roles_info: &AccountInfo) -> Result<Roles, ProgramError> {
+        if roles_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if roles_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        // The account is sized for `MAX_MEMBERS`, so the member list leaves trailing zeroes
+        let roles = Roles::deserialize(&mut &roles_info.data.borrow()[..])
+            .map_err(|_| PaymentError::InvalidRolesAccount)?;
+
+        let roles_pda = Pubkey::create_program_address(&[b"roles", &[roles.bump]], program_id)
+            .map_err(|_| PaymentError::InvalidRolesAccount)?;
+        if roles_pda != *roles_info.key {
+            return Err(PaymentError::InvalidRolesAccount.into());
+        }
+
+        Ok(roles)
+    }
+
+    /// Load a merchant PDA, checking it is owned by this program and sits at the canonical
+    /// address for its ID
+    fn load_merchant(
+        program_id: &Pubkey,
+        merchant_info: &AccountInfo,
+    ) -> Result<Merchant, ProgramError> {
+        if merchant_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if merchant_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        // The account is sized for `MAX_ALLOWED_MINTS`, so the mint list leaves trailing zeroes
+        let merchant = Merchant::deserialize(&mut &merchant_info.data.borrow()[..])
+            .map_err(|_| PaymentError::InvalidMerchantAccount)?;
+
+        let merchant_pda = Pubkey::create_program_address(
+            &[b"merchant", &merchant.merchant_id, &[merchant.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidMerchantAccount)?;
+        if merchant_pda != *merchant_info.key {
+            return Err(PaymentError::InvalidMerchantAccount.into());
+        }
+
+        Ok(merchant)
+    }
+
+    /// Load the merchant an order was paid to, checking it is the one the order recorded
+    fn load_order_merchant(
+        program_id: &Pubkey,
+        processed_order: &ProcessedOrder,
+        merchant_info: &AccountInfo,
+    ) -> Result<Merchant, ProgramError> {
+        if *merchant_info.key != processed_order.merchant {
+            return Err(PaymentError::InvalidMerchantAccount.into());
+        }
+
+        Self::load_merchant(program_id, merchant_info)
+    }
+
+    /// Load an affiliate PDA, checking it is owned by this program and sits at the canonical
+    /// address for its API key hash
+    fn load_affiliate(
+        program_id: &Pubkey,
+        affiliate_info: &AccountInfo,
+    ) -> Result<Affiliate, ProgramError> {
+        if affiliate_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if affiliate_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let affiliate = Affiliate::try_from_slice(&affiliate_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidAffiliateAccount)?;
+
+        let affiliate_pda = Pubkey::create_program_address(
+            &[b"affiliate", &affiliate.api_key_hash, &[affiliate.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidAffiliateAccount)?;
+        if affiliate_pda != *affiliate_info.key {
+            return Err(PaymentError::InvalidAffiliateAccount.into());
+        }
+
+        Ok(affiliate)
+    }
+
+    /// Load the affiliate of a payment, passed as the program id for a payment without
+    /// commission; a suspended affiliate cannot earn commission
+    fn load_payment_affiliate(
+        program_id: &Pubkey,
+        affiliate_info: &AccountInfo,
+    ) -> Result<Option<Affiliate>, ProgramError> {
+        if affiliate_info.key == program_id {
+            return Ok(None);
+        }
+
+        let affiliate = Self::load_affiliate(program_id, affiliate_info)?;
+        if affiliate.status != AffiliateStatus::Active {
+            return Err(PaymentError::AffiliateSuspended.into());
+        }
+
+        Ok(Some(affiliate))
+    }
+
+    /// The API key owner and commission rate of a payment through `affiliate`, or the
+    /// default address and no commission without one
+    fn affiliate_commission(affiliate: Option<&Affiliate>) -> (Pubkey, u16) {
+        match affiliate {
+            Some(affiliate) => (affiliate.owner, affiliate.commission_bps),
+            None => (Pubkey::default(), 0),
+        }
+    }
+
+    /// Validate an affiliate's commission rate, which may exceed neither the maximum
+    /// commission nor what the total fee cap leaves after the platform fee
+    fn check_affiliate_commission(config: &PaymentConfig, commission_bps: u16) -> ProgramResult {
+        let max_commission_bps = config
+            .max_commission_bps
+            .min(config.max_total_fee_bps.saturating_sub(config.platform_fee_bps));
+        if commission_bps > max_commission_bps {
+            return Err(PaymentError::InvalidCommissionRate.into());
+        }
+
+        Ok(())
+    }
+
+    /// Load the commission ledger of `affiliate` for `mint`, checking it is owned by this
+    /// program and sits at the canonical address
+    fn load_commission_ledger(
+        program_id: &Pubkey,
+        affiliate: &Pubkey,
+        mint: &Pubkey,
+        ledger_info: &AccountInfo,
+    ) -> Result<CommissionLedger, ProgramError> {
+        if ledger_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if ledger_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let ledger = CommissionLedger::try_from_slice(&ledger_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidCommissionLedgerAccount)?;
+        if ledger.affiliate != *affiliate || ledger.mint != *mint {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        let ledger_pda = Pubkey::create_program_address(
+            &[b"commission", affiliate.as_ref(), mint.as_ref(), &[ledger.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidCommissionLedgerAccount)?;
+        if ledger_pda != *ledger_info.key {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        Ok(ledger)
+    }
+
+    /// Load the SOL commission ledger of `affiliate`, checking it is owned by this program
+    /// and sits at the canonical address
+    fn load_sol_commission_ledger(
+        program_id: &Pubkey,
+        affiliate: &Pubkey,
+        ledger_info: &AccountInfo,
+    ) -> Result<CommissionLedger, ProgramError> {
+        if ledger_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if ledger_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let ledger = CommissionLedger::try_from_slice(&ledger_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidCommissionLedgerAccount)?;
+        if ledger.affiliate != *affiliate || ledger.mint != spl_token::native_mint::id() {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        let ledger_pda = Pubkey::create_program_address(
+            &[b"sol_commission", affiliate.as_ref(), &[ledger.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidCommissionLedgerAccount)?;
+        if ledger_pda != *ledger_info.key {
+            return Err(PaymentError::InvalidCommissionLedgerAccount.into());
+        }
+
+        Ok(ledger)
+    }
+
+    /// Check the vault is the ledger's PDA token account
+    fn load_commission_vault(
+        program_id: &Pubkey,
+        ledger: &CommissionLedger,
+        ledger_info: &AccountInfo,
+        vault_info: &AccountInfo,
+    ) -> ProgramResult {
+        let (vault_pda, _) = Pubkey::find_program_address(
+            &[b"commission_vault", ledger.affiliate.as_ref(), ledger.mint.as_ref()],
+            program_id,
+        );
+        if vault_pda != *vault_info.key {
+            return Err(PaymentError::InvalidCommissionVaultAccount.into());
+        }
+
+        Self::load_token_account(
+            vault_info,
+            &ledger.mint,
+            ledger_info.key,
+            PaymentError::InvalidCommissionVaultAccount,
+        )?;
+
+        Ok(())
+    }
+
+    /// Check the merchant is active and takes payments in `mint`
+    fn check_merchant_accepts(merchant: &Merchant, mint: &Pubkey) -> ProgramResult {
+        if merchant.status != MerchantStatus::Active {
+            return Err(PaymentError::MerchantSuspended.into());
+        }
+
+        if !merchant.allowed_mints.is_empty() && !merchant.allowed_mints.contains(mint) {
+            return Err(PaymentError::MintNotAllowedForMerchant.into());
+        }
+
+        Ok(())
+    }
+
+    /// Validate a merchant's fee override, which may exceed neither the platform fee limit
+    /// nor the total fee cap, and its allowed mints
+    fn check_merchant_settings(
+        config: &PaymentConfig,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: &[Pubkey],
+    ) -> ProgramResult {
+        if platform_fee_bps.is_some_and(|fee_bps| {
+            fee_bps > config.max_platform_fee_bps || fee_bps > config.max_total_fee_bps
+        }) {
+            return Err(PaymentError::InvalidPlatformFee.into());
+        }
+
+        if allowed_mints.len() > Merchant::MAX_ALLOWED_MINTS {
+            return Err(PaymentError::TooManyAllowedMints.into());
+        }
+
+        Ok(())
+    }
+
+    /// Load a pending change PDA, re-deriving its address from the stored change kind and
+    /// merchant
+    fn load_pending_change(
+        program_id: &Pubkey,
+        pending_change_info: &AccountInfo,
+    ) -> Result<PendingChange, ProgramError> {
+        if pending_change_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if pending_change_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        // Smaller changes leave trailing zeroes in the fixed-size account
+        let pending_change = PendingChange::deserialize(&mut &pending_change_info.data.borrow()[..])
+            .map_err(|_| PaymentError::InvalidPendingChangeAccount)?;
+
+        let merchant_seed = pending_change
+            .change
+            .merchant()
+            .map_or(&[][..], |merchant| merchant.as_ref());
+        let pending_change_pda = Pubkey::create_program_address(
+            &[
+                b"pending_change",
+                &[pending_change.change.kind()],
+                merchant_seed,
+                &[pending_change.bump],
+            ],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidPendingChangeAccount)?;
+        if pending_change_pda != *pending_change_info.key {
+            return Err(PaymentError::InvalidPendingChangeAccount.into());
+        }
+
+        Ok(pending_change)
+    }
+
+    /// Load a supported token PDA, re-deriving its address from the stored mint
+    fn load_supported_token(
+        program_id: &Pubkey,
+        supported_token_info: &AccountInfo,
+    ) -> Result<SupportedToken, ProgramError> {
+        if supported_token_info.data_is_empty() {
+            return Err(PaymentError::TokenNotSupported.into());
+        }
+
+        if supported_token_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let supported_token = SupportedToken::try_from_slice(&supported_token_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidSupportedTokenAccount)?;
+
+        let token_pda = Pubkey::create_program_address(
+            &[b"token", supported_token.mint.as_ref(), &[supported_token.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidSupportedTokenAccount)?;
+        if token_pda != *supported_token_info.key {
+            return Err(PaymentError::InvalidSupportedTokenAccount.into());
+        }
+
+        Ok(supported_token)
+    }
+
+    /// Load a processed order PDA, re-deriving its address from the stored order hash
+    fn load_processed_order(
+        program_id: &Pubkey,
+        processed_order_info: &AccountInfo,
+    ) -> Result<ProcessedOrder, ProgramError> {
+        if processed_order_info.data_is_empty() {
+            return Err(PaymentError::UninitializedAccount.into());
+        }
+
+        if processed_order_info.owner != program_id {
+            return Err(PaymentError::InvalidAccountOwner.into());
+        }
+
+        let processed_order = ProcessedOrder::try_from_slice(&processed_order_info.data.borrow())
+            .map_err(|_| PaymentError::InvalidOrderAccount)?;
+
+        let order_pda = Pubkey::create_program_address(
+            &[b"order", &processed_order.order_id_hash, &[processed_order.bump]],
+            program_id,
+        )
+        .map_err(|_| PaymentError::InvalidOrderAccount)?;
+        if order_pda != *processed_order_info.key {
+            return Err(PaymentError::InvalidOrderAccount.into());
+        }
+
+        Ok(processed_order)
+    }
+
+    /// Load an SPL Token or Token-2022 mint, ignoring any extensions
+    fn load_mint(mint_info: &AccountInfo) -> Result<Mint, ProgramError> {
+        if !Self::is_token_program(mint_info.owner) {
+            return Err(PaymentError::InvalidMint.into());
+        }
+
+        StateWithExtensions::<Mint>::unpack(&mint_info.data.borrow())
+            .map(|mint| mint.base)
+            .map_err(|_| PaymentError::InvalidMint.into())
+    }
+
+    /// Load an SPL Token or Token-2022 account and check its mint and owning wallet
+    fn load_token_account(
+        token_account_info: &AccountInfo,
+        mint: &Pubkey,
+        owner: &Pubkey,
+        owner_error: PaymentError,
+    ) -> Result<TokenAccount, ProgramError> {
+        if !Self::is_token_program(token_account_info.owner) {
+            return Err(PaymentError::InvalidTokenAccount.into());
+        }
+
+        let token_account = StateWithExtensions::<TokenAccount>::unpack(
+            &token_account_info.data.borrow(),
+        )
+        .map(|token_account| token_account.base)
+        .map_err(|_| PaymentError::InvalidTokenAccount)?;
+
+        if token_account.mint != *mint {
+            return Err(PaymentError::TokenMintMismatch.into());
+        }
+
+        if token_account.owner != *owner {
+            return Err(owner_error.into());
+        }
+
+        Ok(token_account)
+    }
+
+    fn is_token_program(program_id: &Pubkey) -> bool {
+        *program_id == spl_token::id() || *program_id == spl_token_2022::id()
+    }
+
+    /// Check the token program is SPL Token or Token-2022 and is the one owning the mint
+    fn check_token_program(
+        token_program_info: &AccountInfo,
+        mint_info: &AccountInfo,
+    ) -> ProgramResult {
+        if !Self::is_token_program(token_program_info.key)
+            || mint_info.owner != token_program_info.key
+        {
+            return Err(PaymentError::InvalidTokenProgram.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the mint account is the one the order was paid in
+    fn check_order_mint(
+        processed_order: &ProcessedOrder,
+        mint_info: &AccountInfo,
+    ) -> ProgramResult {
+        if processed_order.token_mint != *mint_info.key {
+            return Err(PaymentError::TokenMintMismatch.into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the instruction before this one is an Ed25519 program check of a quote for
+    /// `terms` signed by the quote signer, when the config requires quotes
+    fn verify_quote(
+        config: &PaymentConfig,
+        instructions_sysvar_info: &AccountInfo,
+        terms: &QuoteTerms,
+    ) -> ProgramResult {
+        if !solana_instructions_sysvar::check_id(instructions_sysvar_info.key) {
+            return Err(PaymentError::InvalidInstructionsSysvar.into());
+        }
+
+        if config.quote_signer == Pubkey::default() {
+            return Ok(());
+        }
+
+        let instruction = get_instruction_relative(-1, instructions_sysvar_info)
+            .map_err(|_| PaymentError::InvalidQuote)?;
+        if instruction.program_id != ed25519_program::id() {
+            return Err(PaymentError::InvalidQuote.into());
+        }
+
+        let (public_key, message) =
+            Self::parse_ed25519_instruction(&instruction.data).ok_or(PaymentError::InvalidQuote)?;
+        if public_key != config.quote_signer.as_ref() {
+            return Err(PaymentError::InvalidQuote.into());
+        }
+
+        let quote =
+            PaymentQuote::try_from_slice(message).map_err(|_| PaymentError::InvalidQuote)?;
+        if quote.terms != *terms {
+            return Err(PaymentError::InvalidQuote.into());
+        }
+
+        Self::check_deadline(config, quote.expires_at, PaymentError::QuoteExpired)
+    }
+
+    /// Fail with `expired_error` once `expires_at` has passed, and reject deadlines further
+    /// ahead than the config's maximum quote lifetime
+    fn check_deadline(
+        config: &PaymentConfig,
+        expires_at: i64,
+        expired_error: PaymentError,
+    ) -> ProgramResult {
+        let now = Clock::get()?.unix_timestamp;
+        if now > expires_at {
+            return Err(expired_error.into());
+        }
+
+        let max_lifetime = i64::from(config.max_quote_lifetime_secs);
+        if max_lifetime != 0 && expires_at.saturating_sub(now) > max_lifetime {
+            return Err(PaymentError::ExpiryTooFar.into());
+        }
+
+        Ok(())
+    }
+
+    /// Public key and message of an Ed25519 program instruction checking a single signature,
+    /// `None` unless its signature, key and message all sit in the instruction's own data
+    fn parse_ed25519_instruction(data: &[u8]) -> Option<(&[u8], &[u8])> {
+        // One signature count byte, one padding byte, then seven u16 offsets
+        if data.len() < 16 || data[0] != 1 {
+            return None;
+        }
+
+        let offset = |index: usize| {
+            usize::from(u16::from_le_bytes([data[2 + 2 * index], data[3 + 2 * index]]))
+        };
+        let (public_key_offset, message_offset, message_size) = (offset(2), offset(4), offset(5));
+
+        // Pointing at another instruction would let its data differ from what was checked here
+        let current_instruction = usize::from(u16::MAX);
+        if [offset(1), offset(3), offset(6)] != [current_instruction; 3] {
+            return None;
+        }
+
+        let public_key = data.get(public_key_offset..public_key_offset + 32)?;
+        let message = data.get(message_offset..message_offset + message_size)?;
+
+        Some((public_key, message))
+    }
+
+    fn check_system_program(system_program_info: &AccountInfo) -> ProgramResult {
+        if *system_program_info.key != solana_system_interface::program::id() {
+            return Err(PaymentError::InvalidSystemProgram.into());
+        }
+
+        Ok(())
+    }
+
+    /// Close a program account, sending its rent to `destination_info`
+    fn close_account(account_info: &AccountInfo, destination_info: &AccountInfo) -> ProgramResult {
+        let lamports = account_info.lamports();
+        **destination_info.try_borrow_mut_lamports()? = destination_info
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        **account_info.try_borrow_mut_lamports()? = 0;
+
+        account_info.resize(0)?;
+        account_info.assign(&solana_system_interface::program::id());
+
+        Ok(())
+    }
+
+    /// Create the closed order account, funding its rent out of the order's
+    fn mark_order_closed<'a>(
+        program_id: &Pubkey,
+        processed_order_info: &AccountInfo<'a>,
+        closed_order_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        // The account holds no data, so assigning it to the program is enough. Lamports
+        // move afterwards, so the system program call sees balanced accounts.
+        invoke_signed(
+            &system_instruction::assign(closed_order_info.key, program_id),
+            &[closed_order_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+
+        // Lamports already sent to the address count towards its rent
+        let top_up = Rent::get()?
+            .minimum_balance(ClosedOrder::LEN)
+            .saturating_sub(closed_order_info.lamports());
+        **processed_order_info.try_borrow_mut_lamports()? = processed_order_info
+            .lamports()
+            .checked_sub(top_up)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+        **closed_order_info.try_borrow_mut_lamports()? = closed_order_info
+            .lamports()
+            .checked_add(top_up)
+            .ok_or(PaymentError::ArithmeticOverflow)?;
+
+        Ok(())
+    }
+
+    /// Close an escrowed order's vault, sending its rent to the buyer. Transfer fees
+    /// withheld in the vault are harvested to the mint and stray tokens returned to the
+    /// buyer's token account first, so nothing sent to the vault can keep it open.
+    fn close_vault<'a>(
+        program_id: &Pubkey,
+        processed_order: &ProcessedOrder,
+        processed_order_info: &AccountInfo<'a>,
+        vault_info: &AccountInfo<'a>,
+        buyer_info: &AccountInfo<'a>,
+        buyer_token_info: &AccountInfo<'a>,
+        transfer: &TransferAccounts<'a, '_>,
+    ) -> ProgramResult {
+        let stray_amount =
+            Self::load_vault(program_id, processed_order, processed_order_info, vault_info)?;
+        let token_program_id = transfer.token_program_info.key;
+        let mint_info = transfer.mint_info;
+        let signer_seeds: &[&[u8]] =
+            &[b"order", &processed_order.order_id_hash, &[processed_order.bump]];
+
+        let withheld_amount = {
+            let vault_data = vault_info.try_borrow_data()?;
+            let vault = StateWithExtensions::<TokenAccount>::unpack(&vault_data)?;
+            vault
+                .get_extension::<TransferFeeAmount>()
+                .map_or(0, |fee_amount| u64::from(fee_amount.withheld_amount))
+        };
+        if withheld_amount > 0 {
+            invoke(
+                &harvest_withheld_tokens_to_mint(token_program_id, mint_info.key, &[vault_info.key])?,
+                &[mint_info.clone(), vault_info.clone()],
+            )?;
+        }
+
+        if stray_amount > 0 {
+            Self::load_token_account(
+                buyer_token_info,
+                &processed_order.token_mint,
+                &processed_order.buyer,
+                PaymentError::InvalidBuyerTokenAccount,
+            )?;
+            Self::transfer_tokens(
+                transfer,
+                vault_info,
+                buyer_token_info,
+                processed_order_info,
+                &[signer_seeds],
+                stray_amount,
+            )?;
+        }
+
+        invoke_signed(
+            &spl_token_2022::instruction::close_account(
+                token_program_id,
+                vault_info.key,
+                buyer_info.key,
+                processed_order_info.key,
+                &[],
+            )?,
+            &[vault_info.clone(), buyer_info.clone(), processed_order_info.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    /// Transfer `amount` lamports from the buyer, skipping zero-value legs
+    fn transfer_lamports<'a>(
+        source_info: &AccountInfo<'a>,
+        destination_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        invoke(
+            &system_instruction::transfer(source_info.key, destination_info.key, amount),
+            &[
+                source_info.clone(),
+                destination_info.clone(),
+                system_program_info.clone(),
+            ],
+        )
+    }
+
+    /// Transfer `amount` tokens with `transfer_checked` signed by `authority_info`, skipping
+    /// zero-value legs, and return the amount the destination received after any transfer fee
+    ///
+    /// `signer_seeds` is empty for wallet signers and holds the PDA seeds when a
+    /// program account (the order PDA owning a vault) is the authority.
+    fn transfer_tokens<'a>(
+        transfer: &TransferAccounts<'a, '_>,
+        source_info: &AccountInfo<'a>,
+        destination_info: &AccountInfo<'a>,
+        authority_info: &AccountInfo<'a>,
+        signer_seeds: &[&[&[u8]]],
+        amount: u64,
+    ) -> Result<u64, ProgramError> {
+        if amount == 0 {
+            return Ok(0);
+        }
+
+        let (decimals, transfer_fee) = {
+            let mint_data = transfer.mint_info.try_borrow_data()?;
+            let mint = StateWithExtensions::<Mint>::unpack(&mint_data)
+                .map_err(|_| PaymentError::InvalidMint)?;
+            let transfer_fee = match mint.get_extension::<TransferFeeConfig>() {
+                Ok(fee_config) => fee_config
+                    .calculate_epoch_fee(Clock::get()?.epoch, amount)
+                    .ok_or(PaymentError::ArithmeticOverflow)?,
+                Err(_) => 0,
+            };
+            (mint.base.decimals, transfer_fee)
+        };
+
+        // Resolves and appends the mint's transfer hook accounts, if it has a hook
+        spl_token_2022::onchain::invoke_transfer_checked(
+            transfer.token_program_info.key,
+            source_info.clone(),
+            transfer.mint_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            transfer.extra_account_infos,
+            amount,
+            decimals,
+            signer_seeds,
+        )?;
+
+        amount
+            .checked_sub(transfer_fee)
+            .ok_or_else(|| PaymentError::ArithmeticOverflow.into())
+    }
 }
@@ -1,4 +1,5 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use oxmart_payment_core::fees;
 use solana_program::pubkey::Pubkey;
 
 /// Payment configuration account
@@ -7,8 +8,41 @@ pub struct PaymentConfig {
     /// Program authority (admin)
     pub authority: Pubkey,
 
-    /// Hot wallet address to receive payments
-    pub hot_wallet: Pubkey,
+    /// Authority proposed by `ProposeAuthority`, waiting to accept (default when none)
+    pub pending_authority: Pubkey,
+
+    /// Wallets that approve admin instructions together once a multisig is set
+    pub multisig_signers: Vec<Pubkey>,
+
+    /// Approvals needed from `multisig_signers`, 0 when the authority acts alone
+    pub multisig_threshold: u8,
+
+    /// Treasury wallet that receives platform fees
+    pub fee_treasury: Pubkey,
+
+    /// Wallet allowed to refund orders besides the authority
+    pub refund_operator: Pubkey,
+
+    /// Wallet allowed to settle escrow disputes besides the authority
+    pub arbitrator: Pubkey,
+
+    /// Backend key whose signed quote every payment must carry (default when not required)
+    pub quote_signer: Pubkey,
+
+    /// Seconds after an escrowed payment during which the buyer can open a dispute
+    pub dispute_window_secs: u32,
+
+    /// Seconds a queued merchant payout wallet change waits before it can be executed
+    pub payout_wallet_delay_secs: u32,
+
+    /// Seconds a queued platform fee change waits before it can be executed
+    pub platform_fee_delay_secs: u32,
+
+    /// Furthest ahead, in seconds, a payment or quote may set its deadline (0 for no limit)
+    pub max_quote_lifetime_secs: u32,
+
+    /// Seconds after payment before a processed order can be closed
+    pub order_retention_secs: u32,
 
     /// Platform fee in basis points (1 bp = 0.01%)
     pub platform_fee_bps: u16,
@@ -19,6 +53,9 @@ pub struct PaymentConfig {
     /// Maximum commission rate (10000 = 100%)
     pub max_commission_bps: u16,
 
+    /// Cap on platform fee plus commission of a payment (10000 = 100%)
+    pub max_total_fee_bps: u16,
+
     /// Whether the contract is paused
     pub is_paused: bool,
 
@@ -27,10 +64,238 @@ pub struct PaymentConfig {
 }
 
 impl PaymentConfig {
+    pub const LEN: usize = 32 + 32 + (4 + Self::MAX_MULTISIG_SIGNERS * 32) + 1
+        + 32 + 32 + 32 + 32 + 4 + 4 + 4 + 4 + 4 + 2 + 2 + 2 + 2 + 1 + 1; // 387 bytes
+
+    pub const MAX_MULTISIG_SIGNERS: usize = 5;
+
+    pub const MAX_PLATFORM_FEE_BPS: u16 = fees::MAX_PLATFORM_FEE_BPS;
+    pub const MAX_COMMISSION_BPS: u16 = fees::MAX_COMMISSION_BPS;
+    pub const DEFAULT_MAX_TOTAL_FEE_BPS: u16 = fees::DEFAULT_MAX_TOTAL_FEE_BPS;
+    pub const DEFAULT_DISPUTE_WINDOW_SECS: u32 = 7 * 24 * 60 * 60; // 7 days
+    pub const DEFAULT_CHANGE_DELAY_SECS: u32 = oxmart_payment_core::DEFAULT_CHANGE_DELAY_SECS;
+    pub const DEFAULT_MAX_QUOTE_LIFETIME_SECS: u32 =
+        oxmart_payment_core::DEFAULT_MAX_QUOTE_LIFETIME_SECS;
+    pub const DEFAULT_ORDER_RETENTION_SECS: u32 = oxmart_payment_core::DEFAULT_ORDER_RETENTION_SECS;
+
+    /// Whether `key` acts as the admin: the authority, or a multisig signer once a multisig is set
+    pub fn is_admin(&self, key: &Pubkey) -> bool {
+        if self.multisig_threshold == 0 {
+            *key == self.authority
+        } else {
+            self.multisig_signers.contains(key)
+        }
+    }
+
+    /// Check a multisig for `SetMultisig`: no signers with a zero threshold to disable it,
+    /// otherwise up to `MAX_MULTISIG_SIGNERS` distinct signers and a threshold they can reach
+    pub fn is_valid_multisig(signers: &[Pubkey], threshold: u8) -> bool {
+        if threshold == 0 {
+            return signers.is_empty();
+        }
+
+        signers.len() <= Self::MAX_MULTISIG_SIGNERS
+            && usize::from(threshold) <= signers.len()
+            && !signers.contains(&Pubkey::default())
+            && signers
+                .iter()
+                .enumerate()
+                .all(|(i, signer)| !signers[..i].contains(signer))
+    }
+}
+
+/// Payment configuration as laid out by the first release, upgraded by `MigrateConfig`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct LegacyPaymentConfig {
+    pub authority: Pubkey,
+    pub hot_wallet: Pubkey,
+    pub platform_fee_bps: u16,
+    pub max_platform_fee_bps: u16,
+    pub max_commission_bps: u16,
+    pub is_paused: bool,
+    pub bump: u8,
+}
+
+impl LegacyPaymentConfig {
     pub const LEN: usize = 32 + 32 + 2 + 2 + 2 + 1 + 1; // 72 bytes
+}
+
+/// Permission that the authority can grant to other wallets
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Pause and unpause the program
+    Pauser,
+
+    /// Update the platform fee
+    FeeManager,
+
+    /// Add and remove supported tokens
+    TokenManager,
+
+    /// Refund orders
+    RefundOperator,
 
-    pub const MAX_PLATFORM_FEE_BPS: u16 = 1000; // 10%
-    pub const MAX_COMMISSION_BPS: u16 = 10000; // 100%
+    /// Update merchant payout wallets and the fee treasury
+    TreasuryManager,
+}
+
+impl Role {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Wallet holding one or more roles
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoleMember {
+    /// Wallet address
+    pub member: Pubkey,
+
+    /// Bit set of granted roles, one bit per `Role`
+    pub roles: u8,
+}
+
+/// Role assignments account
+///
+/// The authority implicitly holds every role and never needs an entry.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Roles {
+    /// Wallets with at least one role
+    pub members: Vec<RoleMember>,
+
+    /// Bump seed for PDA
+    pub bump: u8,
+}
+
+impl Roles {
+    pub const MAX_MEMBERS: usize = 10;
+    pub const LEN: usize = 4 + Self::MAX_MEMBERS * (32 + 1) + 1; // 335 bytes
+
+    pub fn has_role(&self, member: &Pubkey, role: Role) -> bool {
+        self.members
+            .iter()
+            .any(|entry| entry.member == *member && entry.roles & role.mask() != 0)
+    }
+
+    /// Grant `role` to `member`, returning false when a new entry would exceed `MAX_MEMBERS`
+    pub fn grant(&mut self, member: Pubkey, role: Role) -> bool {
+        if let Some(entry) = self.members.iter_mut().find(|entry| entry.member == member) {
+            entry.roles |= role.mask();
+            return true;
+        }
+
+        if self.members.len() >= Self::MAX_MEMBERS {
+            return false;
+        }
+
+        self.members.push(RoleMember {
+            member,
+            roles: role.mask(),
+        });
+        true
+    }
+
+    /// Revoke `role` from `member`, dropping members left without roles.
+    /// Returns false when the member did not hold the role.
+    pub fn revoke(&mut self, member: &Pubkey, role: Role) -> bool {
+        if !self.has_role(member, role) {
+            return false;
+        }
+
+        self.members.retain_mut(|entry| {
+            if entry.member == *member {
+                entry.roles &= !role.mask();
+            }
+            entry.roles != 0
+        });
+        true
+    }
+}
+
+/// Config change queued behind a timelock
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// Pay a merchant's sales to a new payout wallet
+    PayoutWallet {
+        merchant: Pubkey,
+        new_payout_wallet: Pubkey,
+    },
+
+    /// Charge a new platform fee
+    PlatformFee { new_fee_bps: u16 },
+
+    /// Change the delays themselves
+    TimelockDelays {
+        payout_wallet_delay_secs: u32,
+        platform_fee_delay_secs: u32,
+    },
+}
+
+impl ConfigChange {
+    /// Seed of the pending change PDA, so at most one change of each kind is queued
+    pub fn kind(&self) -> u8 {
+        match self {
+            ConfigChange::PayoutWallet { .. } => 0,
+            ConfigChange::PlatformFee { .. } => 1,
+            ConfigChange::TimelockDelays { .. } => 2,
+        }
+    }
+
+    /// Merchant a payout wallet change applies to. Its key follows the kind in the pending
+    /// change PDA seeds, so each merchant can have its own change queued.
+    pub fn merchant(&self) -> Option<&Pubkey> {
+        match self {
+            ConfigChange::PayoutWallet { merchant, .. } => Some(merchant),
+            _ => None,
+        }
+    }
+
+    /// Role allowed to queue the change besides the admin, `None` when admin only
+    ///
+    /// Payout wallet changes become admin only once a multisig is set.
+    pub fn role(&self, config: &PaymentConfig) -> Option<Role> {
+        match self {
+            ConfigChange::PayoutWallet { .. } if config.multisig_threshold > 0 => None,
+            ConfigChange::PayoutWallet { .. } => Some(Role::TreasuryManager),
+            ConfigChange::PlatformFee { .. } => Some(Role::FeeManager),
+            ConfigChange::TimelockDelays { .. } => None,
+        }
+    }
+
+    /// Seconds the change waits before it can be executed
+    pub fn delay_secs(&self, config: &PaymentConfig) -> u32 {
+        match self {
+            ConfigChange::PayoutWallet { .. } => config.payout_wallet_delay_secs,
+            ConfigChange::PlatformFee { .. } => config.platform_fee_delay_secs,
+            // Shortening a delay must itself wait out the longest current one
+            ConfigChange::TimelockDelays { .. } => config
+                .payout_wallet_delay_secs
+                .max(config.platform_fee_delay_secs),
+        }
+    }
+}
+
+/// Pending config change account
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct PendingChange {
+    /// Change applied by `ExecuteConfigChange`
+    pub change: ConfigChange,
+
+    /// Wallet that queued the change and paid for this account
+    pub proposer: Pubkey,
+
+    /// Timestamp the change was queued at
+    pub queued_at: i64,
+
+    /// Earliest timestamp the change can be executed at
+    pub execute_after: i64,
+
+    /// Bump seed for PDA
+    pub bump: u8,
+}
+
+impl PendingChange {
+    pub const LEN: usize = (1 + 64) + 32 + 8 + 8 + 1; // 114 bytes, sized for the largest change
 }
 
 /// Supported token account
@@ -50,6 +315,173 @@ impl SupportedToken {
     pub const LEN: usize = 32 + 1 + 1; // 34 bytes
 }
 
+/// Whether a merchant can be paid
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerchantStatus {
+    /// Accepts payments
+    Active,
+
+    /// Payments to the merchant are rejected
+    Suspended,
+}
+
+/// Seller paid by `ProcessPayment` and `ProcessSolPayment`, and by escrow releases
+///
+/// The account is sized for `MAX_ALLOWED_MINTS`, so a shorter mint list leaves trailing zeroes.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Merchant {
+    /// SHA-256 hash of the backend's seller ID
+    pub merchant_id: [u8; 32],
+
+    /// Owner of the merchant's payout token accounts, and receives its SOL payments
+    pub payout_wallet: Pubkey,
+
+    /// Whether the merchant can be paid
+    pub status: MerchantStatus,
+
+    /// Platform fee on the merchant's sales, overriding the config's
+    pub platform_fee_bps: Option<u16>,
+
+    /// Mints the merchant accepts, or every supported mint when empty
+    pub allowed_mints: Vec<Pubkey>,
+
+    /// Bump seed for PDA
+    pub bump: u8,
+}
+
+impl Merchant {
+    pub const MAX_ALLOWED_MINTS: usize = 5;
+    pub const LEN: usize = 32 + 32 + 1 + 3 + (4 + Self::MAX_ALLOWED_MINTS * 32) + 1; // 233 bytes
+
+    /// The merchant's fee override, or the config's platform fee
+    pub fn platform_fee_bps(&self, config: &PaymentConfig) -> u16 {
+        self.platform_fee_bps.unwrap_or(config.platform_fee_bps)
+    }
+}
+
+/// Whether an affiliate earns commission
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffiliateStatus {
+    /// Earns commission on payments
+    Active,
+
+    /// Payments naming the affiliate are rejected and its commission cannot be claimed
+    Suspended,
+}
+
+/// API key owner earning commission on the payments it refers
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Affiliate {
+    /// SHA-256 hash of the backend's API key ID
+    pub api_key_hash: [u8; 32],
+
+    /// Wallet allowed to claim the affiliate's accrued commission
+    pub owner: Pubkey,
+
+    /// Agreed commission rate in basis points
+    pub commission_bps: u16,
+
+    /// Whether the affiliate earns commission
+    pub status: AffiliateStatus,
+
+    /// Commission accrued over the affiliate's lifetime, summed across mints
+    pub total_earnings: u64,
+
+    /// Bump seed for PDA
+    pub bump: u8,
+}
+
+impl Affiliate {
+    pub const LEN: usize = 32 + 32 + 2 + 1 + 8 + 1; // 76 bytes
+}
+
+/// Commission an affiliate has accrued in one mint, held in the ledger's vault until claimed,
+/// or as lamports in the SOL commission ledger itself
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct CommissionLedger {
+    /// Affiliate account the commission belongs to
+    pub affiliate: Pubkey,
+
+    /// Mint the commission is paid in
+    pub mint: Pubkey,
+
+    /// Commission received into the vault over the ledger's lifetime
+    pub accrued: u64,
+
+    /// Commission withdrawn by `ClaimCommission`
+    pub claimed: u64,
+
+    /// Unix timestamp of the last claim (0 before the first)
+    pub last_claim_at: i64,
+
+    /// Bump seed for PDA
+    pub bump: u8,
+}
+
+impl CommissionLedger {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 1; // 89 bytes
+}
+
+/// Lifecycle of a processed order
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    /// Paid straight to the merchant
+    Completed,
+
+    /// Held in the order's vault until released
+    Escrowed,
+
+    /// Buyer opened a dispute, waiting for the arbitrator
+    Disputed,
+
+    /// Vault paid out to the merchant, fee treasury and affiliate commission vault
+    Released,
+
+    /// Vault paid back to the buyer after a dispute
+    ReturnedToBuyer,
+}
+
+/// Payment terms a quote must match
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuoteTerms {
+    /// Payment program the quote is for
+    pub program_id: Pubkey,
+
+    /// Order ID hash
+    pub order_id_hash: [u8; 32],
+
+    /// Token mint to pay with (the native mint for SOL payments)
+    pub mint: Pubkey,
+
+    /// Amount to pay
+    pub amount: u64,
+
+    /// Merchant PDA the payment settles to
+    pub merchant: Pubkey,
+
+    /// Commission rate in basis points
+    pub commission_bps: u16,
+
+    /// API key owner earning the commission
+    pub api_key_owner: Pubkey,
+}
+
+/// Payment quote signed by the quote signer through the Ed25519 program
+///
+/// The signed message is the Borsh encoding of the quote, checked by the payment
+/// instruction right after the Ed25519 instruction.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PaymentQuote {
+    pub terms: QuoteTerms,
+
+    /// Unix timestamp after which the quote is rejected
+    pub expires_at: i64,
+}
+
+impl PaymentQuote {
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 32 + 2 + 32 + 8; // 178 bytes
+}
+
 /// Processed order tracking
 #[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
 pub struct ProcessedOrder {
@@ -71,12 +503,24 @@ pub struct ProcessedOrder {
     /// API key owner (for commission tracking)
     pub api_key_owner: Pubkey,
 
+    /// Affiliate PDA the commission accrues to, default for an order without one
+    pub affiliate: Pubkey,
+
     /// Commission amount
     pub commission: u64,
 
     /// Commission rate in basis points
     pub commission_bps: u16,
 
+    /// Total amount refunded to the buyer so far
+    pub refunded_amount: u64,
+
+    /// Merchant PDA paid, or owed an escrowed payment
+    pub merchant: Pubkey,
+
+    /// Order status
+    pub status: OrderStatus,
+
     /// Timestamp
     pub timestamp: i64,
 
@@ -85,5 +529,21 @@ pub struct ProcessedOrder {
 }
 
 impl ProcessedOrder {
-    pub const LEN: usize = 32 + 32 + 32 + 8 + 8 + 32 + 8 + 2 + 8 + 1; // 163 bytes
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 8 + 32 + 32 + 8 + 2 + 8 + 32 + 1 + 8 + 1; // 236 bytes
+
+    /// Whether the order is settled, so closing it loses nothing still needed
+    pub fn is_settled(&self) -> bool {
+        !matches!(self.status, OrderStatus::Escrowed | OrderStatus::Disputed)
+    }
+}
+
+/// Marker left by `CloseOrder`, so a closed order ID cannot be paid again
+///
+/// One account per closed order (PDA: ["closed_order", order_id_hash]). It holds no
+/// data: the program owning the address is the mark. Its rent comes out of the closed
+/// order's.
+pub struct ClosedOrder;
+
+impl ClosedOrder {
+    pub const LEN: usize = 0;
 }
@@ -0,0 +1,4906 @@
+// In-process tests for the payment program using solana-program-test's BanksClient
+
+use borsh::BorshDeserialize;
+use oxmart_payment::{
+    error::PaymentError,
+    instruction::PaymentInstruction,
+    processor::Processor,
+    state::{
+        Affiliate, AffiliateStatus, CommissionLedger, ConfigChange,
+        LegacyPaymentConfig, Merchant, MerchantStatus, OrderStatus, PaymentConfig, PaymentQuote,
+        PendingChange, ProcessedOrder, QuoteTerms, Role, Roles, SupportedToken,
+    },
+};
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    clock::Clock,
+    ed25519_program,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_option::COption,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use solana_system_interface::program as system_program;
+use spl_token_2022::{
+    extension::{
+        transfer_fee::{TransferFee, TransferFeeAmount, TransferFeeConfig},
+        BaseStateWithExtensions, BaseStateWithExtensionsMut, ExtensionType, StateWithExtensions,
+        StateWithExtensionsMut,
+    },
+    state::{Account as TokenAccount, AccountState, Mint},
+};
+
+const INITIAL_BALANCE: u64 = 1_000_000_000;
+const LAMPORTS: u64 = 10_000_000_000;
+const MERCHANT_ID: [u8; 32] = [0xee; 32];
+const API_KEY_HASH: [u8; 32] = [0xaa; 32];
+const AFFILIATE_COMMISSION_BPS: u16 = 500;
+
+struct TestEnv {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    authority: Keypair,
+    buyer: Keypair,
+    merchant: Keypair,
+    /// Wallet with no part in the program, owning accounts that must be rejected
+    other_wallet: Pubkey,
+    fee_treasury: Pubkey,
+    api_key_owner: Keypair,
+    token_program: Pubkey,
+    transfer_fee_bps: u16,
+    mint: Pubkey,
+    buyer_token: Pubkey,
+    other_wallet_token: Pubkey,
+    fee_treasury_token: Pubkey,
+    api_key_owner_token: Pubkey,
+    /// Merchant account registered by `initialized`, paid out to `merchant`
+    merchant_account: Pubkey,
+    merchant_token: Pubkey,
+    /// Affiliate account registered by `initialized`, earning `AFFILIATE_COMMISSION_BPS`
+    /// for `api_key_owner`
+    affiliate_account: Pubkey,
+    /// Bank clock time, kept in step by `advance_clock`
+    now: i64,
+}
+
+/// Accounts and arguments for a `ProcessPayment` instruction, editable per test
+struct Payment {
+    buyer: AccountMeta,
+    buyer_token: Pubkey,
+    merchant_token: Pubkey,
+    fee_treasury_token: Pubkey,
+    /// Commission vault PDA, or the program id for a payment without commission
+    commission_vault: Pubkey,
+    mint: Pubkey,
+    config: Pubkey,
+    supported_token: Pubkey,
+    processed_order: Pubkey,
+    /// Affiliate PDA, or the program id for a payment without commission
+    affiliate: Pubkey,
+    /// Commission ledger PDA, or the program id for a payment without commission
+    commission_ledger: Pubkey,
+    token_program: Pubkey,
+    system_program: Pubkey,
+    instructions_sysvar: Pubkey,
+    closed_order: Pubkey,
+    merchant: Pubkey,
+    order_id: String,
+    amount: u64,
+    expires_at: i64,
+}
+
+impl Payment {
+    fn instruction(&self, program_id: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            *program_id,
+            &PaymentInstruction::ProcessPayment {
+                order_id: self.order_id.clone(),
+                amount: self.amount,
+                product_id: "product-1".to_string(),
+                expires_at: self.expires_at,
+            },
+            vec![
+                self.buyer.clone(),
+                AccountMeta::new(self.buyer_token, false),
+                AccountMeta::new(self.merchant_token, false),
+                AccountMeta::new(self.fee_treasury_token, false),
+                Self::optional_account(program_id, self.commission_vault),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.config, false),
+                AccountMeta::new(self.supported_token, false),
+                AccountMeta::new(self.processed_order, false),
+                Self::optional_account(program_id, self.affiliate),
+                Self::optional_account(program_id, self.commission_ledger),
+                AccountMeta::new_readonly(self.token_program, false),
+                AccountMeta::new_readonly(self.system_program, false),
+                AccountMeta::new_readonly(self.instructions_sysvar, false),
+                AccountMeta::new_readonly(self.closed_order, false),
+                AccountMeta::new_readonly(self.merchant, false),
+            ],
+        )
+    }
+
+    /// Writable account, or the program id passed read-only in its place
+    fn optional_account(program_id: &Pubkey, address: Pubkey) -> AccountMeta {
+        if address == *program_id {
+            AccountMeta::new_readonly(address, false)
+        } else {
+            AccountMeta::new(address, false)
+        }
+    }
+}
+
+impl TestEnv {
+    /// Start a fresh bank with funded authority/buyer, a mint and token accounts, but no config
+    async fn new() -> Self {
+        Self::with_token_program(spl_token::id(), 0).await
+    }
+
+    /// Like `new`, with the mint and token accounts under `token_program` and, when
+    /// `transfer_fee_bps` is non-zero, a Token-2022 transfer fee on the mint
+    async fn with_token_program(token_program: Pubkey, transfer_fee_bps: u16) -> Self {
+        let program_id = Pubkey::new_unique();
+        let authority = Keypair::new();
+        let buyer = Keypair::new();
+
+        let mut program_test =
+            ProgramTest::new("oxmart_payment", program_id, processor!(Processor::process));
+        for wallet in [authority.pubkey(), buyer.pubkey()] {
+            program_test.add_account(
+                wallet,
+                Account {
+                    lamports: LAMPORTS,
+                    owner: system_program::id(),
+                    ..Account::default()
+                },
+            );
+        }
+
+        let context = program_test.start_with_context().await;
+        let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+
+        let mut env = Self {
+            context,
+            program_id,
+            authority,
+            buyer,
+            merchant: Keypair::new(),
+            other_wallet: Pubkey::new_unique(),
+            fee_treasury: Pubkey::new_unique(),
+            api_key_owner: Keypair::new(),
+            token_program,
+            transfer_fee_bps,
+            mint: Pubkey::default(),
+            buyer_token: Pubkey::default(),
+            other_wallet_token: Pubkey::default(),
+            fee_treasury_token: Pubkey::default(),
+            api_key_owner_token: Pubkey::default(),
+            merchant_account: Pubkey::default(),
+            merchant_token: Pubkey::default(),
+            affiliate_account: Pubkey::default(),
+            now: clock.unix_timestamp,
+        };
+
+        let mint = env.add_mint();
+        let (buyer, other_wallet, fee_treasury, api_key_owner, merchant) = (
+            env.buyer.pubkey(),
+            env.other_wallet,
+            env.fee_treasury,
+            env.api_key_owner.pubkey(),
+            env.merchant.pubkey(),
+        );
+        env.mint = mint;
+        env.buyer_token = env.add_token_account(&mint, &buyer, INITIAL_BALANCE);
+        env.other_wallet_token = env.add_token_account(&mint, &other_wallet, 0);
+        env.fee_treasury_token = env.add_token_account(&mint, &fee_treasury, 0);
+        env.api_key_owner_token = env.add_token_account(&mint, &api_key_owner, 0);
+        env.merchant_account = env.merchant_address(&MERCHANT_ID);
+        env.merchant_token = env.add_token_account(&mint, &merchant, 0);
+        env.affiliate_account = env.affiliate_address(&API_KEY_HASH);
+
+        env
+    }
+
+    /// Start a bank with the config initialized, the test mint supported and the merchant
+    /// and affiliate registered
+    async fn initialized() -> Self {
+        Self::initialized_with_token_program(spl_token::id(), 0).await
+    }
+
+    async fn initialized_with_token_program(token_program: Pubkey, transfer_fee_bps: u16) -> Self {
+        let env = Self::with_token_program(token_program, transfer_fee_bps).await;
+        env.process(&[env.initialize_ix()], &[&env.authority])
+            .await
+            .unwrap();
+        env.process(&[env.add_supported_token_ix(&env.mint)], &[&env.authority])
+            .await
+            .unwrap();
+        let ix =
+            env.register_merchant_ix(&env.authority.pubkey(), &MERCHANT_ID, &env.merchant.pubkey());
+        env.process(&[ix], &[&env.authority]).await.unwrap();
+        let ix = env.register_affiliate_ix(
+            &env.authority.pubkey(),
+            &API_KEY_HASH,
+            AFFILIATE_COMMISSION_BPS,
+        );
+        env.process(&[ix], &[&env.authority]).await.unwrap();
+        env
+    }
+
+    fn config_address(&self) -> Pubkey {
+        Pubkey::find_program_address(&[b"config"], &self.program_id).0
+    }
+
+    fn supported_token_address(&self, mint: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"token", mint.as_ref()], &self.program_id).0
+    }
+
+    fn roles_address(&self) -> Pubkey {
+        Pubkey::find_program_address(&[b"roles"], &self.program_id).0
+    }
+
+    fn pending_change_address(&self, change: &ConfigChange) -> Pubkey {
+        let merchant_seed = change
+            .merchant()
+            .map_or(&[][..], |merchant| merchant.as_ref());
+        let seeds: &[&[u8]] = &[b"pending_change", &[change.kind()], merchant_seed];
+        Pubkey::find_program_address(seeds, &self.program_id).0
+    }
+
+    fn order_address(&self, order_id: &str) -> Pubkey {
+        let order_id_hash = solana_sdk::hash::hash(order_id.as_bytes()).to_bytes();
+        Pubkey::find_program_address(&[b"order", &order_id_hash], &self.program_id).0
+    }
+
+    fn vault_address(&self, order_id: &str) -> Pubkey {
+        let order_id_hash = solana_sdk::hash::hash(order_id.as_bytes()).to_bytes();
+        Pubkey::find_program_address(&[b"vault", &order_id_hash], &self.program_id).0
+    }
+
+    fn merchant_address(&self, merchant_id: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"merchant", merchant_id], &self.program_id).0
+    }
+
+    fn affiliate_address(&self, api_key_hash: &[u8; 32]) -> Pubkey {
+        Pubkey::find_program_address(&[b"affiliate", api_key_hash], &self.program_id).0
+    }
+
+    /// The registered affiliate's commission ledger for `mint`
+    fn commission_ledger_address(&self, mint: &Pubkey) -> Pubkey {
+        let seeds: &[&[u8]] = &[b"commission", self.affiliate_account.as_ref(), mint.as_ref()];
+        Pubkey::find_program_address(seeds, &self.program_id).0
+    }
+
+    /// The registered affiliate's commission vault for `mint`
+    fn commission_vault_address(&self, mint: &Pubkey) -> Pubkey {
+        let seeds: &[&[u8]] =
+            &[b"commission_vault", self.affiliate_account.as_ref(), mint.as_ref()];
+        Pubkey::find_program_address(seeds, &self.program_id).0
+    }
+
+    /// The registered affiliate's ledger for SOL commission
+    fn sol_commission_ledger_address(&self) -> Pubkey {
+        let seeds: &[&[u8]] = &[b"sol_commission", self.affiliate_account.as_ref()];
+        Pubkey::find_program_address(seeds, &self.program_id).0
+    }
+
+    fn closed_order_address(&self, order_id: &str) -> Pubkey {
+        let order_id_hash = solana_sdk::hash::hash(order_id.as_bytes()).to_bytes();
+        Pubkey::find_program_address(&[b"closed_order", &order_id_hash], &self.program_id).0
+    }
+
+    /// Move the bank clock forward by `seconds`, on a fresh blockhash so a retried
+    /// instruction is not rejected as a duplicate transaction
+    async fn advance_clock(&mut self, seconds: i64) {
+        // Wait for a blockhash newer than any used so far, so transactions retried after the
+        // clock moves are not deduplicated against their earlier attempts
+        let banks_client = self.context.banks_client.clone();
+        self.context.last_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+        self.context.get_new_latest_blockhash().await.unwrap();
+        let mut clock: Clock = banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp += seconds;
+        self.context.set_sysvar(&clock);
+        self.now = clock.unix_timestamp;
+    }
+
+    fn set_account(&mut self, address: &Pubkey, owner: &Pubkey, data: Vec<u8>) {
+        let lamports = Rent::default().minimum_balance(data.len());
+        let mut account = AccountSharedData::new(lamports, data.len(), owner);
+        account.set_data_from_slice(&data);
+        self.context.set_account(address, &account);
+    }
+
+    fn add_mint(&mut self) -> Pubkey {
+        let address = Pubkey::new_unique();
+        let mint = Mint {
+            mint_authority: COption::Some(self.authority.pubkey()),
+            supply: INITIAL_BALANCE,
+            decimals: 6,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        };
+
+        let data = if self.transfer_fee_bps == 0 {
+            let mut data = vec![0; Mint::LEN];
+            Mint::pack(mint, &mut data).unwrap();
+            data
+        } else {
+            let len = ExtensionType::try_calculate_account_len::<Mint>(&[
+                ExtensionType::TransferFeeConfig,
+            ])
+            .unwrap();
+            let mut data = vec![0; len];
+            let mut state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+            let transfer_fee = TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: self.transfer_fee_bps.into(),
+            };
+            let fee_config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            fee_config.older_transfer_fee = transfer_fee;
+            fee_config.newer_transfer_fee = transfer_fee;
+            state.base = mint;
+            state.pack_base();
+            state.init_account_type().unwrap();
+            data
+        };
+
+        let token_program = self.token_program;
+        self.set_account(&address, &token_program, data);
+        address
+    }
+
+    fn add_token_account(&mut self, mint: &Pubkey, owner: &Pubkey, amount: u64) -> Pubkey {
+        let address = Pubkey::new_unique();
+        let token_account = TokenAccount {
+            mint: *mint,
+            owner: *owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+
+        let data = if self.transfer_fee_bps == 0 {
+            let mut data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(token_account, &mut data).unwrap();
+            data
+        } else {
+            let len = ExtensionType::try_calculate_account_len::<TokenAccount>(&[
+                ExtensionType::TransferFeeAmount,
+            ])
+            .unwrap();
+            let mut data = vec![0; len];
+            let mut state =
+                StateWithExtensionsMut::<TokenAccount>::unpack_uninitialized(&mut data).unwrap();
+            state.init_extension::<TransferFeeAmount>(true).unwrap();
+            state.base = token_account;
+            state.pack_base();
+            state.init_account_type().unwrap();
+            data
+        };
+
+        let token_program = self.token_program;
+        self.set_account(&address, &token_program, data);
+        address
+    }
+
+    async fn process(
+        &self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        let banks_client = self.context.banks_client.clone();
+        let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await
+    }
+
+    async fn account_owner(&self, address: &Pubkey) -> Option<Pubkey> {
+        let banks_client = self.context.banks_client.clone();
+        banks_client
+            .get_account(*address)
+            .await
+            .unwrap()
+            .map(|account| account.owner)
+    }
+
+    async fn account_data(&self, address: &Pubkey) -> Option<Vec<u8>> {
+        let banks_client = self.context.banks_client.clone();
+        banks_client
+            .get_account(*address)
+            .await
+            .unwrap()
+            .map(|account| account.data)
+    }
+
+    async fn config(&self) -> PaymentConfig {
+        let data = self.account_data(&self.config_address()).await.unwrap();
+        PaymentConfig::deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn roles(&self) -> Roles {
+        let data = self.account_data(&self.roles_address()).await.unwrap();
+        Roles::deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn pending_change(&self, change: &ConfigChange) -> Option<PendingChange> {
+        self.account_data(&self.pending_change_address(change))
+            .await
+            .map(|data| PendingChange::deserialize(&mut data.as_slice()).unwrap())
+    }
+
+    async fn supported_token(&self, mint: &Pubkey) -> SupportedToken {
+        let data = self
+            .account_data(&self.supported_token_address(mint))
+            .await
+            .unwrap();
+        SupportedToken::try_from_slice(&data).unwrap()
+    }
+
+    async fn merchant_state(&self) -> Merchant {
+        let data = self.account_data(&self.merchant_account).await.unwrap();
+        Merchant::deserialize(&mut data.as_slice()).unwrap()
+    }
+
+    async fn affiliate_state(&self) -> Affiliate {
+        let data = self.account_data(&self.affiliate_account).await.unwrap();
+        Affiliate::try_from_slice(&data).unwrap()
+    }
+
+    async fn commission_ledger(&self) -> CommissionLedger {
+        let data = self
+            .account_data(&self.commission_ledger_address(&self.mint))
+            .await
+            .unwrap();
+        CommissionLedger::try_from_slice(&data).unwrap()
+    }
+
+    async fn sol_commission_ledger(&self) -> CommissionLedger {
+        let data = self
+            .account_data(&self.sol_commission_ledger_address())
+            .await
+            .unwrap();
+        CommissionLedger::try_from_slice(&data).unwrap()
+    }
+
+    /// Commission waiting to be claimed in the test mint's vault
+    async fn commission_vault_balance(&self) -> u64 {
+        self.token_balance(&self.commission_vault_address(&self.mint))
+            .await
+    }
+
+    async fn processed_order(&self, order_id: &str) -> ProcessedOrder {
+        let data = self.account_data(&self.order_address(order_id)).await.unwrap();
+        ProcessedOrder::try_from_slice(&data).unwrap()
+    }
+
+    async fn token_balance(&self, address: &Pubkey) -> u64 {
+        let data = self.account_data(address).await.unwrap();
+        StateWithExtensions::<TokenAccount>::unpack(&data)
+            .unwrap()
+            .base
+            .amount
+    }
+
+    fn initialize_ix(&self) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::Initialize {
+                fee_treasury: self.fee_treasury,
+            },
+            vec![
+                AccountMeta::new(self.authority.pubkey(), true),
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    fn add_supported_token_ix(&self, mint: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::AddSupportedToken { token_mint: *mint },
+            vec![
+                AccountMeta::new(self.authority.pubkey(), true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.supported_token_address(mint), false),
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    fn remove_supported_token_ix(&self, authority: &Pubkey, mint: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::RemoveSupportedToken,
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.supported_token_address(mint), false),
+            ],
+        )
+    }
+
+    /// Build an admin instruction taking `[authority, config]`
+    fn migrate_config_ix(&self, authority: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::MigrateConfig,
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new(self.config_address(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    /// Write a config account in the first release's layout, as deployed before migration
+    fn set_legacy_config(&mut self, legacy: &LegacyPaymentConfig) {
+        let (config_address, program_id) = (self.config_address(), self.program_id);
+        self.set_account(&config_address, &program_id, borsh::to_vec(legacy).unwrap());
+    }
+
+    fn initialize_roles_ix(&self) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::InitializeRoles,
+            vec![
+                AccountMeta::new(self.authority.pubkey(), true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.roles_address(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    fn roles_admin_ix(&self, authority: &Pubkey, instruction: PaymentInstruction) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &instruction,
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.roles_address(), false),
+            ],
+        )
+    }
+
+    /// Create the roles account and grant `roles` to `member`
+    async fn grant_roles(&self, member: &Pubkey, roles: &[Role]) {
+        let mut instructions = vec![self.initialize_roles_ix()];
+        for role in roles {
+            instructions.push(self.roles_admin_ix(
+                &self.authority.pubkey(),
+                PaymentInstruction::GrantRole {
+                    member: *member,
+                    role: *role,
+                },
+            ));
+        }
+        self.process(&instructions, &[&self.authority]).await.unwrap();
+    }
+
+    /// Append the roles account, which role holders other than the authority must pass
+    fn with_roles(&self, mut instruction: Instruction) -> Instruction {
+        instruction
+            .accounts
+            .push(AccountMeta::new_readonly(self.roles_address(), false));
+        instruction
+    }
+
+    /// Append the other multisig signers after the listed accounts
+    fn with_signers(&self, mut instruction: Instruction, signers: &[&Keypair]) -> Instruction {
+        for signer in signers {
+            instruction
+                .accounts
+                .push(AccountMeta::new_readonly(signer.pubkey(), true));
+        }
+        instruction
+    }
+
+    fn set_multisig_ix(&self, authority: &Pubkey, signers: &[Pubkey], threshold: u8) -> Instruction {
+        self.admin_ix(
+            authority,
+            PaymentInstruction::SetMultisig {
+                signers: signers.to_vec(),
+                threshold,
+            },
+        )
+    }
+
+    /// Set a `threshold`-of-`members.len()` multisig as the authority
+    async fn set_multisig(&self, members: &[&Keypair], threshold: u8) {
+        let signers: Vec<Pubkey> = members.iter().map(|member| member.pubkey()).collect();
+        let ix = self.set_multisig_ix(&self.authority.pubkey(), &signers, threshold);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    fn admin_ix(&self, authority: &Pubkey, instruction: PaymentInstruction) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &instruction,
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new(self.config_address(), false),
+            ],
+        )
+    }
+
+    /// Payment without an affiliate, so without commission
+    fn payment(&self, order_id: &str, amount: u64) -> Payment {
+        Payment {
+            buyer: AccountMeta::new(self.buyer.pubkey(), true),
+            buyer_token: self.buyer_token,
+            merchant_token: self.merchant_token,
+            fee_treasury_token: self.fee_treasury_token,
+            commission_vault: self.program_id,
+            mint: self.mint,
+            config: self.config_address(),
+            supported_token: self.supported_token_address(&self.mint),
+            processed_order: self.order_address(order_id),
+            affiliate: self.program_id,
+            commission_ledger: self.program_id,
+            token_program: self.token_program,
+            system_program: system_program::id(),
+            instructions_sysvar: solana_instructions_sysvar::id(),
+            closed_order: self.closed_order_address(order_id),
+            merchant: self.merchant_account,
+            order_id: order_id.to_string(),
+            amount,
+            expires_at: self.now + 10 * 60,
+        }
+    }
+
+    /// Payment through the registered affiliate
+    fn affiliate_payment(&self, order_id: &str, amount: u64) -> Payment {
+        Payment {
+            commission_vault: self.commission_vault_address(&self.mint),
+            affiliate: self.affiliate_account,
+            commission_ledger: self.commission_ledger_address(&self.mint),
+            ..self.payment(order_id, amount)
+        }
+    }
+
+    async fn pay(&self, payment: &Payment) -> Result<(), BanksClientError> {
+        self.process(&[payment.instruction(&self.program_id)], &[&self.buyer])
+            .await
+    }
+
+    /// SOL payment without an affiliate, so without commission
+    fn sol_payment_ix(&self, order_id: &str, amount: u64) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ProcessSolPayment {
+                order_id: order_id.to_string(),
+                amount,
+                product_id: "product-1".to_string(),
+            },
+            vec![
+                AccountMeta::new(self.buyer.pubkey(), true),
+                AccountMeta::new(self.merchant.pubkey(), false),
+                AccountMeta::new(self.fee_treasury, false),
+                AccountMeta::new_readonly(self.program_id, false),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(solana_instructions_sysvar::id(), false),
+                AccountMeta::new_readonly(self.closed_order_address(order_id), false),
+                AccountMeta::new_readonly(self.merchant_account, false),
+                AccountMeta::new(self.program_id, false),
+            ],
+        )
+    }
+
+    /// SOL payment through the registered affiliate
+    fn affiliate_sol_payment_ix(&self, order_id: &str, amount: u64) -> Instruction {
+        let mut instruction = self.sol_payment_ix(order_id, amount);
+        instruction.accounts[3] = AccountMeta::new(self.sol_commission_ledger_address(), false);
+        instruction.accounts[10] = AccountMeta::new(self.affiliate_account, false);
+        instruction
+    }
+
+    fn set_quote_signer_ix(&self, authority: &Pubkey, quote_signer: &Pubkey) -> Instruction {
+        self.admin_ix(
+            authority,
+            PaymentInstruction::SetQuoteSigner {
+                quote_signer: *quote_signer,
+            },
+        )
+    }
+
+    fn set_max_quote_lifetime_ix(
+        &self,
+        authority: &Pubkey,
+        max_quote_lifetime_secs: u32,
+    ) -> Instruction {
+        self.admin_ix(
+            authority,
+            PaymentInstruction::SetMaxQuoteLifetime {
+                max_quote_lifetime_secs,
+            },
+        )
+    }
+
+    fn set_max_total_fee_ix(&self, authority: &Pubkey, max_total_fee_bps: u16) -> Instruction {
+        self.admin_ix(
+            authority,
+            PaymentInstruction::SetMaxTotalFee { max_total_fee_bps },
+        )
+    }
+
+    async fn set_max_total_fee(&self, max_total_fee_bps: u16) {
+        let ix = self.set_max_total_fee_ix(&self.authority.pubkey(), max_total_fee_bps);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Register an active merchant without a fee override, accepting every supported mint
+    fn register_merchant_ix(
+        &self,
+        authority: &Pubkey,
+        merchant_id: &[u8; 32],
+        payout_wallet: &Pubkey,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::RegisterMerchant {
+                merchant_id: *merchant_id,
+                payout_wallet: *payout_wallet,
+                platform_fee_bps: None,
+                allowed_mints: vec![],
+            },
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.merchant_address(merchant_id), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    /// Update the registered merchant
+    fn update_merchant_ix(
+        &self,
+        authority: &Pubkey,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::UpdateMerchant {
+                status,
+                platform_fee_bps,
+                allowed_mints,
+            },
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.merchant_account, false),
+            ],
+        )
+    }
+
+    async fn set_merchant(
+        &self,
+        status: MerchantStatus,
+        platform_fee_bps: Option<u16>,
+        allowed_mints: Vec<Pubkey>,
+    ) {
+        let ix = self.update_merchant_ix(
+            &self.authority.pubkey(),
+            status,
+            platform_fee_bps,
+            allowed_mints,
+        );
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Register an active affiliate earning `commission_bps` for the API key owner
+    fn register_affiliate_ix(
+        &self,
+        authority: &Pubkey,
+        api_key_hash: &[u8; 32],
+        commission_bps: u16,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::RegisterAffiliate {
+                api_key_hash: *api_key_hash,
+                owner: self.api_key_owner.pubkey(),
+                commission_bps,
+            },
+            vec![
+                AccountMeta::new(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.affiliate_address(api_key_hash), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    /// Update the registered affiliate
+    fn update_affiliate_ix(
+        &self,
+        authority: &Pubkey,
+        owner: &Pubkey,
+        commission_bps: u16,
+        status: AffiliateStatus,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::UpdateAffiliate {
+                owner: *owner,
+                commission_bps,
+                status,
+            },
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.affiliate_account, false),
+            ],
+        )
+    }
+
+    /// Set the registered affiliate's commission rate and status, keeping its owner
+    async fn set_affiliate(&self, commission_bps: u16, status: AffiliateStatus) {
+        let ix = self.update_affiliate_ix(
+            &self.authority.pubkey(),
+            &self.api_key_owner.pubkey(),
+            commission_bps,
+            status,
+        );
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+    }
+
+    /// Claim the registered affiliate's commission in the test mint into `owner_token`
+    fn claim_commission_ix(&self, owner: &Pubkey, owner_token: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ClaimCommission,
+            vec![
+                AccountMeta::new_readonly(*owner, true),
+                AccountMeta::new_readonly(self.affiliate_account, false),
+                AccountMeta::new(self.commission_ledger_address(&self.mint), false),
+                AccountMeta::new(self.commission_vault_address(&self.mint), false),
+                AccountMeta::new(*owner_token, false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.token_program, false),
+            ],
+        )
+    }
+
+    async fn claim_commission(&self) -> Result<(), BanksClientError> {
+        let ix = self.claim_commission_ix(&self.api_key_owner.pubkey(), &self.api_key_owner_token);
+        self.process(&[ix], &[&self.api_key_owner]).await
+    }
+
+    fn claim_sol_commission_ix(&self, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ClaimSolCommission,
+            vec![
+                AccountMeta::new(*owner, true),
+                AccountMeta::new_readonly(self.affiliate_account, false),
+                AccountMeta::new(self.sol_commission_ledger_address(), false),
+            ],
+        )
+    }
+
+    async fn claim_sol_commission(&self) -> Result<(), BanksClientError> {
+        let ix = self.claim_sol_commission_ix(&self.api_key_owner.pubkey());
+        self.process(&[ix], &[&self.api_key_owner]).await
+    }
+
+    /// Require quotes signed by a new key and return it
+    async fn require_quotes(&self) -> Keypair {
+        let quote_signer = Keypair::new();
+        let ix = self.set_quote_signer_ix(&self.authority.pubkey(), &quote_signer.pubkey());
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+        quote_signer
+    }
+
+    /// Quote for a payment of the test mint to the merchant, with commission for the API key
+    /// owner, valid for a minute
+    async fn quote(&self, order_id: &str, amount: u64, commission_bps: u16) -> PaymentQuote {
+        let banks_client = self.context.banks_client.clone();
+        let clock: Clock = banks_client.get_sysvar().await.unwrap();
+        PaymentQuote {
+            terms: QuoteTerms {
+                program_id: self.program_id,
+                order_id_hash: solana_sdk::hash::hash(order_id.as_bytes()).to_bytes(),
+                mint: self.mint,
+                amount,
+                merchant: self.merchant_account,
+                commission_bps,
+                api_key_owner: self.api_key_owner.pubkey(),
+            },
+            expires_at: clock.unix_timestamp + 60,
+        }
+    }
+
+    async fn lamports(&self, address: &Pubkey) -> u64 {
+        let banks_client = self.context.banks_client.clone();
+        banks_client.get_balance(*address).await.unwrap()
+    }
+
+    fn refund_ix(
+        &self,
+        refunder: &Pubkey,
+        refund_source_token: &Pubkey,
+        order_id: &str,
+        amount: u64,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::RefundOrder { amount },
+            vec![
+                AccountMeta::new_readonly(*refunder, true),
+                AccountMeta::new(*refund_source_token, false),
+                AccountMeta::new(self.buyer_token, false),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.token_program, false),
+            ],
+        )
+    }
+
+    /// Fund a token account for `refunder` and refund `amount` of `order_id` from it
+    async fn refund(
+        &mut self,
+        refunder: &Keypair,
+        order_id: &str,
+        amount: u64,
+    ) -> Result<Pubkey, BanksClientError> {
+        let mint = self.mint;
+        let refund_source_token = self.add_token_account(&mint, &refunder.pubkey(), amount);
+        let ix = self.refund_ix(&refunder.pubkey(), &refund_source_token, order_id, amount);
+        self.process(&[ix], &[refunder]).await?;
+        Ok(refund_source_token)
+    }
+
+    /// Escrow payment without an affiliate, so without commission
+    fn escrow_payment_ix(&self, order_id: &str, amount: u64) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ProcessEscrowPayment {
+                order_id: order_id.to_string(),
+                amount,
+                product_id: "product-1".to_string(),
+            },
+            vec![
+                AccountMeta::new(self.buyer.pubkey(), true),
+                AccountMeta::new(self.buyer_token, false),
+                AccountMeta::new(self.vault_address(order_id), false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.supported_token_address(&self.mint), false),
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new_readonly(self.program_id, false),
+                AccountMeta::new_readonly(self.token_program, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(solana_instructions_sysvar::id(), false),
+                AccountMeta::new_readonly(self.closed_order_address(order_id), false),
+                AccountMeta::new_readonly(self.merchant_account, false),
+                AccountMeta::new_readonly(self.program_id, false),
+                AccountMeta::new_readonly(self.program_id, false),
+            ],
+        )
+    }
+
+    /// Escrow payment through the registered affiliate
+    fn affiliate_escrow_payment_ix(&self, order_id: &str, amount: u64) -> Instruction {
+        let mut instruction = self.escrow_payment_ix(order_id, amount);
+        instruction.accounts[7] = AccountMeta::new_readonly(self.affiliate_account, false);
+        instruction.accounts[13] =
+            AccountMeta::new(self.commission_vault_address(&self.mint), false);
+        instruction.accounts[14] =
+            AccountMeta::new(self.commission_ledger_address(&self.mint), false);
+        instruction
+    }
+
+    fn close_order_ix(&self, order_id: &str) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::CloseOrder,
+            vec![
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new(self.buyer.pubkey(), false),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.closed_order_address(order_id), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    /// `CloseOrder` for a released or returned escrow, closing its vault too
+    fn close_escrowed_order_ix(&self, order_id: &str) -> Instruction {
+        let mut instruction = self.close_order_ix(order_id);
+        instruction.accounts.extend([
+            AccountMeta::new(self.vault_address(order_id), false),
+            AccountMeta::new(self.mint, false),
+            AccountMeta::new_readonly(self.token_program, false),
+            AccountMeta::new(self.buyer_token, false),
+        ]);
+        instruction
+    }
+
+    async fn pay_into_escrow(&self, order_id: &str, amount: u64) {
+        let ix = self.escrow_payment_ix(order_id, amount);
+        self.process(&[ix], &[&self.buyer]).await.unwrap();
+    }
+
+    async fn pay_into_escrow_through_affiliate(&self, order_id: &str, amount: u64) {
+        let ix = self.affiliate_escrow_payment_ix(order_id, amount);
+        self.process(&[ix], &[&self.buyer]).await.unwrap();
+    }
+
+    fn release_escrow_ix(&self, releaser: &Pubkey, order_id: &str) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ReleaseEscrow,
+            vec![
+                AccountMeta::new_readonly(*releaser, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new(self.vault_address(order_id), false),
+                AccountMeta::new(self.merchant_token, false),
+                AccountMeta::new(self.fee_treasury_token, false),
+                AccountMeta::new(self.commission_vault_address(&self.mint), false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.token_program, false),
+                AccountMeta::new_readonly(self.merchant_account, false),
+                AccountMeta::new(self.commission_ledger_address(&self.mint), false),
+                AccountMeta::new(self.affiliate_account, false),
+            ],
+        )
+    }
+
+    fn open_dispute_ix(&self, buyer: &Pubkey, order_id: &str) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::OpenDispute,
+            vec![
+                AccountMeta::new_readonly(*buyer, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.order_address(order_id), false),
+            ],
+        )
+    }
+
+    async fn open_dispute(&self, order_id: &str) {
+        let ix = self.open_dispute_ix(&self.buyer.pubkey(), order_id);
+        self.process(&[ix], &[&self.buyer]).await.unwrap();
+    }
+
+    fn resolve_dispute_ix(
+        &self,
+        arbitrator: &Pubkey,
+        order_id: &str,
+        release_to_merchant: bool,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ResolveDispute {
+                release_to_merchant,
+            },
+            vec![
+                AccountMeta::new_readonly(*arbitrator, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.order_address(order_id), false),
+                AccountMeta::new(self.vault_address(order_id), false),
+                AccountMeta::new(self.buyer_token, false),
+                AccountMeta::new(self.merchant_token, false),
+                AccountMeta::new(self.fee_treasury_token, false),
+                AccountMeta::new(self.commission_vault_address(&self.mint), false),
+                AccountMeta::new_readonly(self.mint, false),
+                AccountMeta::new_readonly(self.token_program, false),
+                AccountMeta::new_readonly(self.merchant_account, false),
+                AccountMeta::new(self.commission_ledger_address(&self.mint), false),
+                AccountMeta::new(self.affiliate_account, false),
+            ],
+        )
+    }
+
+    /// Build a `QueueConfigChange` instruction, or one of its shorthands, signed by `proposer`
+    fn queue_change_ix(
+        &self,
+        proposer: &Pubkey,
+        change: &ConfigChange,
+        instruction: PaymentInstruction,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &instruction,
+            vec![
+                AccountMeta::new(*proposer, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.pending_change_address(change), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    fn queue_config_change_ix(&self, proposer: &Pubkey, change: ConfigChange) -> Instruction {
+        self.queue_change_ix(
+            proposer,
+            &change,
+            PaymentInstruction::QueueConfigChange { change },
+        )
+    }
+
+    fn execute_config_change_ix(&self, change: &ConfigChange, proposer: &Pubkey) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new(self.config_address(), false),
+            AccountMeta::new(self.pending_change_address(change), false),
+            AccountMeta::new(*proposer, false),
+        ];
+        if let Some(merchant) = change.merchant() {
+            accounts.push(AccountMeta::new(*merchant, false));
+        }
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::ExecuteConfigChange,
+            accounts,
+        )
+    }
+
+    fn cancel_config_change_ix(
+        &self,
+        authority: &Pubkey,
+        change: &ConfigChange,
+        proposer: &Pubkey,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            self.program_id,
+            &PaymentInstruction::CancelConfigChange,
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(self.config_address(), false),
+                AccountMeta::new(self.pending_change_address(change), false),
+                AccountMeta::new(*proposer, false),
+            ],
+        )
+    }
+
+    /// Queue `change` as the authority, wait out its delay and execute it
+    async fn apply_config_change(&mut self, change: ConfigChange) {
+        let authority = self.authority.pubkey();
+        let ix = self.queue_config_change_ix(&authority, change);
+        self.process(&[ix], &[&self.authority]).await.unwrap();
+        let delay = change.delay_secs(&self.config().await);
+        self.advance_clock(delay as i64).await;
+        let ix = self.execute_config_change_ix(&change, &authority);
+        self.process(&[ix], &[]).await.unwrap();
+    }
+
+    async fn set_platform_fee(&mut self, fee_bps: u16) {
+        self.apply_config_change(ConfigChange::PlatformFee {
+            new_fee_bps: fee_bps,
+        })
+        .await;
+    }
+}
+
+/// Build an Ed25519 program instruction checking `signer`'s signature over `quote`
+fn quote_ix(signer: &Keypair, quote: &PaymentQuote) -> Instruction {
+    let message = borsh::to_vec(quote).unwrap();
+    let signature = signer.sign_message(&message);
+
+    // Offsets header, then the public key, signature and message it points at
+    let (public_key_offset, signature_offset, message_offset) = (16, 48, 112);
+    let mut data = vec![1, 0];
+    for offset in [
+        signature_offset,
+        u16::MAX,
+        public_key_offset,
+        u16::MAX,
+        message_offset,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&offset.to_le_bytes());
+    }
+    data.extend_from_slice(signer.pubkey().as_ref());
+    data.extend_from_slice(signature.as_ref());
+    data.extend_from_slice(&message);
+
+    Instruction::new_with_bytes(ed25519_program::id(), &data, vec![])
+}
+
+fn assert_instruction_error(result: Result<(), BanksClientError>, expected: InstructionError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, expected)
+    );
+}
+
+fn assert_payment_error(result: Result<(), BanksClientError>, expected: PaymentError) {
+    assert_instruction_error(result, InstructionError::Custom(expected as u32));
+}
+
+/// Like `assert_payment_error`, for a payment following its quote instruction
+fn assert_quoted_payment_error(result: Result<(), BanksClientError>, expected: PaymentError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(expected as u32))
+    );
+}
+
+// Initialize
+
+#[tokio::test]
+async fn initialize_creates_config() {
+    let env = TestEnv::new().await;
+
+    env.process(&[env.initialize_ix()], &[&env.authority])
+        .await
+        .unwrap();
+
+    let config = env.config().await;
+    assert_eq!(config.authority, env.authority.pubkey());
+    assert_eq!(config.fee_treasury, env.fee_treasury);
+    assert_eq!(config.refund_operator, Pubkey::default());
+    assert_eq!(config.arbitrator, Pubkey::default());
+    assert_eq!(
+        config.dispute_window_secs,
+        PaymentConfig::DEFAULT_DISPUTE_WINDOW_SECS
+    );
+    assert_eq!(
+        config.payout_wallet_delay_secs,
+        PaymentConfig::DEFAULT_CHANGE_DELAY_SECS
+    );
+    assert_eq!(
+        config.platform_fee_delay_secs,
+        PaymentConfig::DEFAULT_CHANGE_DELAY_SECS
+    );
+    assert_eq!(
+        config.max_quote_lifetime_secs,
+        PaymentConfig::DEFAULT_MAX_QUOTE_LIFETIME_SECS
+    );
+    assert_eq!(
+        config.order_retention_secs,
+        PaymentConfig::DEFAULT_ORDER_RETENTION_SECS
+    );
+    assert_eq!(config.platform_fee_bps, 0);
+    assert_eq!(config.max_platform_fee_bps, PaymentConfig::MAX_PLATFORM_FEE_BPS);
+    assert_eq!(config.max_commission_bps, PaymentConfig::MAX_COMMISSION_BPS);
+    assert_eq!(config.max_total_fee_bps, PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS);
+    assert!(!config.is_paused);
+}
+
+#[tokio::test]
+async fn initialize_twice_fails() {
+    let env = TestEnv::initialized().await;
+
+    let result = env.process(&[env.initialize_ix()], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::AlreadyInitialized);
+}
+
+#[tokio::test]
+async fn initialize_rejects_wrong_config_address() {
+    let env = TestEnv::new().await;
+    let mut ix = env.initialize_ix();
+    ix.accounts[1].pubkey = Pubkey::new_unique();
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidConfigAccount);
+}
+
+#[tokio::test]
+async fn initialize_rejects_wrong_system_program() {
+    let env = TestEnv::new().await;
+    let mut ix = env.initialize_ix();
+    ix.accounts[2].pubkey = spl_token::id();
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidSystemProgram);
+}
+
+#[tokio::test]
+async fn initialize_requires_authority_signature() {
+    let env = TestEnv::new().await;
+    let mut ix = env.initialize_ix();
+    ix.accounts[0].is_signer = false;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn rejects_invalid_instruction_data() {
+    let env = TestEnv::new().await;
+    let ix = Instruction::new_with_bytes(
+        env.program_id,
+        &[255],
+        vec![AccountMeta::new(env.authority.pubkey(), true)],
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidInstruction);
+}
+
+// ProcessPayment
+
+#[tokio::test]
+async fn payment_splits_net_fee_and_commission() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000_000))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500_000);
+    assert_eq!(env.commission_vault_balance().await, 5_000_000);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 100_000_000
+    );
+
+    let data = env.account_data(&env.order_address("order-1")).await.unwrap();
+    let order = ProcessedOrder::try_from_slice(&data).unwrap();
+    assert_eq!(
+        order.order_id_hash,
+        solana_sdk::hash::hash(b"order-1").to_bytes()
+    );
+    assert_eq!(order.buyer, env.buyer.pubkey());
+    assert_eq!(order.token_mint, env.mint);
+    assert_eq!(order.amount, 100_000_000);
+    assert_eq!(order.platform_fee, 2_500_000);
+    assert_eq!(order.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(order.commission, 5_000_000);
+    assert_eq!(order.commission_bps, 500);
+    assert_eq!(order.merchant, env.merchant_account);
+}
+
+#[tokio::test]
+async fn payment_without_fee_or_commission_goes_to_merchant() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 100_000_000);
+    // The commission vault is not checked when there is no affiliate
+    payment.commission_vault = Pubkey::new_unique();
+
+    env.pay(&payment).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 100_000_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 0);
+}
+
+#[tokio::test]
+async fn payment_rejects_duplicate_order() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+
+    let result = env.pay(&env.payment("order-1", 2_000)).await;
+
+    assert_payment_error(result, PaymentError::OrderAlreadyProcessed);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn payment_rejects_when_paused() {
+    let env = TestEnv::initialized().await;
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::ContractPaused);
+}
+
+#[tokio::test]
+async fn payment_rejects_uninitialized_config() {
+    let env = TestEnv::new().await;
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_unregistered_mint() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let mut payment = env.payment("order-1", 1_000);
+    payment.mint = other_mint;
+    payment.supported_token = env.supported_token_address(&other_mint);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::TokenNotSupported);
+}
+
+#[tokio::test]
+async fn payment_rejects_removed_token() {
+    let env = TestEnv::initialized().await;
+    let remove = env.remove_supported_token_ix(&env.authority.pubkey(), &env.mint);
+    env.process(&[remove], &[&env.authority]).await.unwrap();
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::TokenNotSupported);
+}
+
+#[tokio::test]
+async fn payment_rejects_supported_token_for_other_mint() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let mut payment = env.payment("order-1", 1_000);
+    payment.mint = other_mint;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::TokenNotSupported);
+}
+
+#[tokio::test]
+async fn payment_rejects_zero_amount() {
+    let env = TestEnv::initialized().await;
+
+    let result = env.pay(&env.payment("order-1", 0)).await;
+
+    assert_payment_error(result, PaymentError::InvalidAmount);
+}
+
+#[tokio::test]
+async fn payment_splits_max_amount_without_overflow() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    let (mint, buyer) = (env.mint, env.buyer.pubkey());
+    env.buyer_token = env.add_token_account(&mint, &buyer, u64::MAX);
+
+    env.pay(&env.affiliate_payment("order-1", u64::MAX))
+        .await
+        .unwrap();
+
+    let platform_fee = (u128::from(u64::MAX) * 250 / 10000) as u64;
+    let commission = (u128::from(u64::MAX) * 500 / 10000) as u64;
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, platform_fee);
+    assert_eq!(env.commission_vault_balance().await, commission);
+    assert_eq!(
+        env.token_balance(&env.merchant_token).await,
+        u64::MAX - platform_fee - commission
+    );
+    assert_eq!(env.token_balance(&env.buyer_token).await, 0);
+}
+
+#[tokio::test]
+async fn payment_rejects_total_fee_above_default_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_affiliate(PaymentConfig::MAX_COMMISSION_BPS, AffiliateStatus::Active)
+        .await;
+    env.set_platform_fee(100).await;
+
+    let result = env.pay(&env.affiliate_payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn payment_requires_buyer_signature() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.buyer = AccountMeta::new(env.buyer.pubkey(), false);
+
+    let result = env
+        .process(&[payment.instruction(&env.program_id)], &[])
+        .await;
+
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_token_program() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.token_program = system_program::id();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidTokenProgram);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_system_program() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.system_program = spl_token::id();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidSystemProgram);
+}
+
+#[tokio::test]
+async fn payment_rejects_config_not_owned_by_program() {
+    let mut env = TestEnv::initialized().await;
+    let data = env.account_data(&env.config_address()).await.unwrap();
+    let fake_config = Pubkey::new_unique();
+    env.set_account(&fake_config, &Pubkey::new_unique(), data);
+    let mut payment = env.payment("order-1", 1_000);
+    payment.config = fake_config;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidAccountOwner);
+}
+
+#[tokio::test]
+async fn payment_rejects_config_at_wrong_address() {
+    let mut env = TestEnv::initialized().await;
+    let data = env.account_data(&env.config_address()).await.unwrap();
+    let fake_config = Pubkey::new_unique();
+    let program_id = env.program_id;
+    env.set_account(&fake_config, &program_id, data);
+    let mut payment = env.payment("order-1", 1_000);
+    payment.config = fake_config;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidConfigAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_supported_token_not_owned_by_program() {
+    let mut env = TestEnv::initialized().await;
+    let data = env
+        .account_data(&env.supported_token_address(&env.mint))
+        .await
+        .unwrap();
+    let fake_token = Pubkey::new_unique();
+    env.set_account(&fake_token, &Pubkey::new_unique(), data);
+    let mut payment = env.payment("order-1", 1_000);
+    payment.supported_token = fake_token;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidAccountOwner);
+}
+
+#[tokio::test]
+async fn payment_rejects_supported_token_at_wrong_address() {
+    let mut env = TestEnv::initialized().await;
+    let data = env
+        .account_data(&env.supported_token_address(&env.mint))
+        .await
+        .unwrap();
+    let fake_token = Pubkey::new_unique();
+    let program_id = env.program_id;
+    env.set_account(&fake_token, &program_id, data);
+    let mut payment = env.payment("order-1", 1_000);
+    payment.supported_token = fake_token;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidSupportedTokenAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_order_address() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.processed_order = env.order_address("order-2");
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidOrderAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_non_mint() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.mint = env.buyer_token;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidMint);
+}
+
+#[tokio::test]
+async fn payment_rejects_non_token_account() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant_token = env.merchant.pubkey();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidTokenAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_token_account_for_other_mint() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let merchant = env.merchant.pubkey();
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant_token = env.add_token_account(&other_mint, &merchant, 0);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::TokenMintMismatch);
+}
+
+#[tokio::test]
+async fn payment_rejects_buyer_token_account_of_other_owner() {
+    let mut env = TestEnv::initialized().await;
+    let mint = env.mint;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.buyer_token = env.add_token_account(&mint, &Pubkey::new_unique(), 1_000);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidBuyerTokenAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_buyer_owned_merchant_account() {
+    let mut env = TestEnv::initialized().await;
+    let mint = env.mint;
+    let buyer = env.buyer.pubkey();
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant_token = env.add_token_account(&mint, &buyer, 0);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantTokenAccount);
+    assert!(env
+        .account_data(&env.order_address("order-1"))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_fee_treasury_account() {
+    let mut env = TestEnv::initialized().await;
+    let mint = env.mint;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.fee_treasury_token = env.add_token_account(&mint, &Pubkey::new_unique(), 0);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidFeeTreasuryTokenAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_commission_vault() {
+    let mut env = TestEnv::initialized().await;
+    let mint = env.mint;
+    let mut payment = env.affiliate_payment("order-1", 1_000);
+    payment.commission_vault = env.add_token_account(&mint, &Pubkey::new_unique(), 0);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionVaultAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_commission_vault_once_ledger_exists() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 1_000)).await.unwrap();
+    let (mint, ledger) = (env.mint, env.commission_ledger_address(&env.mint));
+    let mut payment = env.affiliate_payment("order-2", 1_000);
+    payment.commission_vault = env.add_token_account(&mint, &ledger, 0);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionVaultAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_commission_ledger() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.affiliate_payment("order-1", 1_000);
+    payment.commission_ledger = Pubkey::new_unique();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionLedgerAccount);
+}
+
+// ProcessSolPayment
+
+const SOL_PAYMENT: u64 = 1_000_000_000;
+
+#[tokio::test]
+async fn sol_payment_splits_net_fee_and_commission() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+
+    env.process(&[env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.lamports(&env.merchant.pubkey()).await, 925_000_000);
+    assert_eq!(env.lamports(&env.fee_treasury).await, 25_000_000);
+    assert_eq!(env.lamports(&env.api_key_owner.pubkey()).await, 0);
+    assert_eq!(
+        env.lamports(&env.sol_commission_ledger_address()).await,
+        Rent::default().minimum_balance(CommissionLedger::LEN) + 50_000_000
+    );
+    let ledger = env.sol_commission_ledger().await;
+    assert_eq!(ledger.affiliate, env.affiliate_account);
+    assert_eq!(ledger.mint, spl_token::native_mint::id());
+    assert_eq!(ledger.accrued, 50_000_000);
+
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.buyer, env.buyer.pubkey());
+    assert_eq!(order.merchant, env.merchant_account);
+    assert_eq!(order.token_mint, spl_token::native_mint::id());
+    assert_eq!(order.amount, SOL_PAYMENT);
+    assert_eq!(order.platform_fee, 25_000_000);
+    assert_eq!(order.commission, 50_000_000);
+    assert_eq!(order.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(order.status, OrderStatus::Completed);
+    assert_eq!(env.affiliate_state().await.total_earnings, 50_000_000);
+}
+
+#[tokio::test]
+async fn sol_payment_without_affiliate_takes_no_commission() {
+    let env = TestEnv::initialized().await;
+    let api_key_owner_lamports = env.lamports(&env.api_key_owner.pubkey()).await;
+
+    env.process(&[env.sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.lamports(&env.merchant.pubkey()).await, SOL_PAYMENT);
+    assert_eq!(
+        env.lamports(&env.api_key_owner.pubkey()).await,
+        api_key_owner_lamports
+    );
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.commission, 0);
+    assert_eq!(order.api_key_owner, Pubkey::default());
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_duplicate_order() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+
+    let result = env
+        .process(&[env.sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::OrderAlreadyProcessed);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_when_paused() {
+    let env = TestEnv::initialized().await;
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+
+    let result = env
+        .process(&[env.sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::ContractPaused);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_zero_amount() {
+    let env = TestEnv::initialized().await;
+
+    let result = env
+        .process(&[env.sol_payment_ix("order-1", 0)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::InvalidAmount);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_token_commission_ledger() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT);
+    ix.accounts[3].pubkey = env.commission_ledger_address(&spl_token::native_mint::id());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionLedgerAccount);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized().await;
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+
+    let result = env
+        .process(&[env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::AffiliateSuspended);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_wallet_other_than_merchant_payout_wallet() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.sol_payment_ix("order-1", SOL_PAYMENT);
+    ix.accounts[1].pubkey = env.other_wallet;
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantWallet);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_unregistered_merchant() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.sol_payment_ix("order-1", SOL_PAYMENT);
+    ix.accounts[9].pubkey = env.merchant_address(&[1; 32]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_merchant_not_accepting_sol() {
+    let env = TestEnv::initialized().await;
+    env.set_merchant(MerchantStatus::Active, None, vec![env.mint])
+        .await;
+
+    let result = env
+        .process(&[env.sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::MintNotAllowedForMerchant);
+}
+
+#[tokio::test]
+async fn sol_payment_applies_merchant_fee_override() {
+    let env = TestEnv::initialized().await;
+    env.set_merchant(MerchantStatus::Active, Some(100), vec![])
+        .await;
+
+    env.process(&[env.sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.lamports(&env.merchant.pubkey()).await, 990_000_000);
+    assert_eq!(env.lamports(&env.fee_treasury).await, 10_000_000);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_wrong_fee_treasury() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.sol_payment_ix("order-1", SOL_PAYMENT);
+    ix.accounts[2].pubkey = Pubkey::new_unique();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::InvalidFeeTreasury);
+}
+
+#[tokio::test]
+async fn sol_payment_requires_buyer_signature() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.sol_payment_ix("order-1", SOL_PAYMENT);
+    ix.accounts[0].is_signer = false;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+// SetQuoteSigner / payment quotes
+
+#[tokio::test]
+async fn quoted_payment_splits_amount() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    let quote_signer = env.require_quotes().await;
+    let payment = env.affiliate_payment("order-1", 100_000);
+    let quote = env.quote("order-1", 100_000, 500).await;
+
+    env.process(
+        &[quote_ix(&quote_signer, &quote), payment.instruction(&env.program_id)],
+        &[&env.buyer],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+}
+
+#[tokio::test]
+async fn payment_requires_quote_once_signer_set() {
+    let env = TestEnv::initialized().await;
+    env.require_quotes().await;
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::InvalidQuote);
+}
+
+#[tokio::test]
+async fn payment_rejects_terms_differing_from_quote() {
+    let env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let quote = env.quote("order-1", 100_000, 500).await;
+
+    // Underpaying, dropping the affiliate or reusing the quote for another order
+    for payment in [
+        env.affiliate_payment("order-1", 50_000),
+        env.payment("order-1", 100_000),
+        env.affiliate_payment("order-2", 100_000),
+    ] {
+        let result = env
+            .process(
+                &[quote_ix(&quote_signer, &quote), payment.instruction(&env.program_id)],
+                &[&env.buyer],
+            )
+            .await;
+        assert_quoted_payment_error(result, PaymentError::InvalidQuote);
+    }
+}
+
+#[tokio::test]
+async fn payment_rejects_quote_for_other_merchant() {
+    let mut env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let other_wallet = Pubkey::new_unique();
+    let ix = env.register_merchant_ix(&env.authority.pubkey(), &[1; 32], &other_wallet);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let quote = env.quote("order-1", 1_000, AFFILIATE_COMMISSION_BPS).await;
+
+    let mint = env.mint;
+    let mut payment = env.affiliate_payment("order-1", 1_000);
+    payment.merchant = env.merchant_address(&[1; 32]);
+    payment.merchant_token = env.add_token_account(&mint, &other_wallet, 0);
+    let result = env
+        .process(
+            &[quote_ix(&quote_signer, &quote), payment.instruction(&env.program_id)],
+            &[&env.buyer],
+        )
+        .await;
+
+    assert_quoted_payment_error(result, PaymentError::InvalidQuote);
+}
+
+#[tokio::test]
+async fn payment_rejects_quote_from_other_signer() {
+    let env = TestEnv::initialized().await;
+    env.require_quotes().await;
+    let payment = env.affiliate_payment("order-1", 1_000);
+    let quote = env.quote("order-1", 1_000, AFFILIATE_COMMISSION_BPS).await;
+
+    let result = env
+        .process(
+            &[quote_ix(&env.buyer, &quote), payment.instruction(&env.program_id)],
+            &[&env.buyer],
+        )
+        .await;
+
+    assert_quoted_payment_error(result, PaymentError::InvalidQuote);
+}
+
+#[tokio::test]
+async fn payment_rejects_expired_quote() {
+    let mut env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let quote = env.quote("order-1", 1_000, AFFILIATE_COMMISSION_BPS).await;
+    env.advance_clock(61).await;
+    let payment = env.affiliate_payment("order-1", 1_000);
+
+    let result = env
+        .process(
+            &[quote_ix(&quote_signer, &quote), payment.instruction(&env.program_id)],
+            &[&env.buyer],
+        )
+        .await;
+
+    assert_quoted_payment_error(result, PaymentError::QuoteExpired);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_instructions_sysvar() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.instructions_sysvar = Pubkey::new_unique();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidInstructionsSysvar);
+}
+
+#[tokio::test]
+async fn quoted_sol_payment_uses_native_mint() {
+    let env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let mut quote = env.quote("order-1", SOL_PAYMENT, AFFILIATE_COMMISSION_BPS).await;
+    let ix = env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT);
+
+    let result = env
+        .process(&[quote_ix(&quote_signer, &quote), ix.clone()], &[&env.buyer])
+        .await;
+    assert_quoted_payment_error(result, PaymentError::InvalidQuote);
+
+    quote.terms.mint = spl_token::native_mint::id();
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.lamports(&env.merchant.pubkey()).await, 950_000_000);
+}
+
+#[tokio::test]
+async fn escrow_payment_requires_quote_once_signer_set() {
+    let env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let ix = env.escrow_payment_ix("order-1", 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_payment_error(result, PaymentError::InvalidQuote);
+
+    let quote = env.quote("order-1", 1_000, AFFILIATE_COMMISSION_BPS).await;
+    let ix = env.affiliate_escrow_payment_ix("order-1", 1_000);
+    env.process(&[quote_ix(&quote_signer, &quote), ix], &[&env.buyer])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 1_000);
+}
+
+#[tokio::test]
+async fn set_quote_signer_to_default_stops_requiring_quotes() {
+    let env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    assert_eq!(env.config().await.quote_signer, quote_signer.pubkey());
+
+    let ix = env.set_quote_signer_ix(&env.authority.pubkey(), &Pubkey::default());
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+}
+
+#[tokio::test]
+async fn set_quote_signer_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_quote_signer_ix(&env.buyer.pubkey(), &env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// Order deadlines / SetMaxQuoteLifetime
+
+#[tokio::test]
+async fn payment_rejects_order_expired_before_processing() {
+    let mut env = TestEnv::initialized().await;
+    let payment = env.payment("order-1", 1_000);
+    env.advance_clock(10 * 60 + 1).await;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::OrderExpired);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 0);
+}
+
+#[tokio::test]
+async fn payment_rejects_expiry_beyond_max_quote_lifetime() {
+    let env = TestEnv::initialized().await;
+    let max_lifetime = i64::from(PaymentConfig::DEFAULT_MAX_QUOTE_LIFETIME_SECS);
+    let mut payment = env.payment("order-1", 1_000);
+    payment.expires_at = env.now + max_lifetime + 1;
+
+    let result = env.pay(&payment).await;
+    assert_payment_error(result, PaymentError::ExpiryTooFar);
+
+    payment.expires_at = env.now + max_lifetime;
+    env.pay(&payment).await.unwrap();
+}
+
+#[tokio::test]
+async fn payment_rejects_quote_beyond_max_quote_lifetime() {
+    let env = TestEnv::initialized().await;
+    let quote_signer = env.require_quotes().await;
+    let payment = env.affiliate_payment("order-1", 1_000);
+    let mut quote = env.quote("order-1", 1_000, AFFILIATE_COMMISSION_BPS).await;
+    quote.expires_at = env.now + 2 * 60 * 60;
+
+    let result = env
+        .process(
+            &[quote_ix(&quote_signer, &quote), payment.instruction(&env.program_id)],
+            &[&env.buyer],
+        )
+        .await;
+
+    assert_quoted_payment_error(result, PaymentError::ExpiryTooFar);
+}
+
+#[tokio::test]
+async fn set_max_quote_lifetime_zero_removes_limit() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_max_quote_lifetime_ix(&env.authority.pubkey(), 0);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert_eq!(env.config().await.max_quote_lifetime_secs, 0);
+
+    let mut payment = env.payment("order-1", 1_000);
+    payment.expires_at = i64::MAX;
+    env.pay(&payment).await.unwrap();
+}
+
+#[tokio::test]
+async fn set_max_quote_lifetime_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_max_quote_lifetime_ix(&env.buyer.pubkey(), 0);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// RefundOrder
+
+#[tokio::test]
+async fn refund_order_returns_full_amount_to_buyer() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    let refund_source_token = env.refund(&authority, "order-1", 100_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&refund_source_token).await, 0);
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 100_000);
+}
+
+#[tokio::test]
+async fn refund_order_accumulates_partial_refunds() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    env.refund(&authority, "order-1", 30_000).await.unwrap();
+    env.refund(&authority, "order-1", 50_000).await.unwrap();
+
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 80_000);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 20_000
+    );
+
+    let result = env.refund(&authority, "order-1", 20_001).await;
+    assert_payment_error(result.map(|_| ()), PaymentError::RefundExceedsPayment);
+
+    env.refund(&authority, "order-1", 20_000).await.unwrap();
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 100_000);
+}
+
+#[tokio::test]
+async fn refund_order_accepts_refund_operator() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let refund_operator = Keypair::new();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::UpdateRefundOperator {
+            new_refund_operator: refund_operator.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert_eq!(env.config().await.refund_operator, refund_operator.pubkey());
+
+    env.refund(&refund_operator, "order-1", 40_000).await.unwrap();
+
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 40_000);
+}
+
+#[tokio::test]
+async fn refund_order_allowed_while_paused() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let ix = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    env.refund(&authority, "order-1", 100_000).await.unwrap();
+}
+
+#[tokio::test]
+async fn refund_order_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let buyer = env.buyer.insecure_clone();
+
+    let result = env.refund(&buyer, "order-1", 1_000).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_zero_amount() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    let result = env.refund(&authority, "order-1", 0).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::InvalidAmount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_amount_above_payment() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    let result = env.refund(&authority, "order-1", 100_001).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::RefundExceedsPayment);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_unprocessed_order() {
+    let mut env = TestEnv::initialized().await;
+    let authority = env.authority.insecure_clone();
+
+    let result = env.refund(&authority, "order-1", 1_000).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_other_buyer_token_account() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let (mint, authority) = (env.mint, env.authority.pubkey());
+    env.buyer_token = env.add_token_account(&mint, &authority, 0);
+    let authority = env.authority.insecure_clone();
+
+    let result = env.refund(&authority, "order-1", 1_000).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::InvalidBuyerTokenAccount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_source_of_other_owner() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let ix = env.refund_ix(&env.authority.pubkey(), &env.other_wallet_token, "order-1", 1_000);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidRefundTokenAccount);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_source_of_other_mint() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let other_mint = env.add_mint();
+    let authority = env.authority.pubkey();
+    let refund_source_token = env.add_token_account(&other_mint, &authority, 1_000);
+    let ix = env.refund_ix(&authority, &refund_source_token, "order-1", 1_000);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::TokenMintMismatch);
+}
+
+#[tokio::test]
+async fn update_refund_operator_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::UpdateRefundOperator {
+            new_refund_operator: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// Escrow
+
+const DISPUTE_WINDOW: i64 = PaymentConfig::DEFAULT_DISPUTE_WINDOW_SECS as i64;
+
+#[tokio::test]
+async fn escrow_payment_holds_amount_in_vault() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 100_000);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 0);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 0);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 100_000
+    );
+
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.status, OrderStatus::Escrowed);
+    assert_eq!(order.merchant, env.merchant_account);
+    assert_eq!(order.platform_fee, 2_500);
+    assert_eq!(order.commission, 5_000);
+}
+
+#[tokio::test]
+async fn escrow_payment_rejects_duplicate_order() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+
+    let result = env.pay(&env.payment("order-1", 100_000)).await;
+
+    assert_payment_error(result, PaymentError::OrderAlreadyProcessed);
+}
+
+#[tokio::test]
+async fn escrow_payment_rejects_wrong_vault_address() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.escrow_payment_ix("order-1", 100_000);
+    ix.accounts[2].pubkey = env.vault_address("order-2");
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::InvalidVaultAccount);
+}
+
+#[tokio::test]
+async fn release_escrow_by_authority_splits_vault() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 0);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+    assert_eq!(env.commission_ledger().await.accrued, 5_000);
+    assert_eq!(env.affiliate_state().await.total_earnings, 5_000);
+    assert_eq!(
+        env.processed_order("order-1").await.status,
+        OrderStatus::Released
+    );
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_other_commission_vault() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+    let mut ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    ix.accounts[6].pubkey = env.api_key_owner_token;
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionVaultAccount);
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_affiliate_other_than_order() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+    let mut ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    ix.accounts[11].pubkey = env.affiliate_address(&[9; 32]);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidAffiliateAccount);
+}
+
+#[tokio::test]
+async fn release_escrow_by_merchant_waits_for_dispute_window() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.release_escrow_ix(&env.merchant.pubkey(), "order-1");
+
+    let result = env.process(&[ix], &[&env.merchant]).await;
+    assert_payment_error(result, PaymentError::DisputeWindowOpen);
+
+    env.advance_clock(DISPUTE_WINDOW + 1).await;
+    let ix = env.release_escrow_ix(&env.merchant.pubkey(), "order-1");
+    env.process(&[ix], &[&env.merchant]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 100_000);
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.release_escrow_ix(&env.buyer.pubkey(), "order-1");
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_direct_payment() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidOrderStatus);
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_token_account_of_other_wallet() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let mut ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    ix.accounts[4].pubkey = env.other_wallet_token;
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantTokenAccount);
+}
+
+#[tokio::test]
+async fn release_escrow_rejects_other_merchant_account() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let mut ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    ix.accounts[9].pubkey = env.merchant_address(&[1; 32]);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantAccount);
+}
+
+#[tokio::test]
+async fn release_escrow_pays_updated_payout_wallet() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let new_payout_wallet = Keypair::new();
+    env.apply_config_change(ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet: new_payout_wallet.pubkey(),
+    })
+    .await;
+    env.advance_clock(DISPUTE_WINDOW + 1).await;
+
+    // The old payout wallet may no longer release
+    let ix = env.release_escrow_ix(&env.merchant.pubkey(), "order-1");
+    let result = env.process(&[ix], &[&env.merchant]).await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+
+    let mint = env.mint;
+    let new_payout_token = env.add_token_account(&mint, &new_payout_wallet.pubkey(), 0);
+    let mut ix = env.release_escrow_ix(&new_payout_wallet.pubkey(), "order-1");
+    ix.accounts[4].pubkey = new_payout_token;
+    env.process(&[ix], &[&new_payout_wallet]).await.unwrap();
+    assert_eq!(env.token_balance(&new_payout_token).await, 100_000);
+}
+
+#[tokio::test]
+async fn open_dispute_blocks_release() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+
+    env.open_dispute("order-1").await;
+
+    assert_eq!(
+        env.processed_order("order-1").await.status,
+        OrderStatus::Disputed
+    );
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::InvalidOrderStatus);
+}
+
+#[tokio::test]
+async fn open_dispute_rejects_after_window() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    env.advance_clock(DISPUTE_WINDOW + 1).await;
+    let ix = env.open_dispute_ix(&env.buyer.pubkey(), "order-1");
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::DisputeWindowClosed);
+}
+
+#[tokio::test]
+async fn open_dispute_rejects_other_caller() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.open_dispute_ix(&env.merchant.pubkey(), "order-1");
+
+    let result = env.process(&[ix], &[&env.merchant]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn resolve_dispute_returns_vault_to_buyer() {
+    let env = TestEnv::initialized().await;
+    let arbitrator = Keypair::new();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::UpdateEscrowSettings {
+            arbitrator: arbitrator.pubkey(),
+            dispute_window_secs: 3_600,
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+    env.open_dispute("order-1").await;
+
+    let ix = env.resolve_dispute_ix(&arbitrator.pubkey(), "order-1", false);
+    env.process(&[ix], &[&arbitrator]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 0);
+    assert_eq!(env.commission_vault_balance().await, 0);
+    assert_eq!(env.commission_ledger().await.accrued, 0);
+    assert_eq!(
+        env.processed_order("order-1").await.status,
+        OrderStatus::ReturnedToBuyer
+    );
+}
+
+#[tokio::test]
+async fn resolve_dispute_releases_vault_to_merchant() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+    env.open_dispute("order-1").await;
+
+    let ix = env.resolve_dispute_ix(&env.authority.pubkey(), "order-1", true);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+    assert_eq!(env.commission_ledger().await.accrued, 5_000);
+    assert_eq!(env.affiliate_state().await.total_earnings, 5_000);
+    assert_eq!(
+        env.processed_order("order-1").await.status,
+        OrderStatus::Released
+    );
+}
+
+#[tokio::test]
+async fn resolve_dispute_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    env.open_dispute("order-1").await;
+    let ix = env.resolve_dispute_ix(&env.merchant.pubkey(), "order-1", true);
+
+    let result = env.process(&[ix], &[&env.merchant]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn resolve_dispute_rejects_undisputed_order() {
+    let env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.resolve_dispute_ix(&env.authority.pubkey(), "order-1", false);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidOrderStatus);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_escrowed_order() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let authority = env.authority.insecure_clone();
+
+    let result = env.refund(&authority, "order-1", 1_000).await;
+
+    assert_payment_error(result.map(|_| ()), PaymentError::InvalidOrderStatus);
+}
+
+#[tokio::test]
+async fn update_escrow_settings_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::UpdateEscrowSettings {
+            arbitrator: env.buyer.pubkey(),
+            dispute_window_secs: 0,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// CloseOrder / SetOrderRetention
+
+const ORDER_RETENTION: i64 = PaymentConfig::DEFAULT_ORDER_RETENTION_SECS as i64;
+
+#[tokio::test]
+async fn close_order_returns_rent_and_blocks_repayment() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+    let order_rent = env.lamports(&env.order_address("order-1")).await;
+    let buyer_lamports = env.lamports(&env.buyer.pubkey()).await;
+    env.advance_clock(ORDER_RETENTION).await;
+
+    env.process(&[env.close_order_ix("order-1")], &[]).await.unwrap();
+
+    assert!(env.account_data(&env.order_address("order-1")).await.is_none());
+    let closed_order = env.closed_order_address("order-1");
+    assert_eq!(env.account_owner(&closed_order).await, Some(env.program_id));
+    assert_eq!(
+        env.lamports(&env.buyer.pubkey()).await,
+        buyer_lamports + order_rent - env.lamports(&closed_order).await
+    );
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+    assert_payment_error(result, PaymentError::OrderAlreadyProcessed);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn close_order_keeps_other_orders_payable() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    env.process(&[env.close_order_ix("order-1")], &[]).await.unwrap();
+
+    for order_id in ["order-2", "order-3", "order-4"] {
+        env.pay(&env.payment(order_id, 1_000)).await.unwrap();
+    }
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 4_000);
+}
+
+#[tokio::test]
+async fn close_order_closes_released_escrow_vault() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let vault = env.vault_address("order-1");
+    let rent = env.lamports(&vault).await + env.lamports(&env.order_address("order-1")).await;
+    // Stray tokens sent to the vault cannot keep it open
+    let mint_to = spl_token_2022::instruction::mint_to(
+        &env.token_program,
+        &env.mint,
+        &vault,
+        &env.authority.pubkey(),
+        &[],
+        7,
+    )
+    .unwrap();
+    env.process(&[mint_to], &[&env.authority]).await.unwrap();
+    let buyer_lamports = env.lamports(&env.buyer.pubkey()).await;
+    let buyer_balance = env.token_balance(&env.buyer_token).await;
+    env.advance_clock(ORDER_RETENTION).await;
+
+    env.process(&[env.close_escrowed_order_ix("order-1")], &[])
+        .await
+        .unwrap();
+
+    assert!(env.account_data(&vault).await.is_none());
+    assert_eq!(env.token_balance(&env.buyer_token).await, buyer_balance + 7);
+    assert!(env.account_data(&env.order_address("order-1")).await.is_none());
+    let closed_order_rent = env.lamports(&env.closed_order_address("order-1")).await;
+    assert_eq!(
+        env.lamports(&env.buyer.pubkey()).await,
+        buyer_lamports + rent - closed_order_rent
+    );
+}
+
+#[tokio::test]
+async fn close_order_requires_vault_of_escrowed_order() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    env.pay_into_escrow("order-2", 100_000).await;
+    for order_id in ["order-1", "order-2"] {
+        let ix = env.release_escrow_ix(&env.authority.pubkey(), order_id);
+        env.process(&[ix], &[&env.authority]).await.unwrap();
+    }
+    env.advance_clock(ORDER_RETENTION).await;
+
+    let result = env.process(&[env.close_order_ix("order-1")], &[]).await;
+    assert_instruction_error(result, InstructionError::NotEnoughAccountKeys);
+
+    let mut ix = env.close_escrowed_order_ix("order-1");
+    ix.accounts[5].pubkey = env.vault_address("order-2");
+    let result = env.process(&[ix], &[]).await;
+    assert_payment_error(result, PaymentError::InvalidVaultAccount);
+}
+
+#[tokio::test]
+async fn close_order_rejects_stray_tokens_to_other_than_buyer() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let mint_to = spl_token_2022::instruction::mint_to(
+        &env.token_program,
+        &env.mint,
+        &env.vault_address("order-1"),
+        &env.authority.pubkey(),
+        &[],
+        7,
+    )
+    .unwrap();
+    env.process(&[mint_to], &[&env.authority]).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    let mut ix = env.close_escrowed_order_ix("order-1");
+    ix.accounts[8].pubkey = env.merchant_token;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_payment_error(result, PaymentError::InvalidBuyerTokenAccount);
+}
+
+#[tokio::test]
+async fn close_order_rejects_during_retention_period() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+    env.advance_clock(ORDER_RETENTION - 60).await;
+
+    let result = env.process(&[env.close_order_ix("order-1")], &[]).await;
+
+    assert_payment_error(result, PaymentError::RetentionPeriodActive);
+}
+
+#[tokio::test]
+async fn close_order_rejects_escrowed_order() {
+    let mut env = TestEnv::initialized().await;
+    env.pay_into_escrow("order-1", 1_000).await;
+    env.advance_clock(ORDER_RETENTION).await;
+
+    let result = env.process(&[env.close_order_ix("order-1")], &[]).await;
+
+    assert_payment_error(result, PaymentError::InvalidOrderStatus);
+}
+
+#[tokio::test]
+async fn close_order_rejects_other_rent_recipient() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+    let mut ix = env.close_order_ix("order-1");
+    ix.accounts[1].pubkey = env.merchant.pubkey();
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn payment_rejects_wrong_closed_order_account() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.closed_order = env.closed_order_address("order-2");
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidClosedOrderAccount);
+}
+
+#[tokio::test]
+async fn set_order_retention_zero_allows_immediate_close() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::SetOrderRetention {
+            order_retention_secs: 0,
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+
+    env.process(&[env.close_order_ix("order-1")], &[]).await.unwrap();
+
+    assert!(env.account_data(&env.order_address("order-1")).await.is_none());
+}
+
+#[tokio::test]
+async fn set_order_retention_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::SetOrderRetention {
+            order_retention_secs: 0,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// SetMaxTotalFee
+
+#[tokio::test]
+async fn payment_accepts_total_fee_at_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.set_max_total_fee(750).await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500);
+}
+
+#[tokio::test]
+async fn payment_rejects_total_fee_above_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_affiliate(501, AffiliateStatus::Active).await;
+    env.set_platform_fee(250).await;
+    env.set_max_total_fee(750).await;
+
+    let result = env.pay(&env.affiliate_payment("order-1", 100_000)).await;
+
+    assert_payment_error(result, PaymentError::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn sol_payment_rejects_total_fee_above_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_affiliate(501, AffiliateStatus::Active).await;
+    env.set_platform_fee(250).await;
+    env.set_max_total_fee(750).await;
+
+    let result = env
+        .process(&[env.affiliate_sol_payment_ix("order-1", 100_000)], &[&env.buyer])
+        .await;
+
+    assert_payment_error(result, PaymentError::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_accepts_100_percent() {
+    let env = TestEnv::initialized().await;
+    env.set_max_total_fee(1_000).await;
+
+    env.set_max_total_fee(PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS)
+        .await;
+
+    assert_eq!(
+        env.config().await.max_total_fee_bps,
+        PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS
+    );
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_above_100_percent() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_max_total_fee_ix(
+        &env.authority.pubkey(),
+        PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS + 1,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMaxTotalFee);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_accepts_platform_fee() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+
+    env.set_max_total_fee(250).await;
+
+    assert_eq!(env.config().await.max_total_fee_bps, 250);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_below_platform_fee() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    let ix = env.set_max_total_fee_ix(&env.authority.pubkey(), 249);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMaxTotalFee);
+}
+
+#[tokio::test]
+async fn set_max_total_fee_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_max_total_fee_ix(&env.buyer.pubkey(), 1_000);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn update_platform_fee_rejects_fee_above_total_fee_cap() {
+    let env = TestEnv::initialized().await;
+    env.set_max_total_fee(100).await;
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 101 },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::TotalFeeTooHigh);
+}
+
+#[tokio::test]
+async fn execute_platform_fee_change_rejects_fee_above_lowered_cap() {
+    let mut env = TestEnv::initialized().await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 500 };
+    let authority = env.authority.pubkey();
+    let ix = env.queue_config_change_ix(&authority, change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.set_max_total_fee(400).await;
+    let delay = change.delay_secs(&env.config().await);
+    env.advance_clock(delay as i64).await;
+
+    let ix = env.execute_config_change_ix(&change, &authority);
+    let result = env.process(&[ix], &[]).await;
+
+    assert_payment_error(result, PaymentError::TotalFeeTooHigh);
+    assert_eq!(env.config().await.platform_fee_bps, 0);
+}
+
+// RegisterMerchant / UpdateMerchant
+
+#[tokio::test]
+async fn register_merchant_creates_active_merchant() {
+    let env = TestEnv::initialized().await;
+
+    let merchant = env.merchant_state().await;
+
+    assert_eq!(merchant.merchant_id, MERCHANT_ID);
+    assert_eq!(merchant.payout_wallet, env.merchant.pubkey());
+    assert_eq!(merchant.status, MerchantStatus::Active);
+    assert_eq!(merchant.platform_fee_bps, None);
+    assert!(merchant.allowed_mints.is_empty());
+    assert_eq!(
+        env.account_data(&env.merchant_account).await.unwrap().len(),
+        Merchant::LEN
+    );
+}
+
+#[tokio::test]
+async fn register_merchant_twice_fails() {
+    let env = TestEnv::initialized().await;
+    let ix = env.register_merchant_ix(&env.authority.pubkey(), &MERCHANT_ID, &Pubkey::new_unique());
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::AlreadyInitialized);
+}
+
+#[tokio::test]
+async fn register_merchant_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.register_merchant_ix(&env.buyer.pubkey(), &[1; 32], &env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn register_merchant_rejects_address_of_other_id() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.register_merchant_ix(&env.authority.pubkey(), &[1; 32], &Pubkey::new_unique());
+    ix.accounts[2].pubkey = env.merchant_address(&[2; 32]);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantAccount);
+}
+
+#[tokio::test]
+async fn update_merchant_sets_status_fee_and_mints() {
+    let env = TestEnv::initialized().await;
+    let mints = vec![env.mint, Pubkey::new_unique()];
+
+    env.set_merchant(MerchantStatus::Suspended, Some(100), mints.clone())
+        .await;
+
+    let merchant = env.merchant_state().await;
+    assert_eq!(merchant.status, MerchantStatus::Suspended);
+    assert_eq!(merchant.platform_fee_bps, Some(100));
+    assert_eq!(merchant.allowed_mints, mints);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_fee_above_max() {
+    let env = TestEnv::initialized().await;
+    let fee_bps = env.config().await.max_platform_fee_bps + 1;
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        MerchantStatus::Active,
+        Some(fee_bps),
+        vec![],
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidPlatformFee);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_fee_above_total_fee_cap() {
+    let env = TestEnv::initialized().await;
+    env.set_max_total_fee(300).await;
+    let ix = env.update_merchant_ix(
+        &env.authority.pubkey(),
+        MerchantStatus::Active,
+        Some(301),
+        vec![],
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidPlatformFee);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_too_many_allowed_mints() {
+    let env = TestEnv::initialized().await;
+    let mints = (0..=Merchant::MAX_ALLOWED_MINTS)
+        .map(|_| Pubkey::new_unique())
+        .collect();
+    let ix = env.update_merchant_ix(&env.authority.pubkey(), MerchantStatus::Active, None, mints);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::TooManyAllowedMints);
+}
+
+#[tokio::test]
+async fn update_merchant_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.update_merchant_ix(&env.buyer.pubkey(), MerchantStatus::Suspended, None, vec![]);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert_eq!(env.merchant_state().await.status, MerchantStatus::Active);
+}
+
+#[tokio::test]
+async fn payment_rejects_suspended_merchant() {
+    let env = TestEnv::initialized().await;
+    env.set_merchant(MerchantStatus::Suspended, None, vec![])
+        .await;
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+    assert_payment_error(result, PaymentError::MerchantSuspended);
+
+    env.set_merchant(MerchantStatus::Active, None, vec![])
+        .await;
+    env.pay(&env.payment("order-2", 1_000)).await.unwrap();
+    assert_eq!(env.token_balance(&env.merchant_token).await, 1_000);
+}
+
+#[tokio::test]
+async fn payment_rejects_mint_not_allowed_for_merchant() {
+    let env = TestEnv::initialized().await;
+    env.set_merchant(MerchantStatus::Active, None, vec![Pubkey::new_unique()])
+        .await;
+
+    let result = env.pay(&env.payment("order-1", 1_000)).await;
+    assert_payment_error(result, PaymentError::MintNotAllowedForMerchant);
+
+    env.set_merchant(MerchantStatus::Active, None, vec![env.mint])
+        .await;
+    env.pay(&env.payment("order-2", 1_000)).await.unwrap();
+}
+
+#[tokio::test]
+async fn payment_uses_merchant_fee_override() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.set_merchant(MerchantStatus::Active, Some(100), vec![])
+        .await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 94_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 1_000);
+    assert_eq!(env.commission_vault_balance().await, 5_000);
+    assert_eq!(env.processed_order("order-1").await.platform_fee, 1_000);
+}
+
+#[tokio::test]
+async fn payment_rejects_merchant_token_account_of_other_wallet() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant_token = env.other_wallet_token;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantTokenAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_unregistered_merchant() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant = env.merchant_address(&[1; 32]);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_other_program_account_as_merchant() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.merchant = env.supported_token_address(&env.mint);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantAccount);
+}
+
+// RegisterAffiliate / UpdateAffiliate
+
+#[tokio::test]
+async fn register_affiliate_creates_active_affiliate() {
+    let env = TestEnv::initialized().await;
+
+    let affiliate = env.affiliate_state().await;
+
+    assert_eq!(affiliate.api_key_hash, API_KEY_HASH);
+    assert_eq!(affiliate.owner, env.api_key_owner.pubkey());
+    assert_eq!(affiliate.commission_bps, AFFILIATE_COMMISSION_BPS);
+    assert_eq!(affiliate.status, AffiliateStatus::Active);
+    assert_eq!(affiliate.total_earnings, 0);
+    assert_eq!(
+        env.account_data(&env.affiliate_account).await.unwrap().len(),
+        Affiliate::LEN
+    );
+}
+
+#[tokio::test]
+async fn register_affiliate_twice_fails() {
+    let env = TestEnv::initialized().await;
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), &API_KEY_HASH, 100);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::AlreadyInitialized);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.register_affiliate_ix(&env.buyer.pubkey(), &[1; 32], 100);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_address_of_other_hash() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.register_affiliate_ix(&env.authority.pubkey(), &[1; 32], 100);
+    ix.accounts[2].pubkey = env.affiliate_address(&[2; 32]);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidAffiliateAccount);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_commission_above_max() {
+    let env = TestEnv::initialized().await;
+    let ix = env.register_affiliate_ix(
+        &env.authority.pubkey(),
+        &[1; 32],
+        PaymentConfig::MAX_COMMISSION_BPS + 1,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionRate);
+}
+
+#[tokio::test]
+async fn register_affiliate_rejects_commission_above_total_fee_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.set_max_total_fee(750).await;
+
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), &[1; 32], 501);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::InvalidCommissionRate);
+
+    let ix = env.register_affiliate_ix(&env.authority.pubkey(), &[1; 32], 500);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_affiliate_sets_owner_rate_and_status() {
+    let env = TestEnv::initialized().await;
+    let owner = Pubkey::new_unique();
+    let ix =
+        env.update_affiliate_ix(&env.authority.pubkey(), &owner, 300, AffiliateStatus::Suspended);
+
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let affiliate = env.affiliate_state().await;
+    assert_eq!(affiliate.owner, owner);
+    assert_eq!(affiliate.commission_bps, 300);
+    assert_eq!(affiliate.status, AffiliateStatus::Suspended);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_commission_above_max() {
+    let env = TestEnv::initialized().await;
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        &env.api_key_owner.pubkey(),
+        PaymentConfig::MAX_COMMISSION_BPS + 1,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionRate);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_commission_above_total_fee_cap() {
+    let mut env = TestEnv::initialized().await;
+    env.set_platform_fee(250).await;
+    env.set_max_total_fee(750).await;
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        &env.api_key_owner.pubkey(),
+        501,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionRate);
+}
+
+#[tokio::test]
+async fn update_affiliate_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.update_affiliate_ix(
+        &env.buyer.pubkey(),
+        &env.buyer.pubkey(),
+        AFFILIATE_COMMISSION_BPS,
+        AffiliateStatus::Active,
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert_eq!(env.affiliate_state().await.owner, env.api_key_owner.pubkey());
+}
+
+#[tokio::test]
+async fn payment_takes_commission_rate_from_affiliate() {
+    let env = TestEnv::initialized().await;
+    env.set_affiliate(1_000, AffiliateStatus::Active).await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    env.pay(&env.affiliate_payment("order-2", 50_000)).await.unwrap();
+
+    assert_eq!(env.commission_vault_balance().await, 15_000);
+    assert_eq!(env.affiliate_state().await.total_earnings, 15_000);
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.api_key_owner, env.api_key_owner.pubkey());
+    assert_eq!(order.commission_bps, 1_000);
+    assert_eq!(order.commission, 10_000);
+}
+
+#[tokio::test]
+async fn payment_without_affiliate_records_no_commission() {
+    let env = TestEnv::initialized().await;
+
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.api_key_owner, Pubkey::default());
+    assert_eq!(order.commission_bps, 0);
+    assert_eq!(order.commission, 0);
+}
+
+#[tokio::test]
+async fn payment_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized().await;
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+
+    let result = env.pay(&env.affiliate_payment("order-1", 1_000)).await;
+    assert_payment_error(result, PaymentError::AffiliateSuspended);
+
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Active)
+        .await;
+    env.pay(&env.affiliate_payment("order-2", 1_000)).await.unwrap();
+}
+
+#[tokio::test]
+async fn payment_rejects_unregistered_affiliate() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.affiliate = env.affiliate_address(&[1; 32]);
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_other_program_account_as_affiliate() {
+    let env = TestEnv::initialized().await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.affiliate = env.merchant_account;
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidAffiliateAccount);
+}
+
+#[tokio::test]
+async fn payment_rejects_affiliate_not_owned_by_program() {
+    let mut env = TestEnv::initialized().await;
+    let data = env.account_data(&env.affiliate_account).await.unwrap();
+    let affiliate = env.affiliate_account;
+    env.set_account(&affiliate, &Pubkey::new_unique(), data);
+
+    let result = env.pay(&env.affiliate_payment("order-1", 1_000)).await;
+
+    assert_payment_error(result, PaymentError::InvalidAccountOwner);
+}
+
+// ClaimCommission
+
+#[tokio::test]
+async fn payment_accrues_commission_in_ledger() {
+    let env = TestEnv::initialized().await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    env.pay(&env.affiliate_payment("order-2", 50_000)).await.unwrap();
+
+    let ledger = env.commission_ledger().await;
+    assert_eq!(ledger.affiliate, env.affiliate_account);
+    assert_eq!(ledger.mint, env.mint);
+    assert_eq!(ledger.accrued, 7_500);
+    assert_eq!(ledger.claimed, 0);
+    assert_eq!(ledger.last_claim_at, 0);
+    assert_eq!(env.commission_vault_balance().await, 7_500);
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 0);
+}
+
+#[tokio::test]
+async fn claim_commission_pays_out_unclaimed_balance() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    env.claim_commission().await.unwrap();
+
+    env.pay(&env.affiliate_payment("order-2", 50_000)).await.unwrap();
+    env.claim_commission().await.unwrap();
+
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 7_500);
+    assert_eq!(env.commission_vault_balance().await, 0);
+    let ledger = env.commission_ledger().await;
+    assert_eq!(ledger.accrued, 7_500);
+    assert_eq!(ledger.claimed, 7_500);
+    let clock: Clock = env.context.banks_client.get_sysvar().await.unwrap();
+    assert_eq!(ledger.last_claim_at, clock.unix_timestamp);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_empty_balance() {
+    let env = TestEnv::initialized().await;
+    env.set_affiliate(0, AffiliateStatus::Active).await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+
+    let result = env.claim_commission().await;
+
+    assert_payment_error(result, PaymentError::NoCommissionToClaim);
+    assert_eq!(env.commission_ledger().await.accrued, 0);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_before_first_payment() {
+    let env = TestEnv::initialized().await;
+
+    let result = env.claim_commission().await;
+
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_other_signer() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let mint = env.mint;
+    let buyer_token = env.add_token_account(&mint, &env.buyer.pubkey(), 0);
+
+    let ix = env.claim_commission_ix(&env.buyer.pubkey(), &buyer_token);
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_token_account_of_other_owner() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let mint = env.mint;
+    let other_token = env.add_token_account(&mint, &Pubkey::new_unique(), 0);
+
+    let ix = env.claim_commission_ix(&env.api_key_owner.pubkey(), &other_token);
+    let result = env.process(&[ix], &[&env.api_key_owner]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionTokenAccount);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_suspended_affiliate() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    env.set_affiliate(AFFILIATE_COMMISSION_BPS, AffiliateStatus::Suspended)
+        .await;
+
+    let result = env.claim_commission().await;
+
+    assert_payment_error(result, PaymentError::AffiliateSuspended);
+}
+
+#[tokio::test]
+async fn claim_commission_follows_affiliate_owner_change() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let new_owner = Keypair::new();
+    let ix = env.update_affiliate_ix(
+        &env.authority.pubkey(),
+        &new_owner.pubkey(),
+        AFFILIATE_COMMISSION_BPS,
+        AffiliateStatus::Active,
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let result = env.claim_commission().await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+
+    let mint = env.mint;
+    let new_owner_token = env.add_token_account(&mint, &new_owner.pubkey(), 0);
+    let ix = env.claim_commission_ix(&new_owner.pubkey(), &new_owner_token);
+    env.process(&[ix], &[&new_owner]).await.unwrap();
+    assert_eq!(env.token_balance(&new_owner_token).await, 5_000);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_pays_owner_wallet() {
+    let env = TestEnv::initialized().await;
+    env.process(&[env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+    env.process(&[env.affiliate_sol_payment_ix("order-2", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+
+    env.claim_sol_commission().await.unwrap();
+
+    assert_eq!(env.lamports(&env.api_key_owner.pubkey()).await, 100_000_000);
+    assert_eq!(
+        env.lamports(&env.sol_commission_ledger_address()).await,
+        Rent::default().minimum_balance(CommissionLedger::LEN)
+    );
+    let ledger = env.sol_commission_ledger().await;
+    assert_eq!(ledger.accrued, 100_000_000);
+    assert_eq!(ledger.claimed, 100_000_000);
+    let result = env.claim_sol_commission().await;
+    assert_payment_error(result, PaymentError::NoCommissionToClaim);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_rejects_other_signer() {
+    let env = TestEnv::initialized().await;
+    env.process(&[env.affiliate_sol_payment_ix("order-1", SOL_PAYMENT)], &[&env.buyer])
+        .await
+        .unwrap();
+
+    let ix = env.claim_sol_commission_ix(&env.buyer.pubkey());
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn claim_sol_commission_rejects_token_ledger() {
+    let env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let mut ix = env.claim_sol_commission_ix(&env.api_key_owner.pubkey());
+    ix.accounts[2].pubkey = env.commission_ledger_address(&env.mint);
+
+    let result = env.process(&[ix], &[&env.api_key_owner]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionLedgerAccount);
+}
+
+#[tokio::test]
+async fn claim_commission_rejects_wrong_vault() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.affiliate_payment("order-1", 100_000)).await.unwrap();
+    let (mint, ledger) = (env.mint, env.commission_ledger_address(&env.mint));
+    let other_vault = env.add_token_account(&mint, &ledger, 0);
+
+    let mut ix = env.claim_commission_ix(&env.api_key_owner.pubkey(), &env.api_key_owner_token);
+    ix.accounts[3].pubkey = other_vault;
+    let result = env.process(&[ix], &[&env.api_key_owner]).await;
+
+    assert_payment_error(result, PaymentError::InvalidCommissionVaultAccount);
+}
+
+// Token-2022
+
+const TRANSFER_FEE_BPS: u16 = 100;
+
+#[tokio::test]
+async fn token_2022_payment_splits_net_fee_and_commission() {
+    let mut env = TestEnv::initialized_with_token_program(spl_token_2022::id(), 0).await;
+    env.set_platform_fee(250).await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000_000))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.merchant_token).await, 92_500_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_500_000);
+    assert_eq!(env.commission_vault_balance().await, 5_000_000);
+    assert_eq!(env.processed_order("order-1").await.amount, 100_000_000);
+}
+
+#[tokio::test]
+async fn transfer_fee_payment_records_received_amounts() {
+    let mut env =
+        TestEnv::initialized_with_token_program(spl_token_2022::id(), TRANSFER_FEE_BPS).await;
+    env.set_platform_fee(250).await;
+
+    env.pay(&env.affiliate_payment("order-1", 100_000_000))
+        .await
+        .unwrap();
+
+    // Each leg loses 1% to the mint's transfer fee
+    assert_eq!(env.token_balance(&env.merchant_token).await, 91_575_000);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_475_000);
+    assert_eq!(env.commission_vault_balance().await, 4_950_000);
+    assert_eq!(
+        env.token_balance(&env.buyer_token).await,
+        INITIAL_BALANCE - 100_000_000
+    );
+
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.amount, 99_000_000);
+    assert_eq!(order.platform_fee, 2_475_000);
+    assert_eq!(order.commission, 4_950_000);
+}
+
+#[tokio::test]
+async fn transfer_fee_claim_empties_commission_vault() {
+    let env = TestEnv::initialized_with_token_program(spl_token_2022::id(), TRANSFER_FEE_BPS).await;
+    env.pay(&env.affiliate_payment("order-1", 100_000_000))
+        .await
+        .unwrap();
+
+    env.claim_commission().await.unwrap();
+
+    // The ledger records what left the vault, the owner receives it less the transfer fee
+    assert_eq!(env.commission_ledger().await.claimed, 4_950_000);
+    assert_eq!(env.commission_vault_balance().await, 0);
+    assert_eq!(env.token_balance(&env.api_key_owner_token).await, 4_900_500);
+}
+
+#[tokio::test]
+async fn transfer_fee_escrow_records_vault_balance() {
+    let mut env =
+        TestEnv::initialized_with_token_program(spl_token_2022::id(), TRANSFER_FEE_BPS).await;
+    env.set_platform_fee(250).await;
+    env.pay_into_escrow_through_affiliate("order-1", 100_000).await;
+
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 99_000);
+    let order = env.processed_order("order-1").await;
+    assert_eq!(order.amount, 99_000);
+    assert_eq!(order.platform_fee, 2_475);
+    assert_eq!(order.commission, 4_950);
+
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.vault_address("order-1")).await, 0);
+    assert_eq!(env.token_balance(&env.merchant_token).await, 90_659);
+    assert_eq!(env.token_balance(&env.fee_treasury_token).await, 2_450);
+    assert_eq!(env.commission_vault_balance().await, 4_900);
+    assert_eq!(env.commission_ledger().await.accrued, 4_900);
+}
+
+#[tokio::test]
+async fn transfer_fee_close_order_harvests_vault_fees() {
+    let mut env =
+        TestEnv::initialized_with_token_program(spl_token_2022::id(), TRANSFER_FEE_BPS).await;
+    env.pay_into_escrow("order-1", 100_000).await;
+    let ix = env.release_escrow_ix(&env.authority.pubkey(), "order-1");
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(ORDER_RETENTION).await;
+
+    env.process(&[env.close_escrowed_order_ix("order-1")], &[])
+        .await
+        .unwrap();
+
+    assert!(env.account_data(&env.vault_address("order-1")).await.is_none());
+    let mint_data = env.account_data(&env.mint).await.unwrap();
+    let mint = StateWithExtensions::<Mint>::unpack(&mint_data).unwrap();
+    let withheld = mint.get_extension::<TransferFeeConfig>().unwrap().withheld_amount;
+    assert_eq!(u64::from(withheld), 1_000);
+}
+
+#[tokio::test]
+async fn token_2022_refund_order_returns_amount_to_buyer() {
+    let mut env = TestEnv::initialized_with_token_program(spl_token_2022::id(), 0).await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let authority = env.authority.insecure_clone();
+
+    env.refund(&authority, "order-1", 100_000).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.buyer_token).await, INITIAL_BALANCE);
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 100_000);
+}
+
+#[tokio::test]
+async fn payment_rejects_token_program_not_owning_mint() {
+    let env = TestEnv::initialized_with_token_program(spl_token_2022::id(), 0).await;
+    let mut payment = env.payment("order-1", 1_000);
+    payment.token_program = spl_token::id();
+
+    let result = env.pay(&payment).await;
+
+    assert_payment_error(result, PaymentError::InvalidTokenProgram);
+}
+
+#[tokio::test]
+async fn refund_order_rejects_mint_other_than_order_mint() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+    let other_mint = env.add_mint();
+    let mut ix = env.refund_ix(&env.authority.pubkey(), &env.other_wallet_token, "order-1", 1_000);
+    ix.accounts[5].pubkey = other_mint;
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::TokenMintMismatch);
+}
+
+// AddSupportedToken / RemoveSupportedToken
+
+#[tokio::test]
+async fn add_supported_token_creates_account() {
+    let env = TestEnv::initialized().await;
+
+    let supported_token = env.supported_token(&env.mint).await;
+
+    assert_eq!(supported_token.mint, env.mint);
+    assert!(supported_token.is_supported);
+}
+
+#[tokio::test]
+async fn add_supported_token_re_enables_removed_token() {
+    let env = TestEnv::initialized().await;
+    let remove = env.remove_supported_token_ix(&env.authority.pubkey(), &env.mint);
+    env.process(&[remove], &[&env.authority]).await.unwrap();
+    assert!(!env.supported_token(&env.mint).await.is_supported);
+
+    env.process(&[env.add_supported_token_ix(&env.mint)], &[&env.authority])
+        .await
+        .unwrap();
+
+    assert!(env.supported_token(&env.mint).await.is_supported);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_unauthorized_caller() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let mut ix = env.add_supported_token_ix(&other_mint);
+    ix.accounts[0].pubkey = env.buyer.pubkey();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_mismatched_mint_argument() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let mut ix = env.add_supported_token_ix(&other_mint);
+    ix.data = borsh::to_vec(&PaymentInstruction::AddSupportedToken {
+        token_mint: Pubkey::new_unique(),
+    })
+    .unwrap();
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::TokenMintMismatch);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_wrong_address() {
+    let mut env = TestEnv::initialized().await;
+    let other_mint = env.add_mint();
+    let mut ix = env.add_supported_token_ix(&other_mint);
+    ix.accounts[2].pubkey = env.supported_token_address(&env.mint);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidSupportedTokenAccount);
+}
+
+#[tokio::test]
+async fn add_supported_token_rejects_non_mint() {
+    let env = TestEnv::initialized().await;
+    let ix = env.add_supported_token_ix(&env.buyer_token);
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMint);
+}
+
+#[tokio::test]
+async fn remove_supported_token_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.remove_supported_token_ix(&env.buyer.pubkey(), &env.mint);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert!(env.supported_token(&env.mint).await.is_supported);
+}
+
+// UpdateFeeTreasury
+
+#[tokio::test]
+async fn update_fee_treasury_sets_treasury() {
+    let env = TestEnv::initialized().await;
+    let new_fee_treasury = Pubkey::new_unique();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::UpdateFeeTreasury { new_fee_treasury },
+    );
+
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert_eq!(env.config().await.fee_treasury, new_fee_treasury);
+}
+
+#[tokio::test]
+async fn update_fee_treasury_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::UpdateFeeTreasury {
+            new_fee_treasury: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// QueueConfigChange / ExecuteConfigChange / CancelConfigChange
+
+#[tokio::test]
+async fn update_payout_wallet_applies_after_delay() {
+    let mut env = TestEnv::initialized().await;
+    let (merchant, new_payout_wallet) = (env.merchant_account, Pubkey::new_unique());
+    let change = ConfigChange::PayoutWallet {
+        merchant,
+        new_payout_wallet,
+    };
+    let authority = env.authority.pubkey();
+    let ix = env.queue_change_ix(
+        &authority,
+        &change,
+        PaymentInstruction::UpdatePayoutWallet {
+            merchant,
+            new_payout_wallet,
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let pending_change = env.pending_change(&change).await.unwrap();
+    assert_eq!(pending_change.change, change);
+    assert_eq!(pending_change.proposer, authority);
+    assert_eq!(
+        pending_change.execute_after - pending_change.queued_at,
+        PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64
+    );
+    assert_eq!(
+        env.merchant_state().await.payout_wallet,
+        env.merchant.pubkey()
+    );
+
+    let ix = env.execute_config_change_ix(&change, &authority);
+    let result = env.process(&[ix], &[]).await;
+    assert_payment_error(result, PaymentError::TimelockNotExpired);
+
+    // Anyone can execute once the delay has passed
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let ix = env.execute_config_change_ix(&change, &authority);
+    env.process(&[ix], &[]).await.unwrap();
+    assert_eq!(env.merchant_state().await.payout_wallet, new_payout_wallet);
+    assert!(env.pending_change(&change).await.is_none());
+}
+
+#[tokio::test]
+async fn update_payout_wallet_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet: env.buyer.pubkey(),
+    };
+    let ix = env.queue_config_change_ix(&env.buyer.pubkey(), change);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert!(env.pending_change(&change).await.is_none());
+}
+
+#[tokio::test]
+async fn update_payout_wallet_queues_one_change_per_merchant() {
+    let env = TestEnv::initialized().await;
+    let other_merchant = env.merchant_address(&[2; 32]);
+    let ix = env.register_merchant_ix(&env.authority.pubkey(), &[2; 32], &env.other_wallet);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let changes =
+        [env.merchant_account, other_merchant].map(|merchant| ConfigChange::PayoutWallet {
+            merchant,
+            new_payout_wallet: Pubkey::new_unique(),
+        });
+
+    for change in changes {
+        let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+        env.process(&[ix], &[&env.authority]).await.unwrap();
+        assert_eq!(env.pending_change(&change).await.unwrap().change, change);
+    }
+
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PayoutWallet {
+            merchant: other_merchant,
+            new_payout_wallet: Pubkey::new_unique(),
+        },
+    );
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::ChangeAlreadyQueued);
+}
+
+#[tokio::test]
+async fn execute_payout_wallet_change_rejects_other_merchant() {
+    let mut env = TestEnv::initialized().await;
+    let ix = env.register_merchant_ix(&env.authority.pubkey(), &[2; 32], &env.other_wallet);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet: Pubkey::new_unique(),
+    };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let mut ix = env.execute_config_change_ix(&change, &env.authority.pubkey());
+    ix.accounts[3].pubkey = env.merchant_address(&[2; 32]);
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_payment_error(result, PaymentError::InvalidMerchantAccount);
+    assert_eq!(
+        env.merchant_state().await.payout_wallet,
+        env.merchant.pubkey()
+    );
+}
+
+#[tokio::test]
+async fn update_platform_fee_accepts_max() {
+    let mut env = TestEnv::initialized().await;
+
+    env.set_platform_fee(PaymentConfig::MAX_PLATFORM_FEE_BPS)
+        .await;
+
+    assert_eq!(
+        env.config().await.platform_fee_bps,
+        PaymentConfig::MAX_PLATFORM_FEE_BPS
+    );
+}
+
+#[tokio::test]
+async fn update_platform_fee_rejects_above_max() {
+    let env = TestEnv::initialized().await;
+    let new_fee_bps = PaymentConfig::MAX_PLATFORM_FEE_BPS + 1;
+    let ix = env.queue_change_ix(
+        &env.authority.pubkey(),
+        &ConfigChange::PlatformFee { new_fee_bps },
+        PaymentInstruction::UpdatePlatformFee { new_fee_bps },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidPlatformFee);
+}
+
+#[tokio::test]
+async fn update_platform_fee_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 100 },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn queue_config_change_rejects_second_change_of_same_kind() {
+    let env = TestEnv::initialized().await;
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 100 },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 200 },
+    );
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::ChangeAlreadyQueued);
+}
+
+#[tokio::test]
+async fn queue_config_change_rejects_wrong_pending_change_address() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.queue_config_change_ix(
+        &env.authority.pubkey(),
+        ConfigChange::PlatformFee { new_fee_bps: 100 },
+    );
+    ix.accounts[2].pubkey = env.pending_change_address(&ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet: env.other_wallet,
+    });
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidPendingChangeAccount);
+}
+
+#[tokio::test]
+async fn execute_config_change_rejects_other_proposer() {
+    let mut env = TestEnv::initialized().await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    let result = env.process(&[ix], &[]).await;
+
+    assert_payment_error(result, PaymentError::InvalidProposer);
+}
+
+#[tokio::test]
+async fn cancel_config_change_drops_change_and_refunds_proposer() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let buyer_lamports = env.lamports(&env.buyer.pubkey()).await;
+    let ix = env.with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    assert!(env.lamports(&env.buyer.pubkey()).await < buyer_lamports);
+
+    let ix = env.cancel_config_change_ix(&env.authority.pubkey(), &change, &env.buyer.pubkey());
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    assert!(env.pending_change(&change).await.is_none());
+    assert_eq!(env.lamports(&env.buyer.pubkey()).await, buyer_lamports);
+
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    let result = env.process(&[ix], &[]).await;
+    assert_payment_error(result, PaymentError::UninitializedAccount);
+    assert_eq!(env.config().await.platform_fee_bps, 0);
+}
+
+#[tokio::test]
+async fn cancel_config_change_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.cancel_config_change_ix(&env.buyer.pubkey(), &change, &env.authority.pubkey());
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert!(env.pending_change(&change).await.is_some());
+}
+
+#[tokio::test]
+async fn timelock_delays_change_waits_longest_delay() {
+    let mut env = TestEnv::initialized().await;
+    env.apply_config_change(ConfigChange::TimelockDelays {
+        payout_wallet_delay_secs: 3 * 24 * 60 * 60,
+        platform_fee_delay_secs: 60 * 60,
+    })
+    .await;
+    let config = env.config().await;
+    assert_eq!(config.payout_wallet_delay_secs, 3 * 24 * 60 * 60);
+    assert_eq!(config.platform_fee_delay_secs, 60 * 60);
+
+    // Platform fee changes now wait an hour
+    let fee_change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), fee_change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let pending_change = env.pending_change(&fee_change).await.unwrap();
+    assert_eq!(pending_change.execute_after - pending_change.queued_at, 60 * 60);
+
+    // Shortening the delays waits out the longest one
+    let delays_change = ConfigChange::TimelockDelays {
+        payout_wallet_delay_secs: 0,
+        platform_fee_delay_secs: 0,
+    };
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), delays_change);
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    env.advance_clock(24 * 60 * 60).await;
+    let ix = env.execute_config_change_ix(&delays_change, &env.authority.pubkey());
+    let result = env.process(&[ix], &[]).await;
+    assert_payment_error(result, PaymentError::TimelockNotExpired);
+}
+
+#[tokio::test]
+async fn timelock_delays_change_rejects_role_holder() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(
+        &env.buyer.pubkey(),
+        &[Role::FeeManager, Role::TreasuryManager],
+    )
+    .await;
+    let ix = env.with_roles(env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::TimelockDelays {
+            payout_wallet_delay_secs: 0,
+            platform_fee_delay_secs: 0,
+        },
+    ));
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+// ProposeAuthority / AcceptAuthority / CancelAuthorityTransfer
+
+#[tokio::test]
+async fn authority_transfer_hands_over_control() {
+    let env = TestEnv::initialized().await;
+    let new_authority = Keypair::new();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: new_authority.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert_eq!(env.config().await.pending_authority, new_authority.pubkey());
+    assert_eq!(env.config().await.authority, env.authority.pubkey());
+
+    let ix = env.admin_ix(&new_authority.pubkey(), PaymentInstruction::AcceptAuthority);
+    env.process(&[ix], &[&new_authority]).await.unwrap();
+
+    let config = env.config().await;
+    assert_eq!(config.authority, new_authority.pubkey());
+    assert_eq!(config.pending_authority, Pubkey::default());
+
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    let result = env.process(&[pause], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+
+    let pause = env.admin_ix(&new_authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&new_authority]).await.unwrap();
+    assert!(env.config().await.is_paused);
+}
+
+#[tokio::test]
+async fn propose_authority_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn propose_authority_rejects_default_address() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: Pubkey::default(),
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidPendingAuthority);
+}
+
+#[tokio::test]
+async fn accept_authority_rejects_other_signer() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: Pubkey::new_unique(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::AcceptAuthority);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotPendingAuthority);
+    assert_eq!(env.config().await.authority, env.authority.pubkey());
+}
+
+#[tokio::test]
+async fn accept_authority_requires_pending_authority_signature() {
+    let env = TestEnv::initialized().await;
+    let new_authority = Pubkey::new_unique();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority { new_authority },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let mut ix = env.admin_ix(&new_authority, PaymentInstruction::AcceptAuthority);
+    ix.accounts[0].is_signer = false;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn accept_authority_rejects_without_proposal() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::AcceptAuthority);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NoPendingAuthority);
+}
+
+#[tokio::test]
+async fn cancel_authority_transfer_clears_pending_authority() {
+    let env = TestEnv::initialized().await;
+    let new_authority = Keypair::new();
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: new_authority.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::CancelAuthorityTransfer,
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert_eq!(env.config().await.pending_authority, Pubkey::default());
+
+    let ix = env.admin_ix(&new_authority.pubkey(), PaymentInstruction::AcceptAuthority);
+    let result = env.process(&[ix], &[&new_authority]).await;
+    assert_payment_error(result, PaymentError::NoPendingAuthority);
+}
+
+#[tokio::test]
+async fn cancel_authority_transfer_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::CancelAuthorityTransfer);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert_eq!(env.config().await.pending_authority, env.buyer.pubkey());
+}
+
+// MigrateConfig
+
+fn legacy_config(env: &TestEnv) -> LegacyPaymentConfig {
+    let (_, bump) = Pubkey::find_program_address(&[b"config"], &env.program_id);
+    LegacyPaymentConfig {
+        authority: env.authority.pubkey(),
+        hot_wallet: env.other_wallet,
+        platform_fee_bps: 250,
+        max_platform_fee_bps: PaymentConfig::MAX_PLATFORM_FEE_BPS,
+        max_commission_bps: PaymentConfig::MAX_COMMISSION_BPS,
+        is_paused: false,
+        bump,
+    }
+}
+
+#[tokio::test]
+async fn migrate_config_upgrades_legacy_layout() {
+    let mut env = TestEnv::new().await;
+    let legacy = legacy_config(&env);
+    env.set_legacy_config(&legacy);
+
+    let ix = env.migrate_config_ix(&env.authority.pubkey());
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+
+    let data = env.account_data(&env.config_address()).await.unwrap();
+    assert_eq!(data.len(), PaymentConfig::LEN);
+    let config = env.config().await;
+    assert_eq!(config.authority, env.authority.pubkey());
+    assert_eq!(config.pending_authority, Pubkey::default());
+    assert_eq!(config.fee_treasury, env.other_wallet);
+    assert_eq!(config.refund_operator, Pubkey::default());
+    assert_eq!(config.arbitrator, Pubkey::default());
+    assert_eq!(
+        config.dispute_window_secs,
+        PaymentConfig::DEFAULT_DISPUTE_WINDOW_SECS
+    );
+    assert_eq!(
+        config.payout_wallet_delay_secs,
+        PaymentConfig::DEFAULT_CHANGE_DELAY_SECS
+    );
+    assert_eq!(
+        config.platform_fee_delay_secs,
+        PaymentConfig::DEFAULT_CHANGE_DELAY_SECS
+    );
+    assert_eq!(
+        config.max_quote_lifetime_secs,
+        PaymentConfig::DEFAULT_MAX_QUOTE_LIFETIME_SECS
+    );
+    assert_eq!(
+        config.order_retention_secs,
+        PaymentConfig::DEFAULT_ORDER_RETENTION_SECS
+    );
+    assert_eq!(config.platform_fee_bps, 250);
+    assert_eq!(config.max_total_fee_bps, PaymentConfig::DEFAULT_MAX_TOTAL_FEE_BPS);
+    assert!(config.multisig_signers.is_empty());
+    assert_eq!(config.multisig_threshold, 0);
+    assert_eq!(config.bump, legacy.bump);
+
+    let banks_client = env.context.banks_client.clone();
+    let account = banks_client
+        .get_account(env.config_address())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(account.lamports >= Rent::default().minimum_balance(PaymentConfig::LEN));
+
+    // The migrated config works with the authority transfer
+    let ix = env.admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::ProposeAuthority {
+            new_authority: env.buyer.pubkey(),
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::AcceptAuthority);
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+    assert_eq!(env.config().await.authority, env.buyer.pubkey());
+}
+
+#[tokio::test]
+async fn migrate_config_rejects_current_layout() {
+    let env = TestEnv::initialized().await;
+    let ix = env.migrate_config_ix(&env.authority.pubkey());
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::ConfigAlreadyMigrated);
+}
+
+#[tokio::test]
+async fn migrate_config_rejects_unauthorized_caller() {
+    let mut env = TestEnv::new().await;
+    let legacy = legacy_config(&env);
+    env.set_legacy_config(&legacy);
+    let ix = env.migrate_config_ix(&env.buyer.pubkey());
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    let data = env.account_data(&env.config_address()).await.unwrap();
+    assert_eq!(data.len(), LegacyPaymentConfig::LEN);
+}
+
+// InitializeRoles / GrantRole / RevokeRole
+
+#[tokio::test]
+async fn grant_role_records_member_roles() {
+    let env = TestEnv::initialized().await;
+    let operator = Pubkey::new_unique();
+
+    env.grant_roles(&operator, &[Role::Pauser, Role::FeeManager])
+        .await;
+
+    let roles = env.roles().await;
+    assert_eq!(roles.members.len(), 1);
+    assert!(roles.has_role(&operator, Role::Pauser));
+    assert!(roles.has_role(&operator, Role::FeeManager));
+    assert!(!roles.has_role(&operator, Role::TreasuryManager));
+}
+
+#[tokio::test]
+async fn initialize_roles_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.initialize_roles_ix();
+    ix.accounts[0].pubkey = env.buyer.pubkey();
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn grant_role_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.roles_admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::GrantRole {
+            member: env.buyer.pubkey(),
+            role: Role::TreasuryManager,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn grant_role_rejects_more_than_max_members() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&Pubkey::new_unique(), &[Role::Pauser]).await;
+    let instructions: Vec<_> = (1..Roles::MAX_MEMBERS)
+        .map(|_| {
+            env.roles_admin_ix(
+                &env.authority.pubkey(),
+                PaymentInstruction::GrantRole {
+                    member: Pubkey::new_unique(),
+                    role: Role::Pauser,
+                },
+            )
+        })
+        .collect();
+    env.process(&instructions, &[&env.authority]).await.unwrap();
+    assert_eq!(env.roles().await.members.len(), Roles::MAX_MEMBERS);
+
+    let ix = env.roles_admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::GrantRole {
+            member: Pubkey::new_unique(),
+            role: Role::Pauser,
+        },
+    );
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::RolesFull);
+}
+
+#[tokio::test]
+async fn revoke_role_removes_access() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.roles_admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::RevokeRole {
+            member: env.buyer.pubkey(),
+            role: Role::Pauser,
+        },
+    );
+    env.process(&[ix], &[&env.authority]).await.unwrap();
+    assert!(env.roles().await.members.is_empty());
+
+    let pause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause));
+    let result = env.process(&[pause], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn revoke_role_rejects_role_not_granted() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let ix = env.roles_admin_ix(
+        &env.authority.pubkey(),
+        PaymentInstruction::RevokeRole {
+            member: env.buyer.pubkey(),
+            role: Role::FeeManager,
+        },
+    );
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::RoleNotGranted);
+}
+
+#[tokio::test]
+async fn pauser_can_pause_and_unpause_but_not_move_funds() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+
+    let pause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause));
+    env.process(&[pause], &[&env.buyer]).await.unwrap();
+    assert!(env.config().await.is_paused);
+
+    let unpause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Unpause));
+    env.process(&[unpause], &[&env.buyer]).await.unwrap();
+    assert!(!env.config().await.is_paused);
+
+    let ix = env.with_roles(env.queue_config_change_ix(
+        &env.buyer.pubkey(),
+        ConfigChange::PayoutWallet {
+            merchant: env.merchant_account,
+            new_payout_wallet: env.buyer.pubkey(),
+        },
+    ));
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn role_holder_must_pass_roles_account() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let pause = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause);
+
+    let result = env.process(&[pause], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn role_check_rejects_roles_account_not_owned_by_program() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let data = env.account_data(&env.roles_address()).await.unwrap();
+    let fake_roles = Pubkey::new_unique();
+    env.set_account(&fake_roles, &Pubkey::new_unique(), data);
+    let mut pause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause));
+    pause.accounts[2].pubkey = fake_roles;
+
+    let result = env.process(&[pause], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::InvalidAccountOwner);
+}
+
+#[tokio::test]
+async fn fee_manager_updates_platform_fee() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::FeeManager]).await;
+    let change = ConfigChange::PlatformFee { new_fee_bps: 100 };
+    let ix = env.with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    env.process(&[ix], &[&env.buyer]).await.unwrap();
+
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    env.process(&[ix], &[]).await.unwrap();
+
+    assert_eq!(env.config().await.platform_fee_bps, 100);
+}
+
+#[tokio::test]
+async fn treasury_manager_updates_payout_wallet_and_fee_treasury() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TreasuryManager])
+        .await;
+    let (new_payout_wallet, new_fee_treasury) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet,
+    };
+    let payout_wallet_ix = env.with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    let fee_treasury_ix = env.with_roles(env.admin_ix(
+        &env.buyer.pubkey(),
+        PaymentInstruction::UpdateFeeTreasury { new_fee_treasury },
+    ));
+
+    env.process(&[payout_wallet_ix, fee_treasury_ix], &[&env.buyer])
+        .await
+        .unwrap();
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.buyer.pubkey());
+    env.process(&[ix], &[]).await.unwrap();
+
+    assert_eq!(env.merchant_state().await.payout_wallet, new_payout_wallet);
+    assert_eq!(env.config().await.fee_treasury, new_fee_treasury);
+
+    let pause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause));
+    let result = env.process(&[pause], &[&env.buyer]).await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn token_manager_adds_and_removes_supported_token() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TokenManager]).await;
+    let other_mint = env.add_mint();
+    let mut add = env.with_roles(env.add_supported_token_ix(&other_mint));
+    add.accounts[0].pubkey = env.buyer.pubkey();
+
+    env.process(&[add], &[&env.buyer]).await.unwrap();
+    assert!(env.supported_token(&other_mint).await.is_supported);
+
+    let remove = env.with_roles(env.remove_supported_token_ix(&env.buyer.pubkey(), &other_mint));
+    env.process(&[remove], &[&env.buyer]).await.unwrap();
+    assert!(!env.supported_token(&other_mint).await.is_supported);
+}
+
+#[tokio::test]
+async fn refund_role_holder_refunds_order() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    let refund_operator = Keypair::new();
+    env.grant_roles(&refund_operator.pubkey(), &[Role::RefundOperator])
+        .await;
+    let mint = env.mint;
+    let refund_source_token = env.add_token_account(&mint, &refund_operator.pubkey(), 40_000);
+    let ix = env.with_roles(env.refund_ix(
+        &refund_operator.pubkey(),
+        &refund_source_token,
+        "order-1",
+        40_000,
+    ));
+
+    env.process(&[ix], &[&refund_operator]).await.unwrap();
+
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 40_000);
+}
+
+// SetMultisig
+
+#[tokio::test]
+async fn multisig_requires_threshold_of_signers() {
+    let env = TestEnv::initialized().await;
+    let members = [Keypair::new(), Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1], &members[2]], 2)
+        .await;
+    let update = |signer: &Keypair| {
+        env.admin_ix(
+            &signer.pubkey(),
+            PaymentInstruction::UpdateRefundOperator {
+                new_refund_operator: env.buyer.pubkey(),
+            },
+        )
+    };
+
+    // The authority alone no longer suffices
+    let result = env.process(&[update(&env.authority)], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    // Neither does a single member, even listed twice
+    let ix = env.with_signers(update(&members[0]), &[&members[0]]);
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    // A member listed without signing does not count
+    let mut ix = env.with_signers(update(&members[0]), &[&members[1]]);
+    ix.accounts[2].is_signer = false;
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    let ix = env.with_signers(update(&members[0]), &[&members[2]]);
+    env.process(&[ix], &[&members[0], &members[2]])
+        .await
+        .unwrap();
+
+    assert_eq!(env.config().await.refund_operator, env.buyer.pubkey());
+}
+
+#[tokio::test]
+async fn set_multisig_records_signers() {
+    let env = TestEnv::initialized().await;
+    let members = [Keypair::new(), Keypair::new()];
+
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let config = env.config().await;
+    assert_eq!(
+        config.multisig_signers,
+        vec![members[0].pubkey(), members[1].pubkey()]
+    );
+    assert_eq!(config.multisig_threshold, 2);
+    assert!(config.is_admin(&members[0].pubkey()));
+    assert!(!config.is_admin(&env.authority.pubkey()));
+}
+
+#[tokio::test]
+async fn set_multisig_rejects_invalid_multisig() {
+    let env = TestEnv::initialized().await;
+    let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let too_many: Vec<Pubkey> = (0..=PaymentConfig::MAX_MULTISIG_SIGNERS)
+        .map(|_| Pubkey::new_unique())
+        .collect();
+    let invalid: [(&[Pubkey], u8); 6] = [
+        (&[first, second], 3),
+        (&[], 1),
+        (&[first], 0),
+        (&[first, first], 2),
+        (&[first, Pubkey::default()], 1),
+        (&too_many, 2),
+    ];
+
+    for (signers, threshold) in invalid {
+        let ix = env.set_multisig_ix(&env.authority.pubkey(), signers, threshold);
+        let result = env.process(&[ix], &[&env.authority]).await;
+        assert_payment_error(result, PaymentError::InvalidMultisig);
+    }
+
+    assert_eq!(env.config().await.multisig_threshold, 0);
+}
+
+#[tokio::test]
+async fn set_multisig_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.set_multisig_ix(&env.buyer.pubkey(), &[env.buyer.pubkey()], 1);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn set_multisig_disabled_restores_authority() {
+    let env = TestEnv::initialized().await;
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let ix = env.with_signers(
+        env.set_multisig_ix(&members[0].pubkey(), &[], 0),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    assert!(env.config().await.is_paused);
+}
+
+#[tokio::test]
+async fn multisig_gates_payout_wallet_changes() {
+    let mut env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::TreasuryManager])
+        .await;
+    // The authority pays for the pending change as one of the members
+    let member = Keypair::new();
+    env.set_multisig(&[&env.authority, &member], 2).await;
+    let new_payout_wallet = Pubkey::new_unique();
+    let change = ConfigChange::PayoutWallet {
+        merchant: env.merchant_account,
+        new_payout_wallet,
+    };
+
+    // The treasury manager can no longer queue payout wallet changes
+    let ix = env.with_roles(env.queue_config_change_ix(&env.buyer.pubkey(), change));
+    let result = env.process(&[ix], &[&env.buyer]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    let ix = env.queue_config_change_ix(&env.authority.pubkey(), change);
+    let result = env.process(&[ix], &[&env.authority]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    let ix = env.with_signers(
+        env.queue_config_change_ix(&env.authority.pubkey(), change),
+        &[&member],
+    );
+    env.process(&[ix], &[&env.authority, &member])
+        .await
+        .unwrap();
+    env.advance_clock(PaymentConfig::DEFAULT_CHANGE_DELAY_SECS as i64)
+        .await;
+    let ix = env.execute_config_change_ix(&change, &env.authority.pubkey());
+    env.process(&[ix], &[]).await.unwrap();
+
+    assert_eq!(env.merchant_state().await.payout_wallet, new_payout_wallet);
+}
+
+#[tokio::test]
+async fn multisig_gates_escrow_settlement_and_refunds() {
+    let mut env = TestEnv::initialized().await;
+    env.pay(&env.payment("order-1", 100_000)).await.unwrap();
+    env.pay_into_escrow("order-2", 100_000).await;
+    env.pay_into_escrow("order-3", 100_000).await;
+    env.open_dispute("order-3").await;
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+    let authority = env.authority.insecure_clone();
+
+    // The bare authority can no longer refund, release or resolve disputes
+    let result = env.refund(&authority, "order-1", 100_000).await;
+    assert_payment_error(result.map(|_| ()), PaymentError::NotAuthorized);
+    let ix = env.release_escrow_ix(&authority.pubkey(), "order-2");
+    let result = env.process(&[ix], &[&authority]).await;
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    let ix = env.resolve_dispute_ix(&authority.pubkey(), "order-3", false);
+    let result = env.process(&[ix], &[&authority]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    // Nor can a single member
+    let ix = env.release_escrow_ix(&members[0].pubkey(), "order-2");
+    let result = env.process(&[ix], &[&members[0]]).await;
+    assert_payment_error(result, PaymentError::MultisigThresholdNotMet);
+
+    let ix = env.with_signers(
+        env.release_escrow_ix(&members[0].pubkey(), "order-2"),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+    let ix = env.with_signers(
+        env.resolve_dispute_ix(&members[0].pubkey(), "order-3", false),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+    let mint = env.mint;
+    let refund_source_token = env.add_token_account(&mint, &members[0].pubkey(), 100_000);
+    let ix = env.with_signers(
+        env.refund_ix(&members[0].pubkey(), &refund_source_token, "order-1", 100_000),
+        &[&members[1]],
+    );
+    env.process(&[ix], &[&members[0], &members[1]])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        env.processed_order("order-2").await.status,
+        OrderStatus::Released
+    );
+    assert_eq!(
+        env.processed_order("order-3").await.status,
+        OrderStatus::ReturnedToBuyer
+    );
+    assert_eq!(env.processed_order("order-1").await.refunded_amount, 100_000);
+}
+
+#[tokio::test]
+async fn multisig_keeps_role_holders() {
+    let env = TestEnv::initialized().await;
+    env.grant_roles(&env.buyer.pubkey(), &[Role::Pauser]).await;
+    let members = [Keypair::new(), Keypair::new()];
+    env.set_multisig(&[&members[0], &members[1]], 2).await;
+
+    let pause = env.with_roles(env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause));
+    env.process(&[pause], &[&env.buyer]).await.unwrap();
+
+    assert!(env.config().await.is_paused);
+}
+
+// Pause / Unpause
+
+#[tokio::test]
+async fn pause_and_unpause() {
+    let env = TestEnv::initialized().await;
+
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    assert!(env.config().await.is_paused);
+
+    let unpause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Unpause);
+    env.process(&[unpause], &[&env.authority]).await.unwrap();
+    assert!(!env.config().await.is_paused);
+
+    env.pay(&env.payment("order-1", 1_000)).await.unwrap();
+}
+
+#[tokio::test]
+async fn pause_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Pause);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+}
+
+#[tokio::test]
+async fn pause_requires_authority_signature() {
+    let env = TestEnv::initialized().await;
+    let mut ix = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    ix.accounts[0].is_signer = false;
+
+    let result = env.process(&[ix], &[]).await;
+
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn unpause_rejects_unauthorized_caller() {
+    let env = TestEnv::initialized().await;
+    let pause = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    env.process(&[pause], &[&env.authority]).await.unwrap();
+    let ix = env.admin_ix(&env.buyer.pubkey(), PaymentInstruction::Unpause);
+
+    let result = env.process(&[ix], &[&env.buyer]).await;
+
+    assert_payment_error(result, PaymentError::NotAuthorized);
+    assert!(env.config().await.is_paused);
+}
+
+#[tokio::test]
+async fn admin_rejects_config_not_owned_by_program() {
+    let mut env = TestEnv::initialized().await;
+    let data = env.account_data(&env.config_address()).await.unwrap();
+    let fake_config = Pubkey::new_unique();
+    env.set_account(&fake_config, &Pubkey::new_unique(), data);
+    let mut ix = env.admin_ix(&env.authority.pubkey(), PaymentInstruction::Pause);
+    ix.accounts[1].pubkey = fake_config;
+
+    let result = env.process(&[ix], &[&env.authority]).await;
+
+    assert_payment_error(result, PaymentError::InvalidAccountOwner);
+}